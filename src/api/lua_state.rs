@@ -1,3 +1,5 @@
+use crate::state::closure::RustFn;
+
 pub trait LuaAPI {
     /* basic stack manipulation */
     fn get_top(&self) -> usize;
@@ -31,12 +33,16 @@ pub trait LuaAPI {
     fn to_numberx(&self, idx: isize) -> Option<f64>;
     fn to_string(&self, idx: isize) -> String;
     fn to_stringx(&self, idx: isize) -> Option<String>;
+    fn to_bytes(&self, idx: isize) -> Vec<u8>;
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>>;
+    fn to_pointer(&self, idx: isize) -> usize;
     /* push functions (rust -> stack) */
     fn push_nil(&mut self);
     fn push_boolean(&mut self, b: bool);
     fn push_integer(&mut self, n: i64);
     fn push_number(&mut self, n: f64);
     fn push_string(&mut self, s: String);
+    fn push_bytes(&mut self, s: Vec<u8>);
     /* comparison and arithmetic functions */
     fn arith(&mut self, op: u8);
     fn compare(&self, idx1: isize, idx2: isize, op: u8) -> bool;
@@ -53,4 +59,20 @@ pub trait LuaAPI {
     fn set_table(&mut self, idx: isize);
     fn set_field(&mut self, idx: isize, k: &str);
     fn set_i(&mut self, idx: isize, i: i64);
+    /* 'load' and 'call' functions (load and run Lua code) */
+    fn call(&mut self, n_args: usize, n_results: isize);
+    // calls as call() does, but an error leaves its value in place of
+    // the function and its arguments, and gives false
+    fn pcall(&mut self, n_args: usize, n_results: isize) -> bool;
+    /* miscellaneous functions */
+    fn error(&mut self) -> !;
+    fn push_rust_function(&mut self, f: RustFn);
+    fn push_global_table(&mut self);
+    fn get_global(&mut self, name: &str) -> i8;
+    fn set_global(&mut self, name: &str);
+    fn register(&mut self, name: &str, f: RustFn);
+    fn get_metatable(&mut self, idx: isize) -> bool;
+    fn set_metatable(&mut self, idx: isize);
+    fn next(&mut self, idx: isize) -> bool;
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool;
 }
\ No newline at end of file
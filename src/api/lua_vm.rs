@@ -4,4 +4,25 @@ pub trait LuaVM {
     fn fetch(&mut self) -> u32;
     fn get_const(&mut self, idx: isize);
     fn get_rk(&mut self, rk: isize);
+    fn mark_to_be_closed(&mut self, idx: isize);
+    // closes the upvalues of the registers from `idx` up
+    fn close(&mut self, idx: isize);
+    // copies `n` varargs to the registers from `idx`, or all of them,
+    // with the top just after, when `n` is negative
+    fn load_vararg(&mut self, idx: isize, n: isize);
+    // returns from the running function the `n` values from `idx` up
+    fn post_call(&mut self, idx: isize, n: usize);
+    // the registers of the running function
+    fn register_count(&self) -> usize;
+    // enters the function below the `n_args` values at the top, which
+    // is to leave `n_results` values in its place, or all of them
+    // when negative
+    fn precall(&mut self, n_args: usize, n_results: isize);
+    fn tail_call(&mut self, idx: isize, n_args: usize);
+    // pushes the value of upvalue `idx` of the running function
+    fn get_upval(&mut self, idx: isize);
+    // pops a value into upvalue `idx` of the running function
+    fn set_upval(&mut self, idx: isize);
+    // puts a new closure of nested prototype `bx` in register `idx`
+    fn load_proto(&mut self, idx: isize, bx: usize);
 }
\ No newline at end of file
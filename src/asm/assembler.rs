@@ -0,0 +1,374 @@
+use std::rc::Rc;
+
+use crate::binchunk::binary_chunk::*;
+use crate::binchunk::header_const;
+use crate::vm::encode_error::EncodeError;
+use crate::vm::instruction::{rk_ask, Instruction, MAXINDEXRK};
+use crate::vm::opcodes::{OpArgMode, OpMode, OPCODES};
+
+use super::asm_error::AsmError;
+
+struct FuncState {
+    line: usize, // where the function starts, for error messages
+    proto: Prototype,
+    max_stack_size: Option<u8>,
+    max_reg: i64,
+    has_lines: bool,
+}
+
+impl FuncState {
+    fn new(line: usize, source: String, line_defined: u32, last_line_defined: u32, is_vararg: u8) -> FuncState {
+        FuncState {
+            line,
+            proto: Prototype {
+                version: header_const::LUAC_VERSION,
+                source,
+                line_defined,
+                last_line_defined,
+                num_params: 0,
+                is_vararg,
+                max_stack_size: 0,
+                code: Vec::new(),
+                constants: Vec::new(),
+                upvalues: Vec::new(),
+                protos: Vec::new(),
+                line_info: Vec::new(),
+                abs_line_info: Vec::new(),
+                loc_vars: Vec::new(),
+                upvalue_names: Vec::new(),
+            },
+            max_stack_size: None,
+            max_reg: -1,
+            has_lines: false,
+        }
+    }
+
+    fn finish(mut self) -> Box<Prototype> {
+        // without `.stack`, reserve up to the highest register mentioned
+        self.proto.max_stack_size = self.max_stack_size.unwrap_or((self.max_reg + 1).clamp(2, 255) as u8);
+        if !self.has_lines {
+            self.proto.line_info.clear();
+        }
+        Box::new(self.proto)
+    }
+}
+
+// The main function is implicit; `.function` ... `.end` nests a child,
+// which CLOSURE refers to by the order it was declared in.
+pub fn assemble(src: &str) -> Result<Box<Prototype>, AsmError> {
+    let mut stack = vec![FuncState::new(0, String::from("=asm"), 0, 0, 1)];
+    for (n, raw) in src.lines().enumerate() {
+        let line = n + 1;
+        let text = strip_comment(raw).trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text.starts_with('.') {
+            let (directive, rest) = split_word(text);
+            let rest = rest.trim();
+            match directive {
+                ".function" => {
+                    let args = parse_ints(line, rest)?;
+                    if args.len() > 2 {
+                        return Err(AsmError::new(line, "usage: .function [linedefined [lastlinedefined]]"));
+                    }
+                    let source = stack.last().unwrap().proto.source.clone();
+                    let line_defined = to_u32(line, args.first().copied().unwrap_or(0))?;
+                    let last_line_defined = to_u32(line, args.get(1).copied().unwrap_or(0))?;
+                    stack.push(FuncState::new(line, source, line_defined, last_line_defined, 0));
+                }
+                ".end" => {
+                    expect_no_args(line, directive, rest)?;
+                    if stack.len() == 1 {
+                        return Err(AsmError::new(line, ".end without .function"));
+                    }
+                    let fs = stack.pop().unwrap();
+                    stack.last_mut().unwrap().proto.protos.push(Rc::from(fs.finish()));
+                }
+                _ => directive_line(stack.last_mut().unwrap(), line, directive, rest)?,
+            }
+        } else {
+            instruction_line(stack.last_mut().unwrap(), line, text)?;
+        }
+    }
+    if stack.len() > 1 {
+        return Err(AsmError::new(stack.last().unwrap().line, ".function without .end"));
+    }
+    Ok(stack.pop().unwrap().finish())
+}
+
+fn directive_line(fs: &mut FuncState, line: usize, directive: &str, rest: &str) -> Result<(), AsmError> {
+    let f = &mut fs.proto;
+    match directive {
+        ".source" => f.source = String::from_utf8_lossy(&parse_string(line, rest)?).into_owned(),
+        ".params" => f.num_params = to_u8(line, single_int(line, directive, rest)?)?,
+        ".stack" => fs.max_stack_size = Some(to_u8(line, single_int(line, directive, rest)?)?),
+        ".vararg" => {
+            expect_no_args(line, directive, rest)?;
+            f.is_vararg = 1;
+        }
+        ".const" => f.constants.push(parse_constant(line, rest)?),
+        ".upvalue" => {
+            // .upvalue <name> <instack> <idx>, as in the `upvalues` section of a listing
+            let (name, rest) = split_word(rest);
+            let args = parse_ints(line, rest)?;
+            if name.is_empty() || args.len() != 2 {
+                return Err(AsmError::new(line, "usage: .upvalue <name> <instack> <idx>"));
+            }
+            f.upvalues.push(Upvalue { instack: to_u8(line, args[0])?, idx: to_u8(line, args[1])?, kind: 0 });
+            f.upvalue_names.push(String::from(name));
+        }
+        ".local" => {
+            // .local <name> <startpc> <endpc>, with 1-based pcs as in a listing
+            let (name, rest) = split_word(rest);
+            let args = parse_ints(line, rest)?;
+            if name.is_empty() || args.len() != 2 || args[0] < 1 || args[1] < 1 {
+                return Err(AsmError::new(line, "usage: .local <name> <startpc> <endpc>"));
+            }
+            f.loc_vars.push(LocVar {
+                var_name: String::from(name),
+                start_pc: to_u32(line, args[0] - 1)?,
+                end_pc: to_u32(line, args[1] - 1)?,
+            });
+        }
+        _ => return Err(AsmError::new(line, format!("unknown directive '{}'", directive))),
+    }
+    Ok(())
+}
+
+// [pc] [[line]] OPNAME operands..., where pc and line are optional so that
+// the code section of a listing can be pasted as is
+fn instruction_line(fs: &mut FuncState, line: usize, text: &str) -> Result<(), AsmError> {
+    let mut words = text.split_whitespace().peekable();
+    if words.peek().is_some_and(|w| w.bytes().all(|b| b.is_ascii_digit())) {
+        words.next();
+    }
+    let mut src_line = None;
+    if let Some(w) = words.peek().and_then(|w| w.strip_prefix('[')) {
+        let w = w.strip_suffix(']').ok_or_else(|| AsmError::new(line, "unterminated line number"))?;
+        if w != "-" {
+            src_line = Some(w.parse::<u32>().map_err(|_| AsmError::new(line, format!("bad line number '{}'", w)))?);
+        }
+        words.next();
+    }
+    let name = words.next().ok_or_else(|| AsmError::new(line, "missing opcode"))?;
+    let op = OPCODES.iter()
+        .position(|o| o.name.trim_end().eq_ignore_ascii_case(name))
+        .ok_or_else(|| AsmError::new(line, format!("unknown opcode '{}'", name)))?;
+    let operands = words
+        .map(|w| parse_int(w).ok_or_else(|| AsmError::new(line, format!("bad operand '{}'", w))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let i = encode(fs, line, op, &operands)?;
+    fs.proto.code.push(i);
+    if let Some(l) = src_line {
+        fs.has_lines = true;
+        fs.proto.line_info.push(l);
+    } else {
+        let last = fs.proto.line_info.last().copied().unwrap_or(fs.proto.line_defined);
+        fs.proto.line_info.push(last);
+    }
+    Ok(())
+}
+
+// operands are given the way `luac -l` prints them: fields in OpArgN mode
+// are left out and constants are written as -1-index
+fn encode(fs: &mut FuncState, line: usize, op: usize, operands: &[i64]) -> Result<u32, AsmError> {
+    let opcode = &OPCODES[op];
+    let name = opcode.name.trim_end();
+    let bad = |e: EncodeError| AsmError::new(line, format!("{}: {}", name, e));
+    let mut args = operands.iter().map(|&x| x as isize);
+    let mut next = |what: &str| {
+        args.next().ok_or_else(|| AsmError::new(line, format!("{}: missing operand {}", name, what)))
+    };
+    let mut reg = |x: isize| fs.max_reg = fs.max_reg.max(x as i64);
+
+    let i = match opcode.op_mode {
+        OpMode::IABC => {
+            let a = next("A")?;
+            reg(a);
+            let mut field = |what: &'static str, mode: OpArgMode| match mode {
+                OpArgMode::OpArgN => Ok(0),
+                OpArgMode::OpArgK => {
+                    let x = next(what)?;
+                    if x < 0 {
+                        return rk_ask(-1 - x).map_err(bad);
+                    }
+                    if x > MAXINDEXRK {
+                        // would be read back as a constant
+                        return Err(bad(EncodeError::OperandOutOfRange { field: what, value: x, max: MAXINDEXRK }));
+                    }
+                    reg(x);
+                    Ok(x)
+                }
+                OpArgMode::OpArgR => {
+                    let x = next(what)?;
+                    reg(x);
+                    Ok(x)
+                }
+                OpArgMode::OpArgU => next(what),
+            };
+            let b = field("B", opcode.arg_b_mode)?;
+            let c = field("C", opcode.arg_c_mode)?;
+            u32::encode_abc(op as u8, a, b, c)
+        }
+        OpMode::IABx => {
+            let a = next("A")?;
+            reg(a);
+            let bx = match opcode.arg_b_mode {
+                OpArgMode::OpArgN => 0,
+                OpArgMode::OpArgK => -1 - next("Bx")?,
+                _ => next("Bx")?,
+            };
+            u32::encode_abx(op as u8, a, bx)
+        }
+        OpMode::IAsBx => {
+            let a = next("A")?;
+            u32::encode_asbx(op as u8, a, next("sBx")?)
+        }
+        OpMode::IAx => {
+            // EXTRAARG is listed as -1-Ax; plain values are taken as is
+            let x = next("Ax")?;
+            u32::encode_ax(op as u8, if x < 0 { -1 - x } else { x })
+        }
+    }.map_err(bad)?;
+    if args.next().is_some() {
+        return Err(AsmError::new(line, format!("{}: too many operands", name)));
+    }
+    Ok(i)
+}
+
+fn parse_constant(line: usize, text: &str) -> Result<Constant, AsmError> {
+    match text {
+        "nil" => return Ok(Constant::Nil),
+        "true" => return Ok(Constant::Boolean(true)),
+        "false" => return Ok(Constant::Boolean(false)),
+        _ => (),
+    }
+    if text.starts_with('"') {
+        return Ok(Constant::Str(parse_string(line, text)?));
+    }
+    if let Some(i) = parse_int(text) {
+        return Ok(Constant::Integer(i));
+    }
+    match text.parse::<f64>() {
+        Ok(n) => Ok(Constant::Number(n)),
+        Err(_) => Err(AsmError::new(line, format!("bad constant '{}'", text))),
+    }
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (neg, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let n = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => digits.parse::<i64>().ok()?,
+        None => return None,
+    };
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+fn parse_ints(line: usize, text: &str) -> Result<Vec<i64>, AsmError> {
+    text.split_whitespace()
+        .map(|w| parse_int(w).ok_or_else(|| AsmError::new(line, format!("bad number '{}'", w))))
+        .collect()
+}
+
+fn single_int(line: usize, directive: &str, text: &str) -> Result<i64, AsmError> {
+    match parse_ints(line, text)?[..] {
+        [n] => Ok(n),
+        _ => Err(AsmError::new(line, format!("{} takes one number", directive))),
+    }
+}
+
+fn expect_no_args(line: usize, directive: &str, text: &str) -> Result<(), AsmError> {
+    if !text.is_empty() {
+        return Err(AsmError::new(line, format!("{} takes no arguments", directive)));
+    }
+    Ok(())
+}
+
+fn to_u8(line: usize, n: i64) -> Result<u8, AsmError> {
+    u8::try_from(n).map_err(|_| AsmError::new(line, format!("{} out of range", n)))
+}
+
+fn to_u32(line: usize, n: i64) -> Result<u32, AsmError> {
+    u32::try_from(n).map_err(|_| AsmError::new(line, format!("{} out of range", n)))
+}
+
+// a double-quoted string with C-like escapes, \xXX and \ddd included
+fn parse_string(line: usize, text: &str) -> Result<Vec<u8>, AsmError> {
+    let bad = |msg: &str| AsmError::new(line, msg);
+    let body = text.strip_prefix('"').ok_or_else(|| bad("expected a string"))?;
+    let mut bytes = body.bytes();
+    let mut s = Vec::new();
+    loop {
+        let b = bytes.next().ok_or_else(|| bad("unfinished string"))?;
+        match b {
+            b'"' => break,
+            b'\\' => {
+                let e = bytes.next().ok_or_else(|| bad("unfinished string"))?;
+                s.push(match e {
+                    b'n' => b'\n',
+                    b't' => b'\t',
+                    b'r' => b'\r',
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'f' => 0x0c,
+                    b'v' => 0x0b,
+                    b'\\' | b'"' | b'\'' => e,
+                    b'x' => {
+                        let hex = [bytes.next(), bytes.next()];
+                        let hex = hex.iter().flatten().map(|&b| b as char).collect::<String>();
+                        u8::from_str_radix(&hex, 16).map_err(|_| bad("bad \\x escape"))?
+                    }
+                    b'0'..=b'9' => {
+                        let mut n = (e - b'0') as u32;
+                        for _ in 0..2 {
+                            match bytes.clone().next() {
+                                Some(d @ b'0'..=b'9') => {
+                                    n = n * 10 + (d - b'0') as u32;
+                                    bytes.next();
+                                }
+                                _ => break,
+                            }
+                        }
+                        u8::try_from(n).map_err(|_| bad("decimal escape too large"))?
+                    }
+                    _ => return Err(bad("invalid escape sequence")),
+                });
+            }
+            _ => s.push(b),
+        }
+    }
+    if bytes.next().is_some() {
+        return Err(bad("unexpected text after string"));
+    }
+    Ok(s)
+}
+
+// `;` starts a comment, as in the annotations `luac -l` appends
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => (),
+        }
+    }
+    text
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], &text[i..]),
+        None => (text, ""),
+    }
+}
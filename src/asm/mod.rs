@@ -0,0 +1,12 @@
+pub mod asm_error;
+mod assembler;
+
+use crate::binchunk::binary_chunk::Prototype;
+use asm_error::AsmError;
+
+// builds a prototype from a listing in the format `luac -l` prints,
+// with directives (`.const`, `.upvalue`, `.function` ...) for the parts
+// a listing only describes
+pub fn assemble(src: &str) -> Result<Box<Prototype>, AsmError> {
+    assembler::assemble(src)
+}
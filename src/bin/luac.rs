@@ -0,0 +1,6 @@
+use lua_engine::cli;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    std::process::exit(cli::luac::main(&args));
+}
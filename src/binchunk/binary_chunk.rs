@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 pub struct Header {
     pub signature: [u8;4],
     pub version: u8,
@@ -12,7 +14,9 @@ pub struct Header {
     pub luac_num: f64,
 }
 
+#[derive(Clone, Default)]
 pub struct Prototype {
+    pub version: u8, // LUAC_VERSION of the chunk this prototype was loaded from
     pub source: String,
     pub line_defined: u32,
     pub last_line_defined: u32,
@@ -22,8 +26,9 @@ pub struct Prototype {
     pub code: Vec<u32>,
     pub constants: Vec<Constant>,
     pub upvalues: Vec<Upvalue>,
-    pub protos: Vec<Box<Prototype>>,
-    pub line_info: Vec<u32>,
+    pub protos: Vec<Rc<Prototype>>, // shared with the closures made from them
+    pub line_info: Vec<u32>, // absolute line of each instruction
+    pub abs_line_info: Vec<AbsLineInfo>, // 5.4 only
     pub loc_vars: Vec<LocVar>,
     pub upvalue_names: Vec<String>,
 }
@@ -34,19 +39,29 @@ pub struct BinaryChunk {
     pub main_func: Box<Prototype>
 }
 
+#[derive(Clone)]
 pub enum Constant {
     Nil,
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String)
+    Str(Vec<u8>) // Lua strings are arbitrary bytes
 }
 
+#[derive(Clone)]
 pub struct Upvalue {
     pub instack: u8,
-    pub idx: u8
+    pub idx: u8,
+    pub kind: u8 // 5.4 only
+}
+
+#[derive(Clone)]
+pub struct AbsLineInfo {
+    pub pc: u32,
+    pub line: u32
 }
 
+#[derive(Clone)]
 pub struct LocVar {
     pub var_name: String,
     pub start_pc: u32,
@@ -0,0 +1,52 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkError {
+    BadSignature,
+    VersionMismatch { expected: u8, found: u8 },
+    FormatMismatch { expected: u8, found: u8 },
+    Corrupted,
+    SizeMismatch { what: &'static str, expected: u8, found: u8 },
+    UnsupportedSize { what: &'static str, found: u8 },
+    EndiannessMismatch,
+    FloatFormatMismatch,
+    UnknownConstantTag { tag: u8, offset: usize },
+    UnexpectedEof { offset: usize },
+    TooDeep { limit: usize },
+    Io(io::ErrorKind),
+    Untranslatable { pc: usize, reason: &'static str },
+    Unrepresentable { pc: usize, reason: &'static str },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::BadSignature => write!(f, "not a precompiled chunk"),
+            ChunkError::VersionMismatch { expected, found } => {
+                write!(f, "version mismatch (expected 0x{:02x}, found 0x{:02x})", expected, found)
+            }
+            ChunkError::FormatMismatch { expected, found } => {
+                write!(f, "format mismatch (expected {}, found {})", expected, found)
+            }
+            ChunkError::Corrupted => write!(f, "corrupted chunk"),
+            ChunkError::SizeMismatch { what, expected, found } => {
+                write!(f, "{} size mismatch (expected {}, found {})", what, expected, found)
+            }
+            ChunkError::UnsupportedSize { what, found } => write!(f, "unsupported {} size {}", what, found),
+            ChunkError::EndiannessMismatch => write!(f, "endianness mismatch"),
+            ChunkError::FloatFormatMismatch => write!(f, "float format mismatch"),
+            ChunkError::UnknownConstantTag { tag, offset } => {
+                write!(f, "unknown constant tag 0x{:02x} at offset {}", tag, offset)
+            }
+            ChunkError::UnexpectedEof { offset } => write!(f, "truncated chunk (unexpected end of data at offset {})", offset),
+            ChunkError::TooDeep { limit } => write!(f, "functions nested more than {} levels deep", limit),
+            ChunkError::Io(kind) => write!(f, "cannot read chunk: {}", kind),
+            ChunkError::Untranslatable { pc, reason } => write!(f, "cannot translate instruction {}: {}", pc + 1, reason),
+            ChunkError::Unrepresentable { pc, reason } => write!(f, "cannot dump instruction {}: {}", pc + 1, reason),
+        }
+    }
+}
+
+impl Error for ChunkError {}
@@ -9,3 +9,6 @@ pub const LUA_INTEGER_SIZE: u8 = 8;
 pub const LUA_NUMBER_SIZE: u8 = 8;
 pub const LUAC_INT: i64 = 0x5678;
 pub const LUAC_NUM: f64 = 370.5;
+
+pub const LUAC_VERSION_51: u8 = 0x51;
+pub const LUAC_VERSION_54: u8 = 0x54;
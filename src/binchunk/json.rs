@@ -0,0 +1,324 @@
+use std::fmt::Write;
+
+use super::json_error::JsonError;
+
+// Objects keep their keys in insertion order so that the output is stable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    // arrays and objects holding only scalars fit on one line
+    fn is_flat(&self) -> bool {
+        match self {
+            Json::Array(items) => items.iter().all(|v| !matches!(v, Json::Array(_) | Json::Object(_))),
+            Json::Object(fields) => fields.iter().all(|(_, v)| !matches!(v, Json::Array(_) | Json::Object(_))),
+            _ => true,
+        }
+    }
+}
+
+pub fn write(value: &Json) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, 0);
+    out.push('\n');
+    out
+}
+
+fn write_value(out: &mut String, value: &Json, indent: usize) {
+    match value {
+        Json::Null => out.push_str("null"),
+        Json::Bool(b) => write!(out, "{}", b).unwrap(),
+        Json::Int(i) => write!(out, "{}", i).unwrap(),
+        // {:?} prints the shortest text that reads back as the same float
+        Json::Float(n) => write!(out, "{:?}", n).unwrap(),
+        Json::Str(s) => write_string(out, s),
+        Json::Array(items) => {
+            let flat = value.is_flat();
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                separate(out, flat, indent + 1, i == 0);
+                write_value(out, item, indent + 1);
+            }
+            if !items.is_empty() && !flat {
+                newline(out, indent);
+            }
+            out.push(']');
+        }
+        Json::Object(fields) => {
+            let flat = value.is_flat();
+            out.push('{');
+            for (i, (key, v)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                separate(out, flat, indent + 1, i == 0);
+                write_string(out, key);
+                out.push_str(": ");
+                write_value(out, v, indent + 1);
+            }
+            if !fields.is_empty() && !flat {
+                newline(out, indent);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn separate(out: &mut String, flat: bool, indent: usize, first: bool) {
+    if !flat {
+        newline(out, indent);
+    } else if !first {
+        out.push(' ');
+    }
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// arrays and objects deeper than this are rejected rather than recursed
+// into; a chunk takes two levels for each of the 200 levels of nested
+// functions it may have
+const MAX_NESTING: usize = 500;
+
+pub fn parse(text: &str) -> Result<Json, JsonError> {
+    let mut parser = Parser { data: text.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.data.len() {
+        return Err(parser.error("unexpected text after value"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &'static str) -> JsonError {
+        JsonError::Syntax { offset: self.pos, msg }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8, msg: &'static str) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(b) {
+            return Err(self.error(msg));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.parse_nested(Parser::parse_object),
+            Some(b'[') => self.parse_nested(Parser::parse_array),
+            Some(b'"') => Ok(Json::Str(self.parse_string()?)),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => {
+                for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+                    if self.data[self.pos..].starts_with(word.as_bytes()) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("unexpected character"))
+            }
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    // parses an array or object, one level deeper
+    fn parse_nested(&mut self, f: fn(&mut Parser<'a>) -> Result<Json, JsonError>) -> Result<Json, JsonError> {
+        if self.depth >= MAX_NESTING {
+            return Err(self.error("too deeply nested"));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_object(&mut self) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.parse_string()?;
+            self.expect(b':', "expected ':'")?;
+            fields.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let mut is_float = false;
+        while let Some(b) = self.peek() {
+            match b {
+                b'0'..=b'9' | b'-' | b'+' => (),
+                b'.' | b'e' | b'E' => is_float = true,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.data[start..self.pos]).unwrap();
+        if !is_float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Json::Int(i));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(n) if text.bytes().any(|b| b.is_ascii_digit()) => Ok(Json::Float(n)),
+            _ => Err(JsonError::Syntax { offset: start, msg: "malformed number" }),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            let b = self.peek().ok_or_else(|| self.error("unfinished string"))?;
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let e = self.peek().ok_or_else(|| self.error("unfinished string"))?;
+                    self.pos += 1;
+                    let c = match e {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.parse_unicode_escape()?,
+                        _ => return Err(self.error("invalid escape sequence")),
+                    };
+                    let mut buf = [0; 4];
+                    s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b if b < 0x20 => return Err(self.error("control character in string")),
+                b => s.push(b),
+            }
+        }
+        // the input is a &str, so whatever was copied through is UTF-8
+        Ok(String::from_utf8(s).unwrap())
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, JsonError> {
+        let hi = self.parse_hex4()?;
+        if !(0xd800..0xdc00).contains(&hi) {
+            return char::from_u32(hi).ok_or_else(|| self.error("invalid \\u escape"));
+        }
+        // a surrogate pair
+        if !self.data[self.pos..].starts_with(b"\\u") {
+            return Err(self.error("unpaired surrogate"));
+        }
+        self.pos += 2;
+        let lo = self.parse_hex4()?;
+        if !(0xdc00..0xe000).contains(&lo) {
+            return Err(self.error("unpaired surrogate"));
+        }
+        char::from_u32(0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)).ok_or_else(|| self.error("invalid \\u escape"))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self.data.get(self.pos..self.pos + 4).ok_or_else(|| self.error("invalid \\u escape"))?;
+        let n = std::str::from_utf8(hex).ok()
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(n)
+    }
+}
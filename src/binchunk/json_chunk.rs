@@ -0,0 +1,409 @@
+use std::rc::Rc;
+
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::{OpMode, OPCODES};
+use crate::vm::opcodes_54::*;
+
+use super::binary_chunk::*;
+use super::header_const;
+use super::json::Json;
+use super::json_error::JsonError;
+
+// bumped whenever a key is renamed, removed or changes meaning
+const SCHEMA_VERSION: i64 = 1;
+
+pub fn chunk_to_json(chunk: &BinaryChunk) -> Json {
+    let h = &chunk.header;
+    Json::Object(vec![
+        field("schema", Json::Int(SCHEMA_VERSION)),
+        field("header", Json::Object(vec![
+            field("signature", Json::Str(to_hex(&h.signature))),
+            field("version", Json::Int(h.version as i64)),
+            field("format", Json::Int(h.format as i64)),
+            field("luac_data", Json::Str(to_hex(&h.luac_data))),
+            field("cint_size", Json::Int(h.cint_size as i64)),
+            field("sizet_size", Json::Int(h.sizet_size as i64)),
+            field("instruction_size", Json::Int(h.instruction_size as i64)),
+            field("lua_integer_size", Json::Int(h.lua_integer_size as i64)),
+            field("lua_number_size", Json::Int(h.lua_number_size as i64)),
+            field("luac_int", Json::Int(h.luac_int)),
+            field("luac_num", float_to_json(h.luac_num)),
+        ])),
+        field("size_upvalues", Json::Int(chunk.size_upvalues as i64)),
+        field("main", proto_to_json(&chunk.main_func)),
+    ])
+}
+
+fn proto_to_json(f: &Prototype) -> Json {
+    let code = f.code.iter().enumerate().map(|(pc, &i)| {
+        let mut fields = vec![
+            field("pc", Json::Int(pc as i64)),
+            field("line", f.line_info.get(pc).map_or(Json::Null, |&l| Json::Int(l as i64))),
+            field("word", Json::Int(i as i64)),
+        ];
+        if f.version == header_const::LUAC_VERSION_54 {
+            fields.extend(operands_54_to_json(i));
+        } else {
+            fields.extend(operands_to_json(i));
+        }
+        Json::Object(fields)
+    }).collect();
+
+    let constants = f.constants.iter().map(|k| {
+        let (ty, value) = match k {
+            Constant::Nil => ("nil", None),
+            Constant::Boolean(b) => ("boolean", Some(Json::Bool(*b))),
+            Constant::Integer(i) => ("integer", Some(Json::Int(*i))),
+            Constant::Number(n) => ("number", Some(float_to_json(*n))),
+            Constant::Str(s) => match std::str::from_utf8(s) {
+                Ok(s) => ("string", Some(Json::Str(String::from(s)))),
+                Err(_) => {
+                    return Json::Object(vec![field("type", Json::Str(String::from("string"))), field("bytes", Json::Str(to_hex(s)))]);
+                }
+            },
+        };
+        let mut fields = vec![field("type", Json::Str(String::from(ty)))];
+        fields.extend(value.map(|v| field("value", v)));
+        Json::Object(fields)
+    }).collect();
+
+    let upvalues = f.upvalues.iter().enumerate().map(|(idx, upval)| Json::Object(vec![
+        field("name", f.upvalue_names.get(idx).map_or(Json::Null, |n| Json::Str(n.clone()))),
+        field("instack", Json::Int(upval.instack as i64)),
+        field("idx", Json::Int(upval.idx as i64)),
+        field("kind", Json::Int(upval.kind as i64)),
+    ])).collect();
+
+    let loc_vars = f.loc_vars.iter().map(|v| Json::Object(vec![
+        field("name", Json::Str(v.var_name.clone())),
+        field("start_pc", Json::Int(v.start_pc as i64)),
+        field("end_pc", Json::Int(v.end_pc as i64)),
+    ])).collect();
+
+    let abs_line_info = f.abs_line_info.iter().map(|info| Json::Object(vec![
+        field("pc", Json::Int(info.pc as i64)),
+        field("line", Json::Int(info.line as i64)),
+    ])).collect();
+
+    Json::Object(vec![
+        field("version", Json::Int(f.version as i64)),
+        field("source", Json::Str(f.source.clone())),
+        field("line_defined", Json::Int(f.line_defined as i64)),
+        field("last_line_defined", Json::Int(f.last_line_defined as i64)),
+        field("num_params", Json::Int(f.num_params as i64)),
+        field("is_vararg", Json::Int(f.is_vararg as i64)),
+        field("max_stack_size", Json::Int(f.max_stack_size as i64)),
+        field("code", Json::Array(code)),
+        field("constants", Json::Array(constants)),
+        field("upvalues", Json::Array(upvalues)),
+        field("loc_vars", Json::Array(loc_vars)),
+        field("abs_line_info", Json::Array(abs_line_info)),
+        field("protos", Json::Array(f.protos.iter().map(|p| proto_to_json(p)).collect())),
+    ])
+}
+
+// op, mode and operands, or nothing for opcodes out of the table
+fn operands_to_json(i: u32) -> Vec<(String, Json)> {
+    if (i.opcode() as usize) >= OPCODES.len() {
+        return Vec::new();
+    }
+    let mut fields = vec![field("op", Json::Str(String::from(i.opname().trim_end())))];
+    match i.opmode() {
+        OpMode::IABC => {
+            let (a, b, c) = i.abc();
+            fields.push(field("mode", Json::Str(String::from("iABC"))));
+            fields.push(field("a", Json::Int(a as i64)));
+            fields.push(field("b", Json::Int(b as i64)));
+            fields.push(field("c", Json::Int(c as i64)));
+        }
+        OpMode::IABx => {
+            let (a, bx) = i.a_bx();
+            fields.push(field("mode", Json::Str(String::from("iABx"))));
+            fields.push(field("a", Json::Int(a as i64)));
+            fields.push(field("bx", Json::Int(bx as i64)));
+        }
+        OpMode::IAsBx => {
+            let (a, sbx) = i.a_sbx();
+            fields.push(field("mode", Json::Str(String::from("iAsBx"))));
+            fields.push(field("a", Json::Int(a as i64)));
+            fields.push(field("sbx", Json::Int(sbx as i64)));
+        }
+        OpMode::IAx => {
+            fields.push(field("mode", Json::Str(String::from("iAx"))));
+            fields.push(field("ax", Json::Int(i.ax() as i64)));
+        }
+    }
+    fields
+}
+
+// the same for a 5.4 instruction, whose iABC form has the k flag and
+// which has an isJ form for jumps
+fn operands_54_to_json(i: u32) -> Vec<(String, Json)> {
+    let opcode = match i.opcode_54() {
+        Some(opcode) => opcode,
+        None => return Vec::new(),
+    };
+    let mut fields = vec![field("op", Json::Str(String::from(opcode.name)))];
+    match opcode.op_mode {
+        OpMode54::IABC => {
+            fields.push(field("mode", Json::Str(String::from("iABC"))));
+            fields.push(field("a", Json::Int(i.a_54() as i64)));
+            fields.push(field("k", Json::Bool(i.k_54())));
+            fields.push(field("b", Json::Int(i.b_54() as i64)));
+            fields.push(field("c", Json::Int(i.c_54() as i64)));
+        }
+        OpMode54::IABx => {
+            fields.push(field("mode", Json::Str(String::from("iABx"))));
+            fields.push(field("a", Json::Int(i.a_54() as i64)));
+            fields.push(field("bx", Json::Int(i.bx_54() as i64)));
+        }
+        OpMode54::IAsBx => {
+            fields.push(field("mode", Json::Str(String::from("iAsBx"))));
+            fields.push(field("a", Json::Int(i.a_54() as i64)));
+            fields.push(field("sbx", Json::Int(i.sbx_54() as i64)));
+        }
+        OpMode54::IAx => {
+            fields.push(field("mode", Json::Str(String::from("iAx"))));
+            fields.push(field("ax", Json::Int(i.ax_54() as i64)));
+        }
+        OpMode54::IsJ => {
+            fields.push(field("mode", Json::Str(String::from("isJ"))));
+            fields.push(field("sj", Json::Int(i.sj_54() as i64)));
+        }
+    }
+    fields
+}
+
+pub fn chunk_from_json(value: &Json) -> Result<BinaryChunk, JsonError> {
+    if get_int(value, "", "schema")? != SCHEMA_VERSION {
+        return Err(invalid("schema", "unsupported schema version"));
+    }
+    let h = get(value, "", "header")?;
+    let header = Header {
+        signature: get_hex(h, "header", "signature")?.try_into().map_err(|_| invalid("header.signature", "expected 4 bytes"))?,
+        version: get_uint(h, "header", "version")?,
+        format: get_uint(h, "header", "format")?,
+        luac_data: get_hex(h, "header", "luac_data")?.try_into().map_err(|_| invalid("header.luac_data", "expected 6 bytes"))?,
+        cint_size: get_uint(h, "header", "cint_size")?,
+        sizet_size: get_uint(h, "header", "sizet_size")?,
+        instruction_size: get_uint(h, "header", "instruction_size")?,
+        lua_integer_size: get_uint(h, "header", "lua_integer_size")?,
+        lua_number_size: get_uint(h, "header", "lua_number_size")?,
+        luac_int: get_int(h, "header", "luac_int")?,
+        luac_num: get_float(h, "header", "luac_num")?,
+    };
+    Ok(BinaryChunk {
+        header,
+        size_upvalues: get_uint(value, "", "size_upvalues")?,
+        main_func: Box::new(proto_from_json(get(value, "", "main")?, "main")?),
+    })
+}
+
+pub fn proto_from_json(value: &Json, path: &str) -> Result<Prototype, JsonError> {
+    let version = get_uint(value, path, "version")?;
+    let mut code = Vec::new();
+    let mut line_info = Vec::new();
+    for (pc, ins) in get_array(value, path, "code")?.iter().enumerate() {
+        let path = format!("{}.code[{}]", path, pc);
+        code.push(instruction_from_json(ins, &path, version)?);
+        match get(ins, &path, "line")? {
+            Json::Null => (),
+            _ => line_info.push(get_uint(ins, &path, "line")?),
+        }
+    }
+    if !line_info.is_empty() && line_info.len() != code.len() {
+        return Err(invalid(&format!("{}.code", path), "line given for some instructions only"));
+    }
+
+    let mut constants = Vec::new();
+    for (idx, k) in get_array(value, path, "constants")?.iter().enumerate() {
+        constants.push(constant_from_json(k, &format!("{}.constants[{}]", path, idx))?);
+    }
+
+    let mut upvalues = Vec::new();
+    let mut upvalue_names = Vec::new();
+    for (idx, upval) in get_array(value, path, "upvalues")?.iter().enumerate() {
+        let path = format!("{}.upvalues[{}]", path, idx);
+        match get(upval, &path, "name")? {
+            Json::Null => (),
+            _ if upvalue_names.len() < idx => return Err(invalid(&path, "named upvalue after an unnamed one")),
+            _ => upvalue_names.push(String::from(get_str(upval, &path, "name")?)),
+        }
+        upvalues.push(Upvalue {
+            instack: get_uint(upval, &path, "instack")?,
+            idx: get_uint(upval, &path, "idx")?,
+            kind: get_uint(upval, &path, "kind")?,
+        });
+    }
+
+    let mut loc_vars = Vec::new();
+    for (idx, v) in get_array(value, path, "loc_vars")?.iter().enumerate() {
+        let path = format!("{}.loc_vars[{}]", path, idx);
+        loc_vars.push(LocVar {
+            var_name: String::from(get_str(v, &path, "name")?),
+            start_pc: get_uint(v, &path, "start_pc")?,
+            end_pc: get_uint(v, &path, "end_pc")?,
+        });
+    }
+
+    let mut abs_line_info = Vec::new();
+    for (idx, info) in get_array(value, path, "abs_line_info")?.iter().enumerate() {
+        let path = format!("{}.abs_line_info[{}]", path, idx);
+        abs_line_info.push(AbsLineInfo { pc: get_uint(info, &path, "pc")?, line: get_uint(info, &path, "line")? });
+    }
+
+    let mut protos = Vec::new();
+    for (idx, p) in get_array(value, path, "protos")?.iter().enumerate() {
+        protos.push(Rc::new(proto_from_json(p, &format!("{}.protos[{}]", path, idx))?));
+    }
+
+    Ok(Prototype {
+        version,
+        source: String::from(get_str(value, path, "source")?),
+        line_defined: get_uint(value, path, "line_defined")?,
+        last_line_defined: get_uint(value, path, "last_line_defined")?,
+        num_params: get_uint(value, path, "num_params")?,
+        is_vararg: get_uint(value, path, "is_vararg")?,
+        max_stack_size: get_uint(value, path, "max_stack_size")?,
+        code,
+        constants,
+        upvalues,
+        protos,
+        line_info,
+        abs_line_info,
+        loc_vars,
+        upvalue_names,
+    })
+}
+
+// `word` wins when present; without it the instruction is encoded from
+// `op` and its operands, so that hand-edited listings can drop it
+fn instruction_from_json(value: &Json, path: &str, version: u8) -> Result<u32, JsonError> {
+    if value.get("word").is_some() {
+        return get_uint(value, path, "word");
+    }
+    let name = get_str(value, path, "op")?;
+    let operand = |key| get_int(value, path, key).map(|x| x as isize);
+    let unknown = || invalid(&format!("{}.op", path), "unknown opcode");
+    let encoded = if version == header_const::LUAC_VERSION_54 {
+        let op = OPCODES_54.iter().position(|o| o.name == name).ok_or_else(unknown)? as u8;
+        match OPCODES_54[op as usize].op_mode {
+            OpMode54::IABC => {
+                let k = match value.get("k") {
+                    None => false,
+                    Some(Json::Bool(k)) => *k,
+                    Some(_) => return Err(invalid(&format!("{}.k", path), "expected a boolean")),
+                };
+                encode_abck_54(op, operand("a")?, k, operand("b")?, operand("c")?)
+            }
+            OpMode54::IABx => encode_abx_54(op, operand("a")?, operand("bx")?),
+            OpMode54::IAsBx => encode_asbx_54(op, operand("a")?, operand("sbx")?),
+            OpMode54::IAx => encode_ax_54(op, operand("ax")?),
+            OpMode54::IsJ => encode_sj_54(op, operand("sj")?),
+        }
+    } else {
+        let op = OPCODES.iter().position(|o| o.name.trim_end() == name).ok_or_else(unknown)? as u8;
+        match OPCODES[op as usize].op_mode {
+            OpMode::IABC => u32::encode_abc(op, operand("a")?, operand("b")?, operand("c")?),
+            OpMode::IABx => u32::encode_abx(op, operand("a")?, operand("bx")?),
+            OpMode::IAsBx => u32::encode_asbx(op, operand("a")?, operand("sbx")?),
+            OpMode::IAx => u32::encode_ax(op, operand("ax")?),
+        }
+    };
+    encoded.map_err(|err| JsonError::Encode { path: String::from(path), err })
+}
+
+fn constant_from_json(value: &Json, path: &str) -> Result<Constant, JsonError> {
+    Ok(match get_str(value, path, "type")? {
+        "nil" => Constant::Nil,
+        "boolean" => match get(value, path, "value")? {
+            Json::Bool(b) => Constant::Boolean(*b),
+            _ => return Err(invalid(&format!("{}.value", path), "expected a boolean")),
+        },
+        "integer" => Constant::Integer(get_int(value, path, "value")?),
+        "number" => Constant::Number(get_float(value, path, "value")?),
+        "string" if value.get("bytes").is_some() => Constant::Str(get_hex(value, path, "bytes")?),
+        "string" => Constant::Str(get_str(value, path, "value")?.as_bytes().to_vec()),
+        _ => return Err(invalid(&format!("{}.type", path), "unknown constant type")),
+    })
+}
+
+fn field(key: &str, value: Json) -> (String, Json) {
+    (String::from(key), value)
+}
+
+// JSON has no infinities or NaN, so those are written as strings
+fn float_to_json(n: f64) -> Json {
+    if n.is_nan() {
+        Json::Str(String::from("nan"))
+    } else if n.is_infinite() {
+        Json::Str(String::from(if n > 0.0 { "inf" } else { "-inf" }))
+    } else {
+        Json::Float(n)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn invalid(path: &str, reason: &'static str) -> JsonError {
+    JsonError::Invalid { path: String::from(path), reason }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn get<'a>(value: &'a Json, path: &str, key: &str) -> Result<&'a Json, JsonError> {
+    value.get(key).ok_or_else(|| JsonError::Missing { path: join(path, key) })
+}
+
+fn get_int(value: &Json, path: &str, key: &str) -> Result<i64, JsonError> {
+    match get(value, path, key)? {
+        Json::Int(i) => Ok(*i),
+        _ => Err(invalid(&join(path, key), "expected an integer")),
+    }
+}
+
+fn get_uint<T: TryFrom<i64>>(value: &Json, path: &str, key: &str) -> Result<T, JsonError> {
+    T::try_from(get_int(value, path, key)?).map_err(|_| invalid(&join(path, key), "out of range"))
+}
+
+fn get_float(value: &Json, path: &str, key: &str) -> Result<f64, JsonError> {
+    match get(value, path, key)? {
+        Json::Float(n) => Ok(*n),
+        Json::Int(i) => Ok(*i as f64),
+        Json::Str(s) if s == "nan" => Ok(f64::NAN),
+        Json::Str(s) if s == "inf" => Ok(f64::INFINITY),
+        Json::Str(s) if s == "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Err(invalid(&join(path, key), "expected a number")),
+    }
+}
+
+fn get_str<'a>(value: &'a Json, path: &str, key: &str) -> Result<&'a str, JsonError> {
+    match get(value, path, key)? {
+        Json::Str(s) => Ok(s),
+        _ => Err(invalid(&join(path, key), "expected a string")),
+    }
+}
+
+fn get_array<'a>(value: &'a Json, path: &str, key: &str) -> Result<&'a [Json], JsonError> {
+    match get(value, path, key)? {
+        Json::Array(items) => Ok(items),
+        _ => Err(invalid(&join(path, key), "expected an array")),
+    }
+}
+
+fn get_hex(value: &Json, path: &str, key: &str) -> Result<Vec<u8>, JsonError> {
+    let s = get_str(value, path, key)?;
+    let bad = || invalid(&join(path, key), "expected hex digits");
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(bad());
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| bad())).collect()
+}
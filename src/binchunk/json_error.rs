@@ -0,0 +1,25 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::vm::encode_error::EncodeError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    Syntax { offset: usize, msg: &'static str },
+    Missing { path: String },
+    Invalid { path: String, reason: &'static str },
+    Encode { path: String, err: EncodeError },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax { offset, msg } => write!(f, "syntax error at offset {}: {}", offset, msg),
+            JsonError::Missing { path } => write!(f, "{}: missing", path),
+            JsonError::Invalid { path, reason } => write!(f, "{}: {}", path, reason),
+            JsonError::Encode { path, err } => write!(f, "{}: {}", path, err),
+        }
+    }
+}
+
+impl Error for JsonError {}
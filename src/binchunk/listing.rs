@@ -0,0 +1,149 @@
+use std::io::{self, Write};
+
+use super::binary_chunk::{Constant, Prototype};
+use super::header_const;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::{OpArgMode, OpMode};
+use crate::vm::opcodes_54::{Instruction54, OpMode54};
+
+// lists a function and the functions nested in it, as `luac -l` does;
+// `full` adds constants, locals and upvalues, as `luac -l -l` does
+pub fn list<W: Write>(w: &mut W, f: &Prototype, full: bool) -> io::Result<()> {
+    print_header(w, f)?;
+    print_code(w, f)?;
+    if full {
+        print_detail(w, f)?;
+    }
+    for p in &f.protos {
+        list(w, p, full)?;
+    }
+    Ok(())
+}
+
+pub fn print_header<W: Write>(w: &mut W, f: &Prototype) -> io::Result<()> {
+    let func_type = if f.line_defined > 0 { "function" } else { "main" };
+    let vararg_flag = if f.is_vararg > 0 { "+" } else { "" };
+
+    writeln!(
+        w,
+        "{} <{}:{},{}> ({} instructions)",
+        func_type,
+        f.source,
+        f.line_defined,
+        f.last_line_defined,
+        f.code.len()
+    )?;
+    writeln!(
+        w,
+        "{}{} params, {} slots, {} upvalues, {} locals, {} constants, {} functions",
+        f.num_params,
+        vararg_flag,
+        f.max_stack_size,
+        f.upvalues.len(),
+        f.loc_vars.len(),
+        f.constants.len(),
+        f.protos.len()
+    )
+}
+
+pub fn print_code<W: Write>(w: &mut W, f: &Prototype) -> io::Result<()> {
+    for (pc, &i) in f.code.iter().enumerate() {
+        let line = match f.line_info.get(pc) {
+            Some(line) => line.to_string(),
+            None => String::from("-"),
+        };
+        write!(w, "\t{}\t[{}]\t", pc + 1, line)?;
+        if f.version == header_const::LUAC_VERSION_54 {
+            print_instruction_54(w, i)?;
+        } else {
+            write!(w, "{} \t", i.opname())?;
+            print_operands(w, i)?;
+        }
+    }
+    Ok(())
+}
+
+// operands as luac 5.4 gives them, but for the comments; the 5.4
+// table has names of any length
+fn print_instruction_54<W: Write>(w: &mut W, i: u32) -> io::Result<()> {
+    let opcode = match i.opcode_54() {
+        Some(opcode) => opcode,
+        None => return writeln!(w, "{:<9}\t", "UNKNOWN"),
+    };
+    write!(w, "{:<9}\t", opcode.name)?;
+    match opcode.op_mode {
+        OpMode54::IABC => {
+            write!(w, "{} {} {}", i.a_54(), i.b_54(), i.c_54())?;
+            if i.k_54() {
+                write!(w, "k")?;
+            }
+        }
+        OpMode54::IABx => write!(w, "{} {}", i.a_54(), i.bx_54())?,
+        OpMode54::IAsBx => write!(w, "{} {}", i.a_54(), i.sbx_54())?,
+        OpMode54::IAx => write!(w, "{}", i.ax_54())?,
+        OpMode54::IsJ => write!(w, "{}", i.sj_54())?,
+    }
+    writeln!(w)
+}
+
+fn print_operands<W: Write>(w: &mut W, i: u32) -> io::Result<()> {
+    match i.opmode() {
+        OpMode::IABC => {
+            let (a, b, c) = i.abc();
+            write!(w, "{}", a)?;
+            if !matches!(i.b_mode(), OpArgMode::OpArgN) {
+                write!(w, " {}", if b > 0xff { -1 - (b & 0xff) } else { b })?;
+            }
+            if !matches!(i.c_mode(), OpArgMode::OpArgN) {
+                write!(w, " {}", if c > 0xff { -1 - (c & 0xff) } else { c })?;
+            }
+        }
+        OpMode::IABx => {
+            let (a, bx) = i.a_bx();
+            write!(w, "{}", a)?;
+            match i.b_mode() {
+                OpArgMode::OpArgK => write!(w, " {}", -1 - bx)?,
+                OpArgMode::OpArgU => write!(w, " {}", bx)?,
+                _ => unreachable!(),
+            }
+        }
+        OpMode::IAsBx => {
+            let (a, sbx) = i.a_sbx();
+            write!(w, "{} {}", a, sbx)?;
+        }
+        OpMode::IAx => {
+            let ax = i.ax();
+            write!(w, "{}", -1 - ax)?;
+        }
+    }
+    writeln!(w)
+}
+
+pub fn print_detail<W: Write>(w: &mut W, f: &Prototype) -> io::Result<()> {
+    writeln!(w, "constants ({}):", f.constants.len())?;
+    for (i, k) in f.constants.iter().enumerate() {
+        writeln!(w, "\t{}\t{}", i + 1, constant_to_string(k))?;
+    }
+
+    writeln!(w, "locals ({}):", f.loc_vars.len())?;
+    for (i, loc_var) in f.loc_vars.iter().enumerate() {
+        writeln!(w, "\t{}\t{}\t{}\t{}", i, loc_var.var_name, loc_var.start_pc + 1, loc_var.end_pc + 1)?;
+    }
+
+    writeln!(w, "upvalues ({}):", f.upvalues.len())?;
+    for (i, upval) in f.upvalues.iter().enumerate() {
+        let name = f.upvalue_names.get(i).map_or("-", String::as_str);
+        writeln!(w, "\t{}\t{}\t{}\t{}", i, name, upval.instack, upval.idx)?;
+    }
+    Ok(())
+}
+
+fn constant_to_string(k: &Constant) -> String {
+    match k {
+        Constant::Nil => String::from("nil"),
+        Constant::Boolean(b) => b.to_string(),
+        Constant::Integer(i) => i.to_string(),
+        Constant::Number(n) => n.to_string(),
+        Constant::Str(s) => String::from_utf8_lossy(s).into_owned(),
+    }
+}
@@ -1,9 +1,71 @@
 pub mod binary_chunk;
+pub mod chunk_error;
+pub mod verify_error;
+pub mod json_error;
 mod reader;
-mod header_const;
+mod reader_51;
+mod reader_54;
+mod translate_51;
+mod verifier;
+mod writer;
+mod json;
+mod json_chunk;
+pub mod header_const;
+pub mod listing;
 mod tag_const;
 
-pub fn undump(data: Vec<u8>) -> binary_chunk::BinaryChunk {
-    let mut reader = reader::Reader{data};
+use std::io::Read;
+
+use chunk_error::ChunkError;
+use json_error::JsonError;
+use verify_error::VerifyError;
+
+pub fn undump(data: &[u8]) -> Result<binary_chunk::BinaryChunk, ChunkError> {
+    let mut reader = reader::Reader{data, pos: 0, platform: Default::default(), depth: 0};
     reader.read_binary_chunk()
-}
\ No newline at end of file
+}
+
+pub fn undump_from<R: Read>(mut src: R) -> Result<binary_chunk::BinaryChunk, ChunkError> {
+    let mut data = Vec::new();
+    src.read_to_end(&mut data).map_err(|e| ChunkError::Io(e.kind()))?;
+    undump(&data)
+}
+
+// turns a chunk loaded from Lua 5.1 bytecode into one this engine can run
+pub fn translate_51(chunk: binary_chunk::BinaryChunk) -> Result<binary_chunk::BinaryChunk, ChunkError> {
+    translate_51::translate(chunk)
+}
+
+// rejects bytecode that would make the VM index out of bounds,
+// recursing into nested functions
+pub fn verify(f: &binary_chunk::Prototype) -> Result<(), VerifyError> {
+    verifier::verify(f)
+}
+
+// writes the chunk in the 5.3 format, which is the only one its
+// functions can be in
+pub fn dump(chunk: &binary_chunk::BinaryChunk) -> Result<Vec<u8>, ChunkError> {
+    _dump(chunk, false)
+}
+
+// drops line_info, loc_vars and upvalue_names, like `luac -s`
+pub fn dump_stripped(chunk: &binary_chunk::BinaryChunk) -> Result<Vec<u8>, ChunkError> {
+    _dump(chunk, true)
+}
+
+fn _dump(chunk: &binary_chunk::BinaryChunk, strip: bool) -> Result<Vec<u8>, ChunkError> {
+    writer::check(&chunk.main_func)?;
+    let mut writer = writer::Writer{data: Vec::new(), strip};
+    writer.write_binary_chunk(chunk);
+    Ok(writer.data)
+}
+
+// renders the chunk as pretty-printed JSON with a fixed key order, so that
+// the output of two builds can be diffed line by line
+pub fn to_json(chunk: &binary_chunk::BinaryChunk) -> String {
+    json::write(&json_chunk::chunk_to_json(chunk))
+}
+
+pub fn from_json(text: &str) -> Result<binary_chunk::BinaryChunk, JsonError> {
+    json_chunk::chunk_from_json(&json::parse(text)?)
+}
@@ -1,160 +1,293 @@
+use std::rc::Rc;
+
 use super::binary_chunk::*;
+use super::chunk_error::ChunkError;
 use super::header_const;
 use super::tag_const;
 
 
-pub struct Reader {
-    pub data: Vec<u8>
+// sizes and byte order of the machine that produced the chunk,
+// as declared by its header
+pub struct Platform {
+    pub little_endian: bool,
+    pub cint_size: u8,
+    pub sizet_size: u8,
+    pub lua_integer_size: u8,
+    pub lua_number_size: u8,
+    pub integral_number: bool // 5.1 only: lua_Number is an integer type
+}
+
+impl Default for Platform {
+    fn default() -> Platform {
+        Platform {
+            little_endian: true,
+            cint_size: header_const::CINT_SIZE,
+            sizet_size: header_const::CSIZET_SIZE,
+            lua_integer_size: header_const::LUA_INTEGER_SIZE,
+            lua_number_size: header_const::LUA_NUMBER_SIZE,
+            integral_number: false
+        }
+    }
+}
+
+// nested functions deeper than this are rejected rather than recursed
+// into, as the parser of Lua bounds them by LUAI_MAXCCALLS
+const MAX_NESTING: usize = 200;
+
+pub struct Reader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+    pub platform: Platform,
+    pub depth: usize
 }
 
-impl Reader {
-    fn read_byte(&mut self) -> u8 {
-        let b = self.data[0];
-        self.data.remove(0);
-        b
+impl<'a> Reader<'a> {
+    pub(super) fn read_byte(&mut self) -> Result<u8, ChunkError> {
+        let b = *self.data.get(self.pos).ok_or(ChunkError::UnexpectedEof { offset: self.data.len() })?;
+        self.pos += 1;
+        Ok(b)
     }
 
-    fn read_uint32(&mut self) -> u32 {
-        let a0 = self.read_byte() as u32;
-        let a1 = self.read_byte() as u32;
-        let a2 = self.read_byte() as u32;
-        let a3 = self.read_byte() as u32;
-        (a3 << 24) | (a2 << 16) | (a1 << 8) | a0
+    fn read_uint(&mut self, size: u8) -> Result<u64, ChunkError> {
+        let bytes = self.read_bytes(size as usize)?;
+        Ok(if self.platform.little_endian {
+            bytes.iter().rev().fold(0, |n, b| n << 8 | *b as u64)
+        } else {
+            bytes.iter().fold(0, |n, b| n << 8 | *b as u64)
+        })
     }
 
-    fn read_uint64(&mut self) -> u64 {
-        let a0 = self.read_uint32() as u64;
-        let a1 = self.read_uint32() as u64;
-        (a1 << 32) | a0
+    pub(super) fn read_int(&mut self, size: u8) -> Result<i64, ChunkError> {
+        let shift = 64 - 8 * size as u32;
+        Ok(((self.read_uint(size)? << shift) as i64) >> shift)
     }
 
-    fn read_int64(&mut self) -> i64 {
-        self.read_uint64() as i64
+    pub(super) fn read_cint(&mut self) -> Result<u32, ChunkError> {
+        let n = self.read_int(self.platform.cint_size)?;
+        u32::try_from(n).map_err(|_| ChunkError::Corrupted)
     }
 
-    fn read_float64(&mut self) -> f64 {
-        use std::f64;
-        f64::from_bits(self.read_uint64())
+    pub(super) fn read_size_t(&mut self) -> Result<usize, ChunkError> {
+        let n = self.read_uint(self.platform.sizet_size)?;
+        usize::try_from(n).map_err(|_| ChunkError::Corrupted)
     }
 
-    fn read_bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut vec = Vec::new();
-        for _ in 0..n {
-            vec.push(self.read_byte());
+    pub(super) fn read_instruction(&mut self) -> Result<u32, ChunkError> {
+        Ok(self.read_uint(header_const::INSTRUCTION_SIZE)? as u32)
+    }
+
+    pub(super) fn read_lua_integer(&mut self) -> Result<i64, ChunkError> {
+        self.read_int(self.platform.lua_integer_size)
+    }
+
+    // narrower lua_Numbers are widened to f64
+    pub(super) fn read_lua_number(&mut self) -> Result<f64, ChunkError> {
+        Ok(match self.platform.lua_number_size {
+            4 => f32::from_bits(self.read_uint(4)? as u32) as f64,
+            _ => f64::from_bits(self.read_uint(8)?),
+        })
+    }
+
+    // LUAC_INT doubles as the byte order mark
+    pub(super) fn read_luac_int(&mut self) -> Result<i64, ChunkError> {
+        let start = self.pos;
+        self.platform.little_endian = true;
+        let n = self.read_lua_integer()?;
+        if n != header_const::LUAC_INT {
+            self.pos = start;
+            self.platform.little_endian = false;
+            if self.read_lua_integer()? != header_const::LUAC_INT {
+                return Err(ChunkError::EndiannessMismatch);
+            }
         }
-        vec
+        Ok(header_const::LUAC_INT)
     }
 
-    fn read_string(&mut self) -> String {
-        let mut size = self.read_byte() as usize;
+    pub(super) fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], ChunkError> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.data.len())
+            .ok_or(ChunkError::UnexpectedEof { offset: self.data.len() })?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, ChunkError> {
+        let mut size = self.read_byte()? as usize;
         if size == 0 {
-            return String::from("");
+            return Ok(Vec::new());
         }
         if size == 0xff {
-            size = self.read_uint64() as usize;
+            size = self.read_size_t()?;
         }
-        let bytes = self.read_bytes(size - 1);
-        String::from_utf8(bytes).unwrap()
+        let len = size.checked_sub(1).ok_or(ChunkError::Corrupted)?;
+        Ok(self.read_bytes(len)?.to_vec())
     }
 
-    fn read_constant(&mut self) -> Constant {
-        match self.read_byte() {
+    // debug info names are only ever displayed
+    fn read_name(&mut self) -> Result<String, ChunkError> {
+        Ok(String::from_utf8_lossy(&self.read_string()?).into_owned())
+    }
+
+    fn read_constant(&mut self) -> Result<Constant, ChunkError> {
+        let offset = self.pos;
+        Ok(match self.read_byte()? {
             tag_const::TAG_NIL => Constant::Nil,
-            tag_const::TAG_BOOLEAN => Constant::Boolean(self.read_byte() != 0),
-            tag_const::TAG_INTEGER => Constant::Integer(self.read_int64()),
-            tag_const::TAG_NUMBER => Constant::Number(self.read_float64()),
-            tag_const::TAG_SHORT_STR => Constant::Str(self.read_string()),
-            tag_const::TAG_LONG_STR => Constant::Str(self.read_string()),
-            _ => unreachable!()
-        }
+            tag_const::TAG_BOOLEAN => Constant::Boolean(self.read_byte()? != 0),
+            tag_const::TAG_INTEGER => Constant::Integer(self.read_lua_integer()?),
+            tag_const::TAG_NUMBER => Constant::Number(self.read_lua_number()?),
+            tag_const::TAG_SHORT_STR => Constant::Str(self.read_string()?),
+            tag_const::TAG_LONG_STR => Constant::Str(self.read_string()?),
+            tag => return Err(ChunkError::UnknownConstantTag { tag, offset })
+        })
     }
 
-    fn read_upvalue(&mut self) -> Upvalue {
-        Upvalue {
-            instack: self.read_byte(),
-            idx: self.read_byte()
-        }
+    fn read_upvalue(&mut self) -> Result<Upvalue, ChunkError> {
+        Ok(Upvalue {
+            instack: self.read_byte()?,
+            idx: self.read_byte()?,
+            kind: 0
+        })
     }
 
-    fn read_loc_var(&mut self) -> LocVar {
-        LocVar {
-            var_name: self.read_string(),
-            start_pc: self.read_uint32(),
-            end_pc: self.read_uint32()
-        }
+    fn read_loc_var(&mut self) -> Result<LocVar, ChunkError> {
+        Ok(LocVar {
+            var_name: self.read_name()?,
+            start_pc: self.read_cint()?,
+            end_pc: self.read_cint()?
+        })
     }
 
-    fn read_vec<T, F>(&mut self, f: F) -> Vec<T>
+    fn read_vec<T, F>(&mut self, f: F) -> Result<Vec<T>, ChunkError>
     where
-        F: Fn(&mut Reader) -> T,
+        F: Fn(&mut Reader<'a>) -> Result<T, ChunkError>,
     {
-        let n = self.read_uint32() as usize;
-        let mut vec = Vec::with_capacity(n);
+        let n = self.read_cint()? as usize;
+        let mut vec = Vec::new();
         for _ in 0..n {
-            vec.push(f(self));
+            vec.push(f(self)?);
         }
-        vec
-    }
-
-    fn read_header(&mut self) -> Header {
-        Header { 
-            signature: self.read_bytes(4).as_slice().try_into().unwrap(), 
-            version: self.read_byte(), 
-            format: self.read_byte(), 
-            luac_data: self.read_bytes(6).as_slice().try_into().unwrap(), 
-            cint_size: self.read_byte(), 
-            sizet_size: self.read_byte(), 
-            instruction_size: self.read_byte(), 
-            lua_integer_size: self.read_byte(), 
-            lua_number_size: self.read_byte(), 
-            luac_int: self.read_int64(), 
-            luac_num: self.read_float64() 
+        Ok(vec)
+    }
+
+    fn read_header(&mut self) -> Result<Header, ChunkError> {
+        Ok(Header {
+            signature: self.read_bytes(4)?.try_into().unwrap(),
+            version: self.read_byte()?,
+            format: self.read_byte()?,
+            luac_data: self.read_bytes(6)?.try_into().unwrap(),
+            cint_size: self.read_platform_size("int", &[2, 4, 8], |p, n| p.cint_size = n)?,
+            sizet_size: self.read_platform_size("size_t", &[4, 8], |p, n| p.sizet_size = n)?,
+            instruction_size: self.read_byte()?,
+            lua_integer_size: self.read_platform_size("lua_Integer", &[4, 8], |p, n| p.lua_integer_size = n)?,
+            lua_number_size: self.read_platform_size("lua_Number", &[4, 8], |p, n| p.lua_number_size = n)?,
+            luac_int: self.read_luac_int()?,
+            luac_num: self.read_lua_number()?
+        })
+    }
+
+    pub(super) fn read_platform_size<F>(&mut self, what: &'static str, supported: &[u8], set: F) -> Result<u8, ChunkError>
+    where
+        F: Fn(&mut Platform, u8),
+    {
+        let size = self.read_byte()?;
+        if !supported.contains(&size) {
+            return Err(ChunkError::UnsupportedSize { what, found: size });
+        }
+        set(&mut self.platform, size);
+        Ok(size)
+    }
+
+    // reads a nested function, one level deeper
+    pub(super) fn read_nested<T, F>(&mut self, f: F) -> Result<T, ChunkError>
+    where
+        F: FnOnce(&mut Reader<'a>) -> Result<T, ChunkError>,
+    {
+        if self.depth >= MAX_NESTING {
+            return Err(ChunkError::TooDeep { limit: MAX_NESTING });
         }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
     }
 
-    fn check_header(&mut self, header: &Header) {
-        assert_eq!(header.signature, header_const::LUA_SIGNATURE, "Not a precompiled chunk!");
-        assert_eq!(header.version, header_const::LUAC_VERSION, "Version mismatch!");
-        assert_eq!(header.format, header_const::LUAC_FORMAT, "Format mismatch!");
-        assert_eq!(header.luac_data, header_const::LUAC_DATA, "Corrupted!");
-        assert_eq!(header.cint_size, header_const::CINT_SIZE, "int size mismamtch!");
-        assert_eq!(header.sizet_size, header_const::CSIZET_SIZE, "size_t size mismatch!");
-        assert_eq!(header.instruction_size, header_const::INSTRUCTION_SIZE, "Instruction size mismatch!");
-        assert_eq!(header.lua_integer_size, header_const::LUA_INTEGER_SIZE, "Lua Integer size mismatch!");
-        assert_eq!(header.lua_number_size, header_const::LUA_NUMBER_SIZE, "Lua Number size mismatch!");
-        assert_eq!(header.luac_int, header_const::LUAC_INT, "Endianness mismatch!");
-        assert_eq!(header.luac_num, header_const::LUAC_NUM, "Float format mismatch!");
+    fn check_header(&mut self) -> Result<Header, ChunkError> {
+        let header = self.read_header()?;
+        check_common_header(&header)?;
+        Ok(header)
     }
 
-    fn read_proto(&mut self, parent_source: &String) -> Box<Prototype> {
-        let mut source = self.read_string();
-        if source == String::from("") {
+    fn read_proto(&mut self, parent_source: &str) -> Result<Box<Prototype>, ChunkError> {
+        let mut source = self.read_name()?;
+        if source.is_empty() {
             source = parent_source.to_string();
         }
-        Box::new(Prototype {
+        let line_defined = self.read_cint()?;
+        let last_line_defined = self.read_cint()?;
+        let num_params = self.read_byte()?;
+        let is_vararg = self.read_byte()?;
+        let max_stack_size = self.read_byte()?;
+        let code = self.read_vec(|r| r.read_instruction())?;
+        let constants = self.read_vec(|r| r.read_constant())?;
+        let upvalues = self.read_vec(|r| r.read_upvalue())?;
+        let protos = self.read_vec(|r| r.read_nested(|r| r.read_proto(&source)).map(Rc::from))?;
+        Ok(Box::new(Prototype {
+            version: header_const::LUAC_VERSION,
             source,
-            line_defined: self.read_uint32(),
-            last_line_defined: self.read_uint32(),
-            num_params: self.read_byte(),
-            is_vararg: self.read_byte(),
-            max_stack_size: self.read_byte(),
-            code: self.read_vec(|r| r.read_uint32()),
-            constants: self.read_vec(|r| r.read_constant()),
-            upvalues: self.read_vec(|r| r.read_upvalue()),
-            protos: self.read_vec(|r| r.read_proto(&parent_source)),
-            line_info: self.read_vec(|r| r.read_uint32()),
-            loc_vars: self.read_vec(|r| r.read_loc_var()),
-            upvalue_names: self.read_vec(|r| r.read_string())
-        })
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues,
+            protos,
+            line_info: self.read_vec(|r| r.read_cint())?,
+            abs_line_info: Vec::new(),
+            loc_vars: self.read_vec(|r| r.read_loc_var())?,
+            upvalue_names: self.read_vec(|r| r.read_name())?
+        }))
     }
 
-    pub fn read_binary_chunk(&mut self) -> BinaryChunk {
-        let header = self.read_header();
-        self.check_header(&header);
-        BinaryChunk { 
-            header, 
-            size_upvalues: self.read_byte(), 
-            main_func: self.read_proto(&String::from("")) 
+    pub fn read_binary_chunk(&mut self) -> Result<BinaryChunk, ChunkError> {
+        // a foreign file should be reported as such rather than as truncated
+        if !self.data.starts_with(&header_const::LUA_SIGNATURE) {
+            return Err(ChunkError::BadSignature);
         }
+        match self.data.get(4) {
+            Some(&header_const::LUAC_VERSION) => (),
+            Some(&header_const::LUAC_VERSION_54) => return self.read_binary_chunk_54(),
+            Some(&header_const::LUAC_VERSION_51) => return self.read_binary_chunk_51(),
+            Some(&version) => return Err(ChunkError::VersionMismatch { expected: header_const::LUAC_VERSION, found: version }),
+            None => return Err(ChunkError::UnexpectedEof { offset: self.data.len() }),
+        }
+        let header = self.check_header()?;
+        Ok(BinaryChunk {
+            header,
+            size_upvalues: self.read_byte()?,
+            main_func: self.read_proto("")?
+        })
+    }
+}
+
+pub(super) fn check_common_header(header: &Header) -> Result<(), ChunkError> {
+    if header.format != header_const::LUAC_FORMAT {
+        return Err(ChunkError::FormatMismatch { expected: header_const::LUAC_FORMAT, found: header.format });
     }
-}
\ No newline at end of file
+    if header.luac_data != header_const::LUAC_DATA {
+        return Err(ChunkError::Corrupted);
+    }
+    check_size("Instruction", header_const::INSTRUCTION_SIZE, header.instruction_size)?;
+    if header.luac_num != header_const::LUAC_NUM {
+        return Err(ChunkError::FloatFormatMismatch);
+    }
+    Ok(())
+}
+
+pub(super) fn check_size(what: &'static str, expected: u8, found: u8) -> Result<(), ChunkError> {
+    if expected != found {
+        return Err(ChunkError::SizeMismatch { what, expected, found });
+    }
+    Ok(())
+}
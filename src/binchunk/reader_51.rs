@@ -0,0 +1,136 @@
+use std::rc::Rc;
+
+use super::binary_chunk::*;
+use super::chunk_error::ChunkError;
+use super::header_const;
+use super::reader::{check_size, Reader};
+use super::tag_const;
+
+impl<'a> Reader<'a> {
+    // 5.1 strings are prefixed with a size_t that counts the trailing '\0'
+    fn read_string_51(&mut self) -> Result<Option<Vec<u8>>, ChunkError> {
+        let size = self.read_size_t()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_bytes(size)?[..size - 1].to_vec()))
+    }
+
+    fn read_name_51(&mut self) -> Result<Option<String>, ChunkError> {
+        Ok(self.read_string_51()?.map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    fn read_constant_51(&mut self) -> Result<Constant, ChunkError> {
+        let offset = self.pos;
+        Ok(match self.read_byte()? {
+            tag_const::TAG_NIL => Constant::Nil,
+            tag_const::TAG_BOOLEAN => Constant::Boolean(self.read_byte()? != 0),
+            tag_const::TAG_NUMBER if self.platform.integral_number => Constant::Integer(self.read_int(self.platform.lua_number_size)?),
+            tag_const::TAG_NUMBER => Constant::Number(self.read_lua_number()?),
+            tag_const::TAG_SHORT_STR => Constant::Str(self.read_string_51()?.unwrap_or_default()),
+            tag => return Err(ChunkError::UnknownConstantTag { tag, offset })
+        })
+    }
+
+    fn read_loc_var_51(&mut self) -> Result<LocVar, ChunkError> {
+        Ok(LocVar {
+            var_name: self.read_name_51()?.unwrap_or_default(),
+            start_pc: self.read_cint()?,
+            end_pc: self.read_cint()?
+        })
+    }
+
+    fn read_vec_51<T, F>(&mut self, f: F) -> Result<Vec<T>, ChunkError>
+    where
+        F: Fn(&mut Reader<'a>) -> Result<T, ChunkError>,
+    {
+        let n = self.read_cint()? as usize;
+        let mut vec = Vec::new();
+        for _ in 0..n {
+            vec.push(f(self)?);
+        }
+        Ok(vec)
+    }
+
+    fn read_header_51(&mut self) -> Result<Header, ChunkError> {
+        let signature = self.read_bytes(4)?.try_into().unwrap();
+        let version = self.read_byte()?;
+        let format = self.read_byte()?;
+        if format != header_const::LUAC_FORMAT {
+            return Err(ChunkError::FormatMismatch { expected: header_const::LUAC_FORMAT, found: format });
+        }
+        self.platform.little_endian = match self.read_byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(ChunkError::EndiannessMismatch),
+        };
+        let cint_size = self.read_platform_size("int", &[2, 4, 8], |p, n| p.cint_size = n)?;
+        let sizet_size = self.read_platform_size("size_t", &[4, 8], |p, n| p.sizet_size = n)?;
+        let instruction_size = self.read_byte()?;
+        check_size("Instruction", header_const::INSTRUCTION_SIZE, instruction_size)?;
+        let lua_number_size = self.read_platform_size("lua_Number", &[4, 8], |p, n| p.lua_number_size = n)?;
+        self.platform.integral_number = match self.read_byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(ChunkError::FloatFormatMismatch),
+        };
+        Ok(Header {
+            signature,
+            version,
+            format,
+            luac_data: [0; 6], // not recorded by 5.1
+            cint_size,
+            sizet_size,
+            instruction_size,
+            lua_integer_size: 0, // not recorded by 5.1
+            lua_number_size,
+            luac_int: 0, // not recorded by 5.1
+            luac_num: 0.0 // not recorded by 5.1
+        })
+    }
+
+    fn read_proto_51(&mut self, parent_source: &str) -> Result<Box<Prototype>, ChunkError> {
+        let source = self.read_name_51()?.unwrap_or_else(|| parent_source.to_string());
+        let line_defined = self.read_cint()?;
+        let last_line_defined = self.read_cint()?;
+        let nups = self.read_byte()?;
+        let num_params = self.read_byte()?;
+        let is_vararg = self.read_byte()?;
+        let max_stack_size = self.read_byte()?;
+        let code = self.read_vec_51(|r| r.read_instruction())?;
+        let constants = self.read_vec_51(|r| r.read_constant_51())?;
+        let protos = self.read_vec_51(|r| r.read_nested(|r| r.read_proto_51(&source)).map(Rc::from))?;
+        let line_info = self.read_vec_51(|r| r.read_cint())?;
+        let loc_vars = self.read_vec_51(|r| r.read_loc_var_51())?;
+        let upvalue_names = self.read_vec_51(|r| Ok(r.read_name_51()?.unwrap_or_default()))?;
+        Ok(Box::new(Prototype {
+            version: header_const::LUAC_VERSION_51,
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            // 5.1 describes upvalues with pseudo-instructions following
+            // CLOSURE in the parent; they are filled in by translation
+            upvalues: (0..nups).map(|_| Upvalue { instack: 0, idx: 0, kind: 0 }).collect(),
+            protos,
+            line_info,
+            abs_line_info: Vec::new(),
+            loc_vars,
+            upvalue_names
+        }))
+    }
+
+    pub(super) fn read_binary_chunk_51(&mut self) -> Result<BinaryChunk, ChunkError> {
+        let header = self.read_header_51()?;
+        let main_func = self.read_proto_51("")?;
+        Ok(BinaryChunk {
+            header,
+            size_upvalues: main_func.upvalues.len() as u8,
+            main_func
+        })
+    }
+}
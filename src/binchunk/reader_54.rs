@@ -0,0 +1,172 @@
+use std::rc::Rc;
+
+use super::binary_chunk::*;
+use super::chunk_error::ChunkError;
+use super::header_const;
+use super::reader::{check_common_header, Reader};
+use super::tag_const;
+
+const ABSLINEINFO: i8 = -0x80;
+
+impl<'a> Reader<'a> {
+    // sizes and counts are stored as MSB-last varints since 5.4
+    fn read_unsigned(&mut self) -> Result<usize, ChunkError> {
+        let limit = usize::MAX >> 7;
+        let mut x: usize = 0;
+        loop {
+            let b = self.read_byte()?;
+            if x > limit {
+                return Err(ChunkError::Corrupted);
+            }
+            x = (x << 7) | (b & 0x7f) as usize;
+            if b & 0x80 != 0 {
+                return Ok(x);
+            }
+        }
+    }
+
+    fn read_int_54(&mut self) -> Result<u32, ChunkError> {
+        u32::try_from(self.read_unsigned()?).map_err(|_| ChunkError::Corrupted)
+    }
+
+    fn read_string_54(&mut self) -> Result<Option<Vec<u8>>, ChunkError> {
+        let size = self.read_unsigned()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_bytes(size - 1)?.to_vec()))
+    }
+
+    fn read_name_54(&mut self) -> Result<Option<String>, ChunkError> {
+        Ok(self.read_string_54()?.map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    fn read_constant_54(&mut self) -> Result<Constant, ChunkError> {
+        let offset = self.pos;
+        Ok(match self.read_byte()? {
+            tag_const::TAG_VNIL => Constant::Nil,
+            tag_const::TAG_VFALSE => Constant::Boolean(false),
+            tag_const::TAG_VTRUE => Constant::Boolean(true),
+            tag_const::TAG_VNUMINT => Constant::Integer(self.read_lua_integer()?),
+            tag_const::TAG_VNUMFLT => Constant::Number(self.read_lua_number()?),
+            tag_const::TAG_VSHRSTR | tag_const::TAG_VLNGSTR => Constant::Str(self.read_string_54()?.unwrap_or_default()),
+            tag => return Err(ChunkError::UnknownConstantTag { tag, offset })
+        })
+    }
+
+    fn read_upvalue_54(&mut self) -> Result<Upvalue, ChunkError> {
+        Ok(Upvalue {
+            instack: self.read_byte()?,
+            idx: self.read_byte()?,
+            kind: self.read_byte()?
+        })
+    }
+
+    fn read_loc_var_54(&mut self) -> Result<LocVar, ChunkError> {
+        Ok(LocVar {
+            var_name: self.read_name_54()?.unwrap_or_default(),
+            start_pc: self.read_int_54()?,
+            end_pc: self.read_int_54()?
+        })
+    }
+
+    fn read_abs_line_info(&mut self) -> Result<AbsLineInfo, ChunkError> {
+        Ok(AbsLineInfo {
+            pc: self.read_int_54()?,
+            line: self.read_int_54()?
+        })
+    }
+
+    fn read_vec_54<T, F>(&mut self, f: F) -> Result<Vec<T>, ChunkError>
+    where
+        F: Fn(&mut Reader<'a>) -> Result<T, ChunkError>,
+    {
+        let n = self.read_unsigned()?;
+        let mut vec = Vec::new();
+        for _ in 0..n {
+            vec.push(f(self)?);
+        }
+        Ok(vec)
+    }
+
+    fn read_header_54(&mut self) -> Result<Header, ChunkError> {
+        Ok(Header {
+            signature: self.read_bytes(4)?.try_into().unwrap(),
+            version: self.read_byte()?,
+            format: self.read_byte()?,
+            luac_data: self.read_bytes(6)?.try_into().unwrap(),
+            cint_size: 0, // not recorded by 5.4
+            sizet_size: 0, // not recorded by 5.4
+            instruction_size: self.read_byte()?,
+            lua_integer_size: self.read_platform_size("lua_Integer", &[4, 8], |p, n| p.lua_integer_size = n)?,
+            lua_number_size: self.read_platform_size("lua_Number", &[4, 8], |p, n| p.lua_number_size = n)?,
+            luac_int: self.read_luac_int()?,
+            luac_num: self.read_lua_number()?
+        })
+    }
+
+    fn read_proto_54(&mut self, parent_source: &str) -> Result<Box<Prototype>, ChunkError> {
+        let source = self.read_name_54()?.unwrap_or_else(|| parent_source.to_string());
+        let line_defined = self.read_int_54()?;
+        let last_line_defined = self.read_int_54()?;
+        let num_params = self.read_byte()?;
+        let is_vararg = self.read_byte()?;
+        let max_stack_size = self.read_byte()?;
+        let code = self.read_vec_54(|r| r.read_instruction())?;
+        let constants = self.read_vec_54(|r| r.read_constant_54())?;
+        let upvalues = self.read_vec_54(|r| r.read_upvalue_54())?;
+        let protos = self.read_vec_54(|r| r.read_nested(|r| r.read_proto_54(&source)).map(Rc::from))?;
+        let rel_line_info = self.read_vec_54(|r| Ok(r.read_byte()? as i8))?;
+        let abs_line_info = self.read_vec_54(|r| r.read_abs_line_info())?;
+        let loc_vars = self.read_vec_54(|r| r.read_loc_var_54())?;
+        let upvalue_names = self.read_vec_54(|r| Ok(r.read_name_54()?.unwrap_or_default()))?;
+        let line_info = decode_line_info(line_defined, &rel_line_info, &abs_line_info)?;
+        Ok(Box::new(Prototype {
+            version: header_const::LUAC_VERSION_54,
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            upvalues,
+            protos,
+            line_info,
+            abs_line_info,
+            loc_vars,
+            upvalue_names
+        }))
+    }
+
+    pub(super) fn read_binary_chunk_54(&mut self) -> Result<BinaryChunk, ChunkError> {
+        let header = self.read_header_54()?;
+        check_common_header(&header)?;
+        Ok(BinaryChunk {
+            header,
+            size_upvalues: self.read_byte()?,
+            main_func: self.read_proto_54("")?
+        })
+    }
+}
+
+// 5.4 stores per-instruction line deltas, with ABSLINEINFO marking
+// the instructions whose line is found in `abs_line_info` instead
+fn decode_line_info(line_defined: u32, rel: &[i8], abs: &[AbsLineInfo]) -> Result<Vec<u32>, ChunkError> {
+    let mut line = line_defined as i64;
+    let mut abs = abs.iter();
+    let mut line_info = Vec::with_capacity(rel.len());
+    for (pc, delta) in rel.iter().enumerate() {
+        if *delta == ABSLINEINFO {
+            match abs.next() {
+                Some(info) if info.pc as usize == pc => line = info.line as i64,
+                _ => return Err(ChunkError::Corrupted),
+            }
+        } else {
+            line += *delta as i64;
+        }
+        line_info.push(line as u32);
+    }
+    Ok(line_info)
+}
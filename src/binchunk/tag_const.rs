@@ -4,3 +4,12 @@ pub const TAG_NUMBER: u8 = 0x03;
 pub const TAG_INTEGER: u8 = 0x13;
 pub const TAG_SHORT_STR: u8 = 0x04;
 pub const TAG_LONG_STR: u8 = 0x14;
+
+/* Lua 5.4 variant tags */
+pub const TAG_VNIL: u8 = 0x00;
+pub const TAG_VFALSE: u8 = 0x01;
+pub const TAG_VTRUE: u8 = 0x11;
+pub const TAG_VNUMINT: u8 = 0x03;
+pub const TAG_VNUMFLT: u8 = 0x13;
+pub const TAG_VSHRSTR: u8 = 0x04;
+pub const TAG_VLNGSTR: u8 = 0x14;
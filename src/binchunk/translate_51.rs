@@ -0,0 +1,259 @@
+use std::rc::Rc;
+
+use crate::vm::instruction::{rk_ask, Instruction, MAXINDEXRK};
+use crate::vm::opcodes::*;
+
+use super::binary_chunk::*;
+use super::chunk_error::ChunkError;
+use super::header_const;
+
+/* Lua 5.1 opcodes */
+const OP51_MOVE: u8 = 0;
+const OP51_LOADNIL: u8 = 3;
+const OP51_GETUPVAL: u8 = 4;
+const OP51_GETGLOBAL: u8 = 5;
+const OP51_SETGLOBAL: u8 = 7;
+const OP51_JMP: u8 = 22;
+const OP51_FORLOOP: u8 = 31;
+const OP51_FORPREP: u8 = 32;
+const OP51_TFORLOOP: u8 = 33;
+const OP51_SETLIST: u8 = 34;
+const OP51_CLOSE: u8 = 35;
+const OP51_CLOSURE: u8 = 36;
+
+// 5.1 opcodes whose operands keep their meaning in 5.3, indexed by 5.1 opcode
+const OP51_TO_53: [Option<u8>; 38] = [
+    Some(OP_MOVE),
+    Some(OP_LOADK),
+    Some(OP_LOADBOOL),
+    None, // LOADNIL
+    Some(OP_GETUPVAL),
+    None, // GETGLOBAL
+    Some(OP_GETTABLE),
+    None, // SETGLOBAL
+    Some(OP_SETUPVAL),
+    Some(OP_SETTABLE),
+    Some(OP_NEWTABLE),
+    Some(OP_SELF),
+    Some(OP_ADD),
+    Some(OP_SUB),
+    Some(OP_MUL),
+    Some(OP_DIV),
+    Some(OP_MOD),
+    Some(OP_POW),
+    Some(OP_UNM),
+    Some(OP_NOT),
+    Some(OP_LEN),
+    Some(OP_CONCAT),
+    Some(OP_JMP),
+    Some(OP_EQ),
+    Some(OP_LT),
+    Some(OP_LE),
+    Some(OP_TEST),
+    Some(OP_TESTSET),
+    Some(OP_CALL),
+    Some(OP_TAILCALL),
+    Some(OP_RETURN),
+    Some(OP_FORLOOP),
+    Some(OP_FORPREP),
+    None, // TFORLOOP
+    None, // SETLIST
+    None, // CLOSE
+    Some(OP_CLOSURE),
+    Some(OP_VARARG),
+];
+
+const VARARG_ISVARARG: u8 = 2;
+
+// replaces the opcode of `i`, keeping its operands
+fn with_opcode(i: u32, op: u8) -> u32 {
+    i & !0x3f | op as u32
+}
+
+// A 5.1 chunk reaches globals through the function environment rather than
+// an `_ENV` upvalue, so every translated function gets `_ENV` appended to its
+// upvalues and GETGLOBAL/SETGLOBAL become GETTABUP/SETTABUP on it.
+pub fn translate(chunk: BinaryChunk) -> Result<BinaryChunk, ChunkError> {
+    if chunk.header.version != header_const::LUAC_VERSION_51 {
+        return Err(ChunkError::VersionMismatch { expected: header_const::LUAC_VERSION_51, found: chunk.header.version });
+    }
+    let env = Upvalue { instack: 1, idx: 0, kind: 0 };
+    let main_func = translate_proto(*chunk.main_func, env)?;
+    Ok(BinaryChunk {
+        header: Header {
+            signature: header_const::LUA_SIGNATURE,
+            version: header_const::LUAC_VERSION,
+            format: header_const::LUAC_FORMAT,
+            luac_data: header_const::LUAC_DATA,
+            cint_size: header_const::CINT_SIZE,
+            sizet_size: header_const::CSIZET_SIZE,
+            instruction_size: header_const::INSTRUCTION_SIZE,
+            lua_integer_size: header_const::LUA_INTEGER_SIZE,
+            lua_number_size: header_const::LUA_NUMBER_SIZE,
+            luac_int: header_const::LUAC_INT,
+            luac_num: header_const::LUAC_NUM,
+        },
+        size_upvalues: main_func.upvalues.len() as u8,
+        main_func: Box::new(main_func),
+    })
+}
+
+fn translate_proto(f: Prototype, env: Upvalue) -> Result<Prototype, ChunkError> {
+    let untranslatable = |pc: usize, reason: &'static str| ChunkError::Untranslatable { pc, reason };
+    let env_idx = f.upvalues.len() as isize;
+    if env_idx > MAXINDEXRK {
+        return Err(untranslatable(0, "too many upvalues"));
+    }
+
+    let mut protos: Vec<Prototype> = f.protos.into_iter().map(Rc::unwrap_or_clone).collect();
+    let mut code = Vec::with_capacity(f.code.len());
+    let mut line_info = Vec::with_capacity(f.line_info.len());
+    let mut pc_map = vec![0; f.code.len() + 1];
+    let mut jumps = Vec::new(); // (new pc, old target) of every relative jump
+    let mut max_stack_size = f.max_stack_size;
+
+    let mut pc = 0;
+    while pc < f.code.len() {
+        let i = f.code[pc];
+        let line = f.line_info.get(pc).copied();
+        let start = code.len();
+        let at = pc;
+        let out_of_range = move |_| untranslatable(at, "operand out of range");
+        pc_map[pc] = start;
+        match i.opcode() {
+            OP51_LOADNIL => {
+                // R(A) .. R(B) := nil  =>  R(A) .. R(A+B) := nil
+                let (a, b, _) = i.abc();
+                code.push(u32::encode_abc(OP_LOADNIL, a, (b - a).max(0), 0).map_err(out_of_range)?);
+            }
+            OP51_GETGLOBAL => {
+                let (a, bx) = i.a_bx();
+                if bx <= MAXINDEXRK {
+                    code.push(u32::encode_abc(OP_GETTABUP, a, env_idx, rk_ask(bx).map_err(out_of_range)?).map_err(out_of_range)?);
+                } else {
+                    code.push(u32::encode_abx(OP_LOADK, a, bx).map_err(out_of_range)?);
+                    code.push(u32::encode_abc(OP_GETTABUP, a, env_idx, a).map_err(out_of_range)?);
+                }
+            }
+            OP51_SETGLOBAL => {
+                let (a, bx) = i.a_bx();
+                if bx <= MAXINDEXRK {
+                    code.push(u32::encode_abc(OP_SETTABUP, env_idx, rk_ask(bx).map_err(out_of_range)?, a).map_err(out_of_range)?);
+                } else {
+                    // the key needs a register of its own
+                    let tmp = f.max_stack_size as isize;
+                    if tmp >= MAXINDEXRK {
+                        return Err(untranslatable(pc, "no free register for a global name"));
+                    }
+                    max_stack_size = max_stack_size.max(f.max_stack_size + 1);
+                    code.push(u32::encode_abx(OP_LOADK, tmp, bx).map_err(out_of_range)?);
+                    code.push(u32::encode_abc(OP_SETTABUP, env_idx, tmp, a).map_err(out_of_range)?);
+                }
+            }
+            OP51_TFORLOOP => {
+                // TFORLOOP A C; JMP back  =>  TFORCALL A C; TFORLOOP A+2 back
+                let (a, _, c) = i.abc();
+                match f.code.get(pc + 1) {
+                    Some(&j) if j.opcode() == OP51_JMP => {
+                        code.push(u32::encode_abc(OP_TFORCALL, a, 0, c).map_err(out_of_range)?);
+                        pc += 1;
+                        pc_map[pc] = code.len();
+                        jumps.push((code.len(), (pc as isize + 1 + j.a_sbx().1) as usize));
+                        code.push(u32::encode_asbx(OP_TFORLOOP, a + 2, 0).map_err(out_of_range)?);
+                    }
+                    _ => return Err(untranslatable(pc, "TFORLOOP without JMP")),
+                }
+            }
+            OP51_SETLIST => {
+                let (_, _, c) = i.abc();
+                code.push(with_opcode(i, OP_SETLIST));
+                if c == 0 {
+                    // the batch number is stored as a raw word
+                    pc += 1;
+                    let n = *f.code.get(pc).ok_or(untranslatable(pc, "SETLIST without batch number"))?;
+                    pc_map[pc] = code.len();
+                    code.push(u32::encode_ax(OP_EXTRAARG, n as isize).map_err(out_of_range)?);
+                }
+            }
+            OP51_CLOSE => {
+                let (a, _, _) = i.abc();
+                code.push(u32::encode_asbx(OP_JMP, a + 1, 0).map_err(out_of_range)?);
+            }
+            OP51_CLOSURE => {
+                let (_, bx) = i.a_bx();
+                code.push(with_opcode(i, OP_CLOSURE));
+                let child = protos.get_mut(bx as usize).ok_or(untranslatable(pc, "CLOSURE index out of range"))?;
+                for upval in child.upvalues.iter_mut() {
+                    pc += 1;
+                    let p = *f.code.get(pc).ok_or(untranslatable(pc, "missing upvalue pseudo-instruction"))?;
+                    let (_, b, _) = p.abc();
+                    *upval = match p.opcode() {
+                        OP51_MOVE => Upvalue { instack: 1, idx: b as u8, kind: 0 },
+                        OP51_GETUPVAL => Upvalue { instack: 0, idx: b as u8, kind: 0 },
+                        _ => return Err(untranslatable(pc, "bad upvalue pseudo-instruction")),
+                    };
+                    pc_map[pc] = code.len();
+                }
+            }
+            op => match OP51_TO_53.get(op as usize).copied().flatten() {
+                Some(op53) => {
+                    if op == OP51_JMP || op == OP51_FORLOOP || op == OP51_FORPREP {
+                        jumps.push((code.len(), (pc as isize + 1 + i.a_sbx().1) as usize));
+                    }
+                    code.push(with_opcode(i, op53));
+                }
+                None => return Err(untranslatable(pc, "unknown opcode")),
+            },
+        }
+        for _ in start..code.len() {
+            line_info.extend(line);
+        }
+        pc += 1;
+    }
+    pc_map[f.code.len()] = code.len();
+
+    for (new_pc, target) in jumps {
+        let target = *pc_map.get(target).ok_or(untranslatable(new_pc, "jump out of range"))?;
+        let i = code[new_pc];
+        let (a, _) = i.a_sbx();
+        code[new_pc] = u32::encode_asbx(i.opcode(), a, target as isize - new_pc as isize - 1)
+            .map_err(|_| untranslatable(new_pc, "jump out of range"))?;
+    }
+
+    let loc_vars = f.loc_vars.into_iter().map(|v| LocVar {
+        var_name: v.var_name,
+        start_pc: pc_map[(v.start_pc as usize).min(f.code.len())] as u32,
+        end_pc: pc_map[(v.end_pc as usize).min(f.code.len())] as u32,
+    }).collect();
+
+    let mut upvalue_names = f.upvalue_names;
+    if upvalue_names.len() == env_idx as usize {
+        upvalue_names.push(String::from("_ENV"));
+    }
+    let mut upvalues = f.upvalues;
+    upvalues.push(env);
+
+    let protos = protos.into_iter()
+        .map(|p| translate_proto(p, Upvalue { instack: 0, idx: env_idx as u8, kind: 0 }).map(Rc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Prototype {
+        version: header_const::LUAC_VERSION,
+        source: f.source,
+        line_defined: f.line_defined,
+        last_line_defined: f.last_line_defined,
+        num_params: f.num_params,
+        is_vararg: (f.is_vararg & VARARG_ISVARARG != 0) as u8,
+        max_stack_size,
+        code,
+        // 5.1 only has floats, and they stay floats so that arithmetic on
+        // them neither wraps nor raises integer errors
+        constants: f.constants,
+        upvalues,
+        protos,
+        line_info,
+        abs_line_info: Vec::new(),
+        loc_vars,
+        upvalue_names,
+    })
+}
@@ -0,0 +1,250 @@
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::*;
+
+use super::binary_chunk::Prototype;
+use super::header_const;
+use super::verify_error::VerifyError;
+
+pub fn verify(f: &Prototype) -> Result<(), VerifyError> {
+    if f.version != header_const::LUAC_VERSION {
+        return Err(VerifyError::UnsupportedVersion(f.version));
+    }
+    if f.code.is_empty() {
+        return Err(VerifyError::EmptyCode);
+    }
+    if f.num_params > f.max_stack_size {
+        return Err(VerifyError::StackTooSmall { num_params: f.num_params, max_stack_size: f.max_stack_size });
+    }
+
+    // the EXTRAARG words of LOADKX and SETLIST, which nothing may jump to
+    let mut operands = vec![false; f.code.len()];
+    let mut pc = 0;
+    while pc < f.code.len() {
+        let i = f.code[pc];
+        let two_words = i.opcode() == OP_LOADKX || i.opcode() == OP_SETLIST && i.abc().2 == 0;
+        if two_words && pc + 1 < f.code.len() {
+            operands[pc + 1] = true;
+            pc += 1;
+        }
+        pc += 1;
+    }
+
+    let mut pc = 0;
+    while pc < f.code.len() {
+        pc = verify_instruction(f, pc, &operands)?;
+    }
+
+    for (idx, p) in f.protos.iter().enumerate() {
+        verify_upvalues(f, p).map_err(|err| VerifyError::InProto { idx, err: Box::new(err) })?;
+        verify(p).map_err(|err| VerifyError::InProto { idx, err: Box::new(err) })?;
+    }
+    Ok(())
+}
+
+// upvalues of a closure are captured from the enclosing function's
+// registers (instack) or from its own upvalues
+fn verify_upvalues(parent: &Prototype, f: &Prototype) -> Result<(), VerifyError> {
+    for (idx, upval) in f.upvalues.iter().enumerate() {
+        let ok = if upval.instack != 0 {
+            upval.idx < parent.max_stack_size
+        } else {
+            (upval.idx as usize) < parent.upvalues.len()
+        };
+        if !ok {
+            return Err(VerifyError::BadUpvalue { idx });
+        }
+    }
+    Ok(())
+}
+
+// checks the instruction at `pc` and returns the pc of the next one
+fn verify_instruction(f: &Prototype, pc: usize, operands: &[bool]) -> Result<usize, VerifyError> {
+    let i = f.code[pc];
+    let op = i.opcode();
+    if op as usize >= OPCODES.len() {
+        return Err(VerifyError::InvalidOpcode { pc, opcode: op });
+    }
+
+    let reg = |r: isize| {
+        if r < 0 || r >= f.max_stack_size as isize {
+            return Err(VerifyError::RegisterOutOfRange { pc, reg: r, max_stack_size: f.max_stack_size });
+        }
+        Ok(())
+    };
+    let constant = |idx: isize| {
+        if idx as usize >= f.constants.len() {
+            return Err(VerifyError::ConstantOutOfRange { pc, idx, len: f.constants.len() });
+        }
+        Ok(())
+    };
+    let rk = |x: isize| if x > 0xff { constant(x & 0xff) } else { reg(x) };
+    let upvalue = |idx: isize| {
+        if idx as usize >= f.upvalues.len() {
+            return Err(VerifyError::UpvalueOutOfRange { pc, idx, len: f.upvalues.len() });
+        }
+        Ok(())
+    };
+    let jump = |sbx: isize| {
+        let target = pc as isize + 1 + sbx;
+        if target < 0 || target >= f.code.len() as isize {
+            return Err(VerifyError::JumpOutOfRange { pc, target });
+        }
+        if operands[target as usize] {
+            return Err(VerifyError::JumpIntoInstruction { pc, target });
+        }
+        Ok(())
+    };
+    // instructions that may skip the next one need two successors
+    let successors = |n: usize| {
+        if pc + n >= f.code.len() {
+            return Err(VerifyError::FallsOffEnd { pc });
+        }
+        Ok(())
+    };
+    let extra_arg = || match f.code.get(pc + 1) {
+        Some(next) if next.opcode() == OP_EXTRAARG => Ok(next.ax()),
+        _ => Err(VerifyError::MissingExtraArg { pc }),
+    };
+
+    match i.opmode() {
+        OpMode::IABC => {
+            let (a, b, c) = i.abc();
+            match op {
+                OP_EQ | OP_LT | OP_LE => {
+                    rk(b)?;
+                    rk(c)?;
+                    successors(2)?;
+                }
+                OP_SETTABUP => {
+                    upvalue(a)?;
+                    rk(b)?;
+                    rk(c)?;
+                }
+                OP_GETUPVAL | OP_SETUPVAL => {
+                    reg(a)?;
+                    upvalue(b)?;
+                }
+                OP_GETTABUP => {
+                    reg(a)?;
+                    upvalue(b)?;
+                    rk(c)?;
+                }
+                OP_LOADBOOL => {
+                    reg(a)?;
+                    if c != 0 {
+                        successors(2)?;
+                    }
+                }
+                OP_LOADNIL => {
+                    reg(a)?;
+                    reg(a + b)?;
+                }
+                OP_SELF => {
+                    reg(a + 1)?;
+                    reg(b)?;
+                    rk(c)?;
+                }
+                OP_CONCAT => {
+                    reg(a)?;
+                    reg(b)?;
+                    reg(c)?;
+                    if c < b {
+                        return Err(VerifyError::RegisterOutOfRange { pc, reg: c, max_stack_size: f.max_stack_size });
+                    }
+                }
+                OP_TEST => {
+                    reg(a)?;
+                    successors(2)?;
+                }
+                OP_TESTSET => {
+                    reg(a)?;
+                    reg(b)?;
+                    successors(2)?;
+                }
+                OP_CALL | OP_TAILCALL => {
+                    reg(a)?;
+                    if b > 0 {
+                        reg(a + b - 1)?;
+                    }
+                    if c > 1 {
+                        reg(a + c - 2)?;
+                    }
+                }
+                OP_RETURN => {
+                    if b != 1 {
+                        reg(a)?;
+                    }
+                    if b > 1 {
+                        reg(a + b - 2)?;
+                    }
+                }
+                OP_VARARG => {
+                    reg(a)?;
+                    if b > 1 {
+                        reg(a + b - 2)?;
+                    }
+                }
+                OP_TFORCALL => {
+                    reg(a + 2 + c.max(1))?;
+                    match f.code.get(pc + 1) {
+                        Some(next) if next.opcode() == OP_TFORLOOP => (),
+                        _ => return Err(VerifyError::MissingTForLoop { pc }),
+                    }
+                }
+                OP_SETLIST => {
+                    reg(a + b)?;
+                    if c == 0 {
+                        extra_arg()?;
+                        successors(2)?;
+                        return Ok(pc + 2);
+                    }
+                }
+                _ => {
+                    reg(a)?;
+                    for (x, mode) in [(b, i.b_mode()), (c, i.c_mode())] {
+                        match mode {
+                            OpArgMode::OpArgR => reg(x)?,
+                            OpArgMode::OpArgK => rk(x)?,
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+        OpMode::IABx => {
+            let (a, bx) = i.a_bx();
+            reg(a)?;
+            match op {
+                OP_LOADK => constant(bx)?,
+                OP_LOADKX => {
+                    constant(extra_arg()?)?;
+                    successors(2)?;
+                    return Ok(pc + 2);
+                }
+                OP_CLOSURE if bx as usize >= f.protos.len() => {
+                    return Err(VerifyError::ProtoOutOfRange { pc, idx: bx, len: f.protos.len() });
+                }
+                _ => (),
+            }
+        }
+        OpMode::IAsBx => {
+            let (a, sbx) = i.a_sbx();
+            match op {
+                OP_JMP => {
+                    if a != 0 {
+                        reg(a - 1)?;
+                    }
+                }
+                OP_FORLOOP | OP_FORPREP => reg(a + 3)?,
+                _ => reg(a + 1)?,
+            }
+            jump(sbx)?;
+        }
+        OpMode::IAx => return Err(VerifyError::UnexpectedExtraArg { pc }),
+    }
+
+    if op != OP_RETURN && op != OP_JMP {
+        successors(1)?;
+    }
+    Ok(pc + 1)
+}
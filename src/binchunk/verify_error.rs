@@ -0,0 +1,60 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    UnsupportedVersion(u8),
+    EmptyCode,
+    StackTooSmall { num_params: u8, max_stack_size: u8 },
+    InvalidOpcode { pc: usize, opcode: u8 },
+    RegisterOutOfRange { pc: usize, reg: isize, max_stack_size: u8 },
+    ConstantOutOfRange { pc: usize, idx: isize, len: usize },
+    UpvalueOutOfRange { pc: usize, idx: isize, len: usize },
+    ProtoOutOfRange { pc: usize, idx: isize, len: usize },
+    JumpOutOfRange { pc: usize, target: isize },
+    JumpIntoInstruction { pc: usize, target: isize },
+    MissingExtraArg { pc: usize },
+    UnexpectedExtraArg { pc: usize },
+    MissingTForLoop { pc: usize },
+    FallsOffEnd { pc: usize },
+    BadUpvalue { idx: usize },
+    InProto { idx: usize, err: Box<VerifyError> },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // pcs are reported 1-based, like `luac -l`
+        match self {
+            VerifyError::UnsupportedVersion(version) => write!(f, "cannot verify version 0x{:02x} bytecode", version),
+            VerifyError::EmptyCode => write!(f, "function has no code"),
+            VerifyError::StackTooSmall { num_params, max_stack_size } => {
+                write!(f, "{} params do not fit in {} slots", num_params, max_stack_size)
+            }
+            VerifyError::InvalidOpcode { pc, opcode } => write!(f, "[{}] invalid opcode {}", pc + 1, opcode),
+            VerifyError::RegisterOutOfRange { pc, reg, max_stack_size } => {
+                write!(f, "[{}] register {} out of range ({} slots)", pc + 1, reg, max_stack_size)
+            }
+            VerifyError::ConstantOutOfRange { pc, idx, len } => {
+                write!(f, "[{}] constant {} out of range ({} constants)", pc + 1, idx, len)
+            }
+            VerifyError::UpvalueOutOfRange { pc, idx, len } => {
+                write!(f, "[{}] upvalue {} out of range ({} upvalues)", pc + 1, idx, len)
+            }
+            VerifyError::ProtoOutOfRange { pc, idx, len } => {
+                write!(f, "[{}] function {} out of range ({} functions)", pc + 1, idx, len)
+            }
+            VerifyError::JumpOutOfRange { pc, target } => write!(f, "[{}] jump to {} out of range", pc + 1, target + 1),
+            VerifyError::JumpIntoInstruction { pc, target } => {
+                write!(f, "[{}] jump to {} lands on the EXTRAARG of the instruction before", pc + 1, target + 1)
+            }
+            VerifyError::MissingExtraArg { pc } => write!(f, "[{}] missing EXTRAARG", pc + 1),
+            VerifyError::UnexpectedExtraArg { pc } => write!(f, "[{}] unexpected EXTRAARG", pc + 1),
+            VerifyError::MissingTForLoop { pc } => write!(f, "[{}] TFORCALL not followed by TFORLOOP", pc + 1),
+            VerifyError::FallsOffEnd { pc } => write!(f, "[{}] execution falls off the end of the function", pc + 1),
+            VerifyError::BadUpvalue { idx } => write!(f, "upvalue {} refers to a missing slot or upvalue", idx),
+            VerifyError::InProto { idx, err } => write!(f, "function {}: {}", idx, err),
+        }
+    }
+}
+
+impl Error for VerifyError {}
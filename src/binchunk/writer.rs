@@ -0,0 +1,159 @@
+use super::binary_chunk::*;
+use super::chunk_error::ChunkError;
+use super::header_const;
+use super::tag_const;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::OP_TBC;
+
+const LUAI_MAXSHORTLEN: usize = 40;
+
+pub struct Writer {
+    pub data: Vec<u8>,
+    pub strip: bool
+}
+
+impl Writer {
+    fn write_byte(&mut self, b: u8) {
+        self.data.push(b);
+    }
+
+    fn write_uint32(&mut self, n: u32) {
+        self.data.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn write_uint64(&mut self, n: u64) {
+        self.data.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn write_int64(&mut self, n: i64) {
+        self.write_uint64(n as u64);
+    }
+
+    fn write_float64(&mut self, n: f64) {
+        self.write_uint64(n.to_bits());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn write_string(&mut self, s: Option<&[u8]>) {
+        match s {
+            None => self.write_byte(0),
+            Some(s) => {
+                let size = s.len() + 1;
+                if size < 0xff {
+                    self.write_byte(size as u8);
+                } else {
+                    self.write_byte(0xff);
+                    self.write_uint64(size as u64);
+                }
+                self.write_bytes(s);
+            }
+        }
+    }
+
+    fn write_constant(&mut self, k: &Constant) {
+        match k {
+            Constant::Nil => self.write_byte(tag_const::TAG_NIL),
+            Constant::Boolean(b) => {
+                self.write_byte(tag_const::TAG_BOOLEAN);
+                self.write_byte(*b as u8);
+            }
+            Constant::Integer(i) => {
+                self.write_byte(tag_const::TAG_INTEGER);
+                self.write_int64(*i);
+            }
+            Constant::Number(n) => {
+                self.write_byte(tag_const::TAG_NUMBER);
+                self.write_float64(*n);
+            }
+            Constant::Str(s) => {
+                let tag = if s.len() <= LUAI_MAXSHORTLEN { tag_const::TAG_SHORT_STR } else { tag_const::TAG_LONG_STR };
+                self.write_byte(tag);
+                self.write_string(Some(s));
+            }
+        }
+    }
+
+    fn write_upvalue(&mut self, upval: &Upvalue) {
+        self.write_byte(upval.instack);
+        self.write_byte(upval.idx);
+    }
+
+    fn write_loc_var(&mut self, loc_var: &LocVar) {
+        self.write_string(Some(loc_var.var_name.as_bytes()));
+        self.write_uint32(loc_var.start_pc);
+        self.write_uint32(loc_var.end_pc);
+    }
+
+    fn write_vec<T, F>(&mut self, vec: &[T], f: F)
+    where
+        F: Fn(&mut Writer, &T),
+    {
+        self.write_uint32(vec.len() as u32);
+        for x in vec {
+            f(self, x);
+        }
+    }
+
+    fn write_header(&mut self) {
+        self.write_bytes(&header_const::LUA_SIGNATURE);
+        self.write_byte(header_const::LUAC_VERSION);
+        self.write_byte(header_const::LUAC_FORMAT);
+        self.write_bytes(&header_const::LUAC_DATA);
+        self.write_byte(header_const::CINT_SIZE);
+        self.write_byte(header_const::CSIZET_SIZE);
+        self.write_byte(header_const::INSTRUCTION_SIZE);
+        self.write_byte(header_const::LUA_INTEGER_SIZE);
+        self.write_byte(header_const::LUA_NUMBER_SIZE);
+        self.write_int64(header_const::LUAC_INT);
+        self.write_float64(header_const::LUAC_NUM);
+    }
+
+    fn write_proto(&mut self, f: &Prototype, parent_source: &str) {
+        // like luac, only write the source when it differs from the parent's
+        if self.strip || f.source.is_empty() || f.source == parent_source {
+            self.write_string(None);
+        } else {
+            self.write_string(Some(f.source.as_bytes()));
+        }
+        self.write_uint32(f.line_defined);
+        self.write_uint32(f.last_line_defined);
+        self.write_byte(f.num_params);
+        self.write_byte(f.is_vararg);
+        self.write_byte(f.max_stack_size);
+        self.write_vec(&f.code, |w, i| w.write_uint32(*i));
+        self.write_vec(&f.constants, |w, k| w.write_constant(k));
+        self.write_vec(&f.upvalues, |w, u| w.write_upvalue(u));
+        self.write_vec(&f.protos, |w, p| w.write_proto(p, &f.source));
+        if self.strip {
+            self.write_uint32(0);
+            self.write_uint32(0);
+            self.write_uint32(0);
+        } else {
+            self.write_vec(&f.line_info, |w, l| w.write_uint32(*l));
+            self.write_vec(&f.loc_vars, |w, v| w.write_loc_var(v));
+            self.write_vec(&f.upvalue_names, |w, s| w.write_string(Some(s.as_bytes())));
+        }
+    }
+
+    pub fn write_binary_chunk(&mut self, chunk: &BinaryChunk) {
+        self.write_header();
+        self.write_byte(chunk.size_upvalues);
+        self.write_proto(&chunk.main_func, "");
+    }
+}
+
+// functions loaded from 5.1 or 5.4 chunks keep their own instructions,
+// which would mean something else in a 5.3 chunk; so would TBC, which
+// this VM adds to the 5.3 set for <close> locals
+pub fn check(f: &Prototype) -> Result<(), ChunkError> {
+    if f.version != header_const::LUAC_VERSION {
+        return Err(ChunkError::VersionMismatch { expected: header_const::LUAC_VERSION, found: f.version });
+    }
+    if let Some(pc) = f.code.iter().position(|i| i.opcode() == OP_TBC) {
+        return Err(ChunkError::Unrepresentable { pc, reason: "to-be-closed variables need Lua 5.4" });
+    }
+    f.protos.iter().try_for_each(|p| check(p))
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
+use crate::compiler::formatter::{format, FormatOptions, QuoteStyle};
+
+const USAGE: &str = "usage: lua-engine fmt [options] [file ...]
+Formats each file, or stdin when none is given, to stdout.
+Available options are:
+  --indent n           indent with n spaces (default 4)
+  --tabs               indent with tabs
+  --quote double|single
+                       preferred quote for strings (default double)
+  --no-trailing-comma  no comma after the last field of a split table
+  --width n            line width (default 100)
+  --check              list the files that are not formatted
+  -w, --write          rewrite the files in place";
+
+// `lua-engine fmt`; returns the exit status
+pub fn main(args: &[String]) -> i32 {
+    match run(args) {
+        Ok(status) => status,
+        Err(msg) => {
+            eprintln!("lua-engine fmt: {}", msg);
+            1
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<i32, String> {
+    let mut options = FormatOptions::default();
+    let (mut check, mut write) = (false, false);
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("'{}' needs an argument\n{}", name, USAGE));
+        match arg.as_str() {
+            "--indent" => options.indent_width = number(value(arg)?)?,
+            "--tabs" => options.use_tabs = true,
+            "--quote" => {
+                options.quote_style = match value(arg)?.as_str() {
+                    "double" => QuoteStyle::Double,
+                    "single" => QuoteStyle::Single,
+                    other => return Err(format!("unknown quote style '{}'", other)),
+                }
+            }
+            "--no-trailing-comma" => options.trailing_comma = false,
+            "--width" => options.line_width = number(value(arg)?)?,
+            "--check" => check = true,
+            "-w" | "--write" => write = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(0);
+            }
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unrecognized option '{}'\n{}", arg, USAGE)),
+            _ => files.push(arg.clone()),
+        }
+    }
+    if files.is_empty() {
+        files.push(String::from("-"));
+    }
+
+    let mut status = 0;
+    for file in &files {
+        let (source, chunk) = if file == "-" {
+            let mut chunk = Vec::new();
+            io::stdin().read_to_end(&mut chunk).map_err(|e| format!("cannot read stdin: {}", e))?;
+            (String::from("=stdin"), chunk)
+        } else {
+            let chunk = fs::read(file).map_err(|e| format!("cannot open {}: {}", file, e))?;
+            (format!("@{}", file), chunk)
+        };
+        let formatted = match format(&source, &chunk, &options) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("lua-engine fmt: {}", e);
+                status = 1;
+                continue;
+            }
+        };
+        if check {
+            if formatted.as_bytes() != chunk {
+                println!("{}", file);
+                status = 1;
+            }
+        } else if write && file != "-" {
+            if formatted.as_bytes() != chunk {
+                fs::write(file, formatted).map_err(|e| format!("cannot write {}: {}", file, e))?;
+            }
+        } else {
+            io::stdout().write_all(formatted.as_bytes()).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(status)
+}
+
+fn number(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("'{}' is not a number", s))
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::io::{self, Read};
+
+use crate::compiler::linter::{lint, LintOptions};
+use crate::compiler::syntax_error::chunk_id;
+
+const USAGE: &str = "usage: lua-engine lint [options] [file ...]
+Checks each file, or stdin when none is given, printing one
+'file:line:column: message [rule]' per warning.
+Available options are:
+  --globals a,b,...    also allow reading these globals
+  --no-std             do not allow the standard library globals";
+
+// `lua-engine lint`; returns the exit status, 1 if anything was reported
+pub fn main(args: &[String]) -> i32 {
+    match run(args) {
+        Ok(status) => status,
+        Err(msg) => {
+            eprintln!("lua-engine lint: {}", msg);
+            1
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<i32, String> {
+    let mut options = LintOptions::default();
+    let mut extra = Vec::new();
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--globals" => {
+                let value = args.next().ok_or(format!("'{}' needs an argument\n{}", arg, USAGE))?;
+                extra.extend(value.split(',').filter(|g| !g.is_empty()).map(String::from));
+            }
+            "--no-std" => options.globals.clear(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(0);
+            }
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unrecognized option '{}'\n{}", arg, USAGE)),
+            _ => files.push(arg.clone()),
+        }
+    }
+    options.globals.extend(extra);
+    if files.is_empty() {
+        files.push(String::from("-"));
+    }
+
+    let mut status = 0;
+    for file in &files {
+        let (source, chunk) = if file == "-" {
+            let mut chunk = Vec::new();
+            io::stdin().read_to_end(&mut chunk).map_err(|e| format!("cannot read stdin: {}", e))?;
+            (String::from("=stdin"), chunk)
+        } else {
+            let chunk = fs::read(file).map_err(|e| format!("cannot open {}: {}", file, e))?;
+            (format!("@{}", file), chunk)
+        };
+        match lint(&source, &chunk, &options) {
+            Ok(lints) => {
+                for l in &lints {
+                    println!("{}:{}", chunk_id(&source), l);
+                }
+                if !lints.is_empty() {
+                    status = 1;
+                }
+            }
+            Err(e) => {
+                eprintln!("lua-engine lint: {}", e);
+                status = 1;
+            }
+        }
+    }
+    Ok(status)
+}
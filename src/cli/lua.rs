@@ -0,0 +1,209 @@
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::panic;
+
+use super::repl;
+use super::run;
+use crate::api::lua_state::LuaAPI;
+use crate::state::lua_state::LuaState;
+
+const USAGE: &str = "usage: lua-engine [options] [script [args]]
+Available options are:
+  -e stat  execute string 'stat'
+  -i       enter interactive mode after executing 'script'
+  -l name  require library 'name'
+  -v       show version information
+  -E       ignore environment variables
+  --       stop handling options
+  -        stop handling options and execute stdin
+Also: lua-engine fmt|lint [options] [file ...]
+Libraries: base, math, string (with no pattern matching) and table";
+
+const LUA_PATH_DEFAULT: &str = "./?.lua;./?/init.lua";
+
+// what the command line asks for, in the order of lua.c
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub interactive: bool,
+    pub version: bool,
+    pub ignore_env: bool,
+    // -e and -l, in order: (option, argument)
+    pub actions: Vec<(char, String)>,
+    // index of the script in the arguments, if any
+    pub script: Option<usize>,
+}
+
+// `lua-engine` as a drop-in for the `lua` command; `args` holds the
+// program name too; returns the exit status
+pub fn main(args: &[String]) -> i32 {
+    let progname = args.first().map_or("lua-engine", String::as_str);
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}: {}\n{}", progname, msg, USAGE);
+            return 1;
+        }
+    };
+    // errors of the VM are panics, reported by run::execute
+    panic::set_hook(Box::new(|_| {}));
+    match run(args, &options) {
+        Ok(()) => 0,
+        Err(msg) => {
+            eprintln!("{}: {}", progname, msg);
+            1
+        }
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        match arg.as_str() {
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            "-i" => {
+                options.interactive = true;
+                options.version = true;
+            }
+            "-v" => options.version = true,
+            "-E" => options.ignore_env = true,
+            _ if arg.starts_with("-e") || arg.starts_with("-l") => {
+                let option = arg.as_bytes()[1] as char;
+                let value = if arg.len() > 2 {
+                    arg[2..].to_string()
+                } else {
+                    i += 1;
+                    args.get(i).filter(|a| !a.starts_with('-')).ok_or(format!("'{}' needs argument", arg))?.clone()
+                };
+                options.actions.push((option, value));
+            }
+            _ if arg.starts_with('-') => return Err(format!("unrecognized option '{}'", arg)),
+            _ => break,
+        }
+        i += 1;
+    }
+    if i < args.len() {
+        options.script = Some(i);
+    }
+    Ok(options)
+}
+
+// the `arg` table: the script at 0, what comes before it at negative
+// indices and its arguments at positive ones; without a script the
+// program name is at 0
+pub fn arg_table(args: &[String], script: Option<usize>) -> Vec<(i64, String)> {
+    let script = script.unwrap_or(0) as i64;
+    args.iter().enumerate().map(|(i, a)| (i as i64 - script, a.clone())).collect()
+}
+
+fn run(args: &[String], options: &Options) -> Result<(), String> {
+    if options.version {
+        print_version();
+    }
+    // every chunk runs in this state, in the order of the command line
+    let ls = &mut run::new_state();
+    ls.create_table(0, args.len());
+    for (i, arg) in arg_table(args, options.script) {
+        ls.push_string(arg);
+        ls.set_i(-2, i);
+    }
+    ls.set_global("arg");
+    if !options.ignore_env {
+        init(ls)?;
+    }
+    for (option, value) in &options.actions {
+        match option {
+            'e' => dochunk(ls, "=(command line)", value.as_bytes(), &[])?,
+            _ => require(ls, value, options.ignore_env)?,
+        }
+    }
+    if let Some(i) = options.script {
+        // the arguments after the script are its `...`, as in lua.c
+        dofile(ls, &args[i], &args[i + 1..])?;
+    }
+    if options.interactive {
+        repl::main(ls);
+    } else if options.script.is_none() && options.actions.is_empty() && !options.version {
+        if io::stdin().is_terminal() {
+            print_version();
+            repl::main(ls);
+        } else {
+            dofile(ls, "-", &[])?;
+        }
+    }
+    Ok(())
+}
+
+fn print_version() {
+    println!("lua-engine {} (Lua 5.3)", env!("CARGO_PKG_VERSION"));
+}
+
+// runs LUA_INIT_5_3 or LUA_INIT: a file when it starts with '@',
+// a chunk otherwise
+fn init(ls: &mut LuaState) -> Result<(), String> {
+    let (name, init) = match env::var("LUA_INIT_5_3") {
+        Ok(init) => ("=LUA_INIT_5_3", init),
+        Err(_) => match env::var("LUA_INIT") {
+            Ok(init) => ("=LUA_INIT", init),
+            Err(_) => return Ok(()),
+        },
+    };
+    match init.strip_prefix('@') {
+        Some(file) => dofile(ls, file, &[]),
+        None => dochunk(ls, name, init.as_bytes(), &[]),
+    }
+}
+
+// looks for a module along LUA_PATH_5_3 or LUA_PATH, where ";;" stands
+// for the default path, and runs it with its name and file as `...`;
+// the global of the same name gets what it returns, or true, as with
+// `name = require(name)` in lua.c
+fn require(ls: &mut LuaState, name: &str, ignore_env: bool) -> Result<(), String> {
+    let path = match ignore_env {
+        true => None,
+        false => env::var("LUA_PATH_5_3").or_else(|_| env::var("LUA_PATH")).ok(),
+    };
+    let path = match path {
+        Some(path) => path.replace(";;", &format!(";{};", LUA_PATH_DEFAULT)),
+        None => String::from(LUA_PATH_DEFAULT),
+    };
+    let file_name = name.replace('.', "/");
+    let mut tried = String::new();
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let file = template.replace('?', &file_name);
+        if fs::metadata(&file).is_ok() {
+            let (source, chunk) = run::read_script(&file)?;
+            let proto = run::load(&source, &chunk)?;
+            run::call(ls, proto, &[String::from(name), file], 1).map_err(report)?;
+            if ls.is_nil(-1) {
+                ls.pop(1);
+                ls.push_boolean(true);
+            }
+            ls.set_global(name);
+            return Ok(());
+        }
+        tried.push_str(&format!("\n\tno file '{}'", file));
+    }
+    Err(format!("module '{}' not found:{}", name, tried))
+}
+
+// runs a script, or stdin for "-", with `args` as its `...`
+fn dofile(ls: &mut LuaState, file: &str, args: &[String]) -> Result<(), String> {
+    let (source, chunk) = run::read_script(file)?;
+    dochunk(ls, &source, &chunk, args)
+}
+
+fn dochunk(ls: &mut LuaState, source: &str, chunk: &[u8], args: &[String]) -> Result<(), String> {
+    let proto = run::load(source, chunk)?;
+    run::call(ls, proto, args, 0).map_err(report)
+}
+
+fn report(failure: run::Failure) -> String {
+    format!("{}\n{}", failure.msg, failure.traceback)
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::run;
+use crate::binchunk::binary_chunk::{BinaryChunk, Header, Prototype};
+use crate::binchunk::{self, header_const, listing};
+use crate::compiler;
+
+const USAGE: &str = "usage: luac [options] [filenames]
+Available options are:
+  -l       list (use -l -l for full listing)
+  -o name  output to file 'name' (default is \"luac.out\")
+  -p       parse only
+  -s       strip debug information
+  -v       show version information
+  --       stop handling options
+  -        stop handling options and process stdin";
+
+const OUTPUT: &str = "luac.out";
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    // 0, 1 or 2 for -l -l
+    pub listing: usize,
+    // None writes to stdout, with "-o -"
+    pub output: Option<String>,
+    pub dumping: bool,
+    pub stripping: bool,
+    pub version: bool,
+    pub files: Vec<String>,
+}
+
+// `luac`; `args` holds the program name too; returns the exit status
+pub fn main(args: &[String]) -> i32 {
+    match parse_args(args).and_then(|options| run(&options)) {
+        Ok(()) => 0,
+        Err(msg) => {
+            eprintln!("luac: {}", msg);
+            1
+        }
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        listing: 0,
+        output: Some(String::from(OUTPUT)),
+        dumping: true,
+        stripping: false,
+        version: false,
+        files: Vec::new(),
+    };
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            "-l" => options.listing += 1,
+            "-o" => {
+                i += 1;
+                options.output = match args.get(i).map(String::as_str) {
+                    Some("-") => None,
+                    Some(output) if !output.starts_with('-') => Some(output.to_string()),
+                    _ => return Err(format!("'-o' needs argument\n{}", USAGE)),
+                };
+            }
+            "-p" => options.dumping = false,
+            "-s" => options.stripping = true,
+            "-v" => options.version = true,
+            arg if arg.starts_with('-') => return Err(format!("unrecognized option '{}'\n{}", arg, USAGE)),
+            _ => break,
+        }
+        i += 1;
+    }
+    options.files = args[i..].to_vec();
+    if options.files.is_empty() && !options.version {
+        return Err(format!("no input files given\n{}", USAGE));
+    }
+    if options.files.is_empty() {
+        // just -v
+        options.dumping = false;
+    }
+    Ok(options)
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    if options.version {
+        println!("luac (lua-engine {}) Lua 5.3", env!("CARGO_PKG_VERSION"));
+        if options.files.is_empty() {
+            return Ok(());
+        }
+    }
+    let mut protos = Vec::new();
+    for file in &options.files {
+        let (source, chunk) = run::read_script(file)?;
+        protos.push(run::load(&source, &chunk)?);
+    }
+    let main_func = combine(protos)?;
+    if options.listing > 0 {
+        listing::list(&mut io::stdout(), &main_func, options.listing > 1).map_err(|e| e.to_string())?;
+    }
+    if options.dumping {
+        let chunk = BinaryChunk {
+            header: Header {
+                signature: header_const::LUA_SIGNATURE,
+                version: header_const::LUAC_VERSION,
+                format: header_const::LUAC_FORMAT,
+                luac_data: header_const::LUAC_DATA,
+                cint_size: header_const::CINT_SIZE,
+                sizet_size: header_const::CSIZET_SIZE,
+                instruction_size: header_const::INSTRUCTION_SIZE,
+                lua_integer_size: header_const::LUA_INTEGER_SIZE,
+                lua_number_size: header_const::LUA_NUMBER_SIZE,
+                luac_int: header_const::LUAC_INT,
+                luac_num: header_const::LUAC_NUM,
+            },
+            size_upvalues: main_func.upvalues.len() as u8,
+            main_func: Box::new(main_func),
+        };
+        let data = if options.stripping { binchunk::dump_stripped(&chunk) } else { binchunk::dump(&chunk) };
+        let data = data.map_err(|e| e.to_string())?;
+        match &options.output {
+            Some(output) => fs::write(output, data).map_err(|e| format!("cannot open {}: {}", output, e))?,
+            None => io::stdout().write_all(&data).map_err(|e| format!("cannot write stdout: {}", e))?,
+        }
+    }
+    Ok(())
+}
+
+// one main function running the given ones in turn, as luac makes
+// for several files
+pub fn combine(mut protos: Vec<Prototype>) -> Result<Prototype, String> {
+    if protos.len() == 1 {
+        return Ok(protos.pop().unwrap());
+    }
+    let calls = "(function()end)();\n".repeat(protos.len());
+    let mut main_func = *compiler::compile("=luac", calls.as_bytes()).map_err(|e| e.to_string())?;
+    for (slot, mut f) in main_func.protos.iter_mut().zip(protos) {
+        // _ENV comes from the new main function
+        if let Some(env) = f.upvalues.first_mut() {
+            env.instack = 0;
+        }
+        *slot = Rc::new(f);
+    }
+    main_func.line_info.clear();
+    Ok(main_func)
+}
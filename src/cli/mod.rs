@@ -0,0 +1,6 @@
+pub mod fmt;
+pub mod lint;
+pub mod lua;
+pub mod luac;
+pub mod repl;
+pub mod run;
@@ -0,0 +1,108 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use super::run;
+use crate::compiler;
+use crate::state::lua_state::LuaState;
+
+#[derive(Debug, PartialEq)]
+pub enum Eval {
+    // the values returned, already converted by tostring
+    Done(Vec<String>),
+    // the chunk ends too early and more lines should be read
+    Incomplete,
+    Error(String),
+}
+
+// the interactive mode of `lua-engine`, running what is typed in `ls`
+// until the end of stdin; returns the exit status
+pub fn main(ls: &mut LuaState) -> i32 {
+    // errors of the VM are panics, reported by eval itself
+    panic::set_hook(Box::new(|_| {}));
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("cannot read stdin: {}", e);
+            return 1;
+        }
+    };
+    // failing to read or write the history is not worth a message
+    let history = history_file();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    // the editor only prompts on a terminal, where it reads the keys
+    let tty = io::stdin().is_terminal();
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() { "> " } else { ">> " };
+        if !tty {
+            print!("{}", prompt);
+            io::stdout().flush().unwrap();
+        }
+        let line = match editor.readline(if tty { prompt } else { "" }) {
+            Ok(line) => line,
+            // ^C drops what was typed so far, as lua.c does
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(_) => {
+                println!();
+                return 0;
+            }
+        };
+        if buffer.is_empty() {
+            // "=exp" is short for "return exp", as in Lua 5.2
+            buffer = match line.strip_prefix('=') {
+                Some(exp) => format!("return {}", exp),
+                None => line,
+            };
+        } else {
+            buffer.push('\n');
+            buffer.push_str(&line);
+        }
+        match eval(ls, &buffer) {
+            Eval::Incomplete => continue,
+            Eval::Done(values) if values.is_empty() => {}
+            Eval::Done(values) => println!("{}", values.join("\t")),
+            Eval::Error(msg) => eprintln!("{}", msg),
+        }
+        if editor.add_history_entry(buffer.as_str()).unwrap_or(false) {
+            if let Some(path) = &history {
+                let _ = editor.append_history(path);
+            }
+        }
+        buffer.clear();
+    }
+}
+
+// evaluates what was typed at the prompt, as an expression list if it
+// is one and as statements otherwise
+pub fn eval(ls: &mut LuaState, input: &str) -> Eval {
+    let proto = match compiler::compile("=stdin", format!("return {}", input).as_bytes()) {
+        Ok(proto) => proto,
+        Err(_) => match compiler::compile("=stdin", input.as_bytes()) {
+            Ok(proto) => proto,
+            Err(e) if e.msg.ends_with("<eof>") => return Eval::Incomplete,
+            Err(e) => return Eval::Error(e.to_string()),
+        },
+    };
+    match run::execute(ls, *proto, &[]) {
+        Ok(values) => Eval::Done(values),
+        Err(failure) => Eval::Error(failure.msg),
+    }
+}
+
+// $LUA_ENGINE_HISTORY, or ~/.lua_engine_history
+fn history_file() -> Option<PathBuf> {
+    match env::var_os("LUA_ENGINE_HISTORY") {
+        Some(path) => Some(path.into()),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".lua_engine_history")),
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::{self, Read};
+use std::panic;
+
+use crate::api::lua_state::LuaAPI;
+use crate::binchunk::{self, binary_chunk::Prototype, header_const};
+use crate::compiler::{self, syntax_error::chunk_id};
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::{number_to_string, LuaValue};
+use crate::stdlib::{self, base};
+
+// an error raised while running a chunk
+pub struct Failure {
+    // prefixed with the position, as error() does
+    pub msg: String,
+    pub traceback: String,
+}
+
+// reads a script, or stdin for "-", giving its chunk name and contents;
+// a first line starting with '#' is dropped but for its newline, which
+// goes too before a precompiled chunk
+pub fn read_script(file: &str) -> Result<(String, Vec<u8>), String> {
+    let (source, mut chunk) = if file == "-" {
+        let mut chunk = Vec::new();
+        io::stdin().read_to_end(&mut chunk).map_err(|e| format!("cannot read stdin: {}", e))?;
+        (String::from("=stdin"), chunk)
+    } else {
+        let chunk = fs::read(file).map_err(|e| format!("cannot open {}: {}", file, e))?;
+        (format!("@{}", file), chunk)
+    };
+    if chunk.first() == Some(&b'#') {
+        let end = chunk.iter().position(|&b| b == b'\n').unwrap_or(chunk.len());
+        chunk.drain(..end);
+        if chunk[1.min(chunk.len())..].starts_with(&header_const::LUA_SIGNATURE) {
+            chunk.remove(0);
+        }
+    }
+    Ok((source, chunk))
+}
+
+// loads a chunk as luaL_loadbuffer does: precompiled when it starts with
+// the signature, source otherwise
+pub fn load(source: &str, chunk: &[u8]) -> Result<Prototype, String> {
+    if !chunk.starts_with(&header_const::LUA_SIGNATURE) {
+        return compiler::compile(source, chunk).map(|proto| *proto).map_err(|e| e.to_string());
+    }
+    let name = chunk_id(source);
+    let mut bin = binchunk::undump(chunk).map_err(|e| format!("{}: {}", name, e))?;
+    match bin.header.version {
+        header_const::LUAC_VERSION_51 => bin = binchunk::translate_51(bin).map_err(|e| format!("{}: {}", name, e))?,
+        header_const::LUAC_VERSION => {}
+        version => {
+            return Err(format!(
+                "{}: version mismatch (expected 0x{:02x}, found 0x{:02x})",
+                name,
+                header_const::LUAC_VERSION,
+                version
+            ))
+        }
+    }
+    binchunk::verify(&bin.main_func).map_err(|e| format!("{}: {}", name, e))?;
+    Ok(*bin.main_func)
+}
+
+// a state with the standard libraries open, to run chunks in one after
+// the other
+pub fn new_state() -> LuaState {
+    let mut ls = LuaState::empty(64);
+    stdlib::open_libs(&mut ls);
+    ls
+}
+
+// runs a main function in `ls` with `args` as its `...`, giving the
+// values it returns converted by tostring
+pub fn execute(ls: &mut LuaState, proto: Prototype, args: &[String]) -> Result<Vec<String>, Failure> {
+    call(ls, proto, args, -1)?;
+    let values = protect(ls, |ls| {
+        let values = (1..=ls.get_top() as isize).map(|idx| String::from_utf8_lossy(&base::tostring(ls, idx)).into_owned());
+        values.collect()
+    })?;
+    ls.set_top(0);
+    Ok(values)
+}
+
+// runs a main function in `ls` with `args` as its `...`, leaving
+// `n_results` of the values it returns on the stack, or all of them
+// when negative
+pub fn call(ls: &mut LuaState, proto: Prototype, args: &[String], n_results: isize) -> Result<(), Failure> {
+    ls.load(proto);
+    for arg in args {
+        ls.push_string(arg.clone());
+    }
+    protect(ls, |ls| ls.call(args.len(), n_results))
+}
+
+// the errors of the VM are panics, caught here, and leave the stack
+// empty
+fn protect<T>(ls: &mut LuaState, f: impl FnOnce(&mut LuaState) -> T) -> Result<T, Failure> {
+    let e = match panic::catch_unwind(panic::AssertUnwindSafe(|| f(&mut *ls))) {
+        Ok(result) => return Ok(result),
+        Err(e) => e,
+    };
+    let frames = call_stack(ls);
+    let err = ls.caught(e);
+    let err = ls.unwind(0, 0, err);
+    let msg = match err {
+        LuaValue::Str(s) => String::from_utf8_lossy(&s).into_owned(),
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => number_to_string(n),
+        val => format!("(error object is a {} value)", ls.type_name(val.ty())),
+    };
+    let mut traceback = String::from("stack traceback:");
+    for (name, line, what) in &frames {
+        match line {
+            Some(line) => traceback.push_str(&format!("\n\t{}:{}: {}", name, line, what)),
+            None => traceback.push_str(&format!("\n\t{}: {}", name, what)),
+        }
+    }
+    traceback.push_str("\n\t[C]: in ?");
+    Err(Failure { msg, traceback })
+}
+
+// the functions running, innermost first: their chunk, the line of the
+// instruction they were at when known, and what they are
+fn call_stack(ls: &LuaState) -> Vec<(String, Option<u32>, String)> {
+    let frames = ls.frames().iter().rev().map(|ci| {
+        let proto = &ci.closure.proto;
+        if ci.closure.rust_fn.is_some() {
+            return (String::from("[C]"), None, String::from("in ?"));
+        }
+        let name = if proto.source.is_empty() { String::from("?") } else { chunk_id(&proto.source) };
+        // the instruction running is the last one fetched
+        let line = (ci.pc as usize).checked_sub(1).and_then(|pc| proto.line_info.get(pc)).copied();
+        let what = match proto.line_defined {
+            0 => String::from("in main chunk"),
+            line => format!("in function <{}:{}>", name, line),
+        };
+        (name, line, what)
+    });
+    frames.collect()
+}
@@ -0,0 +1,200 @@
+// Every statement and expression records the line and column of the
+// token it starts with; constructs that span lines also record where
+// they end, as luac attributes some instructions to that line.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+    pub last_line: usize,
+    // where the token closing the block starts: end, else, elseif,
+    // until, or the end of the chunk
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub exps: Vec<Exp>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    Empty,
+    Break,
+    Label(String),
+    Goto(String),
+    Do(Block),
+    // the expression is always a Call
+    Call(Exp),
+    While { cond: Exp, block: Block },
+    Repeat { block: Block, cond: Exp },
+    If { conds: Vec<Exp>, blocks: Vec<Block>, else_block: Option<Block> },
+    NumericFor { var: Name, init: Box<Exp>, limit: Box<Exp>, step: Option<Box<Exp>>, block: Block, line_of_do: usize },
+    GenericFor { names: Vec<Name>, exps: Vec<Exp>, block: Block, line_of_do: usize },
+    // one attribute for each name
+    Local { names: Vec<Name>, attribs: Vec<Option<Attrib>>, exps: Vec<Exp> },
+    Assign { vars: Vec<Exp>, exps: Vec<Exp> },
+    LocalFunction { name: Name, func: FuncBody },
+    // function a.b.c:m() ... end, with `method` holding m
+    Function { path: Vec<Name>, method: Option<Name>, func: FuncBody },
+}
+
+// `local x <const>` and `local x <close>`, from Lua 5.4
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attrib {
+    Const,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exp {
+    pub kind: ExpKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpKind {
+    Nil,
+    True,
+    False,
+    Vararg,
+    Integer(i64),
+    Float(f64),
+    Str(Vec<u8>),
+    Name(String),
+    Unop { op: UnOp, exp: Box<Exp> },
+    Binop { op: BinOp, lhs: Box<Exp>, rhs: Box<Exp> },
+    Table { fields: Vec<Field>, last_line: usize },
+    Function(Box<FuncBody>),
+    Paren(Box<Exp>),
+    // a.b is stored as a["b"]
+    Index { prefix: Box<Exp>, key: Box<Exp> },
+    Call { prefix: Box<Exp>, method: Option<Name>, args: Vec<Exp>, last_line: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Positional(Exp),
+    Named(Name, Exp),
+    Keyed(Exp, Exp),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub block: Block,
+    pub line: usize,
+    pub last_line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Minus,
+    Not,
+    Len,
+    BNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    // (left, right) priorities as in lparser.c; right-associative
+    // operators bind less tightly on their right
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod | BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::Pow => (14, 13),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Div => "/",
+            BinOp::IDiv => "//",
+            BinOp::BAnd => "&",
+            BinOp::BOr => "|",
+            BinOp::BXor => "~",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Concat => "..",
+            BinOp::Eq => "==",
+            BinOp::Ne => "~=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+}
+
+// priority of unary operators, between ^ and the multiplicative ones
+pub const UNARY_PRIORITY: u8 = 12;
+
+impl UnOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Minus => "-",
+            UnOp::Not => "not",
+            UnOp::Len => "#",
+            UnOp::BNot => "~",
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::binchunk::binary_chunk::{Constant, LocVar, Prototype};
+use crate::vm::encode_error::EncodeError;
+use crate::vm::instruction::{Instruction, MAXARG_BX};
+use crate::vm::opcodes::*;
+
+use super::ast::{Block, FuncBody};
+use super::func_state::{BlockScope, FuncState, LabelDesc, UpvalDesc};
+use super::syntax_error::{chunk_id, SyntaxError};
+
+// limits from llimits.h and lparser.c
+const MAXREGS: usize = 255;
+const MAXVARS: usize = 200;
+const MAXUPVAL: usize = 255;
+
+// where a name refers to, as VLOCAL/VUPVAL/VVOID in lparser.c
+#[derive(Clone, Copy, PartialEq)]
+pub enum VarKind {
+    Local(usize),
+    Upval(usize),
+    Global,
+}
+
+// Lowers the AST of a chunk into prototypes. Functions being compiled are
+// kept on a stack, the innermost last, so that names can be resolved
+// through the enclosing ones.
+pub struct Codegen {
+    source: String,
+    chunk_id: String,
+    funcs: Vec<FuncState>,
+    pub(super) line: usize, // attached to every emitted instruction
+}
+
+pub fn generate(source: &str, block: &Block) -> Result<Prototype, SyntaxError> {
+    let mut cg = Codegen { source: String::from(source), chunk_id: chunk_id(source), funcs: Vec::new(), line: 1 };
+    let mut main = FuncState::new(0, 0, true);
+    main.upvalues.push(UpvalDesc { name: String::from("_ENV"), instack: true, idx: 0 });
+    cg.funcs.push(main);
+    cg.enter_block(false);
+    cg.stat_list(block, false)?;
+    cg.line = block.last_line;
+    cg.emit_abc(OP_RETURN, 0, 1, 0)?;
+    cg.leave_block()?;
+    let main = cg.funcs.pop().unwrap();
+    Ok(main.into_proto(cg.source))
+}
+
+impl Codegen {
+    pub(super) fn fs(&self) -> &FuncState {
+        self.funcs.last().unwrap()
+    }
+
+    pub(super) fn fs_mut(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    pub(super) fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError { chunk_id: self.chunk_id.clone(), line: self.line, column: 0, msg: String::from(msg) }
+    }
+
+    fn error_limit(&self, what: &str, limit: usize) -> SyntaxError {
+        self.error(&format!("too many {} (limit is {}) in {}", what, limit, self.fs().describe()))
+    }
+
+    /* code emission */
+
+    fn emit(&mut self, i: Result<u32, EncodeError>) -> Result<usize, SyntaxError> {
+        let i = i.map_err(|e| self.error(&e.to_string()))?;
+        let line = self.line as u32;
+        let fs = self.fs_mut();
+        fs.code.push(i);
+        fs.line_info.push(line);
+        Ok(fs.code.len() - 1)
+    }
+
+    pub(super) fn emit_abc(&mut self, op: u8, a: usize, b: isize, c: isize) -> Result<usize, SyntaxError> {
+        self.emit(u32::encode_abc(op, a as isize, b, c))
+    }
+
+    pub(super) fn emit_abx(&mut self, op: u8, a: usize, bx: usize) -> Result<usize, SyntaxError> {
+        self.emit(u32::encode_abx(op, a as isize, bx as isize))
+    }
+
+    pub(super) fn emit_asbx(&mut self, op: u8, a: usize, sbx: isize) -> Result<usize, SyntaxError> {
+        self.emit(u32::encode_asbx(op, a as isize, sbx))
+    }
+
+    pub(super) fn emit_ax(&mut self, op: u8, ax: usize) -> Result<usize, SyntaxError> {
+        self.emit(u32::encode_ax(op, ax as isize))
+    }
+
+    pub(super) fn pc(&self) -> usize {
+        self.fs().pc()
+    }
+
+    // R(a) := K(idx), through EXTRAARG when idx does not fit in Bx
+    pub(super) fn load_k(&mut self, a: usize, idx: usize) -> Result<(), SyntaxError> {
+        if idx as isize <= MAXARG_BX {
+            self.emit_abx(OP_LOADK, a, idx)?;
+        } else {
+            self.emit_abx(OP_LOADKX, a, 0)?;
+            self.emit_ax(OP_EXTRAARG, idx)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn constant(&mut self, k: Constant) -> usize {
+        self.fs_mut().constant(k)
+    }
+
+    /* jumps */
+
+    // a JMP to be patched later
+    pub(super) fn jump(&mut self) -> Result<usize, SyntaxError> {
+        self.emit_asbx(OP_JMP, 0, 0)
+    }
+
+    // points the jump-like instruction at `pc` (JMP, FORPREP, FORLOOP,
+    // TFORLOOP) to `target`
+    pub(super) fn fix_jump(&mut self, pc: usize, target: usize) -> Result<(), SyntaxError> {
+        let i = self.fs().code[pc];
+        let (a, _) = i.a_sbx();
+        let offset = target as isize - (pc as isize + 1);
+        let i = u32::encode_asbx(i.opcode(), a, offset).map_err(|_| self.error("control structure too long"))?;
+        self.fs_mut().code[pc] = i;
+        Ok(())
+    }
+
+    pub(super) fn patch_list(&mut self, list: Vec<usize>, target: usize) -> Result<(), SyntaxError> {
+        for pc in list {
+            self.fix_jump(pc, target)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn patch_to_here(&mut self, list: Vec<usize>) -> Result<(), SyntaxError> {
+        let here = self.pc();
+        self.patch_list(list, here)
+    }
+
+    // makes the JMP at `pc` also close upvalues from register `level` up
+    pub(super) fn patch_close(&mut self, pc: usize, level: usize) {
+        let i = self.fs().code[pc];
+        let (_, sbx) = i.a_sbx();
+        self.fs_mut().code[pc] = u32::encode_asbx(OP_JMP, level as isize + 1, sbx).unwrap();
+    }
+
+    /* registers */
+
+    pub(super) fn reserve_regs(&mut self, n: usize) -> Result<(), SyntaxError> {
+        let new_stack = self.fs().free_reg + n;
+        if new_stack > self.fs().max_stack {
+            if new_stack >= MAXREGS {
+                return Err(self.error("function or expression needs too many registers"));
+            }
+            self.fs_mut().max_stack = new_stack;
+        }
+        self.fs_mut().free_reg = new_stack;
+        Ok(())
+    }
+
+    pub(super) fn alloc_reg(&mut self) -> Result<usize, SyntaxError> {
+        self.reserve_regs(1)?;
+        Ok(self.fs().free_reg - 1)
+    }
+
+    pub(super) fn free_reg(&self) -> usize {
+        self.fs().free_reg
+    }
+
+    // releases every register from `mark` up
+    pub(super) fn free_to(&mut self, mark: usize) {
+        self.fs_mut().free_reg = mark;
+    }
+
+    /* variables */
+
+    // activates a local in the next register, which the caller has reserved
+    pub(super) fn add_local(&mut self, name: &str) -> Result<usize, SyntaxError> {
+        if self.fs().nactvar() >= MAXVARS {
+            return Err(self.error_limit("local variables", MAXVARS));
+        }
+        let pc = self.pc() as u32;
+        let fs = self.fs_mut();
+        fs.loc_vars.push(LocVar { var_name: String::from(name), start_pc: pc, end_pc: 0 });
+        fs.actvar.push(fs.loc_vars.len() - 1);
+        Ok(fs.actvar.len() - 1)
+    }
+
+    fn remove_vars(&mut self, level: usize) {
+        let pc = self.pc() as u32;
+        let fs = self.fs_mut();
+        while fs.actvar.len() > level {
+            let idx = fs.actvar.pop().unwrap();
+            fs.loc_vars[idx].end_pc = pc;
+        }
+    }
+
+    pub(super) fn single_var(&mut self, name: &str) -> Result<VarKind, SyntaxError> {
+        let level = self.funcs.len() - 1;
+        self.single_var_aux(level, name, true)
+    }
+
+    // `base` is false when looking from an inner function, in which case
+    // a local found here becomes an upvalue of that function
+    fn single_var_aux(&mut self, level: usize, name: &str, base: bool) -> Result<VarKind, SyntaxError> {
+        if let Some(reg) = self.funcs[level].search_var(name) {
+            if !base {
+                self.funcs[level].mark_upval(reg);
+            }
+            return Ok(VarKind::Local(reg));
+        }
+        if let Some(idx) = self.funcs[level].search_upvalue(name) {
+            return Ok(VarKind::Upval(idx));
+        }
+        if level == 0 {
+            return Ok(VarKind::Global);
+        }
+        let (instack, idx) = match self.single_var_aux(level - 1, name, false)? {
+            VarKind::Global => return Ok(VarKind::Global),
+            VarKind::Local(reg) => (true, reg),
+            VarKind::Upval(idx) => (false, idx),
+        };
+        let fs = &mut self.funcs[level];
+        if fs.upvalues.len() >= MAXUPVAL {
+            let msg = format!("too many upvalues (limit is {}) in {}", MAXUPVAL, fs.describe());
+            return Err(self.error(&msg));
+        }
+        fs.upvalues.push(UpvalDesc { name: String::from(name), instack, idx });
+        Ok(VarKind::Upval(fs.upvalues.len() - 1))
+    }
+
+    // where globals live: the local or upvalue named _ENV
+    pub(super) fn env(&mut self) -> Result<VarKind, SyntaxError> {
+        self.single_var("_ENV")
+    }
+
+    /* blocks, labels and gotos */
+
+    pub(super) fn enter_block(&mut self, is_loop: bool) {
+        let fs = self.fs_mut();
+        let bl = BlockScope {
+            nactvar: fs.nactvar(),
+            first_label: fs.labels.len(),
+            first_goto: fs.gotos.len(),
+            is_loop,
+            upval: false,
+            inside_tbc: fs.blocks.last().is_some_and(|bl| bl.inside_tbc),
+        };
+        fs.blocks.push(bl);
+    }
+
+    pub(super) fn leave_block(&mut self) -> Result<(), SyntaxError> {
+        let (nactvar, is_loop, upval) = {
+            let bl = self.fs().blocks.last().unwrap();
+            (bl.nactvar, bl.is_loop, bl.upval)
+        };
+        if self.fs().blocks.len() > 1 && upval {
+            // a jump to here that closes the upvalues
+            let j = self.jump()?;
+            self.patch_close(j, nactvar);
+            self.patch_to_here(vec![j])?;
+        }
+        if is_loop {
+            let line = self.line;
+            self.label("break", line, false)?;
+        }
+        let bl = self.fs_mut().blocks.pop().unwrap();
+        self.remove_vars(bl.nactvar);
+        let fs = self.fs_mut();
+        fs.free_reg = fs.nactvar();
+        fs.labels.truncate(bl.first_label);
+        if !self.fs().blocks.is_empty() {
+            self.move_gotos_out(&bl)
+        } else if let Some(gt) = self.fs().gotos.get(bl.first_goto) {
+            let msg = if gt.name == "break" {
+                format!("<break> at line {} not inside a loop", gt.line)
+            } else {
+                format!("no visible label '{}' for <goto> at line {}", gt.name, gt.line)
+            };
+            Err(self.error(&msg))
+        } else {
+            Ok(())
+        }
+    }
+
+    // hands the pending gotos of a finished block to the enclosing one
+    fn move_gotos_out(&mut self, bl: &BlockScope) -> Result<(), SyntaxError> {
+        let mut i = bl.first_goto;
+        while i < self.fs().gotos.len() {
+            let gt = &self.fs().gotos[i];
+            if gt.nactvar > bl.nactvar {
+                if bl.upval {
+                    let pc = gt.pc;
+                    self.patch_close(pc, bl.nactvar);
+                }
+                self.fs_mut().gotos[i].nactvar = bl.nactvar;
+            }
+            if !self.find_label(i)? {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // resolves goto `g` against the labels of the current block
+    fn find_label(&mut self, g: usize) -> Result<bool, SyntaxError> {
+        let fs = self.fs();
+        let bl = fs.blocks.last().unwrap();
+        let gt = &fs.gotos[g];
+        let found = fs.labels[bl.first_label..].iter().position(|lb| lb.name == gt.name);
+        let l = match found {
+            Some(l) => bl.first_label + l,
+            None => return Ok(false),
+        };
+        let (gt_pc, gt_nactvar, lb_nactvar) = (gt.pc, gt.nactvar, fs.labels[l].nactvar);
+        if gt_nactvar > lb_nactvar {
+            self.patch_close(gt_pc, lb_nactvar);
+        }
+        self.close_goto(g, l)?;
+        Ok(true)
+    }
+
+    fn close_goto(&mut self, g: usize, l: usize) -> Result<(), SyntaxError> {
+        let fs = self.fs();
+        let (gt, lb) = (&fs.gotos[g], &fs.labels[l]);
+        if gt.nactvar < lb.nactvar {
+            let msg = format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                gt.name,
+                gt.line,
+                fs.local_name(gt.nactvar)
+            );
+            return Err(self.error(&msg));
+        }
+        let (pc, target) = (gt.pc, lb.pc);
+        self.fix_jump(pc, target)?;
+        self.fs_mut().gotos.remove(g);
+        Ok(())
+    }
+
+    // `last` labels are followed only by void statements up to the end of
+    // the block, so the locals of the block are already out of scope
+    pub(super) fn label(&mut self, name: &str, line: usize, last: bool) -> Result<(), SyntaxError> {
+        let fs = self.fs();
+        let bl = fs.blocks.last().unwrap();
+        if let Some(lb) = fs.labels[bl.first_label..].iter().find(|lb| lb.name == name) {
+            let msg = format!("label '{}' already defined on line {}", name, lb.line);
+            return Err(self.error(&msg));
+        }
+        let nactvar = if last { bl.nactvar } else { fs.nactvar() };
+        let first_goto = bl.first_goto;
+        let pc = self.pc();
+        let fs = self.fs_mut();
+        fs.labels.push(LabelDesc { name: String::from(name), pc, line, nactvar });
+        let l = fs.labels.len() - 1;
+        let mut i = first_goto;
+        while i < self.fs().gotos.len() {
+            if self.fs().gotos[i].name == name {
+                self.close_goto(i, l)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn goto(&mut self, name: &str, line: usize) -> Result<(), SyntaxError> {
+        let pc = self.jump()?;
+        let fs = self.fs_mut();
+        let nactvar = fs.nactvar();
+        fs.gotos.push(LabelDesc { name: String::from(name), pc, line, nactvar });
+        let g = fs.gotos.len() - 1;
+        self.find_label(g)?;
+        Ok(())
+    }
+
+    /* functions */
+
+    // compiles a nested function into the protos of the current one,
+    // returning its index there
+    pub(super) fn function(&mut self, body: &FuncBody, is_method: bool) -> Result<usize, SyntaxError> {
+        if self.fs().protos.len() as isize > MAXARG_BX {
+            return Err(self.error_limit("functions", MAXARG_BX as usize));
+        }
+        self.funcs.push(FuncState::new(body.line, body.last_line, body.is_vararg));
+        self.enter_block(false);
+        if is_method {
+            self.add_local("self")?;
+        }
+        for param in &body.params {
+            self.add_local(&param.name)?;
+        }
+        let num_params = self.fs().nactvar();
+        self.fs_mut().num_params = num_params;
+        self.reserve_regs(num_params)?;
+        self.stat_list(&body.block, false)?;
+        self.line = body.last_line;
+        self.emit_abc(OP_RETURN, 0, 1, 0)?;
+        self.leave_block()?;
+        let fs = self.funcs.pop().unwrap();
+        let proto = fs.into_proto(self.source.clone());
+        let parent = self.fs_mut();
+        parent.protos.push(proto);
+        Ok(parent.protos.len() - 1)
+    }
+}
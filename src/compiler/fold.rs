@@ -0,0 +1,414 @@
+use super::ast::*;
+use super::syntax_error::SyntaxError;
+use crate::api::consts::*;
+use crate::state::arith_ops::checked_arith;
+use crate::state::lua_value::{number_to_string, LuaValue};
+
+// what a name in scope stands for
+enum Var {
+    Regular,
+    // <const> and <close> locals, which cannot be assigned to
+    ReadOnly,
+    // a <const> local initialized with a constant, which takes no
+    // register: its uses are replaced with the value, as in Lua 5.4
+    Inline(ExpKind),
+}
+
+// Folds constant expressions and drops branches that can never run,
+// before code is generated for a chunk. Results are computed the way
+// the VM would at run time; whatever would fail or overflow there is
+// left for the VM to do. Assignments to read-only locals are rejected
+// here, as this pass is the one that follows the scopes of locals.
+pub struct Folder {
+    chunk_id: String,
+    vars: Vec<(String, Var)>,
+}
+
+impl Folder {
+    pub fn new(chunk_id: String) -> Folder {
+        Folder { chunk_id, vars: Vec::new() }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Var> {
+        self.vars.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    fn declare(&mut self, name: &Name) {
+        self.vars.push((name.name.clone(), Var::Regular));
+    }
+
+    fn check_assign(&self, name: &str, line: usize) -> Result<(), SyntaxError> {
+        match self.lookup(name) {
+            Some(Var::ReadOnly | Var::Inline(_)) => Err(SyntaxError {
+                chunk_id: self.chunk_id.clone(),
+                line,
+                column: 0,
+                msg: format!("attempt to assign to const variable '{}'", name),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn block(&mut self, block: &mut Block) -> Result<(), SyntaxError> {
+        let nvars = self.vars.len();
+        self.stat_list(block)?;
+        self.vars.truncate(nvars);
+        Ok(())
+    }
+
+    fn stat_list(&mut self, block: &mut Block) -> Result<(), SyntaxError> {
+        for stat in block.stats.iter_mut() {
+            self.stat(stat)?;
+        }
+        if let Some(ret) = &mut block.ret {
+            self.exp_list(&mut ret.exps)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&mut self, stat: &mut Stat) -> Result<(), SyntaxError> {
+        match &mut stat.kind {
+            StatKind::Empty | StatKind::Break | StatKind::Label(_) | StatKind::Goto(_) => {}
+            StatKind::Do(block) => self.block(block)?,
+            StatKind::Call(e) => self.exp(e)?,
+            StatKind::While { cond, block } => {
+                self.exp(cond)?;
+                self.block(block)?;
+                if is_false(cond) && !has_jumps(block, true) {
+                    stat.kind = StatKind::Empty;
+                }
+            }
+            StatKind::Repeat { block, cond } => {
+                // the condition sees the locals of the body
+                let nvars = self.vars.len();
+                self.stat_list(block)?;
+                self.exp(cond)?;
+                self.vars.truncate(nvars);
+            }
+            StatKind::If { .. } => self.if_stat(stat)?,
+            StatKind::NumericFor { var, init, limit, step, block, .. } => {
+                self.exp(init)?;
+                self.exp(limit)?;
+                if let Some(step) = step {
+                    self.exp(step)?;
+                }
+                let nvars = self.vars.len();
+                self.declare(var);
+                self.block(block)?;
+                self.vars.truncate(nvars);
+            }
+            StatKind::GenericFor { names, exps, block, .. } => {
+                self.exp_list(exps)?;
+                let nvars = self.vars.len();
+                names.iter().for_each(|name| self.declare(name));
+                self.block(block)?;
+                self.vars.truncate(nvars);
+            }
+            StatKind::Local { .. } => self.local_stat(stat)?,
+            StatKind::Assign { vars, exps } => {
+                for var in vars.iter_mut() {
+                    match &mut var.kind {
+                        ExpKind::Name(name) => self.check_assign(name, var.line)?,
+                        _ => self.exp(var)?,
+                    }
+                }
+                self.exp_list(exps)?;
+            }
+            StatKind::LocalFunction { name, func } => {
+                self.declare(name);
+                self.function(func, false)?;
+            }
+            StatKind::Function { .. } => self.function_stat(stat)?,
+        }
+        Ok(())
+    }
+
+    fn local_stat(&mut self, stat: &mut Stat) -> Result<(), SyntaxError> {
+        let StatKind::Local { names, attribs, exps } = &mut stat.kind else {
+            return Ok(());
+        };
+        self.exp_list(exps)?;
+        // like Lua 5.4, only the last local may become a compile-time
+        // constant, and only when no values are adjusted
+        let mut inline = None;
+        if names.len() == exps.len() && attribs.last() == Some(&Some(Attrib::Const)) {
+            let e = exps.last().unwrap();
+            if is_false(e) || is_true(e) {
+                inline = Some(e.kind.clone());
+                exps.pop();
+                attribs.pop();
+            }
+        }
+        for (name, attrib) in names.iter().zip(attribs.iter()) {
+            let var = if attrib.is_some() { Var::ReadOnly } else { Var::Regular };
+            self.vars.push((name.name.clone(), var));
+        }
+        if let Some(k) = inline {
+            let name = names.pop().unwrap();
+            self.vars.push((name.name, Var::Inline(k)));
+            if names.is_empty() {
+                stat.kind = StatKind::Empty;
+            }
+        }
+        Ok(())
+    }
+
+    // `function a.b()` with `a` a compile-time constant indexes the
+    // value itself, so it is rewritten into the assignment it stands for
+    fn function_stat(&mut self, stat: &mut Stat) -> Result<(), SyntaxError> {
+        let StatKind::Function { path, method, func } = &mut stat.kind else {
+            return Ok(());
+        };
+        if path.len() == 1 && method.is_none() {
+            self.check_assign(&path[0].name, path[0].line)?;
+        }
+        let is_method = method.is_some();
+        self.function(func, is_method)?;
+        let Some(Var::Inline(k)) = self.lookup(&path[0].name) else {
+            return Ok(());
+        };
+        let mut var = Exp { kind: k.clone(), line: path[0].line, column: path[0].column };
+        for name in path[1..].iter().chain(method.iter()) {
+            let key = Exp { kind: ExpKind::Str(name.name.clone().into_bytes()), line: name.line, column: name.column };
+            var = Exp { kind: ExpKind::Index { prefix: Box::new(var), key: Box::new(key) }, line: name.line, column: name.column };
+        }
+        let mut func = func.clone();
+        if is_method {
+            func.params.insert(0, Name { name: String::from("self"), line: func.line, column: 0 });
+        }
+        let exp = Exp { kind: ExpKind::Function(Box::new(func)), line: stat.line, column: stat.column };
+        stat.kind = StatKind::Assign { vars: vec![var], exps: vec![exp] };
+        Ok(())
+    }
+
+    fn function(&mut self, func: &mut FuncBody, is_method: bool) -> Result<(), SyntaxError> {
+        let nvars = self.vars.len();
+        if is_method {
+            self.vars.push((String::from("self"), Var::Regular));
+        }
+        func.params.iter().for_each(|param| self.declare(param));
+        self.block(&mut func.block)?;
+        self.vars.truncate(nvars);
+        Ok(())
+    }
+
+    // branches under a constant false condition go away, and so does all
+    // that follows a constant true one, which becomes the else part
+    fn if_stat(&mut self, stat: &mut Stat) -> Result<(), SyntaxError> {
+        let StatKind::If { conds, blocks, else_block } = &mut stat.kind else {
+            return Ok(());
+        };
+        for (cond, block) in conds.iter_mut().zip(blocks.iter_mut()) {
+            self.exp(cond)?;
+            self.block(block)?;
+        }
+        if let Some(block) = else_block.as_mut() {
+            self.block(block)?;
+        }
+        let mut i = 0;
+        while i < conds.len() {
+            if is_false(&conds[i]) && !has_jumps(&blocks[i], false) {
+                conds.remove(i);
+                blocks.remove(i);
+            } else if is_true(&conds[i])
+                && blocks[i + 1..].iter().chain(else_block.iter()).all(|b| !has_jumps(b, false))
+            {
+                conds.truncate(i);
+                *else_block = blocks.drain(i..).next();
+            } else {
+                i += 1;
+            }
+        }
+        if conds.is_empty() {
+            stat.kind = match else_block.take() {
+                Some(block) => StatKind::Do(block),
+                None => StatKind::Empty,
+            };
+        }
+        Ok(())
+    }
+
+    fn exp_list(&mut self, exps: &mut [Exp]) -> Result<(), SyntaxError> {
+        exps.iter_mut().try_for_each(|e| self.exp(e))
+    }
+
+    // a chain like a + b + c nests in its left operands as deep as it is
+    // long, which the parser builds in a loop; it is taken apart and
+    // folded back together in a loop too, rather than recursing
+    fn binop(&mut self, e: &mut Exp) -> Result<(), SyntaxError> {
+        let placeholder = Exp { kind: ExpKind::Nil, line: e.line, column: e.column };
+        let mut exp = std::mem::replace(e, placeholder);
+        let mut spine = Vec::new();
+        while let ExpKind::Binop { op, lhs, rhs } = exp.kind {
+            spine.push((op, rhs, exp.line, exp.column));
+            exp = *lhs;
+        }
+        self.exp(&mut exp)?;
+        while let Some((op, mut rhs, line, column)) = spine.pop() {
+            self.exp(&mut rhs)?;
+            exp = Exp { kind: ExpKind::Binop { op, lhs: Box::new(exp), rhs }, line, column };
+            fold_binop_exp(&mut exp);
+        }
+        *e = exp;
+        Ok(())
+    }
+
+    fn exp(&mut self, e: &mut Exp) -> Result<(), SyntaxError> {
+        match &mut e.kind {
+            ExpKind::Name(name) => {
+                if let Some(Var::Inline(k)) = self.lookup(name) {
+                    e.kind = k.clone();
+                }
+            }
+            ExpKind::Unop { op, exp } => {
+                self.exp(exp)?;
+                if let Some(kind) = fold_unop(*op, exp) {
+                    e.kind = kind;
+                }
+            }
+            ExpKind::Binop { .. } => self.binop(e)?,
+            ExpKind::Paren(inner) => {
+                self.exp(inner)?;
+                if is_false(inner) || is_true(inner) {
+                    e.kind = inner.kind.clone();
+                }
+            }
+            ExpKind::Table { fields, .. } => {
+                for field in fields.iter_mut() {
+                    match field {
+                        Field::Positional(v) | Field::Named(_, v) => self.exp(v)?,
+                        Field::Keyed(k, v) => {
+                            self.exp(k)?;
+                            self.exp(v)?;
+                        }
+                    }
+                }
+            }
+            ExpKind::Function(func) => self.function(func, false)?,
+            ExpKind::Index { prefix, key } => {
+                self.exp(prefix)?;
+                self.exp(key)?;
+            }
+            ExpKind::Call { prefix, args, .. } => {
+                self.exp(prefix)?;
+                self.exp_list(args)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// whether dropping `block` could hide an error the code generator would
+// report: a goto or label anywhere, or a break that leaves the block
+fn has_jumps(block: &Block, in_loop: bool) -> bool {
+    block.stats.iter().any(|stat| match &stat.kind {
+        StatKind::Break => !in_loop,
+        StatKind::Label(_) | StatKind::Goto(_) => true,
+        StatKind::Do(b) => has_jumps(b, in_loop),
+        StatKind::If { blocks, else_block, .. } => {
+            blocks.iter().chain(else_block.iter()).any(|b| has_jumps(b, in_loop))
+        }
+        StatKind::While { block, .. }
+        | StatKind::Repeat { block, .. }
+        | StatKind::NumericFor { block, .. }
+        | StatKind::GenericFor { block, .. } => has_jumps(block, true),
+        _ => false,
+    })
+}
+
+// folds `e`, a binary operation on operands already folded
+fn fold_binop_exp(e: &mut Exp) {
+    let ExpKind::Binop { op, lhs, rhs } = &e.kind else { return };
+    match op {
+        BinOp::And | BinOp::Or => {
+            // the result is one of the operands; and/or adjust a call or
+            // vararg on the right to one value, which parentheses keep
+            let keep_lhs = if *op == BinOp::And { is_false(lhs) } else { is_true(lhs) };
+            if keep_lhs {
+                *e = (**lhs).clone();
+            } else if is_false(lhs) || is_true(lhs) {
+                let rhs = (**rhs).clone();
+                match rhs.kind {
+                    ExpKind::Call { .. } | ExpKind::Vararg => e.kind = ExpKind::Paren(Box::new(rhs)),
+                    _ => *e = rhs,
+                }
+            }
+        }
+        _ => {
+            if let Some(kind) = fold_binop(*op, lhs, rhs) {
+                e.kind = kind;
+            }
+        }
+    }
+}
+
+fn fold_unop(op: UnOp, e: &Exp) -> Option<ExpKind> {
+    match op {
+        UnOp::Not if is_false(e) => Some(ExpKind::True),
+        UnOp::Not if is_true(e) => Some(ExpKind::False),
+        UnOp::Minus => arith(LUA_OPUNM, e, e),
+        UnOp::BNot => arith(LUA_OPBNOT, e, e),
+        _ => None,
+    }
+}
+
+fn fold_binop(op: BinOp, lhs: &Exp, rhs: &Exp) -> Option<ExpKind> {
+    let op = match op {
+        BinOp::Add => LUA_OPADD,
+        BinOp::Sub => LUA_OPSUB,
+        BinOp::Mul => LUA_OPMUL,
+        BinOp::Mod => LUA_OPMOD,
+        BinOp::Pow => LUA_OPPOW,
+        BinOp::Div => LUA_OPDIV,
+        BinOp::IDiv => LUA_OPIDIV,
+        BinOp::BAnd => LUA_OPBAND,
+        BinOp::BOr => LUA_OPBOR,
+        BinOp::BXor => LUA_OPBXOR,
+        BinOp::Shl => LUA_OPSHL,
+        BinOp::Shr => LUA_OPSHR,
+        BinOp::Concat => return concat(lhs, rhs),
+        _ => return None,
+    };
+    arith(op, lhs, rhs)
+}
+
+// strings are not converted, as the VM would only do so at run time
+fn arith(op: u8, lhs: &Exp, rhs: &Exp) -> Option<ExpKind> {
+    let (a, b) = (number(lhs)?, number(rhs)?);
+    match checked_arith(&a, &b, op)? {
+        LuaValue::Integer(i) => Some(ExpKind::Integer(i)),
+        LuaValue::Number(n) => Some(ExpKind::Float(n)),
+        _ => None,
+    }
+}
+
+// numbers are written as the VM's concat writes them
+fn concat(lhs: &Exp, rhs: &Exp) -> Option<ExpKind> {
+    let bytes = |e: &Exp| match &e.kind {
+        ExpKind::Str(s) => Some(s.clone()),
+        ExpKind::Integer(i) => Some(i.to_string().into_bytes()),
+        ExpKind::Float(n) => Some(number_to_string(*n).into_bytes()),
+        _ => None,
+    };
+    let mut s = bytes(lhs)?;
+    s.extend(bytes(rhs)?);
+    Some(ExpKind::Str(s))
+}
+
+fn number(e: &Exp) -> Option<LuaValue> {
+    match e.kind {
+        ExpKind::Integer(i) => Some(LuaValue::Integer(i)),
+        ExpKind::Float(n) => Some(LuaValue::Number(n)),
+        _ => None,
+    }
+}
+
+fn is_false(e: &Exp) -> bool {
+    matches!(e.kind, ExpKind::Nil | ExpKind::False)
+}
+
+// a constant that tests true
+fn is_true(e: &Exp) -> bool {
+    matches!(e.kind, ExpKind::True | ExpKind::Integer(_) | ExpKind::Float(_) | ExpKind::Str(_))
+}
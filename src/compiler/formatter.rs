@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+
+use super::ast::*;
+use super::lexer::{Comment, Lexer};
+use super::syntax_error::SyntaxError;
+use super::token::{TokenKind, KEYWORDS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteStyle {
+    Double,
+    Single,
+}
+
+pub struct FormatOptions {
+    pub indent_width: usize,
+    pub use_tabs: bool,
+    // strings holding the preferred quote but not the other one use the other
+    pub quote_style: QuoteStyle,
+    // after the last field of a table constructor split over lines
+    pub trailing_comma: bool,
+    pub line_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions { indent_width: 4, use_tabs: false, quote_style: QuoteStyle::Double, trailing_comma: true, line_width: 100 }
+    }
+}
+
+// Writes a chunk back in canonical form: one statement per line, block
+// contents indented, operators spaced, calls parenthesized and strings
+// quoted alike. Numerals and long strings keep their spelling, and
+// comments are put back before the statement or table field they
+// precede, or at the end of the line they followed.
+pub fn format(source: &str, chunk: &[u8], options: &FormatOptions) -> Result<String, SyntaxError> {
+    // a first line starting with '#' is not Lua, as for the interpreter;
+    // its newline stays so that lines keep their numbers
+    let (shebang, chunk) = match chunk.first() {
+        Some(b'#') => {
+            let end = chunk.iter().position(|&b| b == b'\n').unwrap_or(chunk.len());
+            (Some(String::from_utf8_lossy(&chunk[..end]).into_owned()), &chunk[end..])
+        }
+        _ => (None, chunk),
+    };
+    let block = super::parse(source, chunk)?;
+    let mut printer = Printer::new(source, chunk, options)?;
+    printer.stats(&block, 0);
+    let mut out = String::new();
+    if let Some(line) = shebang {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    let body = printer.out.trim_start_matches('\n');
+    if !body.is_empty() {
+        out.push_str(body);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+struct Printer<'a> {
+    options: &'a FormatOptions,
+    out: String,
+    comments: Vec<Comment>,
+    // whether each comment follows a token on its line
+    trailing: Vec<bool>,
+    next_comment: usize,
+    // (line, column, end line) of every token and comment, in order
+    spans: Vec<(usize, usize, usize)>,
+    // numerals and long strings as written, by where they start
+    literals: HashMap<(usize, usize), String>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(source: &str, chunk: &[u8], options: &'a FormatOptions) -> Result<Printer<'a>, SyntaxError> {
+        let mut lexer = Lexer::new(source, chunk);
+        let mut tokens = Vec::new();
+        let mut literals = HashMap::new();
+        loop {
+            let token = lexer.next_token()?;
+            if token.kind == TokenKind::Eof {
+                break;
+            }
+            let text = lexer.text(&token);
+            tokens.push((token.line, token.column, token.line + text.matches('\n').count()));
+            match token.kind {
+                TokenKind::Integer(_) | TokenKind::Number(_) => {
+                    literals.insert((token.line, token.column), text);
+                }
+                TokenKind::Str(_) if text.starts_with('[') => {
+                    literals.insert((token.line, token.column), text);
+                }
+                _ => {}
+            }
+        }
+        let comments = lexer.comments().to_vec();
+        let trailing = comments
+            .iter()
+            .map(|c| tokens.iter().any(|&(line, column, end)| end == c.line && (line, column) < (c.line, c.column)))
+            .collect();
+        let mut spans = tokens;
+        spans.extend(comments.iter().map(|c| (c.line, c.column, c.end_line)));
+        spans.sort();
+        Ok(Printer { options, out: String::new(), comments, trailing, next_comment: 0, spans, literals })
+    }
+
+    /* layout */
+
+    fn newline(&mut self, level: usize) {
+        self.out.push('\n');
+        if self.options.use_tabs {
+            self.out.push_str(&"\t".repeat(level));
+        } else {
+            self.out.push_str(&" ".repeat(level * self.options.indent_width));
+        }
+    }
+
+    fn column(&self) -> usize {
+        let start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        self.out[start..].chars().count()
+    }
+
+    // whether a blank line comes before (line, column) in the source
+    fn gap_before(&self, line: usize, column: usize) -> bool {
+        let i = self.spans.partition_point(|&(l, c, _)| (l, c) < (line, column));
+        i > 0 && line > self.spans[i - 1].2 + 1
+    }
+
+    fn has_comments_before(&self, line: usize, column: usize) -> bool {
+        self.comments.get(self.next_comment).is_some_and(|c| (c.line, c.column) < (line, column))
+    }
+
+    // writes the comments up to (line, column); the caller goes on with
+    // a new line
+    fn comments_before(&mut self, line: usize, column: usize, level: usize, first: &mut bool) {
+        while self.has_comments_before(line, column) {
+            let i = self.next_comment;
+            let c = &self.comments[i];
+            let (c_line, c_column) = (c.line, c.column);
+            let text = c.text.clone();
+            if self.trailing[i] && !self.out.is_empty() {
+                self.out.push(' ');
+            } else {
+                if !*first && self.gap_before(c_line, c_column) {
+                    self.out.push('\n');
+                }
+                self.newline(level);
+            }
+            self.out.push_str(&text);
+            self.next_comment += 1;
+            *first = false;
+        }
+    }
+
+    /* statements */
+
+    // the statements of `block`, each on a new line at `level`
+    fn stats(&mut self, block: &Block, level: usize) {
+        let mut first = true;
+        for stat in &block.stats {
+            if stat.kind == StatKind::Empty {
+                continue;
+            }
+            self.comments_before(stat.line, stat.column, level, &mut first);
+            if !first && self.gap_before(stat.line, stat.column) {
+                self.out.push('\n');
+            }
+            self.newline(level);
+            // so that it is not read as a call of the previous line
+            if !first && starts_with_paren(stat) {
+                self.out.push(';');
+            }
+            self.stat(stat, level);
+            first = false;
+        }
+        if let Some(ret) = &block.ret {
+            self.comments_before(ret.line, ret.column, level, &mut first);
+            if !first && self.gap_before(ret.line, ret.column) {
+                self.out.push('\n');
+            }
+            self.newline(level);
+            self.out.push_str("return");
+            if !ret.exps.is_empty() {
+                self.out.push(' ');
+                self.exp_list(&ret.exps, level);
+            }
+        }
+        self.comments_before(block.end_line, block.end_column, level, &mut first);
+    }
+
+    // a block one level in, then `closer` on a line of its own, or on the
+    // opening line when there is nothing in between
+    fn body(&mut self, block: &Block, level: usize, closer: &str) {
+        let len = self.out.len();
+        self.stats(block, level + 1);
+        if self.out.len() == len {
+            self.out.push(' ');
+        } else {
+            self.newline(level);
+        }
+        self.out.push_str(closer);
+    }
+
+    fn stat(&mut self, stat: &Stat, level: usize) {
+        match &stat.kind {
+            StatKind::Empty => {}
+            StatKind::Break => self.out.push_str("break"),
+            StatKind::Label(name) => self.out.push_str(&format!("::{}::", name)),
+            StatKind::Goto(name) => self.out.push_str(&format!("goto {}", name)),
+            StatKind::Do(block) => {
+                self.out.push_str("do");
+                self.body(block, level, "end");
+            }
+            StatKind::Call(e) => self.exp(e, level),
+            StatKind::While { cond, block } => {
+                self.out.push_str("while ");
+                self.exp(cond, level);
+                self.out.push_str(" do");
+                self.body(block, level, "end");
+            }
+            StatKind::Repeat { block, cond } => {
+                self.out.push_str("repeat");
+                self.body(block, level, "until ");
+                self.exp(cond, level);
+            }
+            StatKind::If { conds, blocks, else_block } => {
+                for (i, (cond, block)) in conds.iter().zip(blocks).enumerate() {
+                    if i > 0 {
+                        self.newline(level);
+                    }
+                    self.out.push_str(if i == 0 { "if " } else { "elseif " });
+                    self.exp(cond, level);
+                    self.out.push_str(" then");
+                    self.stats(block, level + 1);
+                }
+                if let Some(block) = else_block {
+                    self.newline(level);
+                    self.out.push_str("else");
+                    self.stats(block, level + 1);
+                }
+                self.newline(level);
+                self.out.push_str("end");
+            }
+            StatKind::NumericFor { var, init, limit, step, block, .. } => {
+                self.out.push_str(&format!("for {} = ", var.name));
+                self.exp(init, level);
+                self.out.push_str(", ");
+                self.exp(limit, level);
+                if let Some(step) = step {
+                    self.out.push_str(", ");
+                    self.exp(step, level);
+                }
+                self.out.push_str(" do");
+                self.body(block, level, "end");
+            }
+            StatKind::GenericFor { names, exps, block, .. } => {
+                let names: Vec<&str> = names.iter().map(|n| n.name.as_str()).collect();
+                self.out.push_str(&format!("for {} in ", names.join(", ")));
+                self.exp_list(exps, level);
+                self.out.push_str(" do");
+                self.body(block, level, "end");
+            }
+            StatKind::Local { names, attribs, exps } => {
+                let names: Vec<String> = names
+                    .iter()
+                    .zip(attribs)
+                    .map(|(n, a)| match a {
+                        Some(Attrib::Const) => format!("{} <const>", n.name),
+                        Some(Attrib::Close) => format!("{} <close>", n.name),
+                        None => n.name.clone(),
+                    })
+                    .collect();
+                self.out.push_str(&format!("local {}", names.join(", ")));
+                if !exps.is_empty() {
+                    self.out.push_str(" = ");
+                    self.exp_list(exps, level);
+                }
+            }
+            StatKind::Assign { vars, exps } => {
+                self.exp_list(vars, level);
+                self.out.push_str(" = ");
+                self.exp_list(exps, level);
+            }
+            StatKind::LocalFunction { name, func } => {
+                self.out.push_str(&format!("local function {}", name.name));
+                self.func_body(func, level);
+            }
+            StatKind::Function { path, method, func } => {
+                let path: Vec<&str> = path.iter().map(|n| n.name.as_str()).collect();
+                self.out.push_str(&format!("function {}", path.join(".")));
+                if let Some(method) = method {
+                    self.out.push_str(&format!(":{}", method.name));
+                }
+                self.func_body(func, level);
+            }
+        }
+    }
+
+    fn func_body(&mut self, func: &FuncBody, level: usize) {
+        let mut params: Vec<&str> = func.params.iter().map(|n| n.name.as_str()).collect();
+        if func.is_vararg {
+            params.push("...");
+        }
+        self.out.push_str(&format!("({})", params.join(", ")));
+        self.body(&func.block, level, "end");
+    }
+
+    /* expressions */
+
+    fn exp_list(&mut self, exps: &[Exp], level: usize) {
+        for (i, e) in exps.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.exp(e, level);
+        }
+    }
+
+    fn exp(&mut self, e: &Exp, level: usize) {
+        match &e.kind {
+            ExpKind::Nil => self.out.push_str("nil"),
+            ExpKind::True => self.out.push_str("true"),
+            ExpKind::False => self.out.push_str("false"),
+            ExpKind::Vararg => self.out.push_str("..."),
+            ExpKind::Integer(i) => {
+                let text = self.literals.get(&(e.line, e.column)).cloned().unwrap_or_else(|| i.to_string());
+                self.out.push_str(&text);
+            }
+            ExpKind::Float(n) => {
+                let text = self.literals.get(&(e.line, e.column)).cloned().unwrap_or_else(|| format!("{:?}", n));
+                self.out.push_str(&text);
+            }
+            ExpKind::Str(s) => match self.literals.get(&(e.line, e.column)) {
+                Some(text) => self.out.push_str(&text.clone()),
+                None => self.out.push_str(&quote(s, self.options.quote_style)),
+            },
+            ExpKind::Name(name) => self.out.push_str(name),
+            ExpKind::Unop { op, exp } => {
+                self.out.push_str(match op {
+                    UnOp::Minus => "-",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                    UnOp::BNot => "~",
+                });
+                // "--" would start a comment
+                if *op == UnOp::Minus && matches!(exp.kind, ExpKind::Unop { op: UnOp::Minus, .. }) {
+                    self.out.push(' ');
+                }
+                self.exp(exp, level);
+            }
+            ExpKind::Binop { op, lhs, rhs } => {
+                self.exp(lhs, level);
+                self.out.push_str(&format!(" {} ", binop_str(*op)));
+                self.exp(rhs, level);
+            }
+            ExpKind::Table { fields, last_line } => self.table(fields, *last_line, level),
+            ExpKind::Function(func) => {
+                self.out.push_str("function");
+                self.func_body(func, level);
+            }
+            ExpKind::Paren(inner) => {
+                self.out.push('(');
+                self.exp(inner, level);
+                self.out.push(')');
+            }
+            ExpKind::Index { prefix, key } => {
+                self.exp(prefix, level);
+                match &key.kind {
+                    ExpKind::Str(s) if is_name(s) => {
+                        self.out.push('.');
+                        self.out.push_str(std::str::from_utf8(s).unwrap());
+                    }
+                    _ => {
+                        self.out.push('[');
+                        self.exp(key, level);
+                        self.out.push(']');
+                    }
+                }
+            }
+            ExpKind::Call { prefix, method, args, .. } => {
+                self.exp(prefix, level);
+                if let Some(method) = method {
+                    self.out.push_str(&format!(":{}", method.name));
+                }
+                self.args(args, level);
+            }
+        }
+    }
+
+    // on one line if they fit, else one per line
+    fn args(&mut self, args: &[Exp], level: usize) {
+        let (len, next_comment) = (self.out.len(), self.next_comment);
+        self.out.push('(');
+        self.exp_list(args, level);
+        self.out.push(')');
+        if args.is_empty() || self.out[len..].contains('\n') || self.column() <= self.options.line_width {
+            return;
+        }
+        self.out.truncate(len);
+        self.next_comment = next_comment;
+        self.out.push('(');
+        for (i, arg) in args.iter().enumerate() {
+            self.newline(level + 1);
+            self.exp(arg, level + 1);
+            if i + 1 < args.len() {
+                self.out.push(',');
+            }
+        }
+        self.newline(level);
+        self.out.push(')');
+    }
+
+    // on one line if it fits and holds no function nor comment, else one
+    // field per line
+    fn table(&mut self, fields: &[Field], last_line: usize, level: usize) {
+        let commented = self.has_comments_before(last_line, 0);
+        if !commented {
+            let (len, next_comment) = (self.out.len(), self.next_comment);
+            self.out.push('{');
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    self.out.push_str(", ");
+                }
+                self.field(field, level);
+            }
+            self.out.push('}');
+            if !self.out[len..].contains('\n') && self.column() <= self.options.line_width {
+                return;
+            }
+            self.out.truncate(len);
+            self.next_comment = next_comment;
+        }
+        self.out.push('{');
+        let mut first = true;
+        for (i, field) in fields.iter().enumerate() {
+            let (line, column) = match field {
+                Field::Positional(e) | Field::Keyed(e, _) => (e.line, e.column),
+                Field::Named(name, _) => (name.line, name.column),
+            };
+            self.comments_before(line, column, level + 1, &mut first);
+            self.newline(level + 1);
+            self.field(field, level + 1);
+            if i + 1 < fields.len() || self.options.trailing_comma {
+                self.out.push(',');
+            }
+            first = false;
+        }
+        self.comments_before(last_line, 0, level + 1, &mut first);
+        self.newline(level);
+        self.out.push('}');
+    }
+
+    fn field(&mut self, field: &Field, level: usize) {
+        match field {
+            Field::Positional(e) => self.exp(e, level),
+            Field::Named(name, e) => {
+                self.out.push_str(&format!("{} = ", name.name));
+                self.exp(e, level);
+            }
+            Field::Keyed(k, e) => {
+                self.out.push('[');
+                self.exp(k, level);
+                self.out.push_str("] = ");
+                self.exp(e, level);
+            }
+        }
+    }
+}
+
+fn starts_with_paren(stat: &Stat) -> bool {
+    fn leftmost(e: &Exp) -> bool {
+        match &e.kind {
+            ExpKind::Paren(_) => true,
+            ExpKind::Index { prefix, .. } | ExpKind::Call { prefix, .. } => leftmost(prefix),
+            _ => false,
+        }
+    }
+    match &stat.kind {
+        StatKind::Call(e) => leftmost(e),
+        StatKind::Assign { vars, .. } => leftmost(&vars[0]),
+        _ => false,
+    }
+}
+
+fn binop_str(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Mod => "%",
+        BinOp::Pow => "^",
+        BinOp::Div => "/",
+        BinOp::IDiv => "//",
+        BinOp::BAnd => "&",
+        BinOp::BOr => "|",
+        BinOp::BXor => "~",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+        BinOp::Concat => "..",
+        BinOp::Eq => "==",
+        BinOp::Ne => "~=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+    }
+}
+
+// whether `t.s` may be written for t["s"]
+fn is_name(s: &[u8]) -> bool {
+    let Ok(s) = std::str::from_utf8(s) else {
+        return false;
+    };
+    let mut bytes = s.bytes();
+    bytes.next().is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && !KEYWORDS.iter().any(|(w, _)| *w == s)
+}
+
+// a short string literal for `s`; bytes that are not printable or not
+// part of valid UTF-8 are escaped
+fn quote(s: &[u8], style: QuoteStyle) -> String {
+    let (preferred, other) = match style {
+        QuoteStyle::Double => ('"', '\''),
+        QuoteStyle::Single => ('\'', '"'),
+    };
+    let q = if s.contains(&(preferred as u8)) && !s.contains(&(other as u8)) { other } else { preferred };
+    let utf8 = std::str::from_utf8(s).is_ok();
+    let mut out = Vec::with_capacity(s.len() + 2);
+    out.push(q as u8);
+    for &b in s {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            0x07 => out.extend_from_slice(b"\\a"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            0x0b => out.extend_from_slice(b"\\v"),
+            0x0c => out.extend_from_slice(b"\\f"),
+            _ if b == q as u8 => out.extend_from_slice(&[b'\\', b]),
+            _ if b < 0x20 || b == 0x7f || (b >= 0x80 && !utf8) => out.extend_from_slice(format!("\\{:03}", b).as_bytes()),
+            _ => out.push(b),
+        }
+    }
+    out.push(q as u8);
+    String::from_utf8(out).unwrap()
+}
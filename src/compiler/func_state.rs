@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::binchunk::binary_chunk::{Constant, LocVar, Prototype, Upvalue};
+
+// constants are deduplicated by value; floats by their bits, which keeps
+// 0.0 and -0.0 apart and never matches an integer
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    Str(Vec<u8>),
+}
+
+pub struct UpvalDesc {
+    pub name: String,
+    pub instack: bool, // captures a register of the enclosing function
+    pub idx: usize,
+}
+
+pub struct BlockScope {
+    pub nactvar: usize, // active locals outside the block
+    pub first_label: usize,
+    pub first_goto: usize,
+    pub is_loop: bool,
+    pub upval: bool, // some local of the block is captured as an upvalue
+    pub inside_tbc: bool, // the block is in the scope of a <close> local
+}
+
+// a label, or a pending goto waiting for one
+pub struct LabelDesc {
+    pub name: String,
+    pub pc: usize,
+    pub line: usize,
+    pub nactvar: usize,
+}
+
+// the state of a function being compiled, as FuncState in lparser.h
+pub struct FuncState {
+    pub line_defined: usize,
+    pub last_line_defined: usize,
+    pub num_params: usize,
+    pub is_vararg: bool,
+    pub code: Vec<u32>,
+    pub line_info: Vec<u32>,
+    pub constants: Vec<Constant>,
+    const_index: HashMap<ConstKey, usize>,
+    pub upvalues: Vec<UpvalDesc>,
+    pub protos: Vec<Prototype>,
+    pub loc_vars: Vec<LocVar>,
+    // the loc_vars entry of each active local; a local lives in the
+    // register matching its position here
+    pub actvar: Vec<usize>,
+    pub free_reg: usize,
+    pub max_stack: usize,
+    pub blocks: Vec<BlockScope>,
+    pub labels: Vec<LabelDesc>,
+    pub gotos: Vec<LabelDesc>,
+}
+
+impl FuncState {
+    pub fn new(line_defined: usize, last_line_defined: usize, is_vararg: bool) -> FuncState {
+        FuncState {
+            line_defined,
+            last_line_defined,
+            num_params: 0,
+            is_vararg,
+            code: Vec::new(),
+            line_info: Vec::new(),
+            constants: Vec::new(),
+            const_index: HashMap::new(),
+            upvalues: Vec::new(),
+            protos: Vec::new(),
+            loc_vars: Vec::new(),
+            actvar: Vec::new(),
+            free_reg: 0,
+            max_stack: 0,
+            blocks: Vec::new(),
+            labels: Vec::new(),
+            gotos: Vec::new(),
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn nactvar(&self) -> usize {
+        self.actvar.len()
+    }
+
+    pub fn local_name(&self, reg: usize) -> &str {
+        &self.loc_vars[self.actvar[reg]].var_name
+    }
+
+    // how limit errors name the function
+    pub fn describe(&self) -> String {
+        if self.line_defined == 0 {
+            String::from("main function")
+        } else {
+            format!("function at line {}", self.line_defined)
+        }
+    }
+
+    pub fn constant(&mut self, k: Constant) -> usize {
+        let key = match &k {
+            Constant::Nil => ConstKey::Nil,
+            Constant::Boolean(b) => ConstKey::Boolean(*b),
+            Constant::Integer(i) => ConstKey::Integer(*i),
+            Constant::Number(n) => ConstKey::Number(n.to_bits()),
+            Constant::Str(s) => ConstKey::Str(s.clone()),
+        };
+        if let Some(&idx) = self.const_index.get(&key) {
+            return idx;
+        }
+        self.constants.push(k);
+        self.const_index.insert(key, self.constants.len() - 1);
+        self.constants.len() - 1
+    }
+
+    // the register of the innermost active local called `name`
+    pub fn search_var(&self, name: &str) -> Option<usize> {
+        (0..self.actvar.len()).rev().find(|&reg| self.local_name(reg) == name)
+    }
+
+    pub fn search_upvalue(&self, name: &str) -> Option<usize> {
+        self.upvalues.iter().position(|u| u.name == name)
+    }
+
+    // flags the block declaring the local in `reg` as needing a close
+    pub fn mark_upval(&mut self, reg: usize) {
+        if let Some(bl) = self.blocks.iter_mut().rev().find(|bl| bl.nactvar <= reg) {
+            bl.upval = true;
+        }
+    }
+
+    pub fn into_proto(self, source: String) -> Prototype {
+        Prototype {
+            version: 0x53,
+            source,
+            line_defined: self.line_defined as u32,
+            last_line_defined: self.last_line_defined as u32,
+            num_params: self.num_params as u8,
+            is_vararg: self.is_vararg as u8,
+            max_stack_size: self.max_stack.max(2) as u8,
+            code: self.code,
+            constants: self.constants,
+            upvalue_names: self.upvalues.iter().map(|u| u.name.clone()).collect(),
+            upvalues: self
+                .upvalues
+                .iter()
+                .map(|u| Upvalue { instack: u.instack as u8, idx: u.idx as u8, kind: 0 })
+                .collect(),
+            protos: self.protos.into_iter().map(Rc::new).collect(),
+            line_info: self.line_info,
+            abs_line_info: Vec::new(),
+            loc_vars: self.loc_vars,
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::binchunk::binary_chunk::Constant;
+use crate::vm::inst_table::{int2fb, LFIELDS_PER_FLUSH};
+use crate::vm::instruction::{rk_ask, Instruction, MAXARG_C, MAXINDEXRK};
+use crate::vm::opcodes::*;
+
+use super::ast::*;
+use super::codegen::{Codegen, VarKind};
+use super::gen_stat::is_multi;
+use super::syntax_error::SyntaxError;
+
+impl Codegen {
+    // one value into register `a`
+    pub(super) fn exp(&mut self, exp: &Exp, a: usize) -> Result<(), SyntaxError> {
+        self.line = exp.line;
+        match &exp.kind {
+            ExpKind::Nil => {
+                self.emit_abc(OP_LOADNIL, a, 0, 0)?;
+            }
+            ExpKind::True => {
+                self.emit_abc(OP_LOADBOOL, a, 1, 0)?;
+            }
+            ExpKind::False => {
+                self.emit_abc(OP_LOADBOOL, a, 0, 0)?;
+            }
+            ExpKind::Integer(_) | ExpKind::Float(_) | ExpKind::Str(_) => {
+                let k = self.constant(literal(exp).unwrap());
+                self.load_k(a, k)?;
+            }
+            ExpKind::Vararg => {
+                self.emit_abc(OP_VARARG, a, 2, 0)?;
+            }
+            ExpKind::Name(name) => self.name(name, a)?,
+            ExpKind::Index { prefix, key } => self.index(prefix, key, a)?,
+            ExpKind::Call { .. } | ExpKind::Table { .. } => {
+                // both need the registers above their target
+                if self.free_reg() == a + 1 {
+                    self.top_exp(exp, a)?;
+                } else {
+                    let mark = self.free_reg();
+                    let r = self.alloc_reg()?;
+                    self.top_exp(exp, r)?;
+                    self.emit_abc(OP_MOVE, a, r as isize, 0)?;
+                    self.free_to(mark);
+                }
+            }
+            ExpKind::Function(body) => {
+                let idx = self.function(body, false)?;
+                self.line = body.last_line;
+                self.emit_abx(OP_CLOSURE, a, idx)?;
+            }
+            ExpKind::Paren(inner) => self.exp(inner, a)?,
+            ExpKind::Unop { op, exp: operand } => {
+                let mark = self.free_reg();
+                let b = self.exp_any_reg(operand)?;
+                self.free_to(mark);
+                let op = match op {
+                    UnOp::Minus => OP_UNM,
+                    UnOp::Not => OP_NOT,
+                    UnOp::Len => OP_LEN,
+                    UnOp::BNot => OP_BNOT,
+                };
+                self.line = exp.line;
+                self.emit_abc(op, a, b as isize, 0)?;
+            }
+            ExpKind::Binop { op, lhs, rhs } => self.binop(exp, *op, lhs, rhs, a)?,
+        }
+        Ok(())
+    }
+
+    fn top_exp(&mut self, exp: &Exp, a: usize) -> Result<(), SyntaxError> {
+        match &exp.kind {
+            ExpKind::Table { fields, .. } => self.table(exp.line, fields, a),
+            _ => self.call(exp, a, 1),
+        }
+    }
+
+    fn name(&mut self, name: &str, a: usize) -> Result<(), SyntaxError> {
+        match self.single_var(name)? {
+            VarKind::Local(reg) => {
+                if reg != a {
+                    self.emit_abc(OP_MOVE, a, reg as isize, 0)?;
+                }
+            }
+            VarKind::Upval(idx) => {
+                self.emit_abc(OP_GETUPVAL, a, idx as isize, 0)?;
+            }
+            VarKind::Global => {
+                // the key may need a register, free again once read
+                let mark = self.free_reg();
+                let key = self.str_rk(name.as_bytes())?;
+                self.free_to(mark);
+                match self.env()? {
+                    VarKind::Local(reg) => self.emit_abc(OP_GETTABLE, a, reg as isize, key)?,
+                    VarKind::Upval(idx) => self.emit_abc(OP_GETTABUP, a, idx as isize, key)?,
+                    VarKind::Global => unreachable!("_ENV is always visible"),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, prefix: &Exp, key: &Exp, a: usize) -> Result<(), SyntaxError> {
+        let line = self.line;
+        let mark = self.free_reg();
+        if let ExpKind::Name(name) = &prefix.kind {
+            if let VarKind::Upval(idx) = self.single_var(name)? {
+                let c = self.exp_rk(key)?;
+                self.free_to(mark);
+                self.line = line;
+                self.emit_abc(OP_GETTABUP, a, idx as isize, c)?;
+                return Ok(());
+            }
+        }
+        let b = self.exp_any_reg(prefix)?;
+        let c = self.exp_rk(key)?;
+        self.free_to(mark);
+        self.line = line;
+        self.emit_abc(OP_GETTABLE, a, b as isize, c)?;
+        Ok(())
+    }
+
+    fn binop(&mut self, exp: &Exp, op: BinOp, lhs: &Exp, rhs: &Exp, a: usize) -> Result<(), SyntaxError> {
+        let mark = self.free_reg();
+        match op {
+            BinOp::And | BinOp::Or => {
+                // a and b or c nests in its left operands as deep as it is
+                // long, so the chain goes in a loop from the innermost one
+                let mut chain = vec![exp];
+                while let ExpKind::Binop { lhs, .. } = &chain[chain.len() - 1].kind {
+                    match &lhs.kind {
+                        ExpKind::Binop { op: BinOp::And | BinOp::Or, .. } => chain.push(lhs),
+                        _ => break,
+                    }
+                }
+                let ExpKind::Binop { lhs, .. } = &chain[chain.len() - 1].kind else { unreachable!() };
+                self.exp(lhs, a)?;
+                for e in chain.iter().rev() {
+                    let ExpKind::Binop { op, rhs, .. } = &e.kind else { unreachable!() };
+                    self.line = e.line;
+                    self.emit_abc(OP_TEST, a, 0, (*op == BinOp::Or) as isize)?;
+                    let j = self.jump()?;
+                    self.exp(rhs, a)?;
+                    self.patch_to_here(vec![j])?;
+                }
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let jumps = self.cond_jump(exp, true)?;
+                self.emit_abc(OP_LOADBOOL, a, 0, 1)?;
+                self.patch_to_here(jumps)?;
+                self.emit_abc(OP_LOADBOOL, a, 1, 0)?;
+            }
+            BinOp::Concat => {
+                // a .. b .. c is concatenated at once from consecutive registers
+                let mut operands = vec![lhs];
+                let mut rest = rhs;
+                while let ExpKind::Binop { op: BinOp::Concat, lhs, rhs } = &rest.kind {
+                    operands.push(lhs);
+                    rest = rhs;
+                }
+                operands.push(rest);
+                let base = self.free_reg();
+                for operand in &operands {
+                    let r = self.alloc_reg()?;
+                    self.exp(operand, r)?;
+                }
+                self.free_to(mark);
+                self.line = exp.line;
+                self.emit_abc(OP_CONCAT, a, base as isize, (base + operands.len() - 1) as isize)?;
+            }
+            _ => {
+                // a target that is no local can hold the left operand
+                // meanwhile; a chain like a + b + c, which nests in its
+                // left operands as deep as it is long, then goes in a loop
+                // from the innermost operation, in no more registers
+                // however long it grows
+                let temporary = a >= self.fs().nactvar();
+                let mut chain = vec![exp];
+                while let ExpKind::Binop { lhs, .. } = &chain[chain.len() - 1].kind {
+                    match &lhs.kind {
+                        ExpKind::Binop { op, .. } if temporary && is_arith(*op) => chain.push(lhs),
+                        _ => break,
+                    }
+                }
+                for (i, e) in chain.iter().rev().enumerate() {
+                    let ExpKind::Binop { op, lhs, rhs } = &e.kind else { unreachable!() };
+                    let b = match &lhs.kind {
+                        _ if i > 0 => a as isize,
+                        _ if !temporary || literal(lhs).is_some() => self.exp_rk(lhs)?,
+                        ExpKind::Name(name) if matches!(self.single_var(name)?, VarKind::Local(_)) => self.exp_rk(lhs)?,
+                        _ => {
+                            self.exp(lhs, a)?;
+                            a as isize
+                        }
+                    };
+                    let c = self.exp_rk(rhs)?;
+                    self.free_to(mark);
+                    self.line = e.line;
+                    self.emit_abc(arith_opcode(*op), a, b, c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `n` values into a.., which must be the topmost reserved registers;
+    // with n < 0 calls and '...' leave all their values up to the top
+    pub(super) fn exp_n(&mut self, exp: &Exp, a: usize, n: isize) -> Result<(), SyntaxError> {
+        match &exp.kind {
+            ExpKind::Call { .. } => self.call(exp, a, n),
+            ExpKind::Vararg => {
+                self.line = exp.line;
+                self.emit_abc(OP_VARARG, a, n + 1, 0)?;
+                Ok(())
+            }
+            _ => {
+                self.exp(exp, a)?;
+                if n > 1 {
+                    self.emit_abc(OP_LOADNIL, a + 1, n - 2, 0)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // the register holding the value of `exp`: the local itself for local
+    // names, or a new register
+    pub(super) fn exp_any_reg(&mut self, exp: &Exp) -> Result<usize, SyntaxError> {
+        if let ExpKind::Name(name) = &exp.kind {
+            if let VarKind::Local(reg) = self.single_var(name)? {
+                return Ok(reg);
+            }
+        }
+        let a = self.alloc_reg()?;
+        self.exp(exp, a)?;
+        Ok(a)
+    }
+
+    // an RK operand: a constant when the value is a literal that fits
+    pub(super) fn exp_rk(&mut self, exp: &Exp) -> Result<isize, SyntaxError> {
+        if let Some(k) = literal(exp) {
+            let idx = self.constant(k);
+            if idx as isize <= MAXINDEXRK {
+                return Ok(rk_ask(idx as isize).unwrap());
+            }
+        }
+        Ok(self.exp_any_reg(exp)? as isize)
+    }
+
+    pub(super) fn str_rk(&mut self, s: &[u8]) -> Result<isize, SyntaxError> {
+        let idx = self.constant(Constant::Str(s.to_vec()));
+        if idx as isize <= MAXINDEXRK {
+            return Ok(rk_ask(idx as isize).unwrap());
+        }
+        let a = self.alloc_reg()?;
+        self.load_k(a, idx)?;
+        Ok(a as isize)
+    }
+
+    // evaluates `exps` into new consecutive registers, adjusted to `want`
+    // values; with no `want`, a call or '...' at the end keeps all of its
+    // values and the result is true
+    pub(super) fn exp_list(&mut self, exps: &[Exp], want: Option<usize>) -> Result<bool, SyntaxError> {
+        let base = self.free_reg();
+        for (i, exp) in exps.iter().enumerate() {
+            if i + 1 == exps.len() && is_multi(exp) {
+                match want {
+                    None => {
+                        let a = self.alloc_reg()?;
+                        self.exp_n(exp, a, -1)?;
+                        return Ok(true);
+                    }
+                    Some(want) if want > i => {
+                        let n = want - i;
+                        self.reserve_regs(n)?;
+                        self.exp_n(exp, base + i, n as isize)?;
+                        return Ok(false);
+                    }
+                    _ => {}
+                }
+            }
+            let a = self.alloc_reg()?;
+            self.exp(exp, a)?;
+        }
+        if let Some(want) = want {
+            if want > exps.len() {
+                let missing = want - exps.len();
+                let a = self.free_reg();
+                self.reserve_regs(missing)?;
+                self.emit_abc(OP_LOADNIL, a, missing as isize - 1, 0)?;
+            }
+        }
+        Ok(false)
+    }
+
+    // R(a)... := call, with `n` results (n < 0: all of them); registers
+    // from `a` up are free for the function and its arguments
+    pub(super) fn call(&mut self, exp: &Exp, a: usize, n: isize) -> Result<(), SyntaxError> {
+        let (prefix, method, args) = match &exp.kind {
+            ExpKind::Call { prefix, method, args, .. } => (prefix, method, args),
+            _ => unreachable!("not a call"),
+        };
+        self.free_to(a + 1);
+        let mut nargs = args.len();
+        match method {
+            Some(method) => {
+                let obj = self.exp_any_reg(prefix)?;
+                let key = self.str_rk(method.name.as_bytes())?;
+                self.free_to(a + 1);
+                self.line = method.line;
+                self.emit_abc(OP_SELF, a, obj as isize, key)?;
+                self.reserve_regs(1)?;
+                nargs += 1;
+            }
+            None => self.exp(prefix, a)?,
+        }
+        let multi = self.exp_list(args, None)?;
+        let b = if multi { 0 } else { nargs as isize + 1 };
+        self.line = start_line(exp);
+        self.emit_abc(OP_CALL, a, b, n + 1)?;
+        self.free_to(a + n.max(1) as usize);
+        Ok(())
+    }
+
+    fn table(&mut self, line: usize, fields: &[Field], a: usize) -> Result<(), SyntaxError> {
+        let pc = self.emit_abc(OP_NEWTABLE, a, 0, 0)?;
+        let (mut na, mut nh, mut pending) = (0, 0, 0);
+        for (i, field) in fields.iter().enumerate() {
+            let mark = self.free_reg();
+            match field {
+                Field::Positional(exp) => {
+                    na += 1;
+                    pending += 1;
+                    let r = self.alloc_reg()?;
+                    if i + 1 == fields.len() && is_multi(exp) {
+                        self.exp_n(exp, r, -1)?;
+                        self.line = line;
+                        self.set_list(a, na, 0)?;
+                        na -= 1;
+                        pending = 0;
+                    } else {
+                        self.exp(exp, r)?;
+                        if pending == LFIELDS_PER_FLUSH as usize {
+                            self.line = line;
+                            self.set_list(a, na, pending)?;
+                            pending = 0;
+                            self.free_to(a + 1);
+                        }
+                    }
+                }
+                Field::Named(name, value) => {
+                    nh += 1;
+                    let key = self.str_rk(name.name.as_bytes())?;
+                    let value = self.exp_rk(value)?;
+                    self.line = name.line;
+                    self.emit_abc(OP_SETTABLE, a, key, value)?;
+                    self.free_to(mark);
+                }
+                Field::Keyed(key, value) => {
+                    nh += 1;
+                    let key = self.exp_rk(key)?;
+                    let value = self.exp_rk(value)?;
+                    self.line = line;
+                    self.emit_abc(OP_SETTABLE, a, key, value)?;
+                    self.free_to(mark);
+                }
+            }
+        }
+        if pending > 0 {
+            self.line = line;
+            self.set_list(a, na, pending)?;
+        }
+        self.free_to(a + 1);
+        let i = u32::encode_abc(OP_NEWTABLE, a as isize, int2fb(na) as isize, int2fb(nh) as isize)
+            .map_err(|e| self.error(&e.to_string()))?;
+        self.fs_mut().code[pc] = i;
+        Ok(())
+    }
+
+    // stores `count` pending list items (0: up to the top) ending at the
+    // `total`-th one
+    fn set_list(&mut self, a: usize, total: usize, count: usize) -> Result<(), SyntaxError> {
+        let batch = (total - 1) / LFIELDS_PER_FLUSH as usize + 1;
+        if batch as isize <= MAXARG_C {
+            self.emit_abc(OP_SETLIST, a, count as isize, batch as isize)?;
+        } else {
+            self.emit_abc(OP_SETLIST, a, count as isize, 0)?;
+            self.emit_ax(OP_EXTRAARG, batch)?;
+        }
+        Ok(())
+    }
+
+    // Emits the test of a condition and returns the jumps taken when its
+    // truth equals `jump_if`; otherwise execution falls through. `and`,
+    // `or` and `not` become control flow instead of values.
+    pub(super) fn cond_jump(&mut self, exp: &Exp, jump_if: bool) -> Result<Vec<usize>, SyntaxError> {
+        let mark = self.free_reg();
+        match &exp.kind {
+            ExpKind::Paren(inner) => return self.cond_jump(inner, jump_if),
+            ExpKind::Nil | ExpKind::False => return Ok(if jump_if { vec![] } else { vec![self.jump()?] }),
+            ExpKind::True | ExpKind::Integer(_) | ExpKind::Float(_) | ExpKind::Str(_) => {
+                return Ok(if jump_if { vec![self.jump()?] } else { vec![] })
+            }
+            ExpKind::Unop { op: UnOp::Not, exp: inner } => return self.cond_jump(inner, !jump_if),
+            ExpKind::Binop { op: op @ (BinOp::And | BinOp::Or), .. } => {
+                // every operand but the last jumps when its value decides
+                // the result, true for or and false for and; a chain of
+                // them nests in its left operands, gathered in a loop
+                let decides = *op == BinOp::Or;
+                let mut operands = Vec::new();
+                let mut rest = exp;
+                while let ExpKind::Binop { op: next, lhs, rhs } = &rest.kind {
+                    if next != op {
+                        break;
+                    }
+                    operands.push(&**rhs);
+                    rest = lhs;
+                }
+                let mut early = self.cond_jump(rest, decides)?;
+                let last = operands.remove(0);
+                for operand in operands.into_iter().rev() {
+                    early.extend(self.cond_jump(operand, decides)?);
+                }
+                let jumps = self.cond_jump(last, jump_if)?;
+                return if jump_if == decides {
+                    early.extend(jumps);
+                    Ok(early)
+                } else {
+                    self.patch_to_here(early)?;
+                    Ok(jumps)
+                };
+            }
+            ExpKind::Binop { op, lhs, rhs } if compare_opcode(*op).is_some() => {
+                let (opcode, swap, expect) = compare_opcode(*op).unwrap();
+                let b = self.exp_rk(lhs)?;
+                let c = self.exp_rk(rhs)?;
+                let (b, c) = if swap { (c, b) } else { (b, c) };
+                self.free_to(mark);
+                self.line = exp.line;
+                // the next instruction, the jump, runs when the comparison equals A
+                self.emit_abc(opcode, (expect == jump_if) as usize, b, c)?;
+            }
+            _ => {
+                let a = self.exp_any_reg(exp)?;
+                self.free_to(mark);
+                self.line = exp.line;
+                self.emit_abc(OP_TEST, a, 0, jump_if as isize)?;
+            }
+        }
+        Ok(vec![self.jump()?])
+    }
+}
+
+// the constant a literal expression stands for
+fn literal(exp: &Exp) -> Option<Constant> {
+    match &exp.kind {
+        ExpKind::Nil => Some(Constant::Nil),
+        ExpKind::True => Some(Constant::Boolean(true)),
+        ExpKind::False => Some(Constant::Boolean(false)),
+        ExpKind::Integer(i) => Some(Constant::Integer(*i)),
+        ExpKind::Float(n) => Some(Constant::Number(*n)),
+        ExpKind::Str(s) => Some(Constant::Str(s.clone())),
+        _ => None,
+    }
+}
+
+// calls are attributed to the line where their prefix starts
+fn start_line(exp: &Exp) -> usize {
+    match &exp.kind {
+        ExpKind::Call { prefix, .. } | ExpKind::Index { prefix, .. } => start_line(prefix),
+        _ => exp.line,
+    }
+}
+
+fn is_arith(op: BinOp) -> bool {
+    !matches!(
+        op,
+        BinOp::And | BinOp::Or | BinOp::Concat | BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge
+    )
+}
+
+fn arith_opcode(op: BinOp) -> u8 {
+    match op {
+        BinOp::Add => OP_ADD,
+        BinOp::Sub => OP_SUB,
+        BinOp::Mul => OP_MUL,
+        BinOp::Mod => OP_MOD,
+        BinOp::Pow => OP_POW,
+        BinOp::Div => OP_DIV,
+        BinOp::IDiv => OP_IDIV,
+        BinOp::BAnd => OP_BAND,
+        BinOp::BOr => OP_BOR,
+        BinOp::BXor => OP_BXOR,
+        BinOp::Shl => OP_SHL,
+        BinOp::Shr => OP_SHR,
+        _ => unreachable!("not an arithmetic operator"),
+    }
+}
+
+// (opcode, whether the operands are swapped, the result that makes the
+// comparison true)
+fn compare_opcode(op: BinOp) -> Option<(u8, bool, bool)> {
+    match op {
+        BinOp::Eq => Some((OP_EQ, false, true)),
+        BinOp::Ne => Some((OP_EQ, false, false)),
+        BinOp::Lt => Some((OP_LT, false, true)),
+        BinOp::Le => Some((OP_LE, false, true)),
+        BinOp::Gt => Some((OP_LT, true, true)),
+        BinOp::Ge => Some((OP_LE, true, true)),
+        _ => None,
+    }
+}
@@ -0,0 +1,405 @@
+use crate::binchunk::binary_chunk::Constant;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::*;
+
+use super::ast::*;
+use super::codegen::{Codegen, VarKind};
+use super::syntax_error::SyntaxError;
+
+// where an assignment stores its value
+#[derive(Clone, Copy)]
+pub enum Place {
+    Local(usize),
+    Upval(usize),
+    // t[key] with t in an upvalue (`up`) or in a register
+    Indexed { table: usize, up: bool, key: isize },
+}
+
+impl Codegen {
+    pub(super) fn block(&mut self, block: &Block) -> Result<(), SyntaxError> {
+        self.enter_block(false);
+        self.stat_list(block, false)?;
+        self.leave_block()
+    }
+
+    // the statements of a block without opening a scope for them; the
+    // body of repeat-until keeps its scope open for the condition
+    pub(super) fn stat_list(&mut self, block: &Block, in_repeat: bool) -> Result<(), SyntaxError> {
+        for (i, stat) in block.stats.iter().enumerate() {
+            let last = !in_repeat
+                && block.ret.is_none()
+                && block.stats[i + 1..].iter().all(|s| matches!(s.kind, StatKind::Empty | StatKind::Label(_)));
+            self.stat(stat, last)?;
+            let nactvar = self.fs().nactvar();
+            self.free_to(nactvar);
+        }
+        if let Some(ret) = &block.ret {
+            self.ret_stat(ret)?;
+            let nactvar = self.fs().nactvar();
+            self.free_to(nactvar);
+        }
+        Ok(())
+    }
+
+    fn stat(&mut self, stat: &Stat, last: bool) -> Result<(), SyntaxError> {
+        self.line = stat.line;
+        match &stat.kind {
+            StatKind::Empty => Ok(()),
+            StatKind::Break => self.goto("break", stat.line),
+            StatKind::Goto(name) => self.goto(name, stat.line),
+            StatKind::Label(name) => self.label(name, stat.line, last),
+            StatKind::Do(block) => self.block(block),
+            StatKind::Call(exp) => {
+                let a = self.alloc_reg()?;
+                self.call(exp, a, 0)
+            }
+            StatKind::While { cond, block } => self.while_stat(cond, block),
+            StatKind::Repeat { block, cond } => self.repeat_stat(block, cond),
+            StatKind::If { conds, blocks, else_block } => self.if_stat(conds, blocks, else_block.as_ref()),
+            StatKind::NumericFor { var, init, limit, step, block, .. } => {
+                self.numeric_for(stat.line, var, init, limit, step.as_deref(), block)
+            }
+            StatKind::GenericFor { names, exps, block, line_of_do } => {
+                self.generic_for(*line_of_do, names, exps, block)
+            }
+            StatKind::Local { names, attribs, exps } => self.local_stat(names, attribs, exps),
+            StatKind::Assign { vars, exps } => self.assign_stat(vars, exps),
+            StatKind::LocalFunction { name, func } => {
+                let a = self.alloc_reg()?;
+                let var = self.add_local(&name.name)?;
+                let idx = self.function(func, false)?;
+                self.emit_abx(OP_CLOSURE, a, idx)?;
+                // the local is only debug-visible once it holds the closure
+                let pc = self.pc() as u32;
+                let fs = self.fs_mut();
+                let loc_var = fs.actvar[var];
+                fs.loc_vars[loc_var].start_pc = pc;
+                Ok(())
+            }
+            StatKind::Function { path, method, func } => self.function_stat(stat.line, path, method.as_ref(), func),
+        }
+    }
+
+    fn while_stat(&mut self, cond: &Exp, block: &Block) -> Result<(), SyntaxError> {
+        let start = self.pc();
+        let exits = self.cond_jump(cond, false)?;
+        self.enter_block(true);
+        self.block(block)?;
+        let j = self.jump()?;
+        self.fix_jump(j, start)?;
+        self.leave_block()?;
+        self.patch_to_here(exits)
+    }
+
+    fn repeat_stat(&mut self, block: &Block, cond: &Exp) -> Result<(), SyntaxError> {
+        let start = self.pc();
+        self.enter_block(true);
+        self.enter_block(false);
+        self.stat_list(block, true)?;
+        self.line = cond.line;
+        let back = self.cond_jump(cond, false)?;
+        let (nactvar, upval) = {
+            let bl = self.fs().blocks.last().unwrap();
+            (bl.nactvar, bl.upval)
+        };
+        if upval {
+            for &pc in &back {
+                self.patch_close(pc, nactvar);
+            }
+        }
+        self.leave_block()?;
+        self.patch_list(back, start)?;
+        self.leave_block()
+    }
+
+    fn if_stat(&mut self, conds: &[Exp], blocks: &[Block], else_block: Option<&Block>) -> Result<(), SyntaxError> {
+        let mut escapes = Vec::new();
+        for (i, (cond, block)) in conds.iter().zip(blocks).enumerate() {
+            self.line = cond.line;
+            let exits = self.cond_jump(cond, false)?;
+            self.block(block)?;
+            if i + 1 < conds.len() || else_block.is_some() {
+                escapes.push(self.jump()?);
+            }
+            self.patch_to_here(exits)?;
+        }
+        if let Some(block) = else_block {
+            self.block(block)?;
+        }
+        self.patch_to_here(escapes)
+    }
+
+    fn numeric_for(
+        &mut self,
+        line: usize,
+        var: &Name,
+        init: &Exp,
+        limit: &Exp,
+        step: Option<&Exp>,
+        block: &Block,
+    ) -> Result<(), SyntaxError> {
+        self.enter_block(true);
+        let base = self.free_reg();
+        for exp in [init, limit] {
+            let a = self.alloc_reg()?;
+            self.exp(exp, a)?;
+        }
+        let a = self.alloc_reg()?;
+        match step {
+            Some(exp) => self.exp(exp, a)?,
+            None => {
+                let k = self.constant(Constant::Integer(1));
+                self.load_k(a, k)?;
+            }
+        }
+        for name in ["(for index)", "(for limit)", "(for step)"] {
+            self.add_local(name)?;
+        }
+        self.for_body(line, base, std::slice::from_ref(var), true, block)?;
+        self.leave_block()
+    }
+
+    fn generic_for(&mut self, line: usize, names: &[Name], exps: &[Exp], block: &Block) -> Result<(), SyntaxError> {
+        self.enter_block(true);
+        let base = self.free_reg();
+        self.exp_list(exps, Some(3))?;
+        self.free_to(base + 3);
+        for name in ["(for generator)", "(for state)", "(for control)"] {
+            self.add_local(name)?;
+        }
+        self.for_body(line, base, names, false, block)?;
+        self.leave_block()
+    }
+
+    fn for_body(&mut self, line: usize, base: usize, names: &[Name], is_num: bool, block: &Block) -> Result<(), SyntaxError> {
+        self.line = line;
+        let prep = if is_num { self.emit_asbx(OP_FORPREP, base, 0)? } else { self.jump()? };
+        self.enter_block(false);
+        self.reserve_regs(names.len())?;
+        for name in names {
+            self.add_local(&name.name)?;
+        }
+        self.block(block)?;
+        self.leave_block()?;
+        let here = self.pc();
+        self.fix_jump(prep, here)?;
+        self.line = line;
+        let end_for = if is_num {
+            self.emit_asbx(OP_FORLOOP, base, 0)?
+        } else {
+            self.emit_abc(OP_TFORCALL, base, 0, names.len() as isize)?;
+            self.emit_asbx(OP_TFORLOOP, base + 2, 0)?
+        };
+        self.fix_jump(end_for, prep + 1)
+    }
+
+    fn local_stat(&mut self, names: &[Name], attribs: &[Option<Attrib>], exps: &[Exp]) -> Result<(), SyntaxError> {
+        let base = self.free_reg();
+        self.exp_list(exps, Some(names.len()))?;
+        self.free_to(base + names.len());
+        for name in names {
+            self.add_local(&name.name)?;
+        }
+        if let Some(i) = attribs.iter().position(|&a| a == Some(Attrib::Close)) {
+            // the block gets closed on the way out, as if the local were
+            // captured, and its returns may not be tail calls
+            let bl = self.fs_mut().blocks.last_mut().unwrap();
+            bl.upval = true;
+            bl.inside_tbc = true;
+            self.emit_abc(OP_TBC, base + i, 0, 0)?;
+        }
+        Ok(())
+    }
+
+    fn assign_stat(&mut self, vars: &[Exp], exps: &[Exp]) -> Result<(), SyntaxError> {
+        if vars.len() == 1 && exps.len() == 1 {
+            let place = self.place(&vars[0])?;
+            return self.store(place, &exps[0]);
+        }
+        let mut places = Vec::new();
+        for var in vars {
+            places.push(self.place(var)?);
+        }
+        self.check_conflicts(&mut places)?;
+        let base = self.free_reg();
+        self.exp_list(exps, Some(vars.len()))?;
+        // like luac, assign from the last variable to the first
+        for (i, place) in places.iter().enumerate().rev() {
+            self.line = vars[i].line;
+            self.store_reg(*place, base + i)?;
+        }
+        Ok(())
+    }
+
+    // In `a, a.x = ...` or `t[i], i = ...` the table or key of an indexed
+    // place is a variable that gets assigned before the place is stored
+    // to; such operands are copied first so that the old value is used.
+    fn check_conflicts(&mut self, places: &mut [Place]) -> Result<(), SyntaxError> {
+        let assigned: Vec<Place> =
+            places.iter().copied().filter(|p| !matches!(p, Place::Indexed { .. })).collect();
+        for place in places.iter_mut() {
+            if let Place::Indexed { table, up, key } = place {
+                let table_conflict = assigned.iter().any(|p| match p {
+                    Place::Local(r) => !*up && r == table,
+                    Place::Upval(i) => *up && i == table,
+                    _ => false,
+                });
+                if table_conflict {
+                    let a = self.alloc_reg()?;
+                    let op = if *up { OP_GETUPVAL } else { OP_MOVE };
+                    self.emit_abc(op, a, *table as isize, 0)?;
+                    *table = a;
+                    *up = false;
+                }
+                let key_conflict = assigned.iter().any(|p| matches!(p, Place::Local(r) if *r as isize == *key));
+                if key_conflict {
+                    let a = self.alloc_reg()?;
+                    self.emit_abc(OP_MOVE, a, *key, 0)?;
+                    *key = a as isize;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // evaluates the table and key of an assignment target
+    pub(super) fn place(&mut self, var: &Exp) -> Result<Place, SyntaxError> {
+        match &var.kind {
+            ExpKind::Name(name) => match self.single_var(name)? {
+                VarKind::Local(reg) => Ok(Place::Local(reg)),
+                VarKind::Upval(idx) => Ok(Place::Upval(idx)),
+                VarKind::Global => {
+                    let key = self.str_rk(name.as_bytes())?;
+                    self.env_place(key)
+                }
+            },
+            ExpKind::Index { prefix, key } => {
+                if let ExpKind::Name(name) = &prefix.kind {
+                    if let VarKind::Upval(idx) = self.single_var(name)? {
+                        let key = self.exp_rk(key)?;
+                        return Ok(Place::Indexed { table: idx, up: true, key });
+                    }
+                }
+                let table = self.exp_any_reg(prefix)?;
+                let key = self.exp_rk(key)?;
+                Ok(Place::Indexed { table, up: false, key })
+            }
+            _ => Err(self.error("syntax error")),
+        }
+    }
+
+    fn env_place(&mut self, key: isize) -> Result<Place, SyntaxError> {
+        match self.env()? {
+            VarKind::Local(reg) => Ok(Place::Indexed { table: reg, up: false, key }),
+            VarKind::Upval(idx) => Ok(Place::Indexed { table: idx, up: true, key }),
+            VarKind::Global => unreachable!("_ENV is always visible"),
+        }
+    }
+
+    // stores the value of `exp`, computing it in place when possible
+    fn store(&mut self, place: Place, exp: &Exp) -> Result<(), SyntaxError> {
+        match place {
+            Place::Local(reg) => {
+                if writes_target_last(exp) {
+                    self.exp(exp, reg)
+                } else {
+                    let a = self.alloc_reg()?;
+                    self.exp(exp, a)?;
+                    self.emit_abc(OP_MOVE, reg, a as isize, 0)?;
+                    Ok(())
+                }
+            }
+            Place::Upval(idx) => {
+                let a = self.exp_any_reg(exp)?;
+                self.emit_abc(OP_SETUPVAL, a, idx as isize, 0)?;
+                Ok(())
+            }
+            Place::Indexed { table, up, key } => {
+                let value = self.exp_rk(exp)?;
+                let op = if up { OP_SETTABUP } else { OP_SETTABLE };
+                self.emit_abc(op, table, key, value)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub(super) fn store_reg(&mut self, place: Place, reg: usize) -> Result<(), SyntaxError> {
+        match place {
+            Place::Local(r) => {
+                if r != reg {
+                    self.emit_abc(OP_MOVE, r, reg as isize, 0)?;
+                }
+            }
+            Place::Upval(idx) => {
+                self.emit_abc(OP_SETUPVAL, reg, idx as isize, 0)?;
+            }
+            Place::Indexed { table, up, key } => {
+                let op = if up { OP_SETTABUP } else { OP_SETTABLE };
+                self.emit_abc(op, table, key, reg as isize)?;
+            }
+        }
+        Ok(())
+    }
+
+    // function a.b.c:m() ... end
+    fn function_stat(&mut self, line: usize, path: &[Name], method: Option<&Name>, func: &FuncBody) -> Result<(), SyntaxError> {
+        let mut var = Exp { kind: ExpKind::Name(path[0].name.clone()), line: path[0].line, column: path[0].column };
+        for name in path[1..].iter().chain(method) {
+            let key = Exp { kind: ExpKind::Str(name.name.clone().into_bytes()), line: name.line, column: name.column };
+            var = Exp { kind: ExpKind::Index { prefix: Box::new(var), key: Box::new(key) }, line: name.line, column: name.column };
+        }
+        let place = self.place(&var)?;
+        let a = self.alloc_reg()?;
+        let idx = self.function(func, method.is_some())?;
+        self.emit_abx(OP_CLOSURE, a, idx)?;
+        self.line = line;
+        self.store_reg(place, a)
+    }
+
+    fn ret_stat(&mut self, ret: &Return) -> Result<(), SyntaxError> {
+        self.line = ret.line;
+        match ret.exps.as_slice() {
+            [] => {
+                self.emit_abc(OP_RETURN, 0, 1, 0)?;
+            }
+            [exp @ Exp { kind: ExpKind::Call { .. }, .. }] if !self.fs().blocks.last().unwrap().inside_tbc => {
+                let a = self.alloc_reg()?;
+                self.call(exp, a, -1)?;
+                let pc = self.pc() - 1;
+                let (_, b, _) = self.fs().code[pc].abc();
+                self.fs_mut().code[pc] = u32::encode_abc(OP_TAILCALL, a as isize, b, 0).unwrap();
+                self.line = ret.line;
+                self.emit_abc(OP_RETURN, a, 0, 0)?;
+            }
+            [exp] if !is_multi(exp) => {
+                let a = self.exp_any_reg(exp)?;
+                self.line = ret.line;
+                self.emit_abc(OP_RETURN, a, 2, 0)?;
+            }
+            exps => {
+                let base = self.free_reg();
+                let multi = self.exp_list(exps, None)?;
+                let b = if multi { 0 } else { exps.len() as isize + 1 };
+                self.line = ret.line;
+                self.emit_abc(OP_RETURN, base, b, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// whether evaluating `exp` into a register writes it only with its last
+// instruction, so that it may read the old value before
+fn writes_target_last(exp: &Exp) -> bool {
+    match &exp.kind {
+        ExpKind::Call { .. } | ExpKind::Table { .. } => false,
+        ExpKind::Binop { op: BinOp::And | BinOp::Or, .. } => false,
+        ExpKind::Paren(inner) => writes_target_last(inner),
+        _ => true,
+    }
+}
+
+// calls and '...' can produce any number of values
+pub fn is_multi(exp: &Exp) -> bool {
+    matches!(exp.kind, ExpKind::Call { .. } | ExpKind::Vararg)
+}
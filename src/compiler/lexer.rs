@@ -0,0 +1,548 @@
+use super::syntax_error::{chunk_id, SyntaxError};
+use super::token::{Token, TokenKind, KEYWORDS};
+
+pub struct Lexer<'a> {
+    chunk: &'a [u8],
+    chunk_id: String,
+    pos: usize,
+    line: usize,
+    line_start: usize, // offset of the first byte of the current line
+    ahead: Option<Token>,
+    comments: Vec<Comment>,
+}
+
+// a comment as written, "--" included; the parser skips them, but tools
+// that write the source back need them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &str, chunk: &'a [u8]) -> Lexer<'a> {
+        Lexer {
+            chunk,
+            chunk_id: chunk_id(source),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            ahead: None,
+            comments: Vec::new(),
+        }
+    }
+
+    pub fn chunk_id(&self) -> &str {
+        &self.chunk_id
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    // the comments scanned so far, in order
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    // the token as it was written in the chunk
+    pub fn text(&self, token: &Token) -> String {
+        match token.kind {
+            TokenKind::Eof => String::from("<eof>"),
+            _ => String::from_utf8_lossy(&self.chunk[token.start..token.end]).into_owned(),
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        match self.ahead.take() {
+            Some(token) => Ok(token),
+            None => self.scan(),
+        }
+    }
+
+    pub fn peek_token(&mut self) -> Result<&Token, SyntaxError> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.scan()?);
+        }
+        Ok(self.ahead.as_ref().unwrap())
+    }
+
+    // an error at the current position, quoting what was read since `start`
+    fn error_near(&self, msg: &str, start: usize) -> SyntaxError {
+        let near = if self.pos >= self.chunk.len() && start >= self.chunk.len() {
+            String::from("<eof>")
+        } else {
+            format!("'{}'", String::from_utf8_lossy(&self.chunk[start..self.pos.min(self.chunk.len())]))
+        };
+        SyntaxError {
+            chunk_id: self.chunk_id.clone(),
+            line: self.line,
+            column: start.saturating_sub(self.line_start) + 1,
+            msg: format!("{} near {}", msg, near),
+        }
+    }
+
+    fn current(&self) -> Option<u8> {
+        self.chunk.get(self.pos).copied()
+    }
+
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.chunk.get(self.pos + n).copied()
+    }
+
+    fn check_next(&mut self, b: u8) -> bool {
+        if self.current() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at_newline(&self) -> bool {
+        matches!(self.current(), Some(b'\n' | b'\r'))
+    }
+
+    // skips "\n", "\r", "\n\r" or "\r\n"
+    fn newline(&mut self) {
+        let old = self.current();
+        self.pos += 1;
+        if matches!(self.current(), Some(b'\n' | b'\r')) && self.current() != old {
+            self.pos += 1;
+        }
+        self.line += 1;
+        self.line_start = self.pos;
+    }
+
+    fn scan(&mut self) -> Result<Token, SyntaxError> {
+        loop {
+            let start = self.pos;
+            let (line, column) = (self.line, self.pos - self.line_start + 1);
+            let token = |lexer: &Lexer, kind| Token { kind, line, column, start, end: lexer.pos };
+            let Some(c) = self.current() else {
+                return Ok(token(self, TokenKind::Eof));
+            };
+            let single = |lexer: &mut Lexer, kind| {
+                lexer.pos += 1;
+                Ok(token(lexer, kind))
+            };
+            return match c {
+                b'\n' | b'\r' => {
+                    self.newline();
+                    continue;
+                }
+                b' ' | b'\t' | 0x0b | 0x0c => {
+                    self.pos += 1;
+                    continue;
+                }
+                b'-' => {
+                    if self.peek(1) != Some(b'-') {
+                        return single(self, TokenKind::Minus);
+                    }
+                    self.pos += 2;
+                    let long = match self.bracket_level() {
+                        (level, true) if self.current() == Some(b'[') => Some(level),
+                        _ => None,
+                    };
+                    if let Some(level) = long {
+                        self.pos += level + 2;
+                        self.read_long_string(level, "comment")?;
+                    } else {
+                        while self.current().is_some() && !self.at_newline() {
+                            self.pos += 1;
+                        }
+                    }
+                    let text = String::from_utf8_lossy(&self.chunk[start..self.pos]).into_owned();
+                    self.comments.push(Comment { text, line, column, end_line: self.line });
+                    continue;
+                }
+                b'[' => match self.bracket_level() {
+                    (level, true) => {
+                        self.pos += level + 2;
+                        let s = self.read_long_string(level, "string")?;
+                        Ok(token(self, TokenKind::Str(s)))
+                    }
+                    (0, false) => single(self, TokenKind::LBracket),
+                    (level, false) => {
+                        self.pos += level + 1;
+                        Err(self.error_near("invalid long string delimiter", start))
+                    }
+                },
+                b'=' => self.one_or_two(b'=', TokenKind::Eq, TokenKind::Assign, token),
+                b'<' => {
+                    self.pos += 1;
+                    let kind = if self.check_next(b'=') {
+                        TokenKind::Le
+                    } else if self.check_next(b'<') {
+                        TokenKind::Shl
+                    } else {
+                        TokenKind::Lt
+                    };
+                    Ok(token(self, kind))
+                }
+                b'>' => {
+                    self.pos += 1;
+                    let kind = if self.check_next(b'=') {
+                        TokenKind::Ge
+                    } else if self.check_next(b'>') {
+                        TokenKind::Shr
+                    } else {
+                        TokenKind::Gt
+                    };
+                    Ok(token(self, kind))
+                }
+                b'/' => self.one_or_two(b'/', TokenKind::DoubleSlash, TokenKind::Slash, token),
+                b'~' => self.one_or_two(b'=', TokenKind::Ne, TokenKind::Tilde, token),
+                b':' => self.one_or_two(b':', TokenKind::DoubleColon, TokenKind::Colon, token),
+                b'"' | b'\'' => {
+                    let s = self.read_string(c)?;
+                    Ok(token(self, TokenKind::Str(s)))
+                }
+                b'.' => {
+                    if self.peek(1).is_some_and(|b| b.is_ascii_digit()) {
+                        let kind = self.read_numeral()?;
+                        return Ok(token(self, kind));
+                    }
+                    self.pos += 1;
+                    let kind = if self.check_next(b'.') {
+                        if self.check_next(b'.') {
+                            TokenKind::Dots
+                        } else {
+                            TokenKind::Concat
+                        }
+                    } else {
+                        TokenKind::Dot
+                    };
+                    Ok(token(self, kind))
+                }
+                b'0'..=b'9' => {
+                    let kind = self.read_numeral()?;
+                    Ok(token(self, kind))
+                }
+                b'+' => single(self, TokenKind::Plus),
+                b'*' => single(self, TokenKind::Star),
+                b'%' => single(self, TokenKind::Percent),
+                b'^' => single(self, TokenKind::Caret),
+                b'#' => single(self, TokenKind::Hash),
+                b'&' => single(self, TokenKind::Amp),
+                b'|' => single(self, TokenKind::Pipe),
+                b'(' => single(self, TokenKind::LParen),
+                b')' => single(self, TokenKind::RParen),
+                b'{' => single(self, TokenKind::LBrace),
+                b'}' => single(self, TokenKind::RBrace),
+                b']' => single(self, TokenKind::RBracket),
+                b';' => single(self, TokenKind::Semi),
+                b',' => single(self, TokenKind::Comma),
+                c if c.is_ascii_alphabetic() || c == b'_' => {
+                    while self.current().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                        self.pos += 1;
+                    }
+                    let word = std::str::from_utf8(&self.chunk[start..self.pos]).unwrap();
+                    let kind = match KEYWORDS.iter().find(|(w, _)| *w == word) {
+                        Some((_, k)) => k.clone(),
+                        None => TokenKind::Name(String::from(word)),
+                    };
+                    Ok(token(self, kind))
+                }
+                _ => {
+                    self.pos += 1;
+                    Err(self.error_near("unexpected symbol", start))
+                }
+            };
+        }
+    }
+
+    fn one_or_two<F>(&mut self, second: u8, two: TokenKind, one: TokenKind, token: F) -> Result<Token, SyntaxError>
+    where
+        F: Fn(&Lexer, TokenKind) -> Token,
+    {
+        self.pos += 1;
+        let kind = if self.check_next(second) { two } else { one };
+        Ok(token(self, kind))
+    }
+
+    // With the lexer on a '[', looks for "[=*[" and returns the number
+    // of '=' signs and whether the second '[' is there
+    fn bracket_level(&self) -> (usize, bool) {
+        let mut level = 0;
+        while self.peek(1 + level) == Some(b'=') {
+            level += 1;
+        }
+        (level, self.peek(1 + level) == Some(b'['))
+    }
+
+    fn read_long_string(&mut self, level: usize, what: &str) -> Result<Vec<u8>, SyntaxError> {
+        let mut s = Vec::new();
+        // a newline right after the opening bracket is not part of the string
+        if self.at_newline() {
+            self.newline();
+        }
+        loop {
+            match self.current() {
+                None => return Err(self.error_near(&format!("unfinished long {}", what), self.chunk.len())),
+                Some(b']') => {
+                    let closes = (1..=level).all(|n| self.peek(n) == Some(b'=')) && self.peek(level + 1) == Some(b']');
+                    if closes {
+                        self.pos += level + 2;
+                        return Ok(s);
+                    }
+                    s.push(b']');
+                    self.pos += 1;
+                }
+                Some(b'\n' | b'\r') => {
+                    s.push(b'\n');
+                    self.newline();
+                }
+                Some(b) => {
+                    s.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_string(&mut self, delimiter: u8) -> Result<Vec<u8>, SyntaxError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            match self.current() {
+                None => return Err(self.error_near("unfinished string", self.chunk.len())),
+                Some(b'\n' | b'\r') => return Err(self.error_near("unfinished string", start)),
+                Some(b) if b == delimiter => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(b'\\') => self.read_escape(start, &mut s)?,
+                Some(b) => {
+                    s.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn read_escape(&mut self, start: usize, s: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        self.pos += 1;
+        let Some(c) = self.current() else {
+            // the error is reported for the unfinished string
+            return Ok(());
+        };
+        let simple = match c {
+            b'a' => Some(0x07),
+            b'b' => Some(0x08),
+            b'f' => Some(0x0c),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'v' => Some(0x0b),
+            b'\\' | b'"' | b'\'' => Some(c),
+            _ => None,
+        };
+        if let Some(b) = simple {
+            s.push(b);
+            self.pos += 1;
+            return Ok(());
+        }
+        match c {
+            b'\n' | b'\r' => {
+                s.push(b'\n');
+                self.newline();
+            }
+            b'x' => {
+                self.pos += 1;
+                let mut n = 0;
+                for _ in 0..2 {
+                    match self.current().and_then(|b| (b as char).to_digit(16)) {
+                        Some(d) => n = n * 16 + d,
+                        None => {
+                            self.pos += self.current().is_some() as usize;
+                            return Err(self.error_near("hexadecimal digit expected", start));
+                        }
+                    }
+                    self.pos += 1;
+                }
+                s.push(n as u8);
+            }
+            b'z' => {
+                self.pos += 1;
+                loop {
+                    match self.current() {
+                        Some(b'\n' | b'\r') => self.newline(),
+                        Some(b) if b.is_ascii_whitespace() || b == 0x0b => self.pos += 1,
+                        _ => break,
+                    }
+                }
+            }
+            b'u' => self.read_utf8_escape(start, s)?,
+            b'0'..=b'9' => {
+                let mut n: u32 = 0;
+                for _ in 0..3 {
+                    match self.current() {
+                        Some(d @ b'0'..=b'9') => {
+                            n = n * 10 + (d - b'0') as u32;
+                            self.pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                if n > 255 {
+                    self.pos += self.current().is_some() as usize;
+                    return Err(self.error_near("decimal escape too large", start));
+                }
+                s.push(n as u8);
+            }
+            _ => {
+                self.pos += 1;
+                return Err(self.error_near("invalid escape sequence", start));
+            }
+        }
+        Ok(())
+    }
+
+    // \u{XXX}, encoded the way Lua 5.3 does, which allows values up to 2^31
+    fn read_utf8_escape(&mut self, start: usize, s: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        self.pos += 1;
+        if !self.check_next(b'{') {
+            self.pos += self.current().is_some() as usize;
+            return Err(self.error_near("missing '{'", start));
+        }
+        let mut n: u32 = 0;
+        let mut digits = 0;
+        while let Some(d) = self.current().and_then(|b| (b as char).to_digit(16)) {
+            digits += 1;
+            if n >= 0x8000000 {
+                self.pos += 1;
+                return Err(self.error_near("UTF-8 value too large", start));
+            }
+            n = n * 16 + d;
+            self.pos += 1;
+        }
+        if digits == 0 {
+            self.pos += self.current().is_some() as usize;
+            return Err(self.error_near("hexadecimal digit expected", start));
+        }
+        if !self.check_next(b'}') {
+            self.pos += self.current().is_some() as usize;
+            return Err(self.error_near("missing '}'", start));
+        }
+        s.extend(utf8_encode(n));
+        Ok(())
+    }
+
+    // reads the longest run that may belong to a numeral and converts it,
+    // as read_numeral in llex.c does
+    fn read_numeral(&mut self) -> Result<TokenKind, SyntaxError> {
+        let start = self.pos;
+        let hex = self.current() == Some(b'0') && matches!(self.peek(1), Some(b'x' | b'X'));
+        if hex {
+            self.pos += 2;
+        }
+        let expo: &[u8] = if hex { b"Pp" } else { b"Ee" };
+        loop {
+            match self.current() {
+                Some(b) if expo.contains(&b) => {
+                    self.pos += 1;
+                    if matches!(self.current(), Some(b'+' | b'-')) {
+                        self.pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_hexdigit() || b == b'.' => self.pos += 1,
+                _ => break,
+            }
+        }
+        let text = std::str::from_utf8(&self.chunk[start..self.pos]).unwrap();
+        str_to_number(text).ok_or_else(|| self.error_near("malformed number", start))
+    }
+}
+
+pub fn str_to_number(text: &str) -> Option<TokenKind> {
+    if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            // hexadecimal integers wrap around
+            let n = digits.bytes().fold(0i64, |n, b| {
+                n.wrapping_mul(16).wrapping_add((b as char).to_digit(16).unwrap() as i64)
+            });
+            return Some(TokenKind::Integer(n));
+        }
+        return hex_float(digits).map(TokenKind::Number);
+    }
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        // decimal integers that do not fit become floats
+        if let Ok(i) = text.parse::<i64>() {
+            return Some(TokenKind::Integer(i));
+        }
+    }
+    if !text.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-')) {
+        return None;
+    }
+    text.parse::<f64>().ok().map(TokenKind::Number)
+}
+
+// the digits of a hexadecimal float after "0x": a mantissa with an
+// optional '.', then an optional binary exponent
+fn hex_float(text: &str) -> Option<f64> {
+    let (mantissa, exp) = match text.find(['p', 'P']) {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let mut m = 0.0f64;
+    let mut e: i64 = 0;
+    let mut seen_dot = false;
+    let mut any_digit = false;
+    for b in mantissa.bytes() {
+        match b {
+            b'.' if !seen_dot => seen_dot = true,
+            _ => {
+                let d = (b as char).to_digit(16)?;
+                m = m * 16.0 + d as f64;
+                any_digit = true;
+                if seen_dot {
+                    e -= 4;
+                }
+            }
+        }
+    }
+    if !any_digit {
+        return None;
+    }
+    if let Some(exp) = exp {
+        let digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        e += exp.parse::<i64>().unwrap_or(if exp.starts_with('-') { i64::MIN / 2 } else { i64::MAX / 2 });
+    }
+    Some(m * 2f64.powi(e.clamp(-2200, 2200) as i32))
+}
+
+fn utf8_encode(mut x: u32) -> Vec<u8> {
+    if x < 0x80 {
+        return vec![x as u8];
+    }
+    let mut bytes = Vec::new();
+    let mut mfb = 0x3f; // largest value that fits in the first byte
+    while x > mfb {
+        bytes.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+    }
+    bytes.push(((!mfb << 1) | x) as u8);
+    bytes.reverse();
+    bytes
+}
+
+pub fn tokenize(source: &str, chunk: &[u8]) -> Result<Vec<Token>, SyntaxError> {
+    let mut lexer = Lexer::new(source, chunk);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if eof {
+            return Ok(tokens);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use super::ast::*;
+use super::lexer::Lexer;
+use super::syntax_error::SyntaxError;
+use super::token::TokenKind;
+
+// the globals of the standard library, allowed by default
+pub const STANDARD_GLOBALS: &[&str] = &[
+    "_G", "_VERSION", "arg", "assert", "collectgarbage", "coroutine", "debug", "dofile", "error",
+    "getmetatable", "io", "ipairs", "load", "loadfile", "math", "next", "os", "package", "pairs",
+    "pcall", "print", "rawequal", "rawget", "rawlen", "rawset", "require", "select", "setmetatable",
+    "string", "table", "tonumber", "tostring", "type", "utf8", "xpcall",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub rule: &'static str,
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {} [{}]", self.line, self.column, self.msg, self.rule)
+    }
+}
+
+pub struct LintOptions {
+    // globals that may be read without being assigned in the chunk
+    pub globals: Vec<String>,
+}
+
+impl Default for LintOptions {
+    fn default() -> LintOptions {
+        LintOptions { globals: STANDARD_GLOBALS.iter().map(|g| g.to_string()).collect() }
+    }
+}
+
+// Checks a chunk for likely mistakes, in order of position. The rules are
+//   undefined-global   reading a global that is neither allowed nor
+//                      assigned anywhere in the chunk
+//   unused-local       a local, local function or loop variable that is
+//                      never read; names starting with '_' are exempt
+//   unused-param       the same for parameters
+//   shadowing          a local hiding another one of the same name
+//   unreachable-code   statements after return, break or goto
+//   loop-var-assign    assigning to the variable of a for loop
+//   arg-count          calling a local function with a number of arguments
+//                      that does not match its parameters
+// A comment "-- lint: ignore" silences its line, or the next one when it
+// stands on a line of its own; "-- lint: ignore rule, ..." only silences
+// those rules.
+pub fn lint(source: &str, chunk: &[u8], options: &LintOptions) -> Result<Vec<Lint>, SyntaxError> {
+    let block = super::parse(source, chunk)?;
+    let mut linter = Linter { vars: Vec::new(), lints: Vec::new(), reads: Vec::new(), assigned: HashSet::new() };
+    linter.block(&block);
+    let mut lints = linter.lints;
+    for (name, line, column) in linter.reads {
+        if name != "_ENV" && !linter.assigned.contains(&name) && !options.globals.contains(&name) {
+            lints.push(Lint { rule: "undefined-global", line, column, msg: format!("undefined global '{}'", name) });
+        }
+    }
+    let ignores = ignores(source, chunk)?;
+    lints.retain(|l| {
+        !ignores.iter().any(|(line, rules)| *line == l.line && (rules.is_empty() || rules.iter().any(|r| r == l.rule)))
+    });
+    lints.sort_by_key(|l| (l.line, l.column));
+    Ok(lints)
+}
+
+// the lines silenced by comments, each with the rules it silences, or
+// none for all of them
+fn ignores(source: &str, chunk: &[u8]) -> Result<Vec<(usize, Vec<String>)>, SyntaxError> {
+    let mut lexer = Lexer::new(source, chunk);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        if token.kind == TokenKind::Eof {
+            break;
+        }
+        tokens.push((token.line, token.column));
+    }
+    let mut ignores = Vec::new();
+    for c in lexer.comments() {
+        let text = c.text.trim_start_matches('-').trim();
+        let Some(rules) = text.strip_prefix("lint:").map(str::trim).and_then(|t| t.strip_prefix("ignore")) else {
+            continue;
+        };
+        if !rules.is_empty() && !rules.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let rules = rules.split(|ch: char| ch == ',' || ch.is_whitespace()).filter(|r| !r.is_empty());
+        let own_line = !tokens.iter().any(|&(line, column)| line == c.line && column < c.column);
+        let line = if own_line { c.end_line + 1 } else { c.line };
+        ignores.push((line, rules.map(String::from).collect()));
+    }
+    Ok(ignores)
+}
+
+#[derive(PartialEq)]
+enum VarKind {
+    Local,
+    Function,
+    Param,
+    LoopVar,
+    // the `self` of methods
+    Implicit,
+}
+
+struct Var {
+    name: String,
+    line: usize,
+    column: usize,
+    kind: VarKind,
+    used: bool,
+    assigned: bool,
+    // parameter count and vararg of the function the local was
+    // initialized with
+    arity: Option<(usize, bool)>,
+    // where it gets called, with the argument count and whether the
+    // last argument may add more
+    calls: Vec<(usize, usize, usize, bool)>,
+}
+
+struct Linter {
+    vars: Vec<Var>,
+    lints: Vec<Lint>,
+    // global reads, checked once all assignments are known
+    reads: Vec<(String, usize, usize)>,
+    assigned: HashSet<String>,
+}
+
+impl Linter {
+    fn report(&mut self, rule: &'static str, line: usize, column: usize, msg: String) {
+        self.lints.push(Lint { rule, line, column, msg });
+    }
+
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.vars.iter().rposition(|v| v.name == name)
+    }
+
+    fn declare(&mut self, name: &Name, kind: VarKind) {
+        if !name.name.starts_with('_') && kind != VarKind::Implicit {
+            if let Some(i) = self.resolve(&name.name) {
+                let msg = format!("'{}' shadows a local defined on line {}", name.name, self.vars[i].line);
+                self.report("shadowing", name.line, name.column, msg);
+            }
+        }
+        self.vars.push(Var {
+            name: name.name.clone(),
+            line: name.line,
+            column: name.column,
+            kind,
+            used: false,
+            assigned: false,
+            arity: None,
+            calls: Vec::new(),
+        });
+    }
+
+    // leaves the scope of the locals declared from `level` on
+    fn close(&mut self, level: usize) {
+        for var in self.vars.split_off(level) {
+            if let Some((n, vararg)) = var.arity.filter(|_| !var.assigned) {
+                for (line, column, args, open) in var.calls {
+                    let fixed = if open { args - 1 } else { args };
+                    if (fixed > n && !vararg) || (!open && args < n) {
+                        let msg = format!(
+                            "function '{}' takes {}{} arguments but is called with {}{}",
+                            var.name,
+                            n,
+                            if vararg { " or more" } else { "" },
+                            if open { "at least " } else { "" },
+                            fixed
+                        );
+                        self.report("arg-count", line, column, msg);
+                    }
+                }
+            }
+            if var.used || var.name.starts_with('_') {
+                continue;
+            }
+            let (rule, what) = match var.kind {
+                VarKind::Local => ("unused-local", "local"),
+                VarKind::Function => ("unused-local", "function"),
+                VarKind::LoopVar => ("unused-local", "loop variable"),
+                VarKind::Param => ("unused-param", "parameter"),
+                VarKind::Implicit => continue,
+            };
+            self.report(rule, var.line, var.column, format!("unused {} '{}'", what, var.name));
+        }
+    }
+
+    /* statements */
+
+    fn block(&mut self, block: &Block) {
+        let level = self.vars.len();
+        self.stat_list(block);
+        self.close(level);
+    }
+
+    fn stat_list(&mut self, block: &Block) {
+        let mut dead = false;
+        let mut reported = false;
+        for stat in &block.stats {
+            match stat.kind {
+                StatKind::Label(_) => dead = false,
+                StatKind::Empty => {}
+                _ if dead && !reported => {
+                    self.report("unreachable-code", stat.line, stat.column, String::from("unreachable code"));
+                    reported = true;
+                }
+                _ => {}
+            }
+            self.stat(stat);
+            dead |= terminates(stat);
+        }
+        if let Some(ret) = &block.ret {
+            if dead && !reported {
+                self.report("unreachable-code", ret.line, ret.column, String::from("unreachable code"));
+            }
+            self.exp_list(&ret.exps);
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match &stat.kind {
+            StatKind::Empty | StatKind::Break | StatKind::Label(_) | StatKind::Goto(_) => {}
+            StatKind::Do(block) => self.block(block),
+            StatKind::Call(e) => self.exp(e),
+            StatKind::While { cond, block } => {
+                self.exp(cond);
+                self.block(block);
+            }
+            StatKind::Repeat { block, cond } => {
+                // the condition sees the locals of the body
+                let level = self.vars.len();
+                self.stat_list(block);
+                self.exp(cond);
+                self.close(level);
+            }
+            StatKind::If { conds, blocks, else_block } => {
+                for (cond, block) in conds.iter().zip(blocks) {
+                    self.exp(cond);
+                    self.block(block);
+                }
+                if let Some(block) = else_block {
+                    self.block(block);
+                }
+            }
+            StatKind::NumericFor { var, init, limit, step, block, .. } => {
+                self.exp(init);
+                self.exp(limit);
+                if let Some(step) = step {
+                    self.exp(step);
+                }
+                let level = self.vars.len();
+                self.declare(var, VarKind::LoopVar);
+                self.block(block);
+                self.close(level);
+            }
+            StatKind::GenericFor { names, exps, block, .. } => {
+                self.exp_list(exps);
+                let level = self.vars.len();
+                for name in names {
+                    self.declare(name, VarKind::LoopVar);
+                }
+                self.block(block);
+                self.close(level);
+            }
+            StatKind::Local { names, exps, .. } => {
+                self.exp_list(exps);
+                for (i, name) in names.iter().enumerate() {
+                    let func = exps.get(i).and_then(|e| match &e.kind {
+                        ExpKind::Function(f) => Some((f.params.len(), f.is_vararg)),
+                        _ => None,
+                    });
+                    self.declare(name, if func.is_some() { VarKind::Function } else { VarKind::Local });
+                    self.vars.last_mut().unwrap().arity = func;
+                }
+            }
+            StatKind::Assign { vars, exps } => {
+                self.exp_list(exps);
+                for var in vars {
+                    match &var.kind {
+                        ExpKind::Name(name) => self.assign(name, var.line, var.column),
+                        _ => self.exp(var),
+                    }
+                }
+            }
+            StatKind::LocalFunction { name, func } => {
+                self.declare(name, VarKind::Function);
+                self.vars.last_mut().unwrap().arity = Some((func.params.len(), func.is_vararg));
+                self.function(func, false);
+            }
+            StatKind::Function { path, method, func } => {
+                if path.len() == 1 && method.is_none() {
+                    self.assign(&path[0].name, path[0].line, path[0].column);
+                } else {
+                    self.read(&path[0].name, path[0].line, path[0].column);
+                }
+                self.function(func, method.is_some());
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &str, line: usize, column: usize) {
+        match self.resolve(name) {
+            Some(i) => {
+                if self.vars[i].kind == VarKind::LoopVar {
+                    self.report("loop-var-assign", line, column, format!("assignment to loop variable '{}'", name));
+                }
+                self.vars[i].assigned = true;
+            }
+            None => {
+                self.assigned.insert(name.to_string());
+            }
+        }
+    }
+
+    fn read(&mut self, name: &str, line: usize, column: usize) {
+        match self.resolve(name) {
+            Some(i) => self.vars[i].used = true,
+            None => self.reads.push((name.to_string(), line, column)),
+        }
+    }
+
+    fn function(&mut self, func: &FuncBody, is_method: bool) {
+        let level = self.vars.len();
+        if is_method {
+            let name = Name { name: String::from("self"), line: func.line, column: 0 };
+            self.declare(&name, VarKind::Implicit);
+        }
+        for param in &func.params {
+            self.declare(param, VarKind::Param);
+        }
+        self.block(&func.block);
+        self.close(level);
+    }
+
+    /* expressions */
+
+    fn exp_list(&mut self, exps: &[Exp]) {
+        exps.iter().for_each(|e| self.exp(e));
+    }
+
+    fn exp(&mut self, e: &Exp) {
+        match &e.kind {
+            ExpKind::Name(name) => self.read(name, e.line, e.column),
+            ExpKind::Unop { exp, .. } | ExpKind::Paren(exp) => self.exp(exp),
+            ExpKind::Binop { lhs, rhs, .. } => {
+                self.exp(lhs);
+                self.exp(rhs);
+            }
+            ExpKind::Table { fields, .. } => {
+                for field in fields {
+                    match field {
+                        Field::Positional(v) | Field::Named(_, v) => self.exp(v),
+                        Field::Keyed(k, v) => {
+                            self.exp(k);
+                            self.exp(v);
+                        }
+                    }
+                }
+            }
+            ExpKind::Function(func) => self.function(func, false),
+            ExpKind::Index { prefix, key } => {
+                self.exp(prefix);
+                self.exp(key);
+            }
+            ExpKind::Call { prefix, method, args, .. } => {
+                if let (ExpKind::Name(name), None) = (&prefix.kind, method) {
+                    if let Some(i) = self.resolve(name) {
+                        let open = args.last().is_some_and(|a| matches!(a.kind, ExpKind::Call { .. } | ExpKind::Vararg));
+                        self.vars[i].calls.push((prefix.line, prefix.column, args.len(), open));
+                    }
+                }
+                self.exp(prefix);
+                self.exp_list(args);
+            }
+            _ => {}
+        }
+    }
+}
+
+// whether nothing after `stat` in its block can run, but from a label
+fn terminates(stat: &Stat) -> bool {
+    let block_terminates = |b: &Block| {
+        b.ret.is_some() || b.stats.iter().fold(false, |dead, s| !matches!(s.kind, StatKind::Label(_)) && (dead || terminates(s)))
+    };
+    match &stat.kind {
+        StatKind::Break | StatKind::Goto(_) => true,
+        StatKind::Do(block) => block_terminates(block),
+        StatKind::If { blocks, else_block: Some(else_block), .. } => {
+            blocks.iter().all(block_terminates) && block_terminates(else_block)
+        }
+        _ => false,
+    }
+}
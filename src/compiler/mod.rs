@@ -0,0 +1,30 @@
+pub mod token;
+pub mod lexer;
+pub mod syntax_error;
+pub mod ast;
+pub mod parser;
+pub mod formatter;
+pub mod linter;
+mod codegen;
+mod fold;
+mod func_state;
+mod gen_exp;
+mod gen_stat;
+
+use ast::Block;
+use syntax_error::SyntaxError;
+use crate::binchunk::binary_chunk::Prototype;
+
+// parses a whole chunk into the block of its main function
+pub fn parse(source: &str, chunk: &[u8]) -> Result<Block, SyntaxError> {
+    parser::Parser::new(source, chunk)?.parse_chunk()
+}
+
+// compiles a chunk into the prototype of its main function, as luac does,
+// with constant expressions folded;
+// `source` is kept in the prototype, e.g. "@file.lua" or "=stdin"
+pub fn compile(source: &str, chunk: &[u8]) -> Result<Box<Prototype>, SyntaxError> {
+    let mut block = parse(source, chunk)?;
+    fold::Folder::new(syntax_error::chunk_id(source)).block(&mut block)?;
+    codegen::generate(source, &block).map(Box::new)
+}
@@ -0,0 +1,637 @@
+use super::ast::*;
+use super::lexer::Lexer;
+use super::syntax_error::SyntaxError;
+use super::token::{Token, TokenKind};
+
+// limit on nested blocks and expressions, as LUAI_MAXCCALLS
+const MAX_LEVELS: usize = 200;
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: Token,
+    // whether each enclosing function may use '...'
+    vararg: Vec<bool>,
+    // line where each enclosing function starts, 0 for the main one
+    func_lines: Vec<usize>,
+    levels: usize,
+    last_line: usize, // line of the last token consumed
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &str, chunk: &'a [u8]) -> Result<Parser<'a>, SyntaxError> {
+        let mut lexer = Lexer::new(source, chunk);
+        let current = lexer.next_token()?;
+        Ok(Parser { lexer, current, vararg: vec![true], func_lines: vec![0], levels: 0, last_line: 1 })
+    }
+
+    // the main function: a block that runs until the end of the chunk
+    pub fn parse_chunk(&mut self) -> Result<Block, SyntaxError> {
+        let block = self.block()?;
+        self.check(TokenKind::Eof)?;
+        Ok(block)
+    }
+
+    /* errors */
+
+    pub fn error(&self, msg: &str) -> SyntaxError {
+        let near = match self.current.kind {
+            TokenKind::Eof => String::from("<eof>"),
+            _ => format!("'{}'", self.lexer.text(&self.current)),
+        };
+        SyntaxError {
+            chunk_id: String::from(self.lexer.chunk_id()),
+            line: self.current.line,
+            column: self.current.column,
+            msg: format!("{} near {}", msg, near),
+        }
+    }
+
+    // an error about meaning rather than syntax, so with no token quoted
+    fn sem_error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            chunk_id: String::from(self.lexer.chunk_id()),
+            line: self.current.line,
+            column: self.current.column,
+            msg: String::from(msg),
+        }
+    }
+
+    fn error_expected(&self, kind: &TokenKind) -> SyntaxError {
+        self.error(&format!("{} expected", token_to_str(kind)))
+    }
+
+    fn enter_level(&mut self) -> Result<(), SyntaxError> {
+        self.levels += 1;
+        if self.levels > MAX_LEVELS {
+            let place = match self.func_lines.last() {
+                Some(&line) if line > 0 => format!("function at line {}", line),
+                _ => String::from("main function"),
+            };
+            return Err(self.error(&format!("too many C levels (limit is {}) in {}", MAX_LEVELS, place)));
+        }
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.levels -= 1;
+    }
+
+    /* token handling */
+
+    fn advance(&mut self) -> Result<Token, SyntaxError> {
+        let next = self.lexer.next_token()?;
+        self.last_line = self.current.line;
+        Ok(std::mem::replace(&mut self.current, next))
+    }
+
+    fn is(&self, kind: &TokenKind) -> bool {
+        &self.current.kind == kind
+    }
+
+    fn test_next(&mut self, kind: TokenKind) -> Result<bool, SyntaxError> {
+        if self.is(&kind) {
+            self.advance()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn check(&self, kind: TokenKind) -> Result<(), SyntaxError> {
+        if !self.is(&kind) {
+            return Err(self.error_expected(&kind));
+        }
+        Ok(())
+    }
+
+    fn check_next(&mut self, kind: TokenKind) -> Result<Token, SyntaxError> {
+        self.check(kind)?;
+        self.advance()
+    }
+
+    // the token closing a construct opened by `who` at line `line`
+    fn check_match(&mut self, what: TokenKind, who: TokenKind, line: usize) -> Result<Token, SyntaxError> {
+        if !self.is(&what) {
+            if line == self.current.line {
+                return Err(self.error_expected(&what));
+            }
+            return Err(self.error(&format!(
+                "{} expected (to close {} at line {})",
+                token_to_str(&what),
+                token_to_str(&who),
+                line
+            )));
+        }
+        self.advance()
+    }
+
+    fn name(&mut self) -> Result<Name, SyntaxError> {
+        match &self.current.kind {
+            TokenKind::Name(name) => {
+                let name = Name { name: name.clone(), line: self.current.line, column: self.current.column };
+                self.advance()?;
+                Ok(name)
+            }
+            _ => Err(self.error_expected(&TokenKind::Name(String::new()))),
+        }
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.current.kind {
+            TokenKind::Else | TokenKind::Elseif | TokenKind::End | TokenKind::Eof => true,
+            TokenKind::Until => with_until,
+            _ => false,
+        }
+    }
+
+    /* statements */
+
+    fn block(&mut self) -> Result<Block, SyntaxError> {
+        self.enter_level()?;
+        let mut stats = Vec::new();
+        let mut ret = None;
+        while !self.block_follow(true) {
+            if self.is(&TokenKind::Return) {
+                ret = Some(self.ret_stat()?);
+                break;
+            }
+            stats.push(self.statement()?);
+        }
+        self.leave_level();
+        let (end_line, end_column) = (self.current.line, self.current.column);
+        Ok(Block { stats, ret, last_line: self.last_line, end_line, end_column })
+    }
+
+    fn ret_stat(&mut self) -> Result<Return, SyntaxError> {
+        let Token { line, column, .. } = self.advance()?;
+        let exps = if self.block_follow(true) || self.is(&TokenKind::Semi) {
+            Vec::new()
+        } else {
+            self.exp_list()?
+        };
+        self.test_next(TokenKind::Semi)?;
+        Ok(Return { exps, line, column })
+    }
+
+    fn statement(&mut self) -> Result<Stat, SyntaxError> {
+        let (line, column) = (self.current.line, self.current.column);
+        self.enter_level()?;
+        let kind = match self.current.kind {
+            TokenKind::Semi => {
+                self.advance()?;
+                StatKind::Empty
+            }
+            TokenKind::If => self.if_stat(line)?,
+            TokenKind::While => {
+                self.advance()?;
+                let cond = self.exp()?;
+                self.check_next(TokenKind::Do)?;
+                let block = self.block()?;
+                self.check_match(TokenKind::End, TokenKind::While, line)?;
+                StatKind::While { cond, block }
+            }
+            TokenKind::Do => {
+                self.advance()?;
+                let block = self.block()?;
+                self.check_match(TokenKind::End, TokenKind::Do, line)?;
+                StatKind::Do(block)
+            }
+            TokenKind::For => self.for_stat(line)?,
+            TokenKind::Repeat => {
+                self.advance()?;
+                let block = self.block()?;
+                self.check_match(TokenKind::Until, TokenKind::Repeat, line)?;
+                let cond = self.exp()?;
+                StatKind::Repeat { block, cond }
+            }
+            TokenKind::Function => self.func_stat(line)?,
+            TokenKind::Local => {
+                self.advance()?;
+                if self.test_next(TokenKind::Function)? {
+                    let name = self.name()?;
+                    let func = self.func_body(line)?;
+                    StatKind::LocalFunction { name, func }
+                } else {
+                    self.local_stat()?
+                }
+            }
+            TokenKind::DoubleColon => {
+                self.advance()?;
+                let name = self.name()?;
+                self.check_next(TokenKind::DoubleColon)?;
+                StatKind::Label(name.name)
+            }
+            TokenKind::Break => {
+                self.advance()?;
+                StatKind::Break
+            }
+            TokenKind::Goto => {
+                self.advance()?;
+                StatKind::Goto(self.name()?.name)
+            }
+            _ => self.exp_stat()?,
+        };
+        self.leave_level();
+        Ok(Stat { kind, line, column })
+    }
+
+    fn if_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        let mut conds = Vec::new();
+        let mut blocks = Vec::new();
+        // IF cond THEN block {ELSEIF cond THEN block}
+        loop {
+            self.advance()?;
+            conds.push(self.exp()?);
+            self.check_next(TokenKind::Then)?;
+            blocks.push(self.block()?);
+            if !self.is(&TokenKind::Elseif) {
+                break;
+            }
+        }
+        let else_block = if self.test_next(TokenKind::Else)? { Some(self.block()?) } else { None };
+        self.check_match(TokenKind::End, TokenKind::If, line)?;
+        Ok(StatKind::If { conds, blocks, else_block })
+    }
+
+    fn for_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        self.advance()?;
+        let var = self.name()?;
+        let kind = match self.current.kind {
+            TokenKind::Assign => {
+                self.advance()?;
+                let init = Box::new(self.exp()?);
+                self.check_next(TokenKind::Comma)?;
+                let limit = Box::new(self.exp()?);
+                let step = if self.test_next(TokenKind::Comma)? { Some(Box::new(self.exp()?)) } else { None };
+                let line_of_do = self.current.line;
+                self.check_next(TokenKind::Do)?;
+                let block = self.block()?;
+                StatKind::NumericFor { var, init, limit, step, block, line_of_do }
+            }
+            TokenKind::Comma | TokenKind::In => {
+                let mut names = vec![var];
+                while self.test_next(TokenKind::Comma)? {
+                    names.push(self.name()?);
+                }
+                self.check_next(TokenKind::In)?;
+                let exps = self.exp_list()?;
+                let line_of_do = self.current.line;
+                self.check_next(TokenKind::Do)?;
+                let block = self.block()?;
+                StatKind::GenericFor { names, exps, block, line_of_do }
+            }
+            _ => return Err(self.error("'=' or 'in' expected")),
+        };
+        self.check_match(TokenKind::End, TokenKind::For, line)?;
+        Ok(kind)
+    }
+
+    fn func_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        self.advance()?;
+        // funcname: NAME {'.' NAME} [':' NAME]
+        let mut path = vec![self.name()?];
+        while self.test_next(TokenKind::Dot)? {
+            path.push(self.name()?);
+        }
+        let method = if self.test_next(TokenKind::Colon)? { Some(self.name()?) } else { None };
+        let func = self.func_body(line)?;
+        Ok(StatKind::Function { path, method, func })
+    }
+
+    fn local_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let mut names = Vec::new();
+        let mut attribs = Vec::new();
+        loop {
+            names.push(self.name()?);
+            attribs.push(self.attrib()?);
+            if !self.test_next(TokenKind::Comma)? {
+                break;
+            }
+        }
+        if attribs.iter().filter(|&&a| a == Some(Attrib::Close)).count() > 1 {
+            return Err(self.sem_error("multiple to-be-closed variables in local list"));
+        }
+        let exps = if self.test_next(TokenKind::Assign)? { self.exp_list()? } else { Vec::new() };
+        Ok(StatKind::Local { names, attribs, exps })
+    }
+
+    fn attrib(&mut self) -> Result<Option<Attrib>, SyntaxError> {
+        if !self.test_next(TokenKind::Lt)? {
+            return Ok(None);
+        }
+        let attrib = self.name()?;
+        self.check_next(TokenKind::Gt)?;
+        match attrib.name.as_str() {
+            "const" => Ok(Some(Attrib::Const)),
+            "close" => Ok(Some(Attrib::Close)),
+            other => Err(self.sem_error(&format!("unknown attribute '{}'", other))),
+        }
+    }
+
+    fn exp_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let first = self.suffixed_exp()?;
+        if self.is(&TokenKind::Assign) || self.is(&TokenKind::Comma) {
+            let mut vars = vec![first];
+            loop {
+                if !matches!(vars.last().unwrap().kind, ExpKind::Name(_) | ExpKind::Index { .. }) {
+                    return Err(self.error("syntax error"));
+                }
+                if !self.test_next(TokenKind::Comma)? {
+                    break;
+                }
+                vars.push(self.suffixed_exp()?);
+            }
+            self.check_next(TokenKind::Assign)?;
+            let exps = self.exp_list()?;
+            return Ok(StatKind::Assign { vars, exps });
+        }
+        if !matches!(first.kind, ExpKind::Call { .. }) {
+            return Err(self.error("syntax error"));
+        }
+        Ok(StatKind::Call(first))
+    }
+
+    /* functions */
+
+    // the implicit `self` of methods is left to the code generator
+    fn func_body(&mut self, line: usize) -> Result<FuncBody, SyntaxError> {
+        self.check_next(TokenKind::LParen)?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if !self.is(&TokenKind::RParen) {
+            loop {
+                match self.current.kind {
+                    TokenKind::Name(_) => params.push(self.name()?),
+                    TokenKind::Dots => {
+                        self.advance()?;
+                        is_vararg = true;
+                    }
+                    _ => return Err(self.error("<name> or '...' expected")),
+                }
+                if is_vararg || !self.test_next(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.check_next(TokenKind::RParen)?;
+        self.vararg.push(is_vararg);
+        self.func_lines.push(line);
+        let block = self.block()?;
+        self.vararg.pop();
+        self.func_lines.pop();
+        let last_line = self.current.line;
+        self.check_match(TokenKind::End, TokenKind::Function, line)?;
+        Ok(FuncBody { params, is_vararg, block, line, last_line })
+    }
+
+    /* expressions */
+
+    fn exp_list(&mut self) -> Result<Vec<Exp>, SyntaxError> {
+        let mut exps = vec![self.exp()?];
+        while self.test_next(TokenKind::Comma)? {
+            exps.push(self.exp()?);
+        }
+        Ok(exps)
+    }
+
+    pub fn exp(&mut self) -> Result<Exp, SyntaxError> {
+        self.sub_exp(0)
+    }
+
+    // parses operators with a left priority above `limit`
+    fn sub_exp(&mut self, limit: u8) -> Result<Exp, SyntaxError> {
+        self.enter_level()?;
+        let (line, column) = (self.current.line, self.current.column);
+        let mut exp = match unary_op(&self.current.kind) {
+            Some(op) => {
+                self.advance()?;
+                let operand = self.sub_exp(UNARY_PRIORITY)?;
+                Exp { kind: ExpKind::Unop { op, exp: Box::new(operand) }, line, column }
+            }
+            None => self.simple_exp()?,
+        };
+        while let Some(op) = binary_op(&self.current.kind) {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            let Token { line, column, .. } = self.advance()?;
+            let rhs = self.sub_exp(right)?;
+            exp = Exp { kind: ExpKind::Binop { op, lhs: Box::new(exp), rhs: Box::new(rhs) }, line, column };
+        }
+        self.leave_level();
+        Ok(exp)
+    }
+
+    // every construct gets its own function to keep the frames of the
+    // recursive path small, as nesting goes 200 levels deep
+    fn simple_exp(&mut self) -> Result<Exp, SyntaxError> {
+        match self.current.kind {
+            TokenKind::LBrace => self.table_constructor(),
+            TokenKind::Function => self.function_exp(),
+            TokenKind::Name(_) | TokenKind::LParen => self.suffixed_exp(),
+            TokenKind::Number(_)
+            | TokenKind::Integer(_)
+            | TokenKind::Str(_)
+            | TokenKind::Nil
+            | TokenKind::True
+            | TokenKind::False
+            | TokenKind::Dots => self.literal(),
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Exp, SyntaxError> {
+        let kind = match &self.current.kind {
+            TokenKind::Number(n) => ExpKind::Float(*n),
+            TokenKind::Integer(i) => ExpKind::Integer(*i),
+            TokenKind::Str(s) => ExpKind::Str(s.clone()),
+            TokenKind::Nil => ExpKind::Nil,
+            TokenKind::True => ExpKind::True,
+            TokenKind::False => ExpKind::False,
+            _ => {
+                if !self.vararg.last().unwrap() {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                ExpKind::Vararg
+            }
+        };
+        let Token { line, column, .. } = self.advance()?;
+        Ok(Exp { kind, line, column })
+    }
+
+    fn function_exp(&mut self) -> Result<Exp, SyntaxError> {
+        let Token { line, column, .. } = self.advance()?;
+        let func = self.func_body(line)?;
+        Ok(Exp { kind: ExpKind::Function(Box::new(func)), line, column })
+    }
+
+    fn primary_exp(&mut self) -> Result<Exp, SyntaxError> {
+        match self.current.kind {
+            TokenKind::Name(_) => {
+                let name = self.name()?;
+                Ok(Exp { kind: ExpKind::Name(name.name), line: name.line, column: name.column })
+            }
+            TokenKind::LParen => self.paren_exp(),
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    fn paren_exp(&mut self) -> Result<Exp, SyntaxError> {
+        let Token { line, column, .. } = self.advance()?;
+        let exp = self.exp()?;
+        self.check_match(TokenKind::RParen, TokenKind::LParen, line)?;
+        Ok(Exp { kind: ExpKind::Paren(Box::new(exp)), line, column })
+    }
+
+    // primaryexp { '.' NAME | '[' exp ']' | ':' NAME funcargs | funcargs }
+    fn suffixed_exp(&mut self) -> Result<Exp, SyntaxError> {
+        let mut exp = self.primary_exp()?;
+        while let TokenKind::Dot
+        | TokenKind::LBracket
+        | TokenKind::Colon
+        | TokenKind::LParen
+        | TokenKind::Str(_)
+        | TokenKind::LBrace = self.current.kind
+        {
+            exp = self.suffix(exp)?;
+        }
+        Ok(exp)
+    }
+
+    fn suffix(&mut self, prefix: Exp) -> Result<Exp, SyntaxError> {
+        let (line, column) = (self.current.line, self.current.column);
+        let prefix = Box::new(prefix);
+        let kind = match self.current.kind {
+            TokenKind::Dot => {
+                self.advance()?;
+                let name = self.name()?;
+                let key = Exp { kind: ExpKind::Str(name.name.into_bytes()), line: name.line, column: name.column };
+                ExpKind::Index { prefix, key: Box::new(key) }
+            }
+            TokenKind::LBracket => {
+                self.advance()?;
+                let key = self.exp()?;
+                self.check_next(TokenKind::RBracket)?;
+                ExpKind::Index { prefix, key: Box::new(key) }
+            }
+            TokenKind::Colon => {
+                self.advance()?;
+                let method = Some(self.name()?);
+                let (args, last_line) = self.func_args()?;
+                ExpKind::Call { prefix, method, args, last_line }
+            }
+            _ => {
+                let (args, last_line) = self.func_args()?;
+                ExpKind::Call { prefix, method: None, args, last_line }
+            }
+        };
+        Ok(Exp { kind, line, column })
+    }
+
+    fn func_args(&mut self) -> Result<(Vec<Exp>, usize), SyntaxError> {
+        let line = self.current.line;
+        match &self.current.kind {
+            TokenKind::Str(s) => {
+                let arg = Exp { kind: ExpKind::Str(s.clone()), line, column: self.current.column };
+                self.advance()?;
+                Ok((vec![arg], line))
+            }
+            TokenKind::LBrace => {
+                let table = self.table_constructor()?;
+                let last_line = match table.kind {
+                    ExpKind::Table { last_line, .. } => last_line,
+                    _ => line,
+                };
+                Ok((vec![table], last_line))
+            }
+            TokenKind::LParen => {
+                self.advance()?;
+                let args = if self.is(&TokenKind::RParen) { Vec::new() } else { self.exp_list()? };
+                let last_line = self.current.line;
+                self.check_match(TokenKind::RParen, TokenKind::LParen, line)?;
+                Ok((args, last_line))
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    fn table_constructor(&mut self) -> Result<Exp, SyntaxError> {
+        let (line, column) = (self.current.line, self.current.column);
+        self.check_next(TokenKind::LBrace)?;
+        let mut fields = Vec::new();
+        while !self.is(&TokenKind::RBrace) {
+            fields.push(self.field()?);
+            if !self.test_next(TokenKind::Comma)? && !self.test_next(TokenKind::Semi)? {
+                break;
+            }
+        }
+        let last_line = self.current.line;
+        self.check_match(TokenKind::RBrace, TokenKind::LBrace, line)?;
+        Ok(Exp { kind: ExpKind::Table { fields, last_line }, line, column })
+    }
+
+    fn field(&mut self) -> Result<Field, SyntaxError> {
+        match self.current.kind {
+            TokenKind::Name(_) if self.lexer.peek_token()?.kind == TokenKind::Assign => {
+                let name = self.name()?;
+                self.advance()?;
+                Ok(Field::Named(name, self.exp()?))
+            }
+            TokenKind::LBracket => {
+                self.advance()?;
+                let key = self.exp()?;
+                self.check_next(TokenKind::RBracket)?;
+                self.check_next(TokenKind::Assign)?;
+                Ok(Field::Keyed(key, self.exp()?))
+            }
+            _ => Ok(Field::Positional(self.exp()?)),
+        }
+    }
+}
+
+fn unary_op(kind: &TokenKind) -> Option<UnOp> {
+    match kind {
+        TokenKind::Minus => Some(UnOp::Minus),
+        TokenKind::Not => Some(UnOp::Not),
+        TokenKind::Hash => Some(UnOp::Len),
+        TokenKind::Tilde => Some(UnOp::BNot),
+        _ => None,
+    }
+}
+
+fn binary_op(kind: &TokenKind) -> Option<BinOp> {
+    Some(match kind {
+        TokenKind::Plus => BinOp::Add,
+        TokenKind::Minus => BinOp::Sub,
+        TokenKind::Star => BinOp::Mul,
+        TokenKind::Percent => BinOp::Mod,
+        TokenKind::Caret => BinOp::Pow,
+        TokenKind::Slash => BinOp::Div,
+        TokenKind::DoubleSlash => BinOp::IDiv,
+        TokenKind::Amp => BinOp::BAnd,
+        TokenKind::Pipe => BinOp::BOr,
+        TokenKind::Tilde => BinOp::BXor,
+        TokenKind::Shl => BinOp::Shl,
+        TokenKind::Shr => BinOp::Shr,
+        TokenKind::Concat => BinOp::Concat,
+        TokenKind::Eq => BinOp::Eq,
+        TokenKind::Ne => BinOp::Ne,
+        TokenKind::Lt => BinOp::Lt,
+        TokenKind::Le => BinOp::Le,
+        TokenKind::Gt => BinOp::Gt,
+        TokenKind::Ge => BinOp::Ge,
+        TokenKind::And => BinOp::And,
+        TokenKind::Or => BinOp::Or,
+        _ => return None,
+    })
+}
+
+// how a token is named in "... expected" messages, as luaX_token2str
+fn token_to_str(kind: &TokenKind) -> String {
+    match kind {
+        TokenKind::Eof | TokenKind::Name(_) | TokenKind::Str(_) | TokenKind::Integer(_) | TokenKind::Number(_) => {
+            kind.to_string()
+        }
+        _ => format!("'{}'", kind),
+    }
+}
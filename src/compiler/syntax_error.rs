@@ -0,0 +1,43 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub chunk_id: String,
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.chunk_id, self.line, self.msg)
+    }
+}
+
+impl Error for SyntaxError {}
+
+// how a chunk name shows up in messages, as luaO_chunkid does it:
+// "@file" is a file name, "=name" is used as is and anything else
+// is the source itself
+pub fn chunk_id(source: &str) -> String {
+    const LUA_IDSIZE: usize = 60;
+    if let Some(name) = source.strip_prefix('=') {
+        name.chars().take(LUA_IDSIZE - 1).collect()
+    } else if let Some(file) = source.strip_prefix('@') {
+        if file.len() < LUA_IDSIZE {
+            String::from(file)
+        } else {
+            let tail: String = file.chars().rev().take(LUA_IDSIZE - 4).collect();
+            format!("...{}", tail.chars().rev().collect::<String>())
+        }
+    } else {
+        let first = source.lines().next().unwrap_or("");
+        let max = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+        if first.len() < source.len() || first.chars().count() > max {
+            format!("[string \"{}...\"]", first.chars().take(max).collect::<String>())
+        } else {
+            format!("[string \"{}\"]", first)
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Eof,
+    Name(String),
+    Str(Vec<u8>),
+    Integer(i64),
+    Number(f64),
+
+    /* keywords */
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    /* operators and punctuation */
+    Plus,        // +
+    Minus,       // -
+    Star,        // *
+    Slash,       // /
+    DoubleSlash, // //
+    Percent,     // %
+    Caret,       // ^
+    Hash,        // #
+    Amp,         // &
+    Tilde,       // ~
+    Pipe,        // |
+    Shl,         // <<
+    Shr,         // >>
+    Concat,      // ..
+    Dots,        // ...
+    Eq,          // ==
+    Ne,          // ~=
+    Le,          // <=
+    Ge,          // >=
+    Lt,          // <
+    Gt,          // >
+    Assign,      // =
+    LParen,      // (
+    RParen,      // )
+    LBrace,      // {
+    RBrace,      // }
+    LBracket,    // [
+    RBracket,    // ]
+    DoubleColon, // ::
+    Semi,        // ;
+    Colon,       // :
+    Comma,       // ,
+    Dot,         // .
+}
+
+pub const KEYWORDS: [(&str, TokenKind); 22] = [
+    ("and", TokenKind::And),
+    ("break", TokenKind::Break),
+    ("do", TokenKind::Do),
+    ("else", TokenKind::Else),
+    ("elseif", TokenKind::Elseif),
+    ("end", TokenKind::End),
+    ("false", TokenKind::False),
+    ("for", TokenKind::For),
+    ("function", TokenKind::Function),
+    ("goto", TokenKind::Goto),
+    ("if", TokenKind::If),
+    ("in", TokenKind::In),
+    ("local", TokenKind::Local),
+    ("nil", TokenKind::Nil),
+    ("not", TokenKind::Not),
+    ("or", TokenKind::Or),
+    ("repeat", TokenKind::Repeat),
+    ("return", TokenKind::Return),
+    ("then", TokenKind::Then),
+    ("true", TokenKind::True),
+    ("until", TokenKind::Until),
+    ("while", TokenKind::While),
+];
+
+// A token and where it starts. `start..end` are byte offsets into the
+// chunk, so that messages can quote the token as it was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for TokenKind {
+    // the fixed text of the token, or a description of what it holds
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((word, _)) = KEYWORDS.iter().find(|(_, k)| k == self) {
+            return write!(f, "{}", word);
+        }
+        let s = match self {
+            TokenKind::Eof => "<eof>",
+            TokenKind::Name(_) => "<name>",
+            TokenKind::Str(_) => "<string>",
+            TokenKind::Integer(_) => "<integer>",
+            TokenKind::Number(_) => "<number>",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::DoubleSlash => "//",
+            TokenKind::Percent => "%",
+            TokenKind::Caret => "^",
+            TokenKind::Hash => "#",
+            TokenKind::Amp => "&",
+            TokenKind::Tilde => "~",
+            TokenKind::Pipe => "|",
+            TokenKind::Shl => "<<",
+            TokenKind::Shr => ">>",
+            TokenKind::Concat => "..",
+            TokenKind::Dots => "...",
+            TokenKind::Eq => "==",
+            TokenKind::Ne => "~=",
+            TokenKind::Le => "<=",
+            TokenKind::Ge => ">=",
+            TokenKind::Lt => "<",
+            TokenKind::Gt => ">",
+            TokenKind::Assign => "=",
+            TokenKind::LParen => "(",
+            TokenKind::RParen => ")",
+            TokenKind::LBrace => "{",
+            TokenKind::RBrace => "}",
+            TokenKind::LBracket => "[",
+            TokenKind::RBracket => "]",
+            TokenKind::DoubleColon => "::",
+            TokenKind::Semi => ";",
+            TokenKind::Colon => ":",
+            TokenKind::Comma => ",",
+            TokenKind::Dot => ".",
+            _ => unreachable!(),
+        };
+        write!(f, "{}", s)
+    }
+}
@@ -0,0 +1,10 @@
+pub mod vm;
+pub mod api;
+pub mod state;
+pub mod binchunk;
+pub mod asm;
+pub mod optimizer;
+pub mod compiler;
+pub mod cli;
+pub mod stdlib;
+mod test;
@@ -1,11 +1,11 @@
-mod vm;
-mod api;
-mod state;
-mod binchunk;
-mod test;
+use lua_engine::cli;
 
 fn main() {
-
+    let args: Vec<String> = std::env::args().collect();
+    let status = match args.get(1).map(String::as_str) {
+        Some("fmt") => cli::fmt::main(&args[2..]),
+        Some("lint") => cli::lint::main(&args[2..]),
+        _ => cli::lua::main(&args),
+    };
+    std::process::exit(status);
 }
-
-
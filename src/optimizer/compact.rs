@@ -0,0 +1,119 @@
+use crate::binchunk::binary_chunk::{Constant, LocVar, Prototype};
+use crate::vm::instruction::*;
+use crate::vm::opcodes::*;
+
+use super::flow;
+use super::peephole::same_constant;
+
+// Drops the instructions marked in `dead`. Jumps into a dropped
+// instruction land on the next one that is kept.
+pub fn remove_instructions(f: &mut Prototype, dead: &[bool]) {
+    let mut new_pc = Vec::with_capacity(f.code.len() + 1);
+    let mut kept = 0;
+    for &d in dead {
+        new_pc.push(kept);
+        if !d {
+            kept += 1;
+        }
+    }
+    new_pc.push(kept);
+
+    let mut code = Vec::with_capacity(kept);
+    let mut line_info = Vec::with_capacity(kept);
+    for (pc, &i) in f.code.iter().enumerate() {
+        if dead[pc] {
+            continue;
+        }
+        let i = match flow::jump_target(i, pc) {
+            Some(target) => {
+                let sbx = new_pc[target.min(f.code.len())] as isize - code.len() as isize - 1;
+                u32::encode_asbx(i.opcode(), i.a_sbx().0, sbx).unwrap_or(i)
+            }
+            None => i,
+        };
+        code.push(i);
+        line_info.extend(f.line_info.get(pc));
+    }
+
+    let remap = |pc: u32| new_pc[(pc as usize).min(f.code.len())] as u32;
+    f.loc_vars = f.loc_vars.iter().map(|v| LocVar {
+        var_name: v.var_name.clone(),
+        start_pc: remap(v.start_pc),
+        end_pc: remap(v.end_pc),
+    }).collect();
+    f.code = code;
+    f.line_info = line_info;
+}
+
+// Drops unused constants and merges duplicates, keeping the order in
+// which they were first declared.
+pub fn compact_constants(f: &mut Prototype) {
+    let mut used = vec![false; f.constants.len()];
+    for_each_constant(&mut f.code, |k| {
+        if let Some(u) = used.get_mut(k) {
+            *u = true;
+        }
+        k
+    });
+
+    let mut constants: Vec<Constant> = Vec::new();
+    let mut new_idx = vec![0; f.constants.len()];
+    for (idx, k) in f.constants.iter().enumerate() {
+        if !used[idx] {
+            continue;
+        }
+        new_idx[idx] = match constants.iter().position(|other| same_constant(other, k)) {
+            Some(n) => n,
+            None => {
+                constants.push(copy_constant(k));
+                constants.len() - 1
+            }
+        };
+    }
+    if constants.len() == f.constants.len() {
+        return;
+    }
+    for_each_constant(&mut f.code, |k| new_idx.get(k).copied().unwrap_or(k));
+    f.constants = constants;
+}
+
+// calls `f` on every constant index the code refers to, replacing it
+// with what `f` returns
+fn for_each_constant<F: FnMut(usize) -> usize>(code: &mut [u32], mut f: F) {
+    for pc in 0..code.len() {
+        let i = code[pc];
+        if i.opcode() as usize >= OPCODES.len() {
+            continue;
+        }
+        match i.opmode() {
+            OpMode::IABC => {
+                let (a, mut b, mut c) = i.abc();
+                for (x, mode) in [(&mut b, i.b_mode()), (&mut c, i.c_mode())] {
+                    if matches!(mode, OpArgMode::OpArgK) && is_k(*x) {
+                        *x = f(index_k(*x) as usize) as isize | BITRK;
+                    }
+                }
+                code[pc] = u32::encode_abc(i.opcode(), a, b, c).unwrap_or(i);
+            }
+            OpMode::IABx if i.opcode() == OP_LOADK => {
+                let (a, bx) = i.a_bx();
+                code[pc] = u32::encode_abx(OP_LOADK, a, f(bx as usize) as isize).unwrap_or(i);
+            }
+            OpMode::IABx if i.opcode() == OP_LOADKX && pc + 1 < code.len() => {
+                let ax = code[pc + 1].ax();
+                code[pc + 1] = u32::encode_ax(OP_EXTRAARG, f(ax as usize) as isize).unwrap_or(code[pc + 1]);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn copy_constant(k: &Constant) -> Constant {
+    match k {
+        Constant::Nil => Constant::Nil,
+        Constant::Boolean(b) => Constant::Boolean(*b),
+        Constant::Integer(i) => Constant::Integer(*i),
+        Constant::Number(n) => Constant::Number(*n),
+        Constant::Str(s) => Constant::Str(s.clone()),
+    }
+}
@@ -0,0 +1,76 @@
+use crate::vm::instruction::Instruction;
+use crate::vm::opcodes::*;
+
+// where a relative jump at `pc` goes, if it is one
+pub fn jump_target(i: u32, pc: usize) -> Option<usize> {
+    match i.opcode() {
+        OP_JMP | OP_FORLOOP | OP_FORPREP | OP_TFORLOOP => usize::try_from(pc as isize + 1 + i.a_sbx().1).ok(),
+        _ => None,
+    }
+}
+
+// instructions that may skip over the one after them
+pub fn skips_next(i: u32) -> bool {
+    match i.opcode() {
+        OP_EQ | OP_LT | OP_LE | OP_TEST | OP_TESTSET => true,
+        OP_LOADBOOL => i.abc().2 != 0,
+        _ => false,
+    }
+}
+
+pub fn has_extra_arg(i: u32) -> bool {
+    match i.opcode() {
+        OP_LOADKX => true,
+        OP_SETLIST => i.abc().2 == 0,
+        _ => false,
+    }
+}
+
+// an instruction reached by skipping or read as an operand of the one
+// before it has to stay where it is
+pub fn pinned(code: &[u32], pc: usize) -> bool {
+    pc > 0 && (skips_next(code[pc - 1]) || has_extra_arg(code[pc - 1]))
+}
+
+// pcs that execution can reach other than by falling through
+pub fn jump_targets(code: &[u32]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (pc, &i) in code.iter().enumerate() {
+        if let Some(t) = jump_target(i, pc) {
+            if t < targets.len() {
+                targets[t] = true;
+            }
+        }
+        if skips_next(i) && pc + 2 < targets.len() {
+            targets[pc + 2] = true;
+        }
+    }
+    targets.truncate(code.len());
+    targets
+}
+
+fn successors(code: &[u32], pc: usize) -> Vec<usize> {
+    let i = code[pc];
+    let next = match i.opcode() {
+        OP_RETURN => vec![],
+        OP_JMP | OP_FORPREP => vec![],
+        // the skipped instruction is kept alive with the skip so that
+        // removing it cannot shift where the skip lands
+        _ if skips_next(i) => vec![pc + 1, pc + 2],
+        _ => vec![pc + 1],
+    };
+    next.into_iter().chain(jump_target(i, pc)).filter(|&t| t < code.len()).collect()
+}
+
+pub fn unreachable(code: &[u32]) -> Vec<bool> {
+    let mut unreachable = vec![true; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || !unreachable[pc] {
+            continue;
+        }
+        unreachable[pc] = false;
+        pending.extend(successors(code, pc));
+    }
+    unreachable
+}
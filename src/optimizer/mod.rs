@@ -0,0 +1,37 @@
+mod compact;
+mod flow;
+mod peephole;
+
+use std::rc::Rc;
+
+use crate::binchunk::binary_chunk::Prototype;
+use crate::binchunk::header_const;
+
+// Rewrites `f` and its nested functions into equivalent but shorter code.
+// Only 5.3 bytecode is touched; other versions are returned as they are.
+pub fn optimize(mut f: Prototype) -> Prototype {
+    if f.version != header_const::LUAC_VERSION {
+        return f;
+    }
+    // each round may expose more work for the others, e.g. a folded
+    // constant leaves its operands unused and a threaded jump leaves
+    // the one it skipped unreachable
+    for _ in 0..f.code.len().max(1) {
+        let mut changed = peephole::fold_constants(&mut f);
+        changed |= peephole::thread_jumps(&mut f);
+        let mut dead = peephole::redundant(&f);
+        for (d, r) in dead.iter_mut().zip(flow::unreachable(&f.code)) {
+            *d |= r;
+        }
+        if dead.contains(&true) {
+            compact::remove_instructions(&mut f, &dead);
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+    compact::compact_constants(&mut f);
+    f.protos = f.protos.into_iter().map(|p| Rc::new(optimize(Rc::unwrap_or_clone(p)))).collect();
+    f
+}
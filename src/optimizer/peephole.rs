@@ -0,0 +1,144 @@
+use crate::binchunk::binary_chunk::{Constant, Prototype};
+use crate::state::arith_ops::checked_arith;
+use crate::state::lua_value::LuaValue;
+use crate::vm::instruction::*;
+use crate::vm::opcodes::*;
+
+use super::flow;
+
+// ADD .. SHR with two constant operands become a LOADK of the result,
+// computed the way the VM would at run time
+pub fn fold_constants(f: &mut Prototype) -> bool {
+    let mut changed = false;
+    for pc in 0..f.code.len() {
+        let i = f.code[pc];
+        let op = i.opcode();
+        if !(OP_ADD..=OP_SHR).contains(&op) {
+            continue;
+        }
+        let (a, b, c) = i.abc();
+        if !is_k(b) || !is_k(c) {
+            continue;
+        }
+        let (Some(x), Some(y)) = (f.constants.get(index_k(b) as usize), f.constants.get(index_k(c) as usize)) else {
+            continue;
+        };
+        let Some(k) = fold(op, x, y) else {
+            continue;
+        };
+        let idx = match f.constants.iter().position(|other| same_constant(other, &k)) {
+            Some(idx) => idx,
+            None => {
+                f.constants.push(k);
+                f.constants.len() - 1
+            }
+        };
+        if let Ok(load) = u32::encode_abx(OP_LOADK, a, idx as isize) {
+            f.code[pc] = load;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn fold(op: u8, x: &Constant, y: &Constant) -> Option<Constant> {
+    let value = |k: &Constant| match k {
+        Constant::Integer(i) => Some(LuaValue::Integer(*i)),
+        Constant::Number(n) => Some(LuaValue::Number(*n)),
+        _ => None,
+    };
+    let (x, y) = (value(x)?, value(y)?);
+    match checked_arith(&x, &y, op - OP_ADD)? {
+        LuaValue::Integer(i) => Some(Constant::Integer(i)),
+        LuaValue::Number(n) => Some(Constant::Number(n)),
+        _ => None,
+    }
+}
+
+pub fn same_constant(x: &Constant, y: &Constant) -> bool {
+    match (x, y) {
+        (Constant::Nil, Constant::Nil) => true,
+        (Constant::Boolean(a), Constant::Boolean(b)) => a == b,
+        (Constant::Integer(a), Constant::Integer(b)) => a == b,
+        // bitwise, so that 0.0 and -0.0 stay apart
+        (Constant::Number(a), Constant::Number(b)) => a.to_bits() == b.to_bits(),
+        (Constant::Str(a), Constant::Str(b)) => a == b,
+        _ => false,
+    }
+}
+
+// a JMP landing on another plain JMP goes straight to where that one goes
+pub fn thread_jumps(f: &mut Prototype) -> bool {
+    let mut changed = false;
+    for pc in 0..f.code.len() {
+        let i = f.code[pc];
+        if i.opcode() != OP_JMP {
+            continue;
+        }
+        let Some(mut target) = flow::jump_target(i, pc) else {
+            continue;
+        };
+        // bounded, as a cycle of jumps never reaches a final target
+        for _ in 0..f.code.len() {
+            match f.code.get(target) {
+                Some(&j) if j.opcode() == OP_JMP && j.a_sbx().0 == 0 && target != pc => match flow::jump_target(j, target) {
+                    Some(next) if next != target => target = next,
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        let sbx = target as isize - pc as isize - 1;
+        if sbx != i.a_sbx().1 {
+            if let Ok(jmp) = u32::encode_asbx(OP_JMP, i.a_sbx().0, sbx) {
+                f.code[pc] = jmp;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// instructions whose removal cannot change what the function does:
+// self moves, jumps to the next instruction, a MOVE whose target is
+// overwritten right away and the second half of `MOVE a b; MOVE b a`
+pub fn redundant(f: &Prototype) -> Vec<bool> {
+    let code = &f.code;
+    let targets = flow::jump_targets(code);
+    let mut dead = vec![false; code.len()];
+    for pc in 0..code.len() {
+        if flow::pinned(code, pc) {
+            continue;
+        }
+        let i = code[pc];
+        match i.opcode() {
+            OP_MOVE => {
+                let (a, b, _) = i.abc();
+                if a == b {
+                    dead[pc] = true;
+                    continue;
+                }
+                let Some(&next) = code.get(pc + 1) else {
+                    continue;
+                };
+                if next.opcode() != OP_MOVE {
+                    continue;
+                }
+                let (na, nb, _) = next.abc();
+                if na == a && nb != a {
+                    dead[pc] = true;
+                } else if na == b && nb == a && !targets[pc + 1] && !dead[pc] {
+                    dead[pc + 1] = true;
+                }
+            }
+            OP_JMP => {
+                let (a, sbx) = i.a_sbx();
+                if a == 0 && sbx == 0 {
+                    dead[pc] = true;
+                }
+            }
+            _ => (),
+        }
+    }
+    dead
+}
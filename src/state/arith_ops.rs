@@ -1,4 +1,5 @@
 use super::lua_value::LuaValue;
+use crate::api::consts::*;
 
 pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
     (|a, b| a+b, |a, b| a+b),
@@ -28,8 +29,8 @@ pub fn _arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
         }
     } else {
         if iop != inone {
-            if let Some(x) = a.to_integer() {
-                if let Some(y) = b.to_integer() {
+            if let Some(x) = integer_operand(a) {
+                if let Some(y) = integer_operand(b) {
                     return Some(LuaValue::Integer(iop(x, y)));
                 }
             }
@@ -43,6 +44,40 @@ pub fn _arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
     None
 }
 
+// _arith as a compiler may apply it ahead of time: None also for what
+// would raise an error or overflow at run time, and for a NaN result,
+// which is not equal to itself and so cannot be shared as a constant
+pub fn checked_arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
+    if let (Some(m), Some(n)) = (a.to_integer(), b.to_integer()) {
+        let ok = match op {
+            LUA_OPADD => m.checked_add(n).is_some(),
+            LUA_OPSUB => m.checked_sub(n).is_some(),
+            LUA_OPMUL => m.checked_mul(n).is_some(),
+            LUA_OPMOD | LUA_OPIDIV => n != 0 && m.checked_rem(n).is_some(),
+            LUA_OPSHL | LUA_OPSHR => n.unsigned_abs() < 64,
+            LUA_OPUNM => m.checked_neg().is_some(),
+            _ => true,
+        };
+        if !ok {
+            return None;
+        }
+    }
+    match _arith(a, b, op)? {
+        LuaValue::Number(n) if n.is_nan() => None,
+        v => Some(v),
+    }
+}
+
+
+// arithmetic stays in integers only when both operands are integers, or
+// strings that read as integers: 1.0 + 1 is 2.0, and -0.0 keeps its sign
+fn integer_operand(v: &LuaValue) -> Option<i64> {
+    match v {
+        LuaValue::Integer(i) => Some(*i),
+        LuaValue::Str(s) => std::str::from_utf8(s).ok()?.trim().parse().ok(),
+        _ => None,
+    }
+}
 
 fn inone(_a: i64, _b: i64) -> i64 {
     0
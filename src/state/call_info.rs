@@ -0,0 +1,16 @@
+use std::rc::Rc;
+
+use super::closure::Closure;
+use super::lua_value::LuaValue;
+
+// a function being run, as in ldo.c
+pub struct CallInfo {
+    pub closure: Rc<Closure>,
+    pub pc: isize,
+    // stack slot of register 0; the function itself sits just below
+    pub base: usize,
+    // the arguments beyond the fixed parameters, for `...`
+    pub varargs: Vec<LuaValue>,
+    // how many results the caller expects, or -1 for all of them
+    pub n_results: isize,
+}
@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::binchunk::binary_chunk::Prototype;
+use super::lua_state::LuaState;
+use super::lua_value::LuaValue;
+
+// a function written in Rust: it finds its arguments from index 1 up
+// and leaves its results at the top, giving how many there are
+pub type RustFn = fn(&mut LuaState) -> usize;
+
+// a variable captured by closures: the stack slot of the local while
+// it is in scope, then its last value
+pub enum Upval {
+    Open(usize),
+    Closed(LuaValue),
+}
+
+// a Lua function as it runs: its prototype, shared by every closure
+// made from it, and the variables it captured; or a Rust function,
+// with an empty prototype
+pub struct Closure {
+    pub proto: Rc<Prototype>,
+    pub rust_fn: Option<RustFn>,
+    pub upvals: Vec<Rc<RefCell<Upval>>>,
+}
+
+impl Closure {
+    // a closure whose upvalues are all nil
+    pub fn new(proto: Rc<Prototype>) -> Closure {
+        let upvals = proto.upvalues.iter().map(|_| Rc::new(RefCell::new(Upval::Closed(LuaValue::Nil)))).collect();
+        Closure { proto, rust_fn: None, upvals }
+    }
+
+    pub fn new_rust(f: RustFn) -> Closure {
+        Closure { proto: Rc::new(Prototype::default()), rust_fn: Some(f), upvals: Vec::new() }
+    }
+}
+
+// functions are equal only to themselves
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        ptr::eq(self, other)
+    }
+}
@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 use crate::state::lua_value::LuaValue;
 
 pub fn _eq(a: &LuaValue, b: &LuaValue) -> bool {
@@ -27,7 +29,8 @@ pub fn _eq(a: &LuaValue, b: &LuaValue) -> bool {
             LuaValue::Number(y) => x == y,
             _ => false
         }
-        _ => unimplemented!()
+        LuaValue::Table(x) => matches!(b, LuaValue::Table(y) if Rc::ptr_eq(x, y)),
+        LuaValue::Function(x) => matches!(b, LuaValue::Function(y) if Rc::ptr_eq(x, y)),
     }
 }
 
@@ -4,6 +4,7 @@ use super::lua_value::LuaValue;
 pub struct LuaStack {
     pub slots: Vec<LuaValue>,
     pub top: usize,  // equal to the length of vector
+    pub base: usize, // slot of register 0 of the running function, where index 1 points
 }
 
 impl LuaStack {
@@ -11,6 +12,7 @@ impl LuaStack {
         LuaStack {
             slots: Vec::with_capacity(size),
             top: 0,
+            base: 0,
         }
     }
 
@@ -19,7 +21,7 @@ impl LuaStack {
     }
 
     pub fn set_top(&mut self, idx: isize) {
-        let new_top = if idx<0 {self.abs_index(idx).unwrap() + 1} else {idx as usize}; // if start with 0, it may change
+        let new_top = if idx<0 {self.abs_index(idx).unwrap() + 1} else {self.base + idx as usize};
 
         for _ in new_top..self.top {
             self.pop();
@@ -40,10 +42,10 @@ impl LuaStack {
     }
 
     pub fn abs_index(&self, idx: isize) -> Option<usize> {
-        // parameter index start with 1 => return index start with 0
-        if idx >= 0 && (idx as usize - 1) < self.top {
-            Some(idx as usize - 1)
-        } else if (idx + self.top as isize) >= 0 {
+        // parameter index start with 1 at the base => return slot index
+        if idx > 0 && self.base + (idx as usize - 1) < self.top {
+            Some(self.base + idx as usize - 1)
+        } else if idx < 0 && (idx + self.top as isize) >= self.base as isize {
             Some((idx + self.top as isize) as usize)
         } else {
             None
@@ -63,13 +65,9 @@ impl LuaStack {
         self.slots[i] = val;
     }
 
-    pub fn reverse(&mut self, from: isize, to: isize) {
-        let mut from_abs = self.abs_index(from).unwrap();
-        let mut to_abs = self.abs_index(to).unwrap();
-        while from_abs < to_abs {
-            self.slots.swap(from_abs, to_abs);
-            from_abs += 1;
-            to_abs -= 1;
-        }
+    // removes the slots from `slot` up
+    pub fn split_off(&mut self, slot: usize) -> Vec<LuaValue> {
+        self.top = slot;
+        self.slots.split_off(slot)
     }
 }
@@ -1,4 +1,6 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 
 use crate::api::consts;
@@ -6,36 +8,295 @@ use crate::api::lua_state::LuaAPI;
 use crate::api::lua_vm::LuaVM;
 use crate::binchunk::binary_chunk::Constant;
 use crate::binchunk::binary_chunk::Prototype;
+use crate::compiler::syntax_error::chunk_id;
+use crate::vm::instruction::Instruction;
+use super::call_info::CallInfo;
+use super::closure::{Closure, RustFn, Upval};
 use super::lua_stack::LuaStack;
 use super::lua_table::LuaTable;
-use super::lua_value::LuaValue;
+use super::lua_value::{number_to_string, LuaValue};
 use super::arith_ops::*;
 use super::compare_ops::*;
 
+// as LUAI_MAXSTACK, the most slots the stack may grow to
+const LUAI_MAXSTACK: usize = 1_000_000;
+// as LUAI_MAXCCALLS, the most Rust functions that may be running at
+// once, each of them deeper in the Rust stack
+const LUAI_MAXCCALLS: usize = 200;
+
+// the payload of the panic unwinding from error(); the value thrown
+// waits in the state, values not being Send
+struct Thrown;
+
 // #[derive(Debug)]
 pub struct LuaState {
     stack: LuaStack,
-    proto: Prototype,
-    pc: isize
+    // the functions being run, the innermost last
+    frames: Vec<CallInfo>,
+    // the upvalues still pointing into the stack, by slot, shared by
+    // every closure capturing the same local
+    open_upvals: BTreeMap<usize, Rc<RefCell<Upval>>>,
+    // the table of the globals, the _ENV of every main function loaded
+    globals: Rc<RefCell<LuaTable>>,
+    // the value error() is unwinding with
+    thrown: Option<LuaValue>,
+    // how many of the frames are of Rust functions
+    n_ccalls: usize,
+    // the slots of the to-be-closed variables in scope, in the order
+    // they were marked
+    tbc_slots: Vec<usize>,
+    // the metatable shared by all strings
+    string_mt: Option<Rc<RefCell<LuaTable>>>,
 }
 
 impl LuaState {
-    pub fn new(stack_size: usize, proto: Prototype) -> LuaState {
-        LuaState { 
+    // a state with no function running and no globals yet
+    pub fn empty(stack_size: usize) -> LuaState {
+        LuaState {
             stack: LuaStack::new(stack_size),
-            proto,
-            pc: 0 
+            frames: Vec::new(),
+            open_upvals: BTreeMap::new(),
+            globals: Rc::new(RefCell::new(LuaTable::new(0, 0))),
+            thrown: None,
+            n_ccalls: 0,
+            tbc_slots: Vec::new(),
+            string_mt: None,
+        }
+    }
+
+    // a state about to run `proto` as its main function, whose results
+    // take its place
+    pub fn new(stack_size: usize, proto: Prototype) -> LuaState {
+        let mut ls = LuaState::empty(stack_size);
+        ls.load(proto);
+        ls.precall(0, -1);
+        ls
+    }
+
+    // pushes a closure of the main function `proto`, whose only upvalue
+    // is _ENV, the table of the globals
+    pub fn load(&mut self, proto: Prototype) {
+        let closure = Closure::new(Rc::new(proto));
+        if let Some(env) = closure.upvals.first() {
+            *env.borrow_mut() = Upval::Closed(LuaValue::Table(self.globals.clone()));
+        }
+        self.stack.push(LuaValue::Function(Rc::new(closure)));
+    }
+
+    pub fn frames(&self) -> &[CallInfo] {
+        &self.frames
+    }
+
+    fn ci(&self) -> &CallInfo {
+        self.frames.last().expect("no function is running")
+    }
+
+    fn ci_mut(&mut self) -> &mut CallInfo {
+        self.frames.last_mut().expect("no function is running")
+    }
+
+    // enters `closure`, sitting in stack slot `func` with `n_args`
+    // arguments above it: the missing parameters become nil and the
+    // extra arguments go to the varargs, or away
+    pub fn push_frame(&mut self, closure: Rc<Closure>, func: usize, n_args: usize, n_results: isize) {
+        let base = func + 1;
+        let proto = &closure.proto;
+        let n_params = proto.num_params as usize;
+        let mut varargs = Vec::new();
+        // whatever the caller left above the arguments is not for the callee
+        if base + n_args < self.stack.top {
+            self.stack.split_off(base + n_args);
+        }
+        if n_args > n_params {
+            varargs = self.stack.split_off(base + n_params);
+            if proto.is_vararg == 0 {
+                varargs.clear();
+            }
+        }
+        if base + proto.max_stack_size as usize > LUAI_MAXSTACK {
+            panic!("stack overflow");
+        }
+        self.stack.base = base;
+        self.stack.set_top(proto.max_stack_size as isize);
+        self.frames.push(CallInfo { closure, pc: 0, base, varargs, n_results });
+    }
+
+    // runs the Rust function in stack slot `func` with the arguments
+    // above it, then leaves it with the results it gave
+    fn call_rust(&mut self, closure: Rc<Closure>, func: usize, n_results: isize) {
+        if self.n_ccalls >= LUAI_MAXCCALLS {
+            panic!("stack overflow");
+        }
+        let f = closure.rust_fn.expect("not a Rust function");
+        self.n_ccalls += 1;
+        self.stack.base = func + 1;
+        self.frames.push(CallInfo { closure, pc: 0, base: func + 1, varargs: Vec::new(), n_results });
+        let n = f(self);
+        self.n_ccalls -= 1;
+        self.pop_frame(-(n as isize), n);
+    }
+
+    // leaves the running function with the `n` values from index `idx`
+    // up as its results, which take the place of the function on the
+    // stack; the caller gets as many as it expects and its registers
+    // back, unless it takes all of them and the top with them
+    pub fn pop_frame(&mut self, idx: isize, n: usize) {
+        let first = self.stack.abs_index(idx).unwrap_or(self.stack.top);
+        let mut results: Vec<LuaValue> = self.stack.slots[first..(first + n).min(self.stack.top)].to_vec();
+        results.resize(n, LuaValue::Nil);
+        let ci = self.frames.pop().expect("no function is running");
+        if ci.n_results >= 0 {
+            results.resize(ci.n_results as usize, LuaValue::Nil);
+        }
+        self.stack.split_off(ci.base - 1);
+        for val in results {
+            self.stack.push(val);
+        }
+        if let Some(caller) = self.frames.last() {
+            self.stack.base = caller.base;
+            if ci.n_results >= 0 {
+                let size = self.register_count().max(self.get_top());
+                self.stack.set_top(size as isize);
+            }
+        } else {
+            self.stack.base = 0;
+        }
+    }
+
+    // runs the running function, and those it calls, until it returns
+    pub fn run(&mut self) {
+        let depth = self.frames.len();
+        while self.frames.len() >= depth {
+            let i = self.fetch();
+            i.execute(self);
+        }
+    }
+
+    // "chunk:line: " for the function `level` frames below the running
+    // one, as luaL_where gives; empty for Rust functions
+    pub fn position(&self, level: usize) -> String {
+        let ci = match self.frames.len().checked_sub(level + 1) {
+            Some(i) => &self.frames[i],
+            None => return String::new(),
+        };
+        let proto = &ci.closure.proto;
+        // the instruction running is the last one fetched
+        match (ci.pc as usize).checked_sub(1).and_then(|pc| proto.line_info.get(pc)) {
+            Some(line) if ci.closure.rust_fn.is_none() => {
+                let name = if proto.source.is_empty() { String::from("?") } else { chunk_id(&proto.source) };
+                format!("{}:{}: ", name, line)
+            }
+            _ => String::new(),
+        }
+    }
+
+    // pushes `t[k]`, giving its type; strings are indexed through the
+    // __index table of their metatable, where the string library is
+    fn index_get(&mut self, t: &LuaValue, k: &LuaValue) -> i8 {
+        let tbl = match t {
+            LuaValue::Table(tbl) => Some(tbl.clone()),
+            LuaValue::Str(_) => match self.string_mt.as_ref().map(|mt| mt.borrow().get(&LuaValue::Str(b"__index".to_vec()))) {
+                Some(LuaValue::Table(tbl)) => Some(tbl),
+                _ => None,
+            },
+            _ => None,
+        };
+        let tbl = match tbl {
+            Some(tbl) => tbl,
+            None => panic!("attempt to index a {} value", self.type_name(t.ty())),
+        };
+        let v = tbl.borrow().get(k);
+        let vty = v.ty();
+        self.stack.push(v);
+        vty
+    }
+
+    // raises an error with `val` as its value, which pcall gives back
+    pub fn throw(&mut self, val: LuaValue) -> ! {
+        self.thrown = Some(val);
+        panic::resume_unwind(Box::new(Thrown));
+    }
+
+    // the value of an error caught as a panic: what error() threw, or
+    // the message of a panic of the VM with where it happened first,
+    // which for Rust functions is where they were called
+    pub fn caught(&mut self, payload: Box<dyn std::any::Any + Send>) -> LuaValue {
+        if payload.is::<Thrown>() {
+            return self.thrown.take().unwrap_or(LuaValue::Nil);
+        }
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(e) => e.downcast_ref::<&str>().map_or(String::from("unknown error"), |msg| msg.to_string()),
+        };
+        let level = match self.frames.last() {
+            Some(ci) if ci.closure.rust_fn.is_some() => 1,
+            _ => 0,
+        };
+        LuaValue::Str(format!("{}{}", self.position(level), msg).into_bytes())
+    }
+
+    // gives up the functions entered after the first `depth` ones, and
+    // the stack from `slot` up: its upvalues get closed, then its
+    // to-be-closed variables with `err`, which an error while closing
+    // one replaces; gives the error in the end
+    pub fn unwind(&mut self, depth: usize, slot: usize, mut err: LuaValue) -> LuaValue {
+        self.frames.truncate(depth);
+        self.n_ccalls = self.frames.iter().filter(|ci| ci.closure.rust_fn.is_some()).count();
+        self.stack.base = self.frames.last().map_or(0, |ci| ci.base);
+        self.close_upvals(slot);
+        while let Some(tbc) = self.tbc_slots.pop_if(|tbc| *tbc >= slot) {
+            let top = self.stack.top;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.call_close(tbc, err.clone())));
+            if let Err(payload) = result {
+                let e = self.caught(payload);
+                err = self.unwind(depth, top, e);
+            }
+        }
+        if slot < self.stack.top {
+            self.stack.split_off(slot);
+        }
+        err
+    }
+
+    // calls the __close metamethod of the variable in stack slot `slot`
+    // with the value and `err`, leaving the stack as it was
+    fn call_close(&mut self, slot: usize, err: LuaValue) {
+        let val = self.stack.slots[slot].clone();
+        let top = self.stack.top;
+        self.stack.push(metamethod(&val, "__close"));
+        self.stack.push(val);
+        self.stack.push(err);
+        self.call(2, 0);
+        self.stack.split_off(top);
+    }
+
+    // the upvalues pointing to slots from `level` up keep their values
+    // from now on
+    fn close_upvals(&mut self, level: usize) {
+        for (slot, upval) in self.open_upvals.split_off(&level) {
+            *upval.borrow_mut() = Upval::Closed(self.stack.slots[slot].clone());
         }
     }
+
+    // the name of the local in register `idx` at the current instruction,
+    // found through the debug info
+    fn local_name(&self, idx: isize) -> Option<&str> {
+        let ci = self.ci();
+        let pc = (ci.pc - 1) as u32;
+        ci.closure.proto.loc_vars.iter()
+            .filter(|v| v.start_pc <= pc && pc < v.end_pc)
+            .nth(idx as usize - 1)
+            .map(|v| v.var_name.as_str())
+    }
 }
 
 impl LuaAPI for LuaState {
     fn get_top(&self) -> usize {
-        self.stack.top
+        self.stack.top - self.stack.base
     }
 
     fn abs_index(&self, idx: isize) -> usize {
-        self.stack.abs_index(idx).unwrap()
+        self.stack.abs_index(idx).unwrap() - self.stack.base + 1
     }
 
     fn check_stack(&mut self, _n: isize) -> bool {
@@ -72,11 +333,16 @@ impl LuaAPI for LuaState {
         self.pop(1);
     }
 
+    // rotates the values from `idx` up `n` places toward the top, or
+    // away from it when negative
     fn rotate(&mut self, idx: isize, n: isize) {
-        let m = if n >= 0 { -n } else { idx-n };
-        self.stack.reverse(idx, m-1);
-        self.stack.reverse(m, -1);
-        self.stack.reverse(idx, -1);
+        let first = self.stack.abs_index(idx).unwrap();
+        let slots = &mut self.stack.slots[first..self.stack.top];
+        if n >= 0 {
+            slots.rotate_right(n as usize);
+        } else {
+            slots.rotate_left(n.unsigned_abs());
+        }
     }
 
     fn set_top(&mut self, idx: isize) {
@@ -188,14 +454,31 @@ impl LuaAPI for LuaState {
     }
 
     fn to_stringx(&self, idx: isize) -> Option<String> {
+        self.to_bytesx(idx).map(|s| String::from_utf8_lossy(&s).into_owned())
+    }
+
+    fn to_bytes(&self, idx: isize) -> Vec<u8> {
+        self.to_bytesx(idx).unwrap()
+    }
+
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>> {
         match self.stack.get(idx).unwrap() {
             LuaValue::Str(s) => Some(s),
-            LuaValue::Number(n) => Some(n.to_string()),
-            LuaValue::Integer(i) => Some(i.to_string()),
+            LuaValue::Number(n) => Some(number_to_string(n).into_bytes()),
+            LuaValue::Integer(i) => Some(i.to_string().into_bytes()),
             _ => None,
         }
     }
 
+    // identifies a table, as lua_topointer does; 0 for other values
+    fn to_pointer(&self, idx: isize) -> usize {
+        match self.stack.get(idx).unwrap() {
+            LuaValue::Table(t) => Rc::as_ptr(&t) as usize,
+            LuaValue::Function(f) => Rc::as_ptr(&f) as usize,
+            _ => 0,
+        }
+    }
+
     fn push_nil(&mut self) {
         self.stack.push(LuaValue::Nil);
     }
@@ -213,6 +496,10 @@ impl LuaAPI for LuaState {
     }
 
     fn push_string(&mut self, s: String) {
+        self.stack.push(LuaValue::Str(s.into_bytes()));
+    }
+
+    fn push_bytes(&mut self, s: Vec<u8>) {
         self.stack.push(LuaValue::Str(s));
     }
 
@@ -254,13 +541,13 @@ impl LuaAPI for LuaState {
 
     fn concat(&mut self, n: isize) {
         if n == 0 {
-            self.stack.push(LuaValue::Str(String::new()));
+            self.stack.push(LuaValue::Str(Vec::new()));
         } else if n >= 2 {
             for _ in 1..n {
                 if self.is_string(-1) && self.is_string(-2) {
-                    let s2: String = self.to_string(-1);
-                    let mut s1 = self.to_string(-2);
-                    s1.push_str(&s2);
+                    let s2 = self.to_bytes(-1);
+                    let mut s1 = self.to_bytes(-2);
+                    s1.extend_from_slice(&s2);
                     self.stack.pop();
                     self.stack.pop();
                     self.stack.push(LuaValue::Str(s1));
@@ -283,36 +570,21 @@ impl LuaAPI for LuaState {
     fn get_table(&mut self, idx: isize) -> i8 {
         if let Some(t) = self.stack.get(idx) {
             let k = self.stack.pop();
-            if let LuaValue::Table(tbl) = t {
-                let v = tbl.borrow().get(&k);
-                let vty = v.ty();
-                self.stack.push(v);
-                return vty;
-            } 
+            return self.index_get(&t, &k);
         }
         panic!()
     }
 
     fn get_field(&mut self, idx: isize, k: &str) -> i8 {
         if let Some(t) = self.stack.get(idx) {
-            if let LuaValue::Table(tbl) = t {
-                let v = tbl.borrow().get(&LuaValue::Str(k.to_string()));
-                let vty = v.ty();
-                self.stack.push(v);
-                return vty;
-            } 
+            return self.index_get(&t, &LuaValue::Str(k.as_bytes().to_vec()));
         }
         panic!()
     }
 
     fn get_i(&mut self, idx: isize, i: i64) -> i8 {
         if let Some(t) = self.stack.get(idx) {
-            if let LuaValue::Table(tbl) = t {
-                let v = tbl.borrow().get(&LuaValue::Integer(i));
-                let vty = v.ty();
-                self.stack.push(v);
-                return vty;
-            } 
+            return self.index_get(&t, &LuaValue::Integer(i));
         }
         panic!()
     }
@@ -325,6 +597,7 @@ impl LuaAPI for LuaState {
                 tbl.borrow_mut().put(&k, &v);
                 return;
             }
+            panic!("attempt to index a {} value", self.type_name(t.ty()));
         }
         panic!()
     }
@@ -333,7 +606,7 @@ impl LuaAPI for LuaState {
         if let Some(t) = self.stack.get(idx) {
             let v = self.stack.pop();
             if let LuaValue::Table(tbl) = t {
-                tbl.borrow_mut().put(&LuaValue::Str(k.to_string()), &v);
+                tbl.borrow_mut().put(&LuaValue::Str(k.as_bytes().to_vec()), &v);
                 return;
             }
         }
@@ -349,27 +622,132 @@ impl LuaAPI for LuaState {
         }
     }
 
+    fn call(&mut self, n_args: usize, n_results: isize) {
+        let depth = self.frames.len();
+        self.precall(n_args, n_results);
+        // Rust functions are done already
+        if self.frames.len() > depth {
+            self.run();
+        }
+    }
+
+    fn pcall(&mut self, n_args: usize, n_results: isize) -> bool {
+        let depth = self.frames.len();
+        let slot = self.stack.top - n_args - 1;
+        match panic::catch_unwind(AssertUnwindSafe(|| self.call(n_args, n_results))) {
+            Ok(()) => true,
+            Err(payload) => {
+                let err = self.caught(payload);
+                let err = self.unwind(depth, slot, err);
+                self.stack.push(err);
+                false
+            }
+        }
+    }
+
+    fn error(&mut self) -> ! {
+        let err = self.stack.pop();
+        self.throw(err)
+    }
+
+    fn push_rust_function(&mut self, f: RustFn) {
+        self.stack.push(LuaValue::Function(Rc::new(Closure::new_rust(f))));
+    }
+
+    fn push_global_table(&mut self) {
+        self.stack.push(LuaValue::Table(self.globals.clone()));
+    }
+
+    fn get_global(&mut self, name: &str) -> i8 {
+        let val = self.globals.borrow().get(&LuaValue::Str(name.as_bytes().to_vec()));
+        let ty = val.ty();
+        self.stack.push(val);
+        ty
+    }
+
+    fn set_global(&mut self, name: &str) {
+        let val = self.stack.pop();
+        self.globals.borrow_mut().put(&LuaValue::Str(name.as_bytes().to_vec()), &val);
+    }
+
+    fn register(&mut self, name: &str, f: RustFn) {
+        self.push_rust_function(f);
+        self.set_global(name);
+    }
+
+    fn get_metatable(&mut self, idx: isize) -> bool {
+        let mt = match self.stack.get(idx) {
+            Some(LuaValue::Table(t)) => t.borrow().metatable.clone(),
+            Some(LuaValue::Str(_)) => self.string_mt.clone(),
+            _ => None,
+        };
+        match mt {
+            Some(mt) => {
+                self.stack.push(LuaValue::Table(mt));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_metatable(&mut self, idx: isize) {
+        // `idx` counts with the metatable still on top
+        let idx = self.abs_index(idx) as isize;
+        let mt = match self.stack.pop() {
+            LuaValue::Table(mt) => Some(mt),
+            LuaValue::Nil => None,
+            _ => panic!("table expected"),
+        };
+        match self.stack.get(idx) {
+            Some(LuaValue::Table(t)) => t.borrow_mut().metatable = mt,
+            Some(LuaValue::Str(_)) => self.string_mt = mt,
+            _ => panic!("table expected"),
+        }
+    }
 
+    fn next(&mut self, idx: isize) -> bool {
+        let t = self.stack.get(idx);
+        let key = self.stack.pop();
+        let entry = match t {
+            Some(LuaValue::Table(t)) => t.borrow_mut().next(&key),
+            _ => panic!("table expected"),
+        };
+        match entry {
+            Some((key, val)) => {
+                self.stack.push(key);
+                self.stack.push(val);
+                true
+            }
+            None => false,
+        }
+    }
 
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool {
+        match (self.stack.get(idx1), self.stack.get(idx2)) {
+            (Some(a), Some(b)) => _eq(&a, &b),
+            _ => false,
+        }
+    }
 }
 
 impl LuaVM for LuaState {
     fn pc(&self) -> isize {
-        self.pc
+        self.ci().pc
     }
 
     fn add_pc(&mut self, n: isize) {
-        self.pc += n;
+        self.ci_mut().pc += n;
     }
 
     fn fetch(&mut self) -> u32 {
-        let instr = self.proto.code[self.pc as usize];
-        self.pc += 1;
+        let ci = self.ci_mut();
+        let instr = ci.closure.proto.code[ci.pc as usize];
+        ci.pc += 1;
         instr
     }
 
     fn get_const(&mut self, idx: isize) {
-        let c = &self.proto.constants[idx as usize];
+        let c = &self.ci().closure.proto.constants[idx as usize];
         let val = match c {
             Constant::Nil => LuaValue::Nil,
             Constant::Boolean(b) => LuaValue::Boolean(*b),
@@ -387,4 +765,127 @@ impl LuaVM for LuaState {
             self.push_value(rk + 1);
         }
     }
+
+    // as in Lua 5.4, nil and false need no closing; any other value
+    // needs a __close metamethod
+    fn mark_to_be_closed(&mut self, idx: isize) {
+        let val = self.stack.get(idx).unwrap();
+        if !val._to_boolean() {
+            return;
+        }
+        if metamethod(&val, "__close").is_nil() {
+            let name = self.local_name(idx).unwrap_or("?");
+            let msg = format!("{}variable '{}' got a non-closable value", self.position(0), name);
+            self.throw(LuaValue::Str(msg.into_bytes()));
+        }
+        self.tbc_slots.push(self.stack.abs_index(idx).unwrap());
+    }
+
+    // registers from `idx` up go out of scope: the upvalues pointing to
+    // them keep their values from now on, and the variables among them
+    // marked to be closed get closed, the last marked first
+    fn close(&mut self, idx: isize) {
+        let level = self.stack.base + idx as usize - 1;
+        self.close_upvals(level);
+        while let Some(tbc) = self.tbc_slots.pop_if(|tbc| *tbc >= level) {
+            self.call_close(tbc, LuaValue::Nil);
+        }
+    }
+
+    fn load_vararg(&mut self, idx: isize, n: isize) {
+        let varargs = self.ci().varargs.clone();
+        let n = if n < 0 {
+            self.set_top(idx - 1 + varargs.len() as isize);
+            varargs.len()
+        } else {
+            n as usize
+        };
+        for i in 0..n {
+            let val = varargs.get(i).cloned().unwrap_or(LuaValue::Nil);
+            self.stack.set(idx + i as isize, val);
+        }
+    }
+
+    fn post_call(&mut self, idx: isize, n: usize) {
+        self.pop_frame(idx, n);
+    }
+
+    fn register_count(&self) -> usize {
+        self.ci().closure.proto.max_stack_size as usize
+    }
+
+    fn precall(&mut self, n_args: usize, n_results: isize) {
+        let func = self.stack.top - n_args - 1;
+        match self.stack.slots[func].clone() {
+            LuaValue::Function(closure) if closure.rust_fn.is_some() => self.call_rust(closure, func, n_results),
+            LuaValue::Function(closure) => self.push_frame(closure, func, n_args, n_results),
+            val => panic!("attempt to call a {} value", self.type_name(val.ty())),
+        }
+    }
+
+    // the running function makes way for the one at `idx` with the
+    // `n_args` values above it, and its results go to its caller
+    fn tail_call(&mut self, idx: isize, n_args: usize) {
+        let first = self.stack.abs_index(idx).unwrap();
+        // Rust functions run right away, as in lvm.c, so that errors
+        // they raise still have the position of the caller
+        if matches!(&self.stack.slots[first], LuaValue::Function(f) if f.rust_fn.is_some()) {
+            self.stack.split_off(first + n_args + 1);
+            self.precall(n_args, -1);
+            let n = self.stack.top - first;
+            self.pop_frame(idx, n);
+            return;
+        }
+        let call = self.stack.split_off(first).into_iter().take(n_args + 1);
+        let ci = self.frames.pop().expect("no function is running");
+        self.stack.split_off(ci.base - 1);
+        call.for_each(|val| self.stack.push(val));
+        self.precall(n_args, ci.n_results);
+    }
+
+    fn get_upval(&mut self, idx: isize) {
+        let upval = self.ci().closure.upvals[idx as usize].clone();
+        let val = match &*upval.borrow() {
+            Upval::Open(slot) => self.stack.slots[*slot].clone(),
+            Upval::Closed(val) => val.clone(),
+        };
+        self.stack.push(val);
+    }
+
+    fn set_upval(&mut self, idx: isize) {
+        let val = self.stack.pop();
+        let upval = self.ci().closure.upvals[idx as usize].clone();
+        match &mut *upval.borrow_mut() {
+            Upval::Open(slot) => self.stack.slots[*slot] = val,
+            Upval::Closed(v) => *v = val,
+        };
+    }
+
+    fn load_proto(&mut self, idx: isize, bx: usize) {
+        let ci = self.frames.last().expect("no function is running");
+        let proto = ci.closure.proto.protos[bx].clone();
+        let upvals = proto.upvalues.iter().map(|uv| match uv.instack {
+            // a local of the running function, shared with the closures
+            // that captured it already
+            1 => {
+                let slot = ci.base + uv.idx as usize;
+                let upval = self.open_upvals.entry(slot).or_insert_with(|| Rc::new(RefCell::new(Upval::Open(slot))));
+                upval.clone()
+            }
+            _ => ci.closure.upvals[uv.idx as usize].clone(),
+        });
+        let closure = Closure { upvals: upvals.collect(), rust_fn: None, proto };
+        self.stack.set(idx, LuaValue::Function(Rc::new(closure)));
+    }
+}
+
+// field `event` of the metatable of `val`, or nil
+fn metamethod(val: &LuaValue, event: &str) -> LuaValue {
+    match val {
+        LuaValue::Table(t) => match &t.borrow().metatable {
+            Some(mt) => mt.borrow().get(&LuaValue::Str(event.as_bytes().to_vec())),
+            None => LuaValue::Nil,
+        },
+        _ => LuaValue::Nil,
+    }
 }
@@ -1,22 +1,42 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ptr;
+use std::rc::Rc;
 
 use super::lua_value::LuaValue;
 
-#[derive(PartialEq, Clone)]
+#[derive(Clone)]
 pub struct LuaTable {
     arr: Vec<LuaValue>,
-    map: HashMap<LuaValue, LuaValue>
+    map: HashMap<LuaValue, LuaValue>,
+    pub metatable: Option<Rc<RefCell<LuaTable>>>,
+    // the keys of the hash part in the order next() goes through them,
+    // and where each one is; built by the first next() into the hash
+    // part, dropped when a key gets added
+    keys: Vec<LuaValue>,
+    key_pos: HashMap<LuaValue, usize>,
 }
 
 impl LuaTable {
     pub fn new(n_arr: usize, n_rec: usize) -> LuaTable {
-        LuaTable { arr: Vec::with_capacity(n_arr), map: HashMap::with_capacity(n_rec) }
+        LuaTable {
+            arr: Vec::with_capacity(n_arr),
+            map: HashMap::with_capacity(n_rec),
+            metatable: None,
+            keys: Vec::new(),
+            key_pos: HashMap::new(),
+        }
     }
 
     pub fn len(&self) -> usize {
         self.arr.len()
     }
 
+    // whether len() is 0; the hash part may still hold keys
+    pub fn is_empty(&self) -> bool {
+        self.arr.is_empty()
+    }
+
     pub fn get(&self, key: &LuaValue) -> LuaValue {
         if let Some(idx) = to_index(key) {
             if idx <= self.arr.len() {
@@ -61,12 +81,41 @@ impl LuaTable {
         }
 
         if !val.is_nil() {
-            self.map.insert(key.clone(), val.clone());
+            if self.map.insert(key.clone(), val.clone()).is_none() {
+                self.keys.clear();
+                self.key_pos.clear();
+            }
         } else {
             self.map.remove(&key);
         }
     }
 
+    // the entry after `key` in a traversal, the array part first, or the
+    // first one for nil; None when there are no more. Keys may be
+    // cleared while going through them.
+    pub fn next(&mut self, key: &LuaValue) -> Option<(LuaValue, LuaValue)> {
+        if self.keys.is_empty() {
+            self.keys = self.map.keys().cloned().collect();
+            self.key_pos = self.keys.iter().enumerate().map(|(i, k)| (k.clone(), i)).collect();
+        }
+        let from = match self.key_pos.get(key) {
+            Some(pos) => pos + 1,
+            None => {
+                // a slot cleared at the end of the array is no longer in it
+                let idx = match key {
+                    LuaValue::Nil => 0,
+                    _ => to_index(key).expect("invalid key to 'next'"),
+                };
+                let mut arr = self.arr.iter().enumerate().skip(idx);
+                if let Some((i, val)) = arr.find(|(_, val)| !val.is_nil()) {
+                    return Some((LuaValue::Integer(i as i64 + 1), val.clone()));
+                }
+                0
+            }
+        };
+        self.keys[from..].iter().find_map(|key| self.map.get(key).map(|val| (key.clone(), val.clone())))
+    }
+
     pub fn shrink_array(&mut self) {
         while !self.arr.is_empty() {
             if self.arr.last().unwrap().is_nil() {
@@ -92,6 +141,13 @@ impl LuaTable {
     }
 }
 
+// tables are equal only to themselves
+impl PartialEq for LuaTable {
+    fn eq(&self, other: &LuaTable) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
 pub fn float_to_integer(n: f64) -> Option<i64> {
     let i = n as i64;
     if i as f64 == n {
@@ -5,6 +5,7 @@ use std::ptr;
 use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
+use super::closure::Closure;
 use super::lua_table::LuaTable;
 
 #[derive(Clone, PartialEq)]
@@ -13,8 +14,9 @@ pub enum LuaValue {
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String),
-    Table(Rc<RefCell<LuaTable>>)
+    Str(Vec<u8>),
+    Table(Rc<RefCell<LuaTable>>),
+    Function(Rc<Closure>),
 }
 
 impl LuaValue {
@@ -25,7 +27,8 @@ impl LuaValue {
             LuaValue::Integer(_) => consts::LUA_TNUMBER,
             LuaValue::Number(_) => consts::LUA_TNUMBER,
             LuaValue::Str(_) => consts::LUA_TSTRING,
-            LuaValue::Table(_) => consts::LUA_TTABLE
+            LuaValue::Table(_) => consts::LUA_TTABLE,
+            LuaValue::Function(_) => consts::LUA_TFUNCTION,
         }
     }
 
@@ -41,7 +44,7 @@ impl LuaValue {
         match self {
             LuaValue::Integer(i) => Some(*i as f64),
             LuaValue::Number(n) => Some(*n),
-            LuaValue::Str(s) => s.parse::<f64>().ok(), // TODO
+            LuaValue::Str(s) => std::str::from_utf8(s).ok()?.parse::<f64>().ok(), // TODO
             _ => None,
         }
     }
@@ -63,6 +66,49 @@ impl LuaValue {
     }
 }
 
+// converts a float as lua_Number2str does, with "%.14g", adding ".0"
+// when the result would read back as an integer
+pub fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        return String::from(if n.is_sign_negative() { "-nan" } else { "nan" });
+    }
+    if n.is_infinite() {
+        return String::from(if n < 0.0 { "-inf" } else { "inf" });
+    }
+    let s = format_g14(n);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+// "%.14g" for finite floats: 14 significant digits, in exponent form
+// when the exponent is below -4 or not below 14, without trailing zeros
+fn format_g14(n: f64) -> String {
+    const PRECISION: i32 = 14;
+    if n == 0.0 {
+        return String::from(if n.is_sign_negative() { "-0" } else { "0" });
+    }
+    // the exponent after rounding to the precision
+    let sci = format!("{:.*e}", PRECISION as usize - 1, n);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let trim = |s: &str| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s.to_string()
+        }
+    };
+    if !(-4..PRECISION).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exp.abs())
+    } else {
+        trim(&format!("{:.*}", (PRECISION - 1 - exp) as usize, n))
+    }
+}
+
 fn float_to_integer(n: f64) -> Option<i64> {
     let i = n as i64;
     if i as f64 == n {
@@ -72,7 +118,8 @@ fn float_to_integer(n: f64) -> Option<i64> {
     }
 }
 
-fn string_to_integer(s: &String) -> Option<i64> {
+fn string_to_integer(s: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(s).ok()?;
     if let Ok(i) = s.parse::<i64>() {
         Some(i)
     } else if let Ok(n) = s.parse::<f64>() {
@@ -115,6 +162,7 @@ impl Hash for LuaValue {
             LuaValue::Number(n) => n.to_bits().hash(state),
             LuaValue::Str(s) => s.hash(state),
             LuaValue::Table(t) => ptr::hash(t, state),
+            LuaValue::Function(f) => ptr::hash(Rc::as_ptr(f), state),
         }
     }
 }
@@ -126,8 +174,9 @@ impl fmt::Debug for LuaValue {
             LuaValue::Boolean(b) => write!(f, "({})", b),
             LuaValue::Integer(i) => write!(f, "({})", i),
             LuaValue::Number(n) => write!(f, "({})", n),
-            LuaValue::Str(s) => write!(f, "({})", s),
+            LuaValue::Str(s) => write!(f, "({})", String::from_utf8_lossy(s)),
             LuaValue::Table(_) => write!(f, "(table)"),
+            LuaValue::Function(_) => write!(f, "(function)"),
         }
     }
 }
@@ -1,6 +1,8 @@
-mod lua_value;
+pub mod lua_value;
 mod lua_stack;
 pub mod lua_state;
 pub mod arith_ops;
 pub mod compare_ops;
-pub mod lua_table;
\ No newline at end of file
+pub mod lua_table;
+pub mod closure;
+pub mod call_info;
@@ -0,0 +1,156 @@
+use std::rc::Rc;
+
+use crate::api::consts::*;
+use crate::api::lua_state::LuaAPI;
+use crate::state::closure::RustFn;
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+
+// raises `msg` as an error of the function calling the running one,
+// as luaL_error does
+pub fn error(ls: &mut LuaState, msg: &str) -> ! {
+    let msg = format!("{}{}", ls.position(1), msg);
+    ls.throw(LuaValue::Str(msg.into_bytes()))
+}
+
+// raises an error about argument `arg` of the running function, named
+// after the global holding it
+pub fn arg_error(ls: &mut LuaState, arg: isize, extra_msg: &str) -> ! {
+    let msg = format!("bad argument #{} to '{}' ({})", arg, function_name(ls), extra_msg);
+    error(ls, &msg)
+}
+
+pub fn type_error(ls: &mut LuaState, arg: isize, expected: &str) -> ! {
+    let found = String::from(ls.type_name(ls.type_id(arg)));
+    arg_error(ls, arg, &format!("{} expected, got {}", expected, found))
+}
+
+// the global whose value is the running function, or the field of a
+// global table such as `string` holding it, or "?"
+fn function_name(ls: &mut LuaState) -> String {
+    let running = match ls.frames().last() {
+        Some(ci) => Rc::as_ptr(&ci.closure) as usize,
+        None => return String::from("?"),
+    };
+    ls.push_global_table();
+    let mut name = field_name(ls, running);
+    if name.is_none() {
+        ls.push_nil();
+        while ls.next(-2) {
+            if ls.is_table(-1) && ls.type_id(-2) == LUA_TSTRING {
+                name = field_name(ls, running);
+                if name.is_some() {
+                    ls.pop(2);
+                    break;
+                }
+            }
+            ls.pop(1);
+        }
+    }
+    ls.pop(1);
+    name.unwrap_or_else(|| String::from("?"))
+}
+
+// the key of the table on top whose value is the function `f`
+fn field_name(ls: &mut LuaState, f: usize) -> Option<String> {
+    ls.push_nil();
+    while ls.next(-2) {
+        if ls.is_function(-1) && ls.to_pointer(-1) == f && ls.type_id(-2) == LUA_TSTRING {
+            let name = ls.to_string(-2);
+            ls.pop(2);
+            return Some(name);
+        }
+        ls.pop(1);
+    }
+    None
+}
+
+// pushes a new table with the functions of a library
+pub fn new_lib(ls: &mut LuaState, funcs: &[(&str, RustFn)]) {
+    ls.create_table(0, funcs.len());
+    for &(name, f) in funcs {
+        ls.push_rust_function(f);
+        ls.set_field(-2, name);
+    }
+}
+
+pub fn check_any(ls: &mut LuaState, arg: isize) {
+    if ls.is_none(arg) {
+        arg_error(ls, arg, "value expected");
+    }
+}
+
+pub fn check_type(ls: &mut LuaState, arg: isize, tp: i8) {
+    if ls.type_id(arg) != tp {
+        let expected = String::from(ls.type_name(tp));
+        type_error(ls, arg, &expected);
+    }
+}
+
+pub fn check_integer(ls: &mut LuaState, arg: isize) -> i64 {
+    if ls.is_none(arg) {
+        type_error(ls, arg, "number");
+    }
+    if ls.is_integer(arg) {
+        return ls.to_integer(arg);
+    }
+    match ls.to_numberx(arg) {
+        Some(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < -(i64::MIN as f64) => n as i64,
+        Some(_) => arg_error(ls, arg, "number has no integer representation"),
+        None => type_error(ls, arg, "number"),
+    }
+}
+
+pub fn check_number(ls: &mut LuaState, arg: isize) -> f64 {
+    match ls.to_numberx(arg) {
+        Some(n) => n,
+        None => type_error(ls, arg, "number"),
+    }
+}
+
+pub fn opt_number(ls: &mut LuaState, arg: isize, default: f64) -> f64 {
+    if ls.is_none_or_nil(arg) {
+        default
+    } else {
+        check_number(ls, arg)
+    }
+}
+
+// the bytes of a string argument, or of a number converted to one
+pub fn check_bytes(ls: &mut LuaState, arg: isize) -> Vec<u8> {
+    match ls.type_id(arg) {
+        LUA_TSTRING | LUA_TNUMBER => ls.to_bytes(arg),
+        _ => type_error(ls, arg, "string"),
+    }
+}
+
+pub fn opt_bytes(ls: &mut LuaState, arg: isize, default: &[u8]) -> Vec<u8> {
+    if ls.is_none_or_nil(arg) {
+        default.to_vec()
+    } else {
+        check_bytes(ls, arg)
+    }
+}
+
+pub fn opt_integer(ls: &mut LuaState, arg: isize, default: i64) -> i64 {
+    if ls.is_none_or_nil(arg) {
+        default
+    } else {
+        check_integer(ls, arg)
+    }
+}
+
+// pushes field `event` of the metatable of the value at `idx`, giving
+// its type, or LUA_TNIL with nothing pushed
+pub fn get_metafield(ls: &mut LuaState, idx: isize, event: &str) -> i8 {
+    if !ls.get_metatable(idx) {
+        return LUA_TNIL;
+    }
+    let tp = ls.get_field(-1, event);
+    if tp == LUA_TNIL {
+        ls.pop(2);
+    } else {
+        ls.remove(-2);
+    }
+    tp
+}
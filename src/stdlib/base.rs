@@ -0,0 +1,344 @@
+use std::io::{self, Write};
+
+use crate::api::consts::*;
+use crate::api::lua_state::LuaAPI;
+use crate::compiler::lexer::str_to_number;
+use crate::compiler::token::TokenKind;
+use crate::state::closure::RustFn;
+use crate::state::lua_state::LuaState;
+use super::auxlib::{self, check_any, check_integer, check_type, get_metafield, opt_integer};
+
+// the basic functions, as in lbaselib.c
+const FUNCS: &[(&str, RustFn)] = &[
+    ("assert", assert),
+    ("error", error),
+    ("getmetatable", getmetatable),
+    ("ipairs", ipairs),
+    ("next", next),
+    ("pairs", pairs),
+    ("pcall", pcall),
+    ("print", print),
+    ("rawequal", rawequal),
+    ("rawget", rawget),
+    ("rawlen", rawlen),
+    ("rawset", rawset),
+    ("select", select),
+    ("setmetatable", setmetatable),
+    ("tonumber", tonumber),
+    ("tostring", tostring_),
+    ("type", type_),
+    ("xpcall", xpcall),
+];
+
+pub fn open(ls: &mut LuaState) {
+    for &(name, f) in FUNCS {
+        ls.register(name, f);
+    }
+    ls.push_global_table();
+    ls.set_global("_G");
+    ls.push_string(String::from("Lua 5.3"));
+    ls.set_global("_VERSION");
+}
+
+// converts the value at `idx` as luaL_tolstring does, through its
+// __tostring metamethod when it has one
+pub fn tostring(ls: &mut LuaState, idx: isize) -> Vec<u8> {
+    let idx = ls.abs_index(idx) as isize;
+    if get_metafield(ls, idx, "__tostring") != LUA_TNIL {
+        ls.push_value(idx);
+        ls.call(1, 1);
+        if !ls.is_string(-1) {
+            auxlib::error(ls, "'__tostring' must return a string");
+        }
+        let s = ls.to_bytes(-1);
+        ls.pop(1);
+        return s;
+    }
+    match ls.type_id(idx) {
+        LUA_TNIL => b"nil".to_vec(),
+        LUA_TBOOLEAN => ls.to_boolean(idx).to_string().into_bytes(),
+        LUA_TNUMBER | LUA_TSTRING => ls.to_bytes(idx),
+        tp => format!("{}: 0x{:08x}", ls.type_name(tp), ls.to_pointer(idx)).into_bytes(),
+    }
+}
+
+// print(...)
+fn print(ls: &mut LuaState) -> usize {
+    let mut line = Vec::new();
+    for idx in 1..=ls.get_top() as isize {
+        if idx > 1 {
+            line.push(b'\t');
+        }
+        line.extend(tostring(ls, idx));
+    }
+    line.push(b'\n');
+    let _ = io::stdout().lock().write_all(&line);
+    0
+}
+
+// tostring(v)
+fn tostring_(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    let s = tostring(ls, 1);
+    ls.push_bytes(s);
+    1
+}
+
+// type(v)
+fn type_(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    let name = String::from(ls.type_name(ls.type_id(1)));
+    ls.push_string(name);
+    1
+}
+
+// tonumber(e [, base])
+fn tonumber(ls: &mut LuaState) -> usize {
+    if ls.is_none_or_nil(2) {
+        check_any(ls, 1);
+        match ls.type_id(1) {
+            LUA_TNUMBER => {
+                ls.set_top(1);
+                return 1;
+            }
+            LUA_TSTRING => match string_to_number(&ls.to_bytes(1)) {
+                Some(TokenKind::Integer(i)) => {
+                    ls.push_integer(i);
+                    return 1;
+                }
+                Some(TokenKind::Number(n)) => {
+                    ls.push_number(n);
+                    return 1;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    } else {
+        let base = check_integer(ls, 2);
+        check_type(ls, 1, LUA_TSTRING);
+        if !(2..=36).contains(&base) {
+            auxlib::arg_error(ls, 2, "base out of range");
+        }
+        if let Some(i) = string_to_integer(&ls.to_bytes(1), base as u32) {
+            ls.push_integer(i);
+            return 1;
+        }
+    }
+    ls.push_nil();
+    1
+}
+
+// a numeral with a sign and spaces around it, as lua_stringtonumber takes
+fn string_to_number(s: &[u8]) -> Option<TokenKind> {
+    let s = std::str::from_utf8(s).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    match str_to_number(digits)? {
+        TokenKind::Integer(i) if neg => Some(TokenKind::Integer(i.wrapping_neg())),
+        TokenKind::Number(n) if neg => Some(TokenKind::Number(-n)),
+        numeral => Some(numeral),
+    }
+}
+
+// an integer written in `base`, wrapping around as Lua does
+fn string_to_integer(s: &[u8], base: u32) -> Option<i64> {
+    let s = std::str::from_utf8(s).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        n = n.wrapping_mul(base as i64).wrapping_add(c.to_digit(base)? as i64);
+    }
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+// ipairs(t)
+fn ipairs(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    ls.push_rust_function(ipairs_aux);
+    ls.push_value(1);
+    ls.push_integer(0);
+    3
+}
+
+fn ipairs_aux(ls: &mut LuaState) -> usize {
+    let i = check_integer(ls, 2).wrapping_add(1);
+    ls.push_integer(i);
+    if ls.get_i(1, i) == LUA_TNIL {
+        1
+    } else {
+        2
+    }
+}
+
+// pairs(t)
+fn pairs(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    if get_metafield(ls, 1, "__pairs") == LUA_TNIL {
+        ls.push_rust_function(next);
+        ls.push_value(1);
+        ls.push_nil();
+    } else {
+        ls.push_value(1);
+        ls.call(1, 3);
+    }
+    3
+}
+
+// next(table [, index])
+fn next(ls: &mut LuaState) -> usize {
+    check_type(ls, 1, LUA_TTABLE);
+    ls.set_top(2);
+    if ls.next(1) {
+        2
+    } else {
+        ls.push_nil();
+        1
+    }
+}
+
+// select(n, ...)
+fn select(ls: &mut LuaState) -> usize {
+    let n = ls.get_top() as i64;
+    if ls.type_id(1) == LUA_TSTRING && ls.to_bytes(1) == b"#" {
+        ls.push_integer(n - 1);
+        return 1;
+    }
+    let mut i = check_integer(ls, 1);
+    if i < 0 {
+        i = n.saturating_add(i);
+    } else if i > n {
+        i = n;
+    }
+    if i < 1 {
+        auxlib::arg_error(ls, 1, "index out of range");
+    }
+    (n - i) as usize
+}
+
+// rawequal(v1, v2)
+fn rawequal(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    check_any(ls, 2);
+    let eq = ls.raw_equal(1, 2);
+    ls.push_boolean(eq);
+    1
+}
+
+// rawlen(v)
+fn rawlen(ls: &mut LuaState) -> usize {
+    if !matches!(ls.type_id(1), LUA_TTABLE | LUA_TSTRING) {
+        auxlib::arg_error(ls, 1, "table or string expected");
+    }
+    ls.len(1);
+    1
+}
+
+// rawget(table, index)
+fn rawget(ls: &mut LuaState) -> usize {
+    check_type(ls, 1, LUA_TTABLE);
+    check_any(ls, 2);
+    ls.set_top(2);
+    ls.get_table(1);
+    1
+}
+
+// rawset(table, index, value)
+fn rawset(ls: &mut LuaState) -> usize {
+    check_type(ls, 1, LUA_TTABLE);
+    check_any(ls, 2);
+    check_any(ls, 3);
+    ls.set_top(3);
+    ls.set_table(1);
+    1
+}
+
+// getmetatable(object)
+fn getmetatable(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    if !ls.get_metatable(1) {
+        ls.push_nil();
+        return 1;
+    }
+    // a __metatable field stands in for the metatable
+    if ls.get_field(-1, "__metatable") == LUA_TNIL {
+        ls.pop(1);
+    }
+    1
+}
+
+// setmetatable(table, metatable)
+fn setmetatable(ls: &mut LuaState) -> usize {
+    check_type(ls, 1, LUA_TTABLE);
+    if !matches!(ls.type_id(2), LUA_TNIL | LUA_TTABLE) {
+        auxlib::type_error(ls, 2, "nil or table");
+    }
+    if get_metafield(ls, 1, "__metatable") != LUA_TNIL {
+        auxlib::error(ls, "cannot change a protected metatable");
+    }
+    ls.set_top(2);
+    ls.set_metatable(1);
+    1
+}
+
+// assert(v [, message])
+fn assert(ls: &mut LuaState) -> usize {
+    if !ls.is_none(1) && ls.to_boolean(1) {
+        return ls.get_top();
+    }
+    check_any(ls, 1);
+    ls.remove(1);
+    ls.push_string(String::from("assertion failed!"));
+    ls.set_top(1);
+    error(ls)
+}
+
+// error(message [, level]): strings get the position of the function
+// `level` calls up, the one calling error() by default
+fn error(ls: &mut LuaState) -> usize {
+    let level = opt_integer(ls, 2, 1);
+    ls.set_top(1);
+    if ls.type_id(1) == LUA_TSTRING && level > 0 {
+        let mut msg = ls.position(level as usize).into_bytes();
+        msg.extend(ls.to_bytes(1));
+        ls.pop(1);
+        ls.push_bytes(msg);
+    }
+    ls.error()
+}
+
+// pcall(f, ...)
+fn pcall(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    let ok = ls.pcall(ls.get_top() - 1, -1);
+    ls.push_boolean(ok);
+    ls.insert(1);
+    ls.get_top()
+}
+
+// xpcall(f, msgh, ...): the handler gets the value of an error and
+// gives what to return in its place
+fn xpcall(ls: &mut LuaState) -> usize {
+    check_type(ls, 2, LUA_TFUNCTION);
+    // the handler goes below the function
+    ls.push_value(2);
+    ls.remove(2);
+    ls.insert(1);
+    if ls.pcall(ls.get_top() - 2, -1) {
+        ls.push_boolean(true);
+        ls.replace(1);
+        return ls.get_top();
+    }
+    ls.pcall(1, 1);
+    ls.push_boolean(false);
+    ls.insert(1);
+    2
+}
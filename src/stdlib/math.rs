@@ -0,0 +1,281 @@
+use std::cell::Cell;
+use std::f64::consts::PI;
+
+use crate::api::consts::*;
+use crate::api::lua_state::LuaAPI;
+use crate::state::closure::RustFn;
+use crate::state::lua_state::LuaState;
+use super::auxlib::{self, check_any, check_integer, check_number, new_lib};
+
+// the mathematical functions, as in lmathlib.c
+const FUNCS: &[(&str, RustFn)] = &[
+    ("abs", abs),
+    ("acos", acos),
+    ("asin", asin),
+    ("atan", atan),
+    ("ceil", ceil),
+    ("cos", cos),
+    ("exp", exp),
+    ("floor", floor),
+    ("fmod", fmod),
+    ("log", log),
+    ("max", max),
+    ("min", min),
+    ("modf", modf),
+    ("random", random),
+    ("randomseed", randomseed),
+    ("sin", sin),
+    ("sqrt", sqrt),
+    ("tan", tan),
+    ("tointeger", tointeger),
+    ("type", type_),
+    ("ult", ult),
+];
+
+pub fn open(ls: &mut LuaState) {
+    new_lib(ls, FUNCS);
+    ls.push_number(PI);
+    ls.set_field(-2, "pi");
+    ls.push_number(f64::INFINITY);
+    ls.set_field(-2, "huge");
+    ls.push_integer(i64::MAX);
+    ls.set_field(-2, "maxinteger");
+    ls.push_integer(i64::MIN);
+    ls.set_field(-2, "mininteger");
+    ls.set_global("math");
+}
+
+// pushes `n` as an integer when it has an integer value that fits
+fn push_num_int(ls: &mut LuaState, n: f64) {
+    if n >= i64::MIN as f64 && n < -(i64::MIN as f64) {
+        ls.push_integer(n as i64);
+    } else {
+        ls.push_number(n);
+    }
+}
+
+// the functions of one float, which give a float
+fn float_fn(ls: &mut LuaState, f: fn(f64) -> f64) -> usize {
+    let x = check_number(ls, 1);
+    ls.push_number(f(x));
+    1
+}
+
+fn acos(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::acos)
+}
+
+fn asin(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::asin)
+}
+
+fn cos(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::cos)
+}
+
+fn exp(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::exp)
+}
+
+fn sin(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::sin)
+}
+
+fn sqrt(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::sqrt)
+}
+
+fn tan(ls: &mut LuaState) -> usize {
+    float_fn(ls, f64::tan)
+}
+
+// math.atan(y [, x])
+fn atan(ls: &mut LuaState) -> usize {
+    let y = check_number(ls, 1);
+    let x = auxlib::opt_number(ls, 2, 1.0);
+    ls.push_number(y.atan2(x));
+    1
+}
+
+// math.log(x [, base])
+fn log(ls: &mut LuaState) -> usize {
+    let x = check_number(ls, 1);
+    let res = if ls.is_none_or_nil(2) {
+        x.ln()
+    } else {
+        match check_number(ls, 2) {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        }
+    };
+    ls.push_number(res);
+    1
+}
+
+// math.abs(x)
+fn abs(ls: &mut LuaState) -> usize {
+    if ls.is_integer(1) {
+        let i = ls.to_integer(1);
+        ls.push_integer(i.wrapping_abs());
+    } else {
+        let x = check_number(ls, 1);
+        ls.push_number(x.abs());
+    }
+    1
+}
+
+// math.floor(x)
+fn floor(ls: &mut LuaState) -> usize {
+    if ls.is_integer(1) {
+        ls.set_top(1);
+    } else {
+        let x = check_number(ls, 1);
+        push_num_int(ls, x.floor());
+    }
+    1
+}
+
+// math.ceil(x)
+fn ceil(ls: &mut LuaState) -> usize {
+    if ls.is_integer(1) {
+        ls.set_top(1);
+    } else {
+        let x = check_number(ls, 1);
+        push_num_int(ls, x.ceil());
+    }
+    1
+}
+
+// math.fmod(x, y): the remainder of the division that rounds towards zero
+fn fmod(ls: &mut LuaState) -> usize {
+    if ls.is_integer(1) && ls.is_integer(2) {
+        let (m, d) = (ls.to_integer(1), ls.to_integer(2));
+        if d == 0 {
+            auxlib::arg_error(ls, 2, "zero");
+        }
+        // wrapping, as m % -1 overflows for the smallest integer
+        ls.push_integer(m.wrapping_rem(d));
+    } else {
+        let (x, y) = (check_number(ls, 1), check_number(ls, 2));
+        ls.push_number(x % y);
+    }
+    1
+}
+
+// math.modf(x): the integral part and the fractional part, as floats
+fn modf(ls: &mut LuaState) -> usize {
+    if ls.is_integer(1) {
+        ls.set_top(1);
+        ls.push_number(0.0);
+        return 2;
+    }
+    let x = check_number(ls, 1);
+    let ip = x.trunc();
+    ls.push_number(ip);
+    ls.push_number(if x == ip { 0.0 } else { x - ip });
+    2
+}
+
+// math.tointeger(x)
+fn tointeger(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    match ls.to_numberx(1) {
+        _ if ls.is_integer(1) => ls.set_top(1),
+        Some(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < -(i64::MIN as f64) => ls.push_integer(n as i64),
+        _ => ls.push_nil(),
+    }
+    1
+}
+
+// math.type(x)
+fn type_(ls: &mut LuaState) -> usize {
+    check_any(ls, 1);
+    match ls.type_id(1) {
+        LUA_TNUMBER if ls.is_integer(1) => ls.push_string(String::from("integer")),
+        LUA_TNUMBER => ls.push_string(String::from("float")),
+        _ => ls.push_nil(),
+    }
+    1
+}
+
+// math.ult(m, n)
+fn ult(ls: &mut LuaState) -> usize {
+    let m = check_integer(ls, 1);
+    let n = check_integer(ls, 2);
+    ls.push_boolean((m as u64) < (n as u64));
+    1
+}
+
+// math.min(x, ...) and math.max(x, ...)
+fn min(ls: &mut LuaState) -> usize {
+    extreme(ls, false)
+}
+
+fn max(ls: &mut LuaState) -> usize {
+    extreme(ls, true)
+}
+
+fn extreme(ls: &mut LuaState, max: bool) -> usize {
+    let n = ls.get_top() as isize;
+    check_number(ls, 1);
+    let mut best = 1;
+    for i in 2..=n {
+        check_number(ls, i);
+        let better = if max { ls.compare(best, i, LUA_OPLT) } else { ls.compare(i, best, LUA_OPLT) };
+        if better {
+            best = i;
+        }
+    }
+    ls.push_value(best);
+    1
+}
+
+// the generator behind math.random, a xorshift64* whose seed
+// math.randomseed sets
+thread_local! {
+    static SEED: Cell<u64> = const { Cell::new(0x2545_f491_4f6c_dd1d) };
+}
+
+fn next_random() -> u64 {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        seed.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+// math.random([m [, n]])
+fn random(ls: &mut LuaState) -> usize {
+    let r = next_random();
+    let (low, up) = match ls.get_top() {
+        0 => {
+            // the 53 high bits, for a float in [0, 1)
+            ls.push_number((r >> 11) as f64 / (1u64 << 53) as f64);
+            return 1;
+        }
+        1 => (1, check_integer(ls, 1)),
+        2 => (check_integer(ls, 1), check_integer(ls, 2)),
+        _ => auxlib::error(ls, "wrong number of arguments"),
+    };
+    if low > up {
+        auxlib::arg_error(ls, ls.get_top() as isize, "interval is empty");
+    }
+    // the size of the interval less one fits in 64 bits unsigned
+    let size = (up as u64).wrapping_sub(low as u64);
+    let offset = if size == u64::MAX { r } else { r % (size + 1) };
+    ls.push_integer((low as u64).wrapping_add(offset) as i64);
+    1
+}
+
+// math.randomseed(x)
+fn randomseed(ls: &mut LuaState) -> usize {
+    let x = check_number(ls, 1);
+    // xorshift gets stuck at zero
+    let seed = if ls.is_integer(1) { ls.to_integer(1) as u64 } else { x.to_bits() };
+    SEED.with(|s| s.set(if seed == 0 { 1 } else { seed }));
+    0
+}
@@ -0,0 +1,16 @@
+pub mod auxlib;
+pub mod base;
+pub mod math;
+pub mod string;
+pub mod table;
+
+use crate::state::lua_state::LuaState;
+
+// opens the libraries a standalone interpreter starts with: all of
+// those this crate has, which are base, math, string and table
+pub fn open_libs(ls: &mut LuaState) {
+    base::open(ls);
+    math::open(ls);
+    string::open(ls);
+    table::open(ls);
+}
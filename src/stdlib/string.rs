@@ -0,0 +1,460 @@
+use crate::api::consts::*;
+use crate::api::lua_state::LuaAPI;
+use crate::state::closure::RustFn;
+use crate::state::lua_state::LuaState;
+use super::auxlib::{self, check_any, check_bytes, check_integer, check_number, new_lib, opt_bytes, opt_integer};
+use super::base;
+
+// the string functions, as in lstrlib.c, but for those matching
+// patterns
+const FUNCS: &[(&str, RustFn)] = &[
+    ("byte", byte),
+    ("char", char),
+    ("format", format),
+    ("len", len),
+    ("lower", lower),
+    ("rep", rep),
+    ("reverse", reverse),
+    ("sub", sub),
+    ("upper", upper),
+];
+
+// the longest string rep and format build
+const MAX_SIZE: usize = i32::MAX as usize;
+
+pub fn open(ls: &mut LuaState) {
+    new_lib(ls, FUNCS);
+    // strings index the library, so that s:len() calls string.len(s)
+    ls.create_table(0, 1);
+    ls.push_value(-2);
+    ls.set_field(-2, "__index");
+    ls.push_string(String::new());
+    ls.push_value(-2);
+    ls.set_metatable(-2);
+    ls.pop(2);
+    ls.set_global("string");
+}
+
+// a position in a string of length `len`, negative ones counting from
+// the end, as posrelat does
+fn relative(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+// the range i..j of a string of length `len` as a slice range, empty
+// when there is nothing between them
+fn range(i: i64, j: i64, len: usize) -> std::ops::Range<usize> {
+    let i = relative(i, len).max(1) as usize;
+    let j = relative(j, len).min(len as i64);
+    if j < i as i64 {
+        0..0
+    } else {
+        i - 1..j as usize
+    }
+}
+
+// string.len(s)
+fn len(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    ls.push_integer(s.len() as i64);
+    1
+}
+
+// string.sub(s, i [, j])
+fn sub(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    let i = check_integer(ls, 2);
+    let j = opt_integer(ls, 3, -1);
+    ls.push_bytes(s[range(i, j, s.len())].to_vec());
+    1
+}
+
+// string.upper(s) and string.lower(s), of ASCII letters only
+fn upper(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    ls.push_bytes(s.to_ascii_uppercase());
+    1
+}
+
+fn lower(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    ls.push_bytes(s.to_ascii_lowercase());
+    1
+}
+
+// string.reverse(s)
+fn reverse(ls: &mut LuaState) -> usize {
+    let mut s = check_bytes(ls, 1);
+    s.reverse();
+    ls.push_bytes(s);
+    1
+}
+
+// string.rep(s, n [, sep])
+fn rep(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    let n = check_integer(ls, 2);
+    let sep = opt_bytes(ls, 3, b"");
+    if n <= 0 {
+        ls.push_bytes(Vec::new());
+        return 1;
+    }
+    let total = (s.len() + sep.len()).checked_mul(n as usize).map(|t| t - sep.len());
+    match total {
+        Some(total) if total < MAX_SIZE => {
+            let mut out = Vec::with_capacity(total);
+            for k in 0..n {
+                if k > 0 {
+                    out.extend_from_slice(&sep);
+                }
+                out.extend_from_slice(&s);
+            }
+            ls.push_bytes(out);
+            1
+        }
+        _ => auxlib::error(ls, "resulting string too large"),
+    }
+}
+
+// string.byte(s [, i [, j]])
+fn byte(ls: &mut LuaState) -> usize {
+    let s = check_bytes(ls, 1);
+    let i = opt_integer(ls, 2, 1);
+    let j = opt_integer(ls, 3, i);
+    let bytes = &s[range(i, j, s.len())];
+    for &b in bytes {
+        ls.push_integer(b as i64);
+    }
+    bytes.len()
+}
+
+// string.char(...)
+fn char(ls: &mut LuaState) -> usize {
+    let n = ls.get_top() as isize;
+    let mut s = Vec::with_capacity(n as usize);
+    for arg in 1..=n {
+        let c = check_integer(ls, arg);
+        if !(0..=255).contains(&c) {
+            auxlib::arg_error(ls, arg, "value out of range");
+        }
+        s.push(c as u8);
+    }
+    ls.push_bytes(s);
+    1
+}
+
+// a conversion of string.format: flags, width and precision, then the
+// conversion character
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    conv: u8,
+}
+
+// string.format(formatstring, ...)
+fn format(ls: &mut LuaState) -> usize {
+    let fmt = check_bytes(ls, 1);
+    let top = ls.get_top() as isize;
+    let mut out = Vec::new();
+    let mut arg = 1;
+    let mut pos = 0;
+    while pos < fmt.len() {
+        let b = fmt[pos];
+        pos += 1;
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        if fmt.get(pos) == Some(&b'%') {
+            out.push(b'%');
+            pos += 1;
+            continue;
+        }
+        let spec = parse_spec(ls, &fmt, &mut pos);
+        arg += 1;
+        if arg > top {
+            auxlib::arg_error(ls, arg, "no value");
+        }
+        let text = match spec.conv {
+            b'c' => {
+                let c = check_integer(ls, arg) as u8;
+                out.extend(pad(&spec, vec![c], false));
+                continue;
+            }
+            b'd' | b'i' => {
+                let n = check_integer(ls, arg);
+                let digits = n.unsigned_abs().to_string();
+                signed(&spec, n < 0, zero_extend(digits, spec.precision))
+            }
+            b'o' | b'x' | b'X' | b'u' => {
+                let n = check_integer(ls, arg) as u64;
+                let (digits, prefix) = match spec.conv {
+                    b'o' => (format!("{:o}", n), "0"),
+                    b'x' => (format!("{:x}", n), "0x"),
+                    b'X' => (format!("{:X}", n), "0X"),
+                    _ => (n.to_string(), ""),
+                };
+                let digits = zero_extend(digits, spec.precision);
+                if spec.alt && n != 0 && !digits.starts_with('0') {
+                    format!("{}{}", prefix, digits)
+                } else {
+                    digits
+                }
+            }
+            b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let n = check_number(ls, arg);
+                signed(&spec, n.is_sign_negative() && !n.is_nan(), float(&spec, n.abs()))
+            }
+            b'q' => {
+                out.extend(quoted(ls, arg));
+                continue;
+            }
+            b's' => {
+                let mut s = base::tostring(ls, arg);
+                if let Some(p) = spec.precision {
+                    s.truncate(p);
+                }
+                out.extend(pad(&spec, s, false));
+                continue;
+            }
+            c => {
+                let msg = format!("invalid option '%{}' to 'format'", c as char);
+                auxlib::error(ls, &msg)
+            }
+        };
+        out.extend(pad(&spec, text.into_bytes(), true));
+        if out.len() >= MAX_SIZE {
+            auxlib::error(ls, "resulting string too large");
+        }
+    }
+    ls.push_bytes(out);
+    1
+}
+
+fn parse_spec(ls: &mut LuaState, fmt: &[u8], pos: &mut usize) -> Spec {
+    let mut spec = Spec {
+        left: false,
+        plus: false,
+        space: false,
+        alt: false,
+        zero: false,
+        width: 0,
+        precision: None,
+        conv: 0,
+    };
+    while let Some(&b) = fmt.get(*pos) {
+        match b {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            b'0' => spec.zero = true,
+            _ => break,
+        }
+        *pos += 1;
+    }
+    // two digits at most for the width and the precision, as in C Lua
+    let digits = |pos: &mut usize| {
+        let start = *pos;
+        while *pos < fmt.len() && fmt[*pos].is_ascii_digit() {
+            *pos += 1;
+        }
+        (*pos - start, std::str::from_utf8(&fmt[start..*pos]).unwrap().parse().unwrap_or(0))
+    };
+    let (n, width) = digits(pos);
+    spec.width = width;
+    let mut too_long = n > 2;
+    if fmt.get(*pos) == Some(&b'.') {
+        *pos += 1;
+        let (n, precision) = digits(pos);
+        spec.precision = Some(precision);
+        too_long |= n > 2;
+    }
+    if too_long {
+        auxlib::error(ls, "invalid format (width or precision too long)");
+    }
+    match fmt.get(*pos) {
+        Some(&c) => {
+            spec.conv = c;
+            *pos += 1;
+        }
+        None => auxlib::error(ls, "invalid conversion '%' to 'format'"),
+    }
+    spec
+}
+
+// at least `precision` digits, with zeros in front
+fn zero_extend(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(p) if digits.len() < p => format!("{}{}", "0".repeat(p - digits.len()), digits),
+        // a precision of zero writes nothing for zero
+        Some(0) if digits == "0" => String::new(),
+        _ => digits,
+    }
+}
+
+// the sign a number gets in front of its digits
+fn signed(spec: &Spec, negative: bool, digits: String) -> String {
+    let sign = if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}", sign, digits)
+}
+
+// pads `s` to the width, with zeros after the sign for numbers with the
+// 0 flag
+fn pad(spec: &Spec, mut s: Vec<u8>, numeric: bool) -> Vec<u8> {
+    if s.len() >= spec.width {
+        return s;
+    }
+    let fill = spec.width - s.len();
+    if spec.left {
+        s.extend(std::iter::repeat_n(b' ', fill));
+    } else if numeric && spec.zero && s.last().is_some_and(u8::is_ascii_digit) {
+        let at = s.iter().position(u8::is_ascii_alphanumeric).unwrap_or(0);
+        // after a 0x prefix too
+        let at = if s[at..].starts_with(b"0x") || s[at..].starts_with(b"0X") { at + 2 } else { at };
+        s.splice(at..at, std::iter::repeat_n(b'0', fill));
+    } else {
+        s.splice(0..0, std::iter::repeat_n(b' ', fill));
+    }
+    s
+}
+
+// the digits of a float that is not negative, as printf writes them
+fn float(spec: &Spec, n: f64) -> String {
+    let upper = spec.conv.is_ascii_uppercase();
+    let s = if n.is_infinite() {
+        String::from("inf")
+    } else if n.is_nan() {
+        String::from("nan")
+    } else {
+        let p = spec.precision.unwrap_or(6);
+        match spec.conv {
+            b'f' | b'F' => format!("{:.*}", p, n),
+            b'e' | b'E' => exponent(format!("{:.*e}", p, n)),
+            _ => general(n, p, spec.alt),
+        }
+    };
+    if upper {
+        s.to_uppercase()
+    } else {
+        s
+    }
+}
+
+// Rust's 1.5e2 as C's 1.5e+02
+fn exponent(s: String) -> String {
+    match s.split_once('e') {
+        Some((mantissa, exp)) => {
+            let (sign, digits) = match exp.strip_prefix('-') {
+                Some(digits) => ('-', digits),
+                None => ('+', exp),
+            };
+            format!("{}e{}{:0>2}", mantissa, sign, digits)
+        }
+        None => s,
+    }
+}
+
+// %g: %e when the exponent is below -4 or not below the precision,
+// else %f, with no trailing zeros unless with the # flag
+fn general(n: f64, p: usize, alt: bool) -> String {
+    let p = p.max(1);
+    let e = format!("{:.*e}", p - 1, n);
+    let x: i64 = e.split_once('e').map_or(0, |(_, x)| x.parse().unwrap_or(0));
+    let s = if x < -4 || x >= p as i64 {
+        e
+    } else {
+        format!("{:.*}", (p as i64 - 1 - x) as usize, n)
+    };
+    if alt {
+        return exponent(s);
+    }
+    let (mantissa, exp) = match s.split_once('e') {
+        Some((m, x)) => (m.to_string(), Some(x.to_string())),
+        None => (s, None),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        mantissa
+    };
+    match exp {
+        Some(x) => exponent(format!("{}e{}", mantissa, x)),
+        None => mantissa,
+    }
+}
+
+// %q: a value written as Lua reads it back
+fn quoted(ls: &mut LuaState, arg: isize) -> Vec<u8> {
+    match ls.type_id(arg) {
+        LUA_TSTRING => {
+            let s = ls.to_bytes(arg);
+            let mut out = vec![b'"'];
+            for (i, &b) in s.iter().enumerate() {
+                match b {
+                    b'"' | b'\\' => out.extend([b'\\', b]),
+                    b'\n' => out.extend(b"\\\n"),
+                    b'\r' => out.extend(b"\\r"),
+                    0 => out.extend(if s.get(i + 1).is_some_and(u8::is_ascii_digit) { &b"\\000"[..] } else { b"\\0" }),
+                    b if b.is_ascii_control() => {
+                        if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                            out.extend(format!("\\{:03}", b).bytes());
+                        } else {
+                            out.extend(format!("\\{}", b).bytes());
+                        }
+                    }
+                    b => out.push(b),
+                }
+            }
+            out.push(b'"');
+            out
+        }
+        LUA_TNUMBER if ls.is_integer(arg) => {
+            let n = ls.to_integer(arg);
+            // the smallest integer has no literal of its own
+            if n == i64::MIN {
+                b"0x8000000000000000".to_vec()
+            } else {
+                n.to_string().into_bytes()
+            }
+        }
+        LUA_TNUMBER => {
+            let n = ls.to_number(arg);
+            let s = if n == f64::INFINITY {
+                String::from("1e9999")
+            } else if n == f64::NEG_INFINITY {
+                String::from("-1e9999")
+            } else if n.is_nan() {
+                String::from("(0/0)")
+            } else {
+                // the shortest text that reads back as the same float
+                format!("{:?}", n)
+            };
+            s.into_bytes()
+        }
+        LUA_TNIL | LUA_TBOOLEAN => base::tostring(ls, arg),
+        _ => {
+            check_any(ls, arg);
+            auxlib::arg_error(ls, arg, "value has no literal form")
+        }
+    }
+}
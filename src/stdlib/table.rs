@@ -0,0 +1,238 @@
+use crate::api::consts::*;
+use crate::api::lua_state::LuaAPI;
+use crate::state::closure::RustFn;
+use crate::state::lua_state::LuaState;
+use super::auxlib::{self, check_integer, check_type, new_lib, opt_bytes, opt_integer};
+
+// the table manipulation functions, as in ltablib.c
+const FUNCS: &[(&str, RustFn)] = &[
+    ("concat", concat),
+    ("insert", insert),
+    ("move", move_),
+    ("pack", pack),
+    ("remove", remove),
+    ("sort", sort),
+    ("unpack", unpack),
+];
+
+pub fn open(ls: &mut LuaState) {
+    new_lib(ls, FUNCS);
+    ls.set_global("table");
+}
+
+// the length of the table argument `arg`
+fn check_len(ls: &mut LuaState, arg: isize) -> i64 {
+    check_type(ls, arg, LUA_TTABLE);
+    ls.len(arg);
+    let n = ls.to_integer(-1);
+    ls.pop(1);
+    n
+}
+
+// table.concat(list [, sep [, i [, j]]])
+fn concat(ls: &mut LuaState) -> usize {
+    let last = check_len(ls, 1);
+    let sep = opt_bytes(ls, 2, b"");
+    let i = opt_integer(ls, 3, 1);
+    let j = opt_integer(ls, 4, last);
+    let mut s = Vec::new();
+    let mut k = i;
+    while k <= j {
+        ls.get_i(1, k);
+        if !matches!(ls.type_id(-1), LUA_TSTRING | LUA_TNUMBER) {
+            auxlib::error(ls, &format!("invalid value (at index {}) in table for 'concat'", k));
+        }
+        s.extend(ls.to_bytes(-1));
+        ls.pop(1);
+        if k == j {
+            break;
+        }
+        s.extend_from_slice(&sep);
+        k += 1;
+    }
+    ls.push_bytes(s);
+    1
+}
+
+// table.insert(list, [pos,] value)
+fn insert(ls: &mut LuaState) -> usize {
+    // the first empty slot
+    let e = check_len(ls, 1).wrapping_add(1);
+    let pos = match ls.get_top() {
+        2 => e,
+        3 => {
+            let pos = check_integer(ls, 2);
+            // 1 <= pos <= e, unsigned so that one comparison does
+            if (pos as u64).wrapping_sub(1) >= e as u64 {
+                auxlib::arg_error(ls, 2, "position out of bounds");
+            }
+            for i in (pos + 1..=e).rev() {
+                ls.get_i(1, i - 1);
+                ls.set_i(1, i);
+            }
+            pos
+        }
+        _ => auxlib::error(ls, "wrong number of arguments to 'insert'"),
+    };
+    ls.set_i(1, pos);
+    0
+}
+
+// table.remove(list [, pos])
+fn remove(ls: &mut LuaState) -> usize {
+    let size = check_len(ls, 1);
+    let mut pos = opt_integer(ls, 2, size);
+    // a position given may also be the one past the end
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        auxlib::arg_error(ls, 1, "position out of bounds");
+    }
+    ls.get_i(1, pos);
+    while pos < size {
+        ls.get_i(1, pos + 1);
+        ls.set_i(1, pos);
+        pos += 1;
+    }
+    ls.push_nil();
+    ls.set_i(1, pos);
+    1
+}
+
+// table.move(a1, f, e, t [, a2]): a2[t], ... = a1[f], ..., a1[e]
+fn move_(ls: &mut LuaState) -> usize {
+    let f = check_integer(ls, 2);
+    let e = check_integer(ls, 3);
+    let t = check_integer(ls, 4);
+    let dest = if ls.is_none_or_nil(5) { 1 } else { 5 };
+    check_type(ls, 1, LUA_TTABLE);
+    check_type(ls, dest, LUA_TTABLE);
+    if e >= f {
+        if !(f > 0 || e < i64::MAX + f) {
+            auxlib::arg_error(ls, 3, "too many elements to move");
+        }
+        let n = e - f;
+        if t > i64::MAX - n {
+            auxlib::arg_error(ls, 4, "destination wrap around");
+        }
+        // backwards when the ranges overlap that way in one table
+        if t > e || t <= f || (dest != 1 && !ls.raw_equal(1, dest)) {
+            for i in 0..=n {
+                ls.get_i(1, f + i);
+                ls.set_i(dest, t + i);
+            }
+        } else {
+            for i in (0..=n).rev() {
+                ls.get_i(1, f + i);
+                ls.set_i(dest, t + i);
+            }
+        }
+    }
+    ls.push_value(dest);
+    1
+}
+
+// table.pack(...)
+fn pack(ls: &mut LuaState) -> usize {
+    let n = ls.get_top();
+    ls.create_table(n, 1);
+    ls.insert(1);
+    for i in (1..=n as i64).rev() {
+        ls.set_i(1, i);
+    }
+    ls.push_integer(n as i64);
+    ls.set_field(1, "n");
+    1
+}
+
+// table.unpack(list [, i [, j]])
+fn unpack(ls: &mut LuaState) -> usize {
+    let i = opt_integer(ls, 2, 1);
+    let j = if ls.is_none_or_nil(3) { check_len(ls, 1) } else { check_integer(ls, 3) };
+    if i > j {
+        return 0;
+    }
+    let n = (j as u64).wrapping_sub(i as u64);
+    if n >= i32::MAX as u64 || !ls.check_stack(n as isize + 1) {
+        auxlib::error(ls, "too many results to unpack");
+    }
+    for k in i..=j {
+        ls.get_i(1, k);
+    }
+    n as usize + 1
+}
+
+// table.sort(list [, comp]), a merge sort of the values copied above
+// the arguments
+fn sort(ls: &mut LuaState) -> usize {
+    let n = check_len(ls, 1);
+    if n > 1 {
+        if n >= i32::MAX as i64 {
+            auxlib::arg_error(ls, 1, "array too big");
+        }
+        if !ls.is_none_or_nil(2) {
+            check_type(ls, 2, LUA_TFUNCTION);
+        }
+        ls.set_top(2);
+        if !ls.check_stack(n as isize + 3) {
+            auxlib::error(ls, "array too big");
+        }
+        for i in 1..=n {
+            ls.get_i(1, i);
+        }
+        let mut order: Vec<isize> = (3..n as isize + 3).collect();
+        merge_sort(ls, &mut order);
+        for (i, &slot) in order.iter().enumerate() {
+            ls.push_value(slot);
+            ls.set_i(1, i as i64 + 1);
+        }
+    }
+    0
+}
+
+fn merge_sort(ls: &mut LuaState, slots: &mut [isize]) {
+    if slots.len() < 2 {
+        return;
+    }
+    let mid = slots.len() / 2;
+    merge_sort(ls, &mut slots[..mid]);
+    merge_sort(ls, &mut slots[mid..]);
+    let mut merged = Vec::with_capacity(slots.len());
+    let (mut i, mut j) = (0, mid);
+    while i < mid && j < slots.len() {
+        // the right one only when strictly less, which keeps it stable
+        if less(ls, slots[j], slots[i]) {
+            merged.push(slots[j]);
+            j += 1;
+        } else {
+            merged.push(slots[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&slots[i..mid]);
+    merged.extend_from_slice(&slots[j..]);
+    slots.copy_from_slice(&merged);
+}
+
+// whether the value at `a` sorts before the one at `b`, by the
+// comparison function when there is one
+fn less(ls: &mut LuaState, a: isize, b: isize) -> bool {
+    if ls.is_nil(2) {
+        let comparable = matches!((ls.type_id(a), ls.type_id(b)), (LUA_TNUMBER, LUA_TNUMBER) | (LUA_TSTRING, LUA_TSTRING));
+        if !comparable {
+            let (ta, tb) = (ls.type_name(ls.type_id(a)), ls.type_name(ls.type_id(b)));
+            let msg = if ta == tb {
+                format!("attempt to compare two {} values", ta)
+            } else {
+                format!("attempt to compare {} with {}", ta, tb)
+            };
+            auxlib::error(ls, &msg);
+        }
+        return ls.compare(a, b, LUA_OPLT);
+    }
+    ls.push_value(2);
+    ls.push_value(a);
+    ls.push_value(b);
+    ls.call(2, 1);
+    let res = ls.to_boolean(-1);
+    ls.pop(1);
+    res
+}
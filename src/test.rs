@@ -1,158 +1,35 @@
 #[cfg(test)]
 mod test_3ch {
 
-    use std::fs;
     use std::io;
-    use crate::{vm, binchunk};
-
-    use vm::instruction::Instruction;
-    use vm::opcodes::{OpArgMode, OpMode};
-    use binchunk::binary_chunk::{Constant, Prototype};
+    use crate::asm;
+    use crate::binchunk::listing;
 
     #[test]
     fn test() -> io::Result<()> {
         // let mut args = env::args();
         // let _program = args.next().expect("no program name");
         // let arg1 = args.next().expect("no first argument");
-        let data = fs::read(String::from("./test/luac.out")).expect("Cannot open file");
-
-        let binarychunk = binchunk::undump(data);
-        list(&binarychunk.main_func);
-
-        Ok(())
-    }
-
-    fn list(f: &Box<Prototype>) {
-        print_header(&f);
-        print_code(&f);
-        print_detail(&f);
-        for p in &f.protos {
-            list(p);
-        }
-    }
-
-    fn print_header(f: &Box<Prototype>) {
-        let func_type = if f.line_defined > 0 {
-            "function"
-        } else {
-            "main"
-        };
-        let vararg_flag = if f.is_vararg > 0 { "+" } else { "" };
-
-        println!(
-            "{} <{}:{},{}> ({} instructions)",
-            func_type,
-            f.source,
-            f.line_defined,
-            f.last_line_defined,
-            f.code.len()
-        );
-        println!(
-            "{}{} params, {} slots, {} upvalues, {} locals, {} constants, {} functions",
-            f.num_params,
-            vararg_flag,
-            f.max_stack_size,
-            f.upvalues.len(),
-            f.loc_vars.len(),
-            f.constants.len(),
-            f.protos.len()
-        );
-    }
-
-    fn print_code(f: &Box<Prototype>) {
-        for pc in 0..f.code.len() {
-            let line = if !f.line_info.is_empty() {
-                format!("{}", f.line_info[pc])
-            } else {
-                String::from("-")
-            };
-            let i = f.code[pc];
-            print!("\t{}\t[{}]\t{} \t", pc + 1, line, i.opname());
-            print_operands(i);
-        }
-    }
-
-    fn print_operands(i: u32) {
-        match i.opmode() {
-            OpMode::IABC => {
-                let (a, b, c) = i.abc();
-                print!("{}", a);
-                match i.b_mode() {
-                    OpArgMode::OpArgN => (),
-                    _ => print!(" {}", if b > 0xff { -1 - (b & 0xff) } else { b }),
-                }
-                match i.c_mode() {
-                    OpArgMode::OpArgN => (),
-                    _ => print!(" {}", if c > 0xff { -1 - (c & 0xff) } else { c }),
-                }
-            }
-            OpMode::IABx => {
-                let (a, bx) = i.a_bx();
-                print!("{}", a);
-                match i.b_mode() {
-                    OpArgMode::OpArgK => print!(" {}", -1 - bx),
-                    OpArgMode::OpArgU => print!(" {}", bx),
-                    _ => unreachable!(),
-                }
-            }
-            OpMode::IAsBx => {
-                let (a, sbx) = i.a_sbx();
-                print!("{} {}", a, sbx);
-            }
-            OpMode::IAx => {
-                let ax = i.ax();
-                print!("{}", -1 - ax);
-            }
-        }
-        println!();
-    }
-
-    fn print_detail(f: &Box<Prototype>) {
-        println!("constants ({}):", f.constants.len());
-        for i in 0..f.constants.len() {
-            println!("\t{}\t{}", i + 1, constant_to_string(&f.constants[i]));
-        }
-
-        println!("locals ({}):", f.loc_vars.len());
-        for i in 0..f.loc_vars.len() {
-            let loc_var = &f.loc_vars[i];
-            println!(
-                "\t{}\t{}\t{}\t{}",
-                i,
-                loc_var.var_name,
-                loc_var.start_pc + 1,
-                loc_var.end_pc + 1
-            );
-        }
-
-        println!("upvalues ({}):", f.upvalues.len());
-        for i in 0..f.upvalues.len() {
-            let upval = &f.upvalues[i];
-            println!(
-                "\t{}\t{}\t{}\t{}",
-                i,
-                upval_name(&f, i),
-                upval.instack,
-                upval.idx
-            );
-        }
-    }
-
-    fn constant_to_string(k: &Constant) -> String {
-        match k {
-            Constant::Nil => format!("nil"),
-            Constant::Boolean(arg) => format!("{}", arg),
-            Constant::Integer(arg) => format!("{}", arg),
-            Constant::Number(arg) => format!("{}", arg),
-            Constant::Str(arg) => format!("{}", arg),
-        }
-    }
-
-    fn upval_name(f: &Prototype, idx: usize) -> String {
-        f.upvalue_names
-            .get(idx)
-            .unwrap_or(&String::from("-"))
-            .clone()
+        let main_func = asm::assemble(r#"
+            .source "@closure.lua"
+            .upvalue _ENV 1 0
+            .const "f"
+            .local a 2 4
+            1   [1] LOADK       0 -1
+            2   [4] CLOSURE     1 0
+            3   [2] SETTABUP    0 -1 1
+            4   [4] RETURN      0 1
+            .function 2 4
+              .params 1
+              .local x 1 3
+              .upvalue a 1 0
+              .const 1
+              1 [3] GETUPVAL    1 0
+              2 [3] ADD         1 1 -1
+              3 [3] RETURN      1 2
+            .end
+        "#).expect("Cannot assemble chunk");
+        listing::list(&mut io::stdout(), &main_func, true)
     }
 }
 
@@ -219,11 +96,12 @@ mod test_5ch {
     }
 }
 
+#[cfg(test)]
 mod test_6ch {
 
-    use std::{fs, io};
+    use std::io;
     use crate::api::lua_vm::LuaVM;
-    use crate::{vm, binchunk};
+    use crate::{asm, vm, binchunk};
     use crate::state::lua_state::LuaState;
     use crate::api::lua_state::LuaAPI;
     use crate::api::consts::*;
@@ -236,16 +114,36 @@ mod test_6ch {
         // let mut args = env::args();
         // let _program = args.next().expect("no program name");
         // let arg1 = args.next().expect("no first argument");
-        let data = fs::read(String::from("./test/luac.out")).expect("Cannot open file");
-
-        let binarychunk = binchunk::undump(data);
-        lua_main(*binarychunk.main_func);
+        let main_func = asm::assemble(r#"
+            ; local sum = 0
+            ; for i = 1, 100 do
+            ;   if i % 2 == 0 then sum = sum + i end
+            ; end
+            .stack 6
+            .const 0
+            .const 1
+            .const 100
+            .const 2
+            1   [1] LOADK       0 -1    ; 0
+            2   [2] LOADK       1 -2    ; 1
+            3   [2] LOADK       2 -3    ; 100
+            4   [2] LOADK       3 -2    ; 1
+            5   [2] FORPREP     1 4     ; to 10
+            6   [3] MOD         5 4 -4  ; - 2
+            7   [3] EQ          0 5 -1  ; - 0
+            8   [3] JMP         0 1     ; to 10
+            9   [4] ADD         0 0 4
+            10  [2] FORLOOP     1 -5    ; to 6
+            11  [6] RETURN      0 1
+        "#).expect("Cannot assemble chunk");
+        let ls = lua_main(*main_func);
+        assert_eq!(ls.to_integer(1), 2550);
         
 
         Ok(())
     }
 
-    fn lua_main(proto: Prototype) {
+    fn lua_main(proto: Prototype) -> LuaState {
         let n_regs = proto.max_stack_size;
         let mut ls = LuaState::new((n_regs+8) as usize, proto);
         ls.set_top(n_regs as isize);
@@ -259,7 +157,7 @@ mod test_6ch {
             print!("[{:04}] {} ", pc + 1, inst.opname());
             print_stack(&ls);
         }
-
+        ls
     }
 
     fn print_stack(ls: &LuaState) {
@@ -278,11 +176,12 @@ mod test_6ch {
     }
 }
 
+#[cfg(test)]
 mod test_7ch {
     
-    use std::{fs, io};
+    use std::io;
     use crate::api::lua_vm::LuaVM;
-    use crate::{vm, binchunk};
+    use crate::{asm, vm, binchunk};
     use crate::state::lua_state::LuaState;
     use crate::api::lua_state::LuaAPI;
     use crate::api::consts::*;
@@ -295,16 +194,44 @@ mod test_7ch {
         // let mut args = env::args();
         // let _program = args.next().expect("no program name");
         // let arg1 = args.next().expect("no first argument");
-        let data = fs::read(String::from("./test/luac.out")).expect("Cannot open file");
-
-        let binarychunk = binchunk::undump(data);
-        lua_main(*binarychunk.main_func);
+        let main_func = asm::assemble(r#"
+            ; local t = {"a", "b", "c"}
+            ; t[2] = "B"
+            ; t["foo"] = "Bar"
+            ; local s = t[3] .. t[2] .. t[1] .. t["foo"] .. #t
+            .stack 6
+            .const "a"
+            .const "b"
+            .const "c"
+            .const 2
+            .const "B"
+            .const "foo"
+            .const "Bar"
+            .const 3
+            .const 1
+            1   [1] NEWTABLE    0 3 0
+            2   [1] LOADK       1 -1    ; "a"
+            3   [1] LOADK       2 -2    ; "b"
+            4   [1] LOADK       3 -3    ; "c"
+            5   [1] SETLIST     0 3 1   ; 1
+            6   [2] SETTABLE    0 -4 -5 ; 2 "B"
+            7   [3] SETTABLE    0 -6 -7 ; "foo" "Bar"
+            8   [4] GETTABLE    1 0 -8  ; 3
+            9   [4] GETTABLE    2 0 -4  ; 2
+            10  [4] GETTABLE    3 0 -9  ; 1
+            11  [4] GETTABLE    4 0 -6  ; "foo"
+            12  [4] LEN         5 0
+            13  [4] CONCAT      1 1 5
+            14  [4] RETURN      0 1
+        "#).expect("Cannot assemble chunk");
+        let ls = lua_main(*main_func);
+        assert_eq!(ls.to_string(2), "cBaBar3");
         
 
         Ok(())
     }
 
-    fn lua_main(proto: Prototype) {
+    fn lua_main(proto: Prototype) -> LuaState {
         let n_regs = proto.max_stack_size;
         let mut ls = LuaState::new((n_regs+8) as usize, proto);
         ls.set_top(n_regs as isize);
@@ -318,7 +245,7 @@ mod test_7ch {
             print!("[{:04}] {} ", pc + 1, inst.opname());
             print_stack(&ls);
         }
-
+        ls
     }
 
     fn print_stack(ls: &LuaState) {
@@ -335,4 +262,2411 @@ mod test_7ch {
         }
         println!("");
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_chunk_error {
+
+    use crate::binchunk;
+    use crate::compiler;
+    use binchunk::chunk_error::ChunkError;
+
+    fn header() -> Vec<u8> {
+        let mut data = vec![0x1b, 0x4c, 0x75, 0x61, 0x53, 0x00];
+        data.extend_from_slice(&[0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a]);
+        data.extend_from_slice(&[4, 8, 4, 8, 8]);
+        data.extend_from_slice(&0x5678i64.to_le_bytes());
+        data.extend_from_slice(&370.5f64.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test() {
+        let err = binchunk::undump(b"print('hi')").err();
+        assert_eq!(err, Some(ChunkError::BadSignature));
+
+        let mut data = header();
+        data[4] = 0x52;
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::VersionMismatch { expected: 0x53, found: 0x52 }));
+
+        let mut data = header();
+        data[13] = 3;
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::UnsupportedSize { what: "size_t", found: 3 }));
+
+        let mut data = header();
+        data[14] = 8;
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::SizeMismatch { what: "Instruction", expected: 4, found: 8 }));
+
+        let mut data = header();
+        data[17..25].copy_from_slice(&0x1234i64.to_le_bytes());
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::EndiannessMismatch));
+
+        let mut data = header();
+        data[25..33].copy_from_slice(&370.25f64.to_le_bytes());
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::FloatFormatMismatch));
+
+        let data = header()[..20].to_vec();
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::UnexpectedEof { offset: 20 }));
+
+        // main function with a single constant carrying an unknown tag
+        let mut data = header();
+        data.push(1); // size_upvalues
+        data.push(0); // source
+        data.extend_from_slice(&[0; 8]); // line_defined, last_line_defined
+        data.extend_from_slice(&[0, 1, 2]); // num_params, is_vararg, max_stack_size
+        data.extend_from_slice(&0u32.to_le_bytes()); // code
+        data.extend_from_slice(&1u32.to_le_bytes()); // constants
+        let offset = data.len();
+        data.push(0x42);
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::UnknownConstantTag { tag: 0x42, offset }));
+
+        // a long string prefix with a size_t of 0, where the size counts
+        // the missing terminator
+        let mut data = header();
+        data.extend_from_slice(&[1, 0xff]);
+        data.extend_from_slice(&0u64.to_le_bytes());
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::Corrupted));
+
+        // functions nested past the limit, each the only child of the last
+        let mut data = header();
+        data.push(1);
+        for _ in 0..100_000 {
+            data.push(0);
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&[0, 1, 2]);
+            data.extend_from_slice(&[0; 12]); // code, constants, upvalues
+            data.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let err = binchunk::undump(&data).err();
+        assert_eq!(err, Some(ChunkError::TooDeep { limit: 200 }));
+    }
+
+    // an empty main function
+    fn header_chunk() -> Vec<u8> {
+        let mut data = header();
+        data.extend_from_slice(&[1, 0]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0, 1, 2]);
+        data.extend_from_slice(&[0; 28]);
+        data
+    }
+
+    #[test]
+    fn test_nested_source() {
+        let proto = compiler::compile("@x.lua", b"return function() return function() end end").unwrap();
+        let mut chunk = binchunk::undump(&header_chunk()).unwrap();
+        chunk.main_func = proto;
+        let chunk = binchunk::undump(&binchunk::dump(&chunk).unwrap()).unwrap();
+        assert_eq!(chunk.main_func.protos[0].source, "@x.lua");
+        assert_eq!(chunk.main_func.protos[0].protos[0].source, "@x.lua");
+    }
+}
+
+#[cfg(test)]
+mod test_dump {
+
+    use std::rc::Rc;
+    use crate::binchunk;
+    use binchunk::binary_chunk::*;
+    use binchunk::chunk_error::ChunkError;
+
+    fn chunk() -> BinaryChunk {
+        let inner = Prototype {
+            version: 0x53,
+            source: String::from("@test.lua"),
+            line_defined: 2,
+            last_line_defined: 4,
+            num_params: 1,
+            is_vararg: 0,
+            max_stack_size: 2,
+            code: vec![0x00000026, 0x00800026],
+            constants: vec![],
+            upvalues: vec![Upvalue { instack: 1, idx: 0, kind: 0 }],
+            protos: vec![],
+            line_info: vec![3, 4],
+            abs_line_info: vec![],
+            loc_vars: vec![LocVar { var_name: String::from("x"), start_pc: 0, end_pc: 2 }],
+            upvalue_names: vec![String::from("t")],
+        };
+        let main_func = Prototype {
+            version: 0x53,
+            source: String::from("@test.lua"),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_stack_size: 2,
+            code: vec![0x00000001, 0x0000002c, 0x00800026],
+            constants: vec![
+                Constant::Nil,
+                Constant::Boolean(true),
+                Constant::Integer(-7),
+                Constant::Number(0.5),
+                Constant::Str(b"short".to_vec()),
+                Constant::Str(vec![b'x'; 300]),
+            ],
+            upvalues: vec![Upvalue { instack: 1, idx: 0, kind: 0 }],
+            protos: vec![Rc::new(inner)],
+            line_info: vec![1, 4, 4],
+            abs_line_info: vec![],
+            loc_vars: vec![],
+            upvalue_names: vec![String::from("_ENV")],
+        };
+        BinaryChunk {
+            header: Header {
+                signature: [0x1b, 0x4c, 0x75, 0x61],
+                version: 0x53,
+                format: 0,
+                luac_data: [0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a],
+                cint_size: 4,
+                sizet_size: 8,
+                instruction_size: 4,
+                lua_integer_size: 8,
+                lua_number_size: 8,
+                luac_int: 0x5678,
+                luac_num: 370.5,
+            },
+            size_upvalues: 1,
+            main_func: Box::new(main_func),
+        }
+    }
+
+    #[test]
+    fn test() {
+        let data = binchunk::dump(&chunk()).unwrap();
+        let loaded = binchunk::undump(&data).expect("Cannot load chunk");
+        assert_eq!(binchunk::dump(&loaded).unwrap(), data);
+        assert_eq!(loaded.main_func.constants.len(), 6);
+        assert_eq!(loaded.main_func.protos[0].loc_vars[0].var_name, "x");
+
+        let stripped = binchunk::dump_stripped(&loaded).unwrap();
+        assert!(stripped.len() < data.len());
+        let loaded = binchunk::undump(&stripped).expect("Cannot load chunk");
+        assert!(loaded.main_func.source.is_empty());
+        assert!(loaded.main_func.line_info.is_empty());
+        assert!(loaded.main_func.protos[0].loc_vars.is_empty());
+        assert!(loaded.main_func.protos[0].upvalue_names.is_empty());
+        assert_eq!(binchunk::dump(&loaded).unwrap(), stripped);
+    }
+
+    #[test]
+    fn test_tbc() {
+        // TBC is not a 5.3 instruction, so functions using it have no 5.3 chunk
+        let mut chunk = chunk();
+        let mut inner = (*chunk.main_func.protos[0]).clone();
+        inner.code.insert(1, 0x0000002f);
+        inner.line_info.insert(1, 3);
+        chunk.main_func.protos[0] = Rc::new(inner);
+        let err = ChunkError::Unrepresentable { pc: 1, reason: "to-be-closed variables need Lua 5.4" };
+        assert_eq!(binchunk::dump(&chunk).err(), Some(err.clone()));
+        assert_eq!(err.to_string(), "cannot dump instruction 2: to-be-closed variables need Lua 5.4");
+    }
+}
+
+#[cfg(test)]
+mod test_large_chunk {
+
+    use std::io::{self, Cursor, Read};
+    use crate::binchunk;
+    use binchunk::binary_chunk::*;
+    use binchunk::chunk_error::ChunkError;
+
+    fn chunk(n: usize) -> BinaryChunk {
+        let main_func = Prototype {
+            version: 0x53,
+            source: String::from("@large.lua"),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_stack_size: 2,
+            code: vec![0x00000001; n],
+            constants: (0..n as i64 / 16).map(Constant::Integer).collect(),
+            upvalues: vec![],
+            protos: vec![],
+            line_info: vec![1; n],
+            abs_line_info: vec![],
+            loc_vars: vec![],
+            upvalue_names: vec![],
+        };
+        BinaryChunk {
+            header: Header {
+                signature: [0x1b, 0x4c, 0x75, 0x61],
+                version: 0x53,
+                format: 0,
+                luac_data: [0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a],
+                cint_size: 4,
+                sizet_size: 8,
+                instruction_size: 4,
+                lua_integer_size: 8,
+                lua_number_size: 8,
+                luac_int: 0x5678,
+                luac_num: 370.5,
+            },
+            size_upvalues: 0,
+            main_func: Box::new(main_func),
+        }
+    }
+
+    // a stream that counts the bytes it hands out and can fail after them
+    struct Stream {
+        data: Cursor<Vec<u8>>,
+        read: usize,
+        fail: bool,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.data.read(buf)?;
+            self.read += n;
+            if n == 0 && self.fail {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test() {
+        // ~2 MB of code and line info
+        let data = binchunk::dump(&chunk(1 << 18)).unwrap();
+        assert!(data.len() > 2 << 20);
+
+        let loaded = binchunk::undump(&data).expect("Cannot load chunk");
+        assert_eq!(loaded.main_func.code.len(), 1 << 18);
+
+        // the stream is read once, to its end
+        let mut stream = Stream { data: Cursor::new(data.clone()), read: 0, fail: false };
+        let loaded = binchunk::undump_from(&mut stream).expect("Cannot load chunk");
+        assert_eq!(loaded.main_func.line_info.len(), 1 << 18);
+        assert_eq!(stream.read, data.len());
+
+        // a stream cut short, anywhere, is a truncated chunk
+        for len in [100, data.len() / 2, data.len() - 1] {
+            let stream = Stream { data: Cursor::new(data[..len].to_vec()), read: 0, fail: false };
+            let err = binchunk::undump_from(stream).err();
+            assert_eq!(err, Some(ChunkError::UnexpectedEof { offset: len }));
+        }
+
+        // and a failing one gives its error
+        let stream = Stream { data: Cursor::new(data[..100].to_vec()), read: 0, fail: true };
+        let err = binchunk::undump_from(stream).err();
+        assert_eq!(err, Some(ChunkError::Io(io::ErrorKind::ConnectionReset)));
+    }
+}
+
+#[cfg(test)]
+mod test_chunk_54 {
+
+    use crate::binchunk;
+    use binchunk::binary_chunk::Constant;
+    use binchunk::chunk_error::ChunkError;
+    use binchunk::listing;
+
+    fn string(data: &mut Vec<u8>, s: &str) {
+        data.push(0x80 | (s.len() as u8 + 1));
+        data.extend_from_slice(s.as_bytes());
+    }
+
+    #[test]
+    fn test() {
+        let mut data = vec![0x1b, 0x4c, 0x75, 0x61, 0x54, 0x00];
+        data.extend_from_slice(&[0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a]);
+        data.extend_from_slice(&[4, 8, 8]);
+        data.extend_from_slice(&0x5678i64.to_le_bytes());
+        data.extend_from_slice(&370.5f64.to_le_bytes());
+        data.push(1); // size_upvalues
+
+        // main function
+        string(&mut data, "@t.lua");
+        data.extend_from_slice(&[0x80, 0x80, 0, 1, 2]);
+        data.push(0x82);
+        data.extend_from_slice(&0x00000051u32.to_le_bytes());
+        data.extend_from_slice(&0x01000046u32.to_le_bytes());
+        data.push(0x83);
+        data.push(0x03);
+        data.extend_from_slice(&7i64.to_le_bytes());
+        data.push(0x11);
+        data.push(0x04);
+        string(&mut data, "hi");
+        data.extend_from_slice(&[0x81, 1, 0, 0]); // upvalues
+        data.push(0x81); // protos
+
+        // nested function, line 300 recorded as absolute line info
+        data.extend_from_slice(&[0x80, 0x82, 0x83, 1, 0, 2]);
+        data.push(0x81);
+        data.extend_from_slice(&0x01000046u32.to_le_bytes());
+        data.extend_from_slice(&[0x80, 0x80, 0x80]);
+        data.extend_from_slice(&[0x81, 0x80]);
+        data.extend_from_slice(&[0x81, 0x80, 0x02, 0xac]);
+        data.push(0x81);
+        string(&mut data, "a");
+        data.extend_from_slice(&[0x80, 0x81]);
+        data.push(0x80);
+
+        // main debug info
+        data.extend_from_slice(&[0x82, 1, 1]);
+        data.push(0x80);
+        data.push(0x80);
+        data.push(0x81);
+        string(&mut data, "_ENV");
+
+        let chunk = binchunk::undump(&data).expect("Cannot load chunk");
+        let main = &chunk.main_func;
+        assert_eq!(chunk.header.version, 0x54);
+        assert_eq!(main.version, 0x54);
+        assert_eq!(main.code.len(), 2);
+        assert!(matches!(main.constants[0], Constant::Integer(7)));
+        assert!(matches!(main.constants[1], Constant::Boolean(true)));
+        assert!(matches!(&main.constants[2], Constant::Str(s) if s == b"hi"));
+        assert_eq!(main.upvalues[0].instack, 1);
+        assert_eq!(main.line_info, vec![1, 2]);
+        assert_eq!(main.upvalue_names, vec![String::from("_ENV")]);
+
+        let f = &main.protos[0];
+        assert_eq!(f.source, "@t.lua");
+        assert_eq!((f.line_defined, f.last_line_defined, f.num_params), (2, 3, 1));
+        assert_eq!(f.line_info, vec![300]);
+        assert_eq!(f.abs_line_info[0].line, 300);
+        assert_eq!(f.loc_vars[0].var_name, "a");
+        assert_eq!(f.loc_vars[0].end_pc, 1);
+
+        // listed with the names of 5.4, and not written as 5.3
+        let mut out = Vec::new();
+        listing::print_code(&mut out, main).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "\t1\t[1]\tVARARGPREP\t0 0 0\n\t2\t[2]\tRETURN   \t0 0 1\n");
+        let err = binchunk::dump(&chunk).err();
+        assert_eq!(err, Some(ChunkError::VersionMismatch { expected: 0x53, found: 0x54 }));
+    }
+}
+
+#[cfg(test)]
+mod test_chunk_51 {
+
+    use crate::{vm, binchunk};
+    use vm::instruction::Instruction;
+    use binchunk::binary_chunk::Constant;
+
+    fn int(data: &mut Vec<u8>, n: u32) {
+        data.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn string(data: &mut Vec<u8>, s: &str) {
+        data.extend_from_slice(&(s.len() as u64 + 1).to_le_bytes());
+        data.extend_from_slice(s.as_bytes());
+        data.push(0);
+    }
+
+    fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+        b << 23 | c << 14 | a << 6 | op
+    }
+
+    fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+        ((sbx + 131071) as u32) << 14 | a << 6 | op
+    }
+
+    #[test]
+    fn test() {
+        let mut data = vec![0x1b, 0x4c, 0x75, 0x61, 0x51, 0x00, 1, 4, 8, 4, 8, 0];
+
+        // main function:
+        //   local a, b; g = <closure capturing a>; do local c end; y = 1.0
+        string(&mut data, "@old.lua");
+        int(&mut data, 0);
+        int(&mut data, 0);
+        data.extend_from_slice(&[0, 0, 2, 3]); // nups, params, vararg, stack
+        let code = [
+            abc(3, 0, 1, 0),         // LOADNIL 0 1
+            asbx(22, 0, 1),          // JMP to CLOSURE
+            abc(5, 2, 0, 0),         // GETGLOBAL 2 "g"
+            abc(36, 2, 0, 0),        // CLOSURE 2 0
+            abc(0, 0, 0, 0),         //   MOVE 0 0 (upvalue a)
+            299 << 14 | 2 << 6 | 7,  // SETGLOBAL 2 K(299)
+            abc(35, 2, 0, 0),        // CLOSE 2
+            abc(30, 0, 1, 0),        // RETURN 0 1
+        ];
+        int(&mut data, code.len() as u32);
+        code.iter().for_each(|i| int(&mut data, *i));
+        int(&mut data, 300);
+        data.push(4);
+        string(&mut data, "g");
+        data.push(3);
+        data.extend_from_slice(&1.0f64.to_le_bytes());
+        data.extend(std::iter::repeat_n(0, 297)); // nil
+        data.push(4);
+        string(&mut data, "y");
+        int(&mut data, 1); // protos
+
+        // nested function: return a
+        data.extend_from_slice(&0u64.to_le_bytes());
+        int(&mut data, 1);
+        int(&mut data, 1);
+        data.extend_from_slice(&[1, 0, 0, 2]);
+        int(&mut data, 2);
+        int(&mut data, abc(4, 0, 0, 0)); // GETUPVAL 0 0
+        int(&mut data, abc(30, 0, 2, 0)); // RETURN 0 2
+        int(&mut data, 0);
+        int(&mut data, 0);
+        int(&mut data, 0);
+        int(&mut data, 0);
+        int(&mut data, 1);
+        string(&mut data, "a");
+
+        // main debug info
+        int(&mut data, code.len() as u32);
+        (1..=code.len() as u32).for_each(|l| int(&mut data, l));
+        int(&mut data, 1);
+        string(&mut data, "a");
+        int(&mut data, 1);
+        int(&mut data, 8);
+        int(&mut data, 0);
+
+        let chunk = binchunk::undump(&data).expect("Cannot load chunk");
+        assert_eq!(chunk.header.version, 0x51);
+        assert_eq!(chunk.main_func.upvalues.len(), 0);
+        assert_eq!(chunk.main_func.protos[0].upvalues.len(), 1);
+
+        let chunk = binchunk::translate_51(chunk).expect("Cannot translate chunk");
+        let main = &chunk.main_func;
+        let names: Vec<_> = main.code.iter().map(|i| i.opname().trim_end()).collect();
+        assert_eq!(names, ["LOADNIL", "JMP", "GETTABUP", "CLOSURE", "LOADK", "SETTABUP", "JMP", "RETURN"]);
+        assert_eq!(main.code[0].abc(), (0, 1, 0));
+        assert_eq!(main.code[1].a_sbx(), (0, 1));
+        assert_eq!(main.code[2].abc(), (2, 0, 0x100));
+        assert_eq!(main.code[4].a_bx(), (3, 299));
+        assert_eq!(main.code[5].abc(), (0, 3, 2));
+        assert_eq!(main.code[6].a_sbx(), (3, 0));
+        assert_eq!(main.max_stack_size, 4);
+        assert_eq!(main.line_info, vec![1, 2, 3, 4, 6, 6, 7, 8]);
+        assert_eq!((main.loc_vars[0].start_pc, main.loc_vars[0].end_pc), (1, 8));
+        // still a float, as all 5.1 numbers are
+        assert!(matches!(main.constants[1], Constant::Number(n) if n == 1.0));
+        assert_eq!((main.upvalues[0].instack, main.upvalues[0].idx), (1, 0));
+        assert_eq!(main.upvalue_names, vec![String::from("_ENV")]);
+
+        let f = &main.protos[0];
+        assert_eq!(f.source, "@old.lua");
+        assert_eq!((f.upvalues[0].instack, f.upvalues[0].idx), (1, 0));
+        assert_eq!((f.upvalues[1].instack, f.upvalues[1].idx), (0, 0));
+        assert_eq!(f.upvalue_names, vec![String::from("a"), String::from("_ENV")]);
+    }
+}
+
+#[cfg(test)]
+mod test_chunk_platform {
+
+    use crate::binchunk;
+    use binchunk::binary_chunk::Constant;
+
+    // a 32-bit big-endian target with float lua_Number
+    fn int(data: &mut Vec<u8>, n: i32) {
+        data.extend_from_slice(&n.to_be_bytes());
+    }
+
+    #[test]
+    fn test() {
+        let mut data = vec![0x1b, 0x4c, 0x75, 0x61, 0x53, 0x00];
+        data.extend_from_slice(&[0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a]);
+        data.extend_from_slice(&[4, 4, 4, 4, 4]);
+        int(&mut data, 0x5678);
+        data.extend_from_slice(&370.5f32.to_be_bytes());
+        data.push(1);
+
+        data.extend_from_slice(&[8, b'@', b'e', b'm', b'b', b'.', b'l', b'u']);
+        int(&mut data, 0);
+        int(&mut data, 0);
+        data.extend_from_slice(&[0, 1, 2]);
+        int(&mut data, 1);
+        data.extend_from_slice(&0x00800026u32.to_be_bytes());
+        int(&mut data, 3);
+        data.push(0x13);
+        int(&mut data, -2);
+        data.push(0x03);
+        data.extend_from_slice(&0.5f32.to_be_bytes());
+        data.push(0x14);
+        data.push(0xff);
+        int(&mut data, 301);
+        data.extend_from_slice(&[b'x'; 300]);
+        int(&mut data, 0);
+        int(&mut data, 0);
+        int(&mut data, 1);
+        int(&mut data, 7);
+        int(&mut data, 0);
+        int(&mut data, 0);
+
+        let chunk = binchunk::undump(&data).expect("Cannot load chunk");
+        assert_eq!((chunk.header.sizet_size, chunk.header.lua_number_size), (4, 4));
+        assert_eq!(chunk.header.luac_num, 370.5);
+        let main = &chunk.main_func;
+        assert_eq!(main.source, "@emb.lu");
+        assert_eq!(main.code, vec![0x00800026]);
+        assert!(matches!(main.constants[0], Constant::Integer(-2)));
+        assert!(matches!(main.constants[1], Constant::Number(n) if n == 0.5));
+        assert!(matches!(&main.constants[2], Constant::Str(s) if s.len() == 300));
+        assert_eq!(main.line_info, vec![7]);
+
+        // the widened chunk can be written back in the native format
+        let native = binchunk::undump(&binchunk::dump(&chunk).unwrap()).expect("Cannot load chunk");
+        assert_eq!(native.main_func.code, vec![0x00800026]);
+    }
+}
+
+#[cfg(test)]
+mod test_verify {
+
+    use std::rc::Rc;
+    use crate::binchunk;
+    use binchunk::binary_chunk::*;
+    use binchunk::verify_error::VerifyError;
+
+    fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+        b << 23 | c << 14 | a << 6 | op
+    }
+
+    fn abx(op: u32, a: u32, bx: u32) -> u32 {
+        bx << 14 | a << 6 | op
+    }
+
+    fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+        abx(op, a, (sbx + 131071) as u32)
+    }
+
+    fn proto(code: Vec<u32>) -> Prototype {
+        Prototype {
+            version: 0x53,
+            source: String::from("@verify.lua"),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_stack_size: 3,
+            code,
+            constants: vec![Constant::Integer(1), Constant::Str(b"x".to_vec())],
+            upvalues: vec![Upvalue { instack: 1, idx: 0, kind: 0 }],
+            protos: vec![],
+            line_info: vec![],
+            abs_line_info: vec![],
+            loc_vars: vec![],
+            upvalue_names: vec![],
+        }
+    }
+
+    #[test]
+    fn test() {
+        let valid = vec![
+            abx(1, 0, 0),               // LOADK 0 K(0)
+            abc(6, 1, 0, 0x101),        // GETTABUP 1 U(0) K(1)
+            abc(13, 2, 0, 0x100),       // ADD 2 0 K(0)
+            abc(31, 1, 0, 2),           // EQ 1 0 2
+            asbx(30, 0, -5),            // JMP -> 0
+            abx(2, 1, 0), 1 << 6 | 46,  // LOADKX 1; EXTRAARG 1
+            abc(38, 0, 1, 0),           // RETURN 0 1
+        ];
+        assert_eq!(binchunk::verify(&proto(valid)), Ok(()));
+
+        let err = binchunk::verify(&proto(vec![abc(0, 3, 0, 0), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::RegisterOutOfRange { pc: 0, reg: 3, max_stack_size: 3 }));
+
+        let err = binchunk::verify(&proto(vec![abc(13, 0, 0x102, 0), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::ConstantOutOfRange { pc: 0, idx: 2, len: 2 }));
+
+        let err = binchunk::verify(&proto(vec![abx(1, 0, 5), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::ConstantOutOfRange { pc: 0, idx: 5, len: 2 }));
+
+        let err = binchunk::verify(&proto(vec![abc(5, 0, 1, 0), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::UpvalueOutOfRange { pc: 0, idx: 1, len: 1 }));
+
+        let err = binchunk::verify(&proto(vec![asbx(30, 0, 1), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::JumpOutOfRange { pc: 0, target: 2 }));
+
+        let err = binchunk::verify(&proto(vec![abx(2, 0, 0), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::MissingExtraArg { pc: 0 }));
+
+        let err = binchunk::verify(&proto(vec![46, abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::UnexpectedExtraArg { pc: 0 }));
+
+        let err = binchunk::verify(&proto(vec![abc(0, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::FallsOffEnd { pc: 0 }));
+
+        let err = binchunk::verify(&proto(vec![abx(44, 0, 0), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::ProtoOutOfRange { pc: 0, idx: 0, len: 0 }));
+
+        // jumps to the second word of LOADKX or SETLIST
+        let err = binchunk::verify(&proto(vec![abx(2, 0, 0), 46, asbx(30, 0, -2), abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::JumpIntoInstruction { pc: 2, target: 1 }));
+        let err = binchunk::verify(&proto(vec![asbx(30, 0, 1), abc(43, 0, 1, 0), 1 << 6 | 46, abc(38, 0, 1, 0)]));
+        assert_eq!(err, Err(VerifyError::JumpIntoInstruction { pc: 0, target: 2 }));
+
+        // errors in nested functions are reported with their index
+        let mut f = proto(vec![abx(44, 0, 1), abc(38, 0, 1, 0)]);
+        let mut bad = proto(vec![abc(38, 0, 1, 0)]);
+        bad.upvalues[0].idx = 3;
+        f.protos.push(Rc::new(proto(vec![abc(38, 0, 1, 0)])));
+        f.protos.push(Rc::new(bad));
+        let err = binchunk::verify(&f);
+        assert_eq!(err, Err(VerifyError::InProto { idx: 1, err: Box::new(VerifyError::BadUpvalue { idx: 0 }) }));
+    }
+}
+
+#[cfg(test)]
+mod test_byte_strings {
+
+    use crate::{vm, binchunk};
+    use crate::api::lua_state::LuaAPI;
+    use crate::api::lua_vm::LuaVM;
+    use crate::state::lua_state::LuaState;
+    use vm::instruction::Instruction;
+    use binchunk::binary_chunk::*;
+
+    #[test]
+    fn test() {
+        let main_func = Prototype {
+            version: 0x53,
+            source: String::from("@bytes.lua"),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: 1,
+            max_stack_size: 4,
+            code: vec![
+                0x00000001, // LOADK 0 K(0)
+                0x00004041, // LOADK 1 K(1)
+                0x0000409d, // CONCAT 2 0 1
+                0x010000dc, // LEN 3 2
+                0x00800026, // RETURN 0 1
+            ],
+            constants: vec![Constant::Str(vec![0xff, 0x00]), Constant::Str(b"\xe9t\xe9".to_vec())],
+            upvalues: vec![],
+            protos: vec![],
+            line_info: vec![],
+            abs_line_info: vec![],
+            loc_vars: vec![],
+            upvalue_names: vec![],
+        };
+        let chunk = BinaryChunk {
+            header: Header {
+                signature: [0x1b, 0x4c, 0x75, 0x61],
+                version: 0x53,
+                format: 0,
+                luac_data: [0x19, 0x93, 0x0d, 0x0a, 0x1a, 0x0a],
+                cint_size: 4,
+                sizet_size: 8,
+                instruction_size: 4,
+                lua_integer_size: 8,
+                lua_number_size: 8,
+                luac_int: 0x5678,
+                luac_num: 370.5,
+            },
+            size_upvalues: 0,
+            main_func: Box::new(main_func),
+        };
+        let chunk = binchunk::undump(&binchunk::dump(&chunk).unwrap()).expect("Cannot load chunk");
+
+        let mut ls = LuaState::new(8, *chunk.main_func);
+        ls.set_top(4);
+        loop {
+            let inst = ls.fetch();
+            if inst.opcode() == 0x26 {
+                break;
+            }
+            inst.execute(&mut ls);
+        }
+        assert_eq!(ls.to_bytes(1), vec![0xff, 0x00]);
+        assert_eq!(ls.to_bytes(3), b"\xff\x00\xe9t\xe9".to_vec());
+        assert_eq!(ls.to_integer(4), 5);
+
+        ls.push_bytes(vec![0x00, 0x80]);
+        assert!(ls.is_string(-1));
+        assert_eq!(ls.to_bytes(-1), vec![0x00, 0x80]);
+        assert_eq!(ls.to_string(-1), "\u{0}\u{fffd}");
+    }
+}
+
+#[cfg(test)]
+mod test_asm {
+
+    use crate::{asm, binchunk};
+    use asm::asm_error::AsmError;
+    use binchunk::binary_chunk::Constant;
+
+    #[test]
+    fn test() {
+        let f = asm::assemble(r#"
+            .params 2
+            .const "a\tb\x41\066"
+            .const 2.5
+            .const -7
+            .const true
+            ADD         0 1 -2
+            [7] LOADK   2 -1
+            JMP         0 -3
+            RETURN      0 1
+            EXTRAARG    -2
+            .function
+            .end
+            .function 3 5
+              .vararg
+              CLOSURE   0 0
+              .function
+                RETURN  0 1
+              .end
+            .end
+        "#).unwrap();
+        assert_eq!(f.num_params, 2);
+        assert_eq!(f.is_vararg, 1);
+        assert_eq!(f.max_stack_size, 3);
+        assert_eq!(f.code, vec![0x00c0400d, 0x00000081, 0x7fff001e, 0x00800026, 0x0000006e]);
+        assert_eq!(f.line_info, vec![0, 7, 7, 7, 7]);
+        assert!(matches!(&f.constants[0], Constant::Str(s) if s == b"a\tbAB"));
+        assert!(matches!(f.constants[1], Constant::Number(n) if n == 2.5));
+        assert!(matches!(f.constants[2], Constant::Integer(-7)));
+        assert!(matches!(f.constants[3], Constant::Boolean(true)));
+        assert_eq!(f.protos.len(), 2);
+        assert_eq!(f.protos[1].line_defined, 3);
+        assert_eq!(f.protos[1].is_vararg, 1);
+        assert_eq!(f.protos[1].protos.len(), 1);
+        assert_eq!(f.protos[0].is_vararg, 0);
+
+        let err = |src: &str| asm::assemble(src).err();
+        assert_eq!(err("MOVE 0"), Some(AsmError::new(1, "MOVE: missing operand B")));
+        assert_eq!(err("\nMOVE 0 1 2"), Some(AsmError::new(2, "MOVE: too many operands")));
+        assert_eq!(err("FOO 1"), Some(AsmError::new(1, "unknown opcode 'FOO'")));
+        assert_eq!(err("ADD 0 1 -257"), Some(AsmError::new(1, "ADD: operand RK out of range: 256 (max 255)")));
+        assert_eq!(err("ADD 0 256 1"), Some(AsmError::new(1, "ADD: operand B out of range: 256 (max 255)")));
+        assert_eq!(err("MOVE 0 -1"), Some(AsmError::new(1, "MOVE: operand B out of range: -1 (max 511)")));
+        assert_eq!(err(".const \"abc"), Some(AsmError::new(1, "unfinished string")));
+        assert_eq!(err(".function\nRETURN 0 1"), Some(AsmError::new(1, ".function without .end")));
+        assert_eq!(err(".end"), Some(AsmError::new(1, ".end without .function")));
+        assert_eq!(err(".bogus"), Some(AsmError::new(1, "unknown directive '.bogus'")));
+    }
+}
+
+#[cfg(test)]
+mod test_encode {
+
+    use crate::vm;
+    use vm::encode_error::EncodeError;
+    use vm::instruction::*;
+    use vm::opcodes::*;
+
+    #[test]
+    fn test() {
+        let i = u32::encode_abc(OP_ADD, 0, 1, rk_ask(1).unwrap()).unwrap();
+        assert_eq!(i, 0x00c0400d);
+        assert_eq!(i.abc(), (0, 1, 0x101));
+        assert!(is_k(i.abc().2));
+        assert_eq!(index_k(i.abc().2), 1);
+        assert!(!is_k(i.abc().1));
+
+        let i = u32::encode_abx(OP_LOADK, 255, MAXARG_BX).unwrap();
+        assert_eq!(i.a_bx(), (255, MAXARG_BX));
+        for sbx in [-MAXARG_SBX, -1, 0, 1, MAXARG_SBX + 1] {
+            let i = u32::encode_asbx(OP_JMP, 1, sbx).unwrap();
+            assert_eq!(i.a_sbx(), (1, sbx));
+        }
+        let i = u32::encode_ax(OP_EXTRAARG, MAXARG_AX).unwrap();
+        assert_eq!(i.ax(), MAXARG_AX);
+
+        // past the table, as bytecode that was not verified can be
+        assert_eq!(63u32.opname(), "UNKNOWN ");
+
+        let out_of_range = |field, value, max| Err(EncodeError::OperandOutOfRange { field, value, max });
+        assert_eq!(u32::encode_abc(OP_MOVE, 256, 0, 0), out_of_range("A", 256, 255));
+        assert_eq!(u32::encode_abc(OP_MOVE, 0, 512, 0), out_of_range("B", 512, 511));
+        assert_eq!(u32::encode_abc(OP_MOVE, 0, 0, -1), out_of_range("C", -1, 511));
+        assert_eq!(u32::encode_abx(OP_LOADK, 0, MAXARG_BX + 1), out_of_range("Bx", MAXARG_BX + 1, MAXARG_BX));
+        assert_eq!(u32::encode_asbx(OP_JMP, 0, -MAXARG_SBX - 1), out_of_range("sBx", -MAXARG_SBX - 1, MAXARG_SBX + 1));
+        assert_eq!(u32::encode_ax(OP_EXTRAARG, MAXARG_AX + 1), out_of_range("Ax", MAXARG_AX + 1, MAXARG_AX));
+        assert_eq!(rk_ask(MAXINDEXRK + 1), Err(EncodeError::OperandOutOfRange { field: "RK", value: 256, max: 255 }));
+
+        assert_eq!(u32::encode_abc(48, 0, 0, 0), Err(EncodeError::InvalidOpcode(48)));
+        assert_eq!(u32::encode_abc(OP_JMP, 0, 0, 0), Err(EncodeError::WrongMode { opname: "JMP     " }));
+        assert_eq!(u32::encode_abx(OP_MOVE, 0, 0), Err(EncodeError::WrongMode { opname: "MOVE    " }));
+    }
+}
+
+#[cfg(test)]
+mod test_json {
+
+    use std::rc::Rc;
+    use crate::{asm, binchunk};
+    use binchunk::binary_chunk::*;
+    use binchunk::header_const::*;
+    use binchunk::json_error::JsonError;
+
+    fn chunk() -> BinaryChunk {
+        let mut main_func = asm::assemble(r#"
+            .source "@json.lua"
+            .upvalue _ENV 1 0
+            .const "a \"quoted\"\nline"
+            .const 1.5
+            .const -3
+            .const nil
+            .const false
+            .const "\xff\xfe"
+            .local t 2 5
+            1 [1] NEWTABLE  0 0 0
+            2 [2] SETTABLE  0 -1 -2
+            3 [3] CLOSURE   1 0
+            4 [3] JMP       0 -2
+            5 [4] RETURN    0 1
+            .function 3 3
+              .upvalue t 1 0
+              1 [3] GETUPVAL  0 0
+              2 [3] RETURN    0 2
+            .end
+        "#).unwrap();
+        main_func.constants.push(Constant::Number(f64::INFINITY));
+        main_func.constants.push(Constant::Number(1e300));
+        let inner = Rc::get_mut(&mut main_func.protos[0]).unwrap();
+        inner.abs_line_info.push(AbsLineInfo { pc: 0, line: 3 });
+        inner.upvalue_names.clear();
+        BinaryChunk {
+            header: Header {
+                signature: LUA_SIGNATURE,
+                version: LUAC_VERSION,
+                format: LUAC_FORMAT,
+                luac_data: LUAC_DATA,
+                cint_size: CINT_SIZE,
+                sizet_size: CSIZET_SIZE,
+                instruction_size: INSTRUCTION_SIZE,
+                lua_integer_size: LUA_INTEGER_SIZE,
+                lua_number_size: LUA_NUMBER_SIZE,
+                luac_int: LUAC_INT,
+                luac_num: LUAC_NUM,
+            },
+            size_upvalues: 1,
+            main_func,
+        }
+    }
+
+    #[test]
+    fn test() {
+        let chunk = chunk();
+        let json = binchunk::to_json(&chunk);
+        assert!(json.contains(r#"{"pc": 1, "line": 2, "word": 2151694346, "op": "SETTABLE", "mode": "iABC", "a": 0, "b": 256, "c": 257}"#));
+        assert!(json.contains(r#"{"type": "string", "value": "a \"quoted\"\nline"}"#));
+        assert!(json.contains(r#"{"type": "string", "bytes": "fffe"}"#));
+        assert!(json.contains(r#"{"type": "number", "value": "inf"}"#));
+        assert!(json.contains(r#"{"name": null, "instack": 1, "idx": 0, "kind": 0}"#));
+
+        let back = binchunk::from_json(&json).unwrap();
+        assert_eq!(binchunk::dump(&back).unwrap(), binchunk::dump(&chunk).unwrap());
+        assert_eq!(back.main_func.protos[0].abs_line_info.len(), 1);
+        assert_eq!(binchunk::to_json(&back), json);
+
+        // instructions may be given by name and operands alone
+        let edited = json.replace(r#""word": 2151694346, "#, "").replace(r#""b": 256, "c": 257"#, r#""b": 256, "c": 1"#);
+        let back = binchunk::from_json(&edited).unwrap();
+        assert_eq!(back.main_func.code[1], 0x8000400a);
+
+        let edited = json.replace(r#""word": 2151694346, "#, "").replace(r#""b": 256"#, r#""b": 512"#);
+        assert!(matches!(binchunk::from_json(&edited), Err(JsonError::Encode { path, .. }) if path == "main.code[1]"));
+
+        let edited = json.replacen(r#""max_stack_size": 2,"#, "", 1);
+        assert_eq!(binchunk::from_json(&edited).err(), Some(JsonError::Missing { path: String::from("main.max_stack_size") }));
+
+        let edited = json.replace(r#""schema": 1"#, r#""schema": 2"#);
+        assert_eq!(
+            binchunk::from_json(&edited).err(),
+            Some(JsonError::Invalid { path: String::from("schema"), reason: "unsupported schema version" })
+        );
+
+        assert_eq!(binchunk::from_json("{\"schema\": 1,}").err(), Some(JsonError::Syntax { offset: 13, msg: "expected a key" }));
+        assert_eq!(
+            binchunk::from_json(&"[".repeat(1_000_000)).err(),
+            Some(JsonError::Syntax { offset: 500, msg: "too deeply nested" })
+        );
+    }
+
+    #[test]
+    fn test_54() {
+        // the instructions of 5.4 functions are named from the 5.4 table
+        let mut chunk = chunk();
+        chunk.header.version = LUAC_VERSION_54;
+        chunk.main_func.version = LUAC_VERSION_54;
+        chunk.main_func.code = vec![0x80020001, 0x1803c, 0x7ffffeb8, 0xcf, 0x48];
+        let json = binchunk::to_json(&chunk);
+        assert!(json.contains(r#""op": "LOADI", "mode": "iAsBx", "a": 0, "sbx": 5}"#));
+        assert!(json.contains(r#""op": "EQK", "mode": "iABC", "a": 0, "k": true, "b": 1, "c": 0}"#));
+        assert!(json.contains(r#""op": "JMP", "mode": "isJ", "sj": -2}"#));
+        assert!(json.contains(r#""op": "RETURN1", "mode": "iABC", "a": 0, "k": false, "b": 0, "c": 0}"#));
+        // the nested function is still a 5.3 one
+        assert!(json.contains(r#""op": "GETUPVAL", "mode": "iABC", "a": 0, "b": 0, "c": 0}"#));
+
+        let mut edited = json.clone();
+        for word in &chunk.main_func.code {
+            edited = edited.replace(&format!(r#""word": {}, "#, word), "");
+        }
+        let back = binchunk::from_json(&edited).unwrap();
+        assert_eq!(back.main_func.code, chunk.main_func.code);
+        assert_eq!(binchunk::to_json(&back), json);
+
+        let edited = edited.replace(r#""sj": -2"#, r#""sj": 16777217"#);
+        assert!(matches!(binchunk::from_json(&edited), Err(JsonError::Encode { path, .. }) if path == "main.code[2]"));
+    }
+}
+
+#[cfg(test)]
+mod test_optimizer {
+
+    use crate::{asm, binchunk, optimizer};
+    use crate::api::lua_state::LuaAPI;
+    use crate::api::lua_vm::LuaVM;
+    use crate::state::lua_state::LuaState;
+    use crate::vm::instruction::Instruction;
+    use binchunk::binary_chunk::{Constant, Prototype};
+
+    const PROGRAM: &str = r#"
+        .stack 8
+        .const 2
+        .const 3
+        .const 10
+        .const "unused"
+        .const 2
+        .const 1
+        .local x 1 20
+        1   [1] ADD         0 -1 -2     ; R0 = 5
+        2   [2] MOVE        1 1
+        3   [2] MOVE        2 0
+        4   [2] MOVE        0 2
+        5   [3] JMP         0 2         ; to 8
+        6   [4] LOADK       0 -3
+        7   [4] RETURN      0 1
+        8   [5] JMP         0 1         ; to 10
+        9   [5] LOADK       0 -3
+        10  [6] MUL         3 -5 -3     ; R3 = 20
+        11  [7] LOADK       4 -6
+        12  [7] LOADK       5 -3
+        13  [7] LOADK       6 -6
+        14  [7] FORPREP     4 1         ; to 16
+        15  [8] ADD         3 3 7
+        16  [7] FORLOOP     4 -2        ; to 15
+        17  [9] EQ          1 0 -3
+        18  [9] JMP         0 0
+        19  [10] LOADK      0 -2
+        20  [11] RETURN     0 1
+    "#;
+
+    // runs `proto` up to its first RETURN and returns its registers
+    fn run(proto: Prototype) -> Vec<Option<i64>> {
+        let n_regs = proto.max_stack_size as isize;
+        let mut ls = LuaState::new((n_regs + 8) as usize, proto);
+        ls.set_top(n_regs);
+        loop {
+            let inst = ls.fetch();
+            if inst.opcode() == 0x26 {
+                break;
+            }
+            inst.execute(&mut ls);
+        }
+        (1..=n_regs).map(|i| ls.to_integerx(i)).collect()
+    }
+
+    #[test]
+    fn test() {
+        let before = *asm::assemble(PROGRAM).unwrap();
+        let after = optimizer::optimize(*asm::assemble(PROGRAM).unwrap());
+        binchunk::verify(&after).unwrap();
+
+        assert_eq!(after.code.len(), 13);
+        assert_eq!(after.line_info, vec![1, 2, 6, 7, 7, 7, 7, 8, 7, 9, 9, 10, 11]);
+        assert_eq!((after.loc_vars[0].start_pc, after.loc_vars[0].end_pc), (0, 12));
+        let constants: Vec<i64> = after.constants.iter().map(|k| match k {
+            Constant::Integer(i) => *i,
+            _ => panic!("unexpected constant"),
+        }).collect();
+        assert_eq!(constants, vec![3, 10, 1, 5, 20]);
+
+        let expected = run(before);
+        assert_eq!(expected[..4], [Some(3), None, Some(5), Some(75)]);
+        assert_eq!(run(after), expected);
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        // division by zero and NaN are left for run time
+        let f = optimizer::optimize(*asm::assemble(r#"
+            .const 1
+            .const 0
+            .const 0.0
+            IDIV    0 -1 -2
+            DIV     1 -3 -3
+            ADD     1 -1 -1
+            RETURN  0 1
+        "#).unwrap());
+        assert_eq!(f.code[0].opname().trim_end(), "IDIV");
+        assert_eq!(f.code[1].opname().trim_end(), "DIV");
+        assert_eq!(f.code[2].opname().trim_end(), "LOADK");
+
+        // threading a cycle of jumps ends, with the function still looping
+        let f = optimizer::optimize(*asm::assemble(r#"
+            JMP     0 1
+            JMP     0 -2
+            JMP     0 -2
+        "#).unwrap());
+        binchunk::verify(&f).unwrap();
+        assert_eq!(f.code.len(), 1);
+        assert_eq!(f.code[0].a_sbx(), (0, -1));
+    }
+}
+
+#[cfg(test)]
+mod test_lexer {
+
+    use crate::compiler;
+    use compiler::lexer::tokenize;
+    use compiler::token::TokenKind::{self, *};
+
+    fn kinds(src: &str) -> Vec<TokenKind> {
+        tokenize("=test", src.as_bytes()).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    fn error(src: &str) -> String {
+        tokenize("=test", src.as_bytes()).unwrap_err().to_string()
+    }
+
+    fn name(s: &str) -> TokenKind {
+        Name(String::from(s))
+    }
+
+    fn string(s: &[u8]) -> TokenKind {
+        Str(s.to_vec())
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(
+            kinds("a // b >> c :: d ... .. . ~= == <= >= << ~ & | # % ^ = < > / : ;"),
+            vec![
+                name("a"), DoubleSlash, name("b"), Shr, name("c"), DoubleColon, name("d"), Dots, Concat, Dot,
+                Ne, Eq, Le, Ge, Shl, Tilde, Amp, Pipe, Hash, Percent, Caret, Assign, Lt, Gt, Slash, Colon, Semi, Eof,
+            ]
+        );
+        assert_eq!(
+            kinds("local function goto_ x_1() return nil end"),
+            vec![Local, Function, name("goto_"), name("x_1"), LParen, RParen, Return, Nil, End, Eof]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            kinds("3 3.0 0xff 0x10p-1 0xA.8P0 1e2 .5 5. 3e-2 0x.1"),
+            vec![
+                Integer(3), Number(3.0), Integer(255), Number(8.0), Number(10.5), Number(100.0), Number(0.5),
+                Number(5.0), Number(0.03), Number(0.0625), Eof,
+            ]
+        );
+        // decimal integers that overflow become floats, hexadecimal ones wrap around
+        assert_eq!(
+            kinds("9223372036854775807 9223372036854775808 0xffffffffffffffff 0x10000000000000001"),
+            vec![Integer(i64::MAX), Number(9223372036854775808.0), Integer(-1), Integer(1), Eof]
+        );
+        assert_eq!(kinds("3x"), vec![Integer(3), name("x"), Eof]);
+        assert_eq!(error("x = 1..2"), "test:1: malformed number near '1..2'");
+        assert_eq!(error("x = 3f"), "test:1: malformed number near '3f'");
+        assert_eq!(error("x = 0x"), "test:1: malformed number near '0x'");
+        assert_eq!(error("x = 1e+"), "test:1: malformed number near '1e+'");
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            kinds(r#"'a\tb' "\x41\65\066\u{48}\u{7FF}\u{10FFFF}" '\'\"\\' "a\z
+                   b" "\a\b\f\n\r\v""#),
+            vec![
+                string(b"a\tb"),
+                string("AAB\u{48}\u{7ff}\u{10ffff}".as_bytes()),
+                string(b"'\"\\"),
+                string(b"ab"),
+                string(b"\x07\x08\x0c\n\r\x0b"),
+                Eof,
+            ]
+        );
+        assert_eq!(kinds("'\\u{7FFFFFFF}'"), vec![string(&[0xfd, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf]), Eof]);
+        assert_eq!(kinds("'a\\\nb'"), vec![string(b"a\nb"), Eof]);
+        assert_eq!(
+            kinds("[[\nfoo]] [==[a]]b]=]c]==] [[x\r\ny]]"),
+            vec![string(b"foo"), string(b"a]]b]=]c"), string(b"x\ny"), Eof]
+        );
+
+        assert_eq!(error("x = 'abc"), "test:1: unfinished string near <eof>");
+        assert_eq!(error("x = 'abc\nd'"), "test:1: unfinished string near ''abc'");
+        assert_eq!(error("x = '\\q'"), "test:1: invalid escape sequence near ''\\q'");
+        assert_eq!(error("x = '\\300'"), "test:1: decimal escape too large near ''\\300''");
+        assert_eq!(error("x = '\\xZZ'"), "test:1: hexadecimal digit expected near ''\\xZ'");
+        assert_eq!(error("x = '\\u{110000000}'"), "test:1: UTF-8 value too large near ''\\u{110000000'");
+        assert_eq!(error("x = '\\u48'"), "test:1: missing '{' near ''\\u4'");
+        assert_eq!(error("x = '\\u{48'"), "test:1: missing '}' near ''\\u{48''");
+        assert_eq!(error("x = [==[abc"), "test:1: unfinished long string near <eof>");
+        assert_eq!(error("x = [==abc"), "test:1: invalid long string delimiter near '[=='");
+        assert_eq!(error("x = @"), "test:1: unexpected symbol near '@'");
+    }
+
+    #[test]
+    fn test_comments_and_positions() {
+        let src = "-- line comment\nlocal x = 1 --[==[ long\ncomment ]==] + 2\n--[ not long\n  y";
+        let tokens = tokenize("@test.lua", src.as_bytes()).unwrap();
+        let found: Vec<(TokenKind, usize, usize)> = tokens.into_iter().map(|t| (t.kind, t.line, t.column)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (Local, 2, 1), (name("x"), 2, 7), (Assign, 2, 9), (Integer(1), 2, 11),
+                (Plus, 3, 14), (Integer(2), 3, 16), (name("y"), 5, 3), (Eof, 5, 4),
+            ]
+        );
+        assert_eq!(error("--[[ open"), "test:1: unfinished long comment near <eof>");
+        assert_eq!(
+            tokenize("x = 'a", b"x = 'a").unwrap_err().to_string(),
+            "[string \"x = 'a\"]:1: unfinished string near <eof>"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_parser {
+
+    use crate::compiler;
+    use compiler::ast::*;
+
+    fn error(src: &str) -> String {
+        compiler::parse("=test", src.as_bytes()).unwrap_err().to_string()
+    }
+
+    // the expression with every operation parenthesized
+    fn shape(e: &Exp) -> String {
+        match &e.kind {
+            ExpKind::Name(n) => n.clone(),
+            ExpKind::Integer(i) => i.to_string(),
+            ExpKind::Unop { op, exp } => format!("({} {})", op.symbol(), shape(exp)),
+            ExpKind::Binop { op, lhs, rhs } => format!("({} {} {})", shape(lhs), op.symbol(), shape(rhs)),
+            ExpKind::Paren(e) => shape(e),
+            _ => format!("{:?}", e.kind),
+        }
+    }
+
+    fn exp_shape(src: &str) -> String {
+        let block = compiler::parse("=test", format!("return {}", src).as_bytes()).unwrap();
+        shape(&block.ret.unwrap().exps[0])
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(exp_shape("1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(exp_shape("1 - 2 - 3"), "((1 - 2) - 3)");
+        assert_eq!(exp_shape("a .. b .. c"), "(a .. (b .. c))");
+        assert_eq!(exp_shape("2 ^ 3 ^ 2"), "(2 ^ (3 ^ 2))");
+        assert_eq!(exp_shape("-x ^ 2"), "(- (x ^ 2))");
+        assert_eq!(exp_shape("not a == b"), "((not a) == b)");
+        assert_eq!(exp_shape("a or b and c"), "(a or (b and c))");
+        assert_eq!(exp_shape("a | b ~ c & d << 1"), "(a | (b ~ (c & (d << 1))))");
+        assert_eq!(exp_shape("1 .. 2 + 3 < 4"), "((1 .. (2 + 3)) < 4)");
+        assert_eq!(exp_shape("~ # a // - b % c"), "(((~ (# a)) // (- b)) % c)");
+        assert_eq!(exp_shape("(a + b) * c"), "((a + b) * c)");
+    }
+
+    #[test]
+    fn test_statements() {
+        let src = "local a, b = 1\n\
+                   function t.x.y:m(p, ...) return self, ... end\n\
+                   for i = 1, 10, 2 do break end\n\
+                   for k, v in pairs(t) do goto done end\n\
+                   ::done:: if a then elseif b then else end\n\
+                   repeat local z until z; while false do end\n\
+                   t[1], t.k = f{1, x = 2, [3] = 4; 5}, g'str'\n\
+                   o:m() do end local function h() end";
+        let block = compiler::parse("@t.lua", src.as_bytes()).unwrap();
+        let kinds: Vec<&StatKind> = block.stats.iter().map(|s| &s.kind).collect();
+        assert_eq!(kinds.len(), 13);
+        assert!(matches!(kinds[0], StatKind::Local { names, exps, .. } if names.len() == 2 && exps.len() == 1));
+        match kinds[1] {
+            StatKind::Function { path, method, func } => {
+                assert_eq!(path.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(), ["t", "x", "y"]);
+                assert_eq!(method.as_ref().unwrap().name, "m");
+                assert!(func.is_vararg);
+                assert_eq!(func.params.len(), 1);
+                assert_eq!(func.block.ret.as_ref().unwrap().exps.len(), 2);
+                assert_eq!((func.line, func.last_line), (2, 2));
+            }
+            k => panic!("{:?}", k),
+        }
+        assert!(matches!(kinds[2], StatKind::NumericFor { step: Some(_), line_of_do: 3, .. }));
+        assert!(matches!(kinds[3], StatKind::GenericFor { names, .. } if names.len() == 2));
+        assert_eq!(kinds[4], &StatKind::Label(String::from("done")));
+        assert!(matches!(kinds[5], StatKind::If { conds, else_block: Some(_), .. } if conds.len() == 2));
+        assert!(matches!(kinds[6], StatKind::Repeat { .. }));
+        assert_eq!(kinds[7], &StatKind::Empty);
+        assert!(matches!(kinds[8], StatKind::While { .. }));
+        match kinds[9] {
+            StatKind::Assign { vars, exps } => {
+                assert_eq!(vars.len(), 2);
+                match &exps[0].kind {
+                    ExpKind::Call { args, .. } => match &args[0].kind {
+                        ExpKind::Table { fields, .. } => {
+                            assert!(matches!(fields[0], Field::Positional(_)));
+                            assert!(matches!(fields[1], Field::Named(..)));
+                            assert!(matches!(fields[2], Field::Keyed(..)));
+                            assert_eq!(fields.len(), 4);
+                        }
+                        k => panic!("{:?}", k),
+                    },
+                    k => panic!("{:?}", k),
+                }
+                assert!(matches!(&exps[1].kind, ExpKind::Call { args, .. } if args[0].kind == ExpKind::Str(b"str".to_vec())));
+            }
+            k => panic!("{:?}", k),
+        }
+        assert!(matches!(kinds[10], StatKind::Call(Exp { kind: ExpKind::Call { method: Some(_), .. }, .. })));
+        assert!(matches!(kinds[11], StatKind::Do(_)));
+        assert!(matches!(kinds[12], StatKind::LocalFunction { .. }));
+        assert_eq!((block.stats[12].line, block.stats[12].column), (8, 14));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("x = = 1"), "test:1: unexpected symbol near '='");
+        assert_eq!(error("x"), "test:1: syntax error near <eof>");
+        assert_eq!(error("f() = 1"), "test:1: syntax error near '='");
+        assert_eq!(error("if x then"), "test:1: 'end' expected near <eof>");
+        assert_eq!(error("while x do\n\nx()"), "test:3: 'end' expected (to close 'while' at line 1) near <eof>");
+        assert_eq!(error("f(1, 2"), "test:1: ')' expected near <eof>");
+        assert_eq!(error("local 1"), "test:1: <name> expected near '1'");
+        assert_eq!(error("for x do end"), "test:1: '=' or 'in' expected near 'do'");
+        assert_eq!(error("function f(a,) end"), "test:1: <name> or '...' expected near ')'");
+        assert_eq!(error("x = a.b:c"), "test:1: function arguments expected near <eof>");
+        assert_eq!(error("return 1 x = 2"), "test:1: <eof> expected near 'x'");
+        assert_eq!(error("function f() return ... end"), "test:1: cannot use '...' outside a vararg function near '...'");
+        assert_eq!(error("x = {1 2}"), "test:1: '}' expected near '2'");
+        assert_eq!(error("x = 'abc"), "test:1: unfinished string near <eof>");
+        assert_eq!(
+            error(&format!("x = {}1", "(".repeat(300))),
+            "test:1: too many C levels (limit is 200) in main function near '('"
+        );
+        assert!(compiler::parse("=test", b"return ...").is_ok());
+    }
+}
+
+// compiling and running chunks, for the tests of the compiler
+#[cfg(test)]
+mod compile_util {
+
+    use crate::binchunk::binary_chunk::Prototype;
+    use crate::state::lua_state::LuaState;
+    use crate::vm::instruction::Instruction;
+    use crate::{binchunk, compiler};
+
+    pub fn compile(src: &str) -> Box<Prototype> {
+        let f = compiler::compile("=test", src.as_bytes()).unwrap();
+        if let Err(e) = binchunk::verify(&f) {
+            panic!("{}", e);
+        }
+        f
+    }
+
+    pub fn error(src: &str) -> String {
+        match compiler::compile("=test", src.as_bytes()) {
+            Ok(_) => panic!("compiled: {}", src),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    // runs a chunk with no libraries, leaving what it returns from 1 up
+    pub fn run(src: &str) -> LuaState {
+        let proto = *compile(src);
+        let mut ls = LuaState::new(proto.max_stack_size as usize + 8, proto);
+        ls.run();
+        ls
+    }
+
+    pub fn opcodes(f: &Prototype) -> Vec<u8> {
+        f.code.iter().map(|i| i.opcode()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test_codegen {
+
+    use super::compile_util::*;
+    use crate::api::lua_state::LuaAPI;
+    use crate::binchunk::binary_chunk::Constant;
+    use crate::compiler;
+    use crate::vm::instruction::Instruction;
+    use crate::vm::opcodes::*;
+
+    #[test]
+    fn test_run() {
+        let ls = run("
+            local sum = 0
+            for i = 1, 100 do
+              if i % 2 == 0 then sum = sum + i end
+            end
+            return sum");
+        assert_eq!(ls.to_integer(1), 2550);
+
+        let ls = run("
+            local n, steps = 27, 0
+            while n ~= 1 do
+              if n % 2 == 0 then n = n // 2 else n = 3 * n + 1 end
+              steps = steps + 1
+            end
+            local k = 0
+            repeat local d = k * 2; k = k + 1 until d >= 10
+            local s = 'x' .. k .. (steps > 100 and 'big' or 'small')
+            local a, b, c = not nil, 1 < 2 and 2 <= 1, -k
+            return steps, k, s, a, b, c");
+        assert_eq!(ls.to_integer(1), 111);
+        assert_eq!(ls.to_integer(2), 6);
+        assert_eq!(ls.to_string(3), "x6big");
+        assert!(ls.to_boolean(4));
+        assert!(!ls.to_boolean(5));
+        assert_eq!(ls.to_integer(6), -6);
+
+        let ls = run("
+            local x, y = 1, 2
+            x, y = y, x
+            local t = {x, y, n = 'n', [10] = 'ten'}
+            t[1], t.n = t.n, t[1]
+            return x, y");
+        assert_eq!(ls.to_integer(1), 2);
+        assert_eq!(ls.to_integer(2), 1);
+    }
+
+    #[test]
+    fn test_set_list() {
+        let items: Vec<String> = (1..=120).map(|i| i.to_string()).collect();
+        let src = format!("local t = {{{}}} local n, a, b = #t, t[50] + t[51], t[120] return n, a, b", items.join(", "));
+        let f = compile(&src);
+        let set_lists: Vec<(isize, isize, isize)> =
+            f.code.iter().filter(|i| i.opcode() == OP_SETLIST).map(|i| i.abc()).collect();
+        assert_eq!(set_lists, vec![(0, 50, 1), (0, 50, 2), (0, 20, 3)]);
+        assert!(f.max_stack_size <= 60);
+        let ls = run(&src);
+        assert_eq!(ls.to_integer(1), 120);
+        assert_eq!(ls.to_integer(2), 101);
+        assert_eq!(ls.to_integer(3), 120);
+
+        // past 511 batches the batch number moves to an EXTRAARG
+        let n = 50 * 512 + 3;
+        let src = format!("local t = {{{}}} local n, last = #t, t[{}] return n, last", "7, ".repeat(n - 1) + "9", n);
+        let f = compile(&src);
+        let code = &f.code;
+        let pc = code.iter().rposition(|i| i.opcode() == OP_SETLIST).unwrap();
+        assert_eq!(code[pc].abc(), (0, 3, 0));
+        assert_eq!((code[pc + 1].opcode(), code[pc + 1].ax()), (OP_EXTRAARG, 513));
+        let ls = run(&src);
+        assert_eq!(ls.to_integer(1), n as i64);
+        assert_eq!(ls.to_integer(2), 9);
+    }
+
+    #[test]
+    fn test_many_constants() {
+        // past 256 constants a global's name goes through a register, which
+        // must not stay taken between consecutive values
+        let keys: String = (0..300).map(|i| format!("t.k{} = {}\n", i, i)).collect();
+        let src = format!("t = {{}}\n{}a, b, c = 1, 2, 3\nlocal x, y, z = a, b, c\nreturn x, y, z", keys);
+        let f = compile(&src);
+        assert!(f.constants.len() > 256);
+        let ls = run(&src);
+        assert_eq!([ls.to_integer(1), ls.to_integer(2), ls.to_integer(3)], [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_long_chain() {
+        // left-associative operators reuse the target for the left operand
+        let src = format!("y = 1\nlocal x = {}\nlocal z = x * 2\nreturn x, z", vec!["y"; 100].join(" + "));
+        let f = compile(&src);
+        assert!(f.max_stack_size <= 4);
+        let ls = run(&src);
+        assert_eq!((ls.to_integer(1), ls.to_integer(2)), (100, 200));
+        // but never a local, which the right operand may still read
+        let ls = run("local a, b = 1, 2\na = b - a + b\nreturn a");
+        assert_eq!(ls.to_integer(1), 3);
+    }
+
+    #[test]
+    fn test_deep_chains() {
+        // chains nest in their left operands as deep as they are long
+        let chain = |op: &str| vec!["y"; 3000].join(op);
+        let src = format!(
+            "local y = 1\nlocal x = {}\nlocal z = {}\nif {} then x = x + 1 end\nreturn x, z",
+            chain(" + "),
+            chain(" and "),
+            chain(" or ")
+        );
+        let ls = run(&src);
+        assert_eq!((ls.to_integer(1), ls.to_integer(2)), (3001, 1));
+    }
+
+    #[test]
+    fn test_structure() {
+        let f = compile("local a = 'k' .. 'k'
+local function f(x, ...)
+  local g = function() return a, x end
+  return f(x, 'k', 'k', ...)
+end
+t = {f()}
+");
+        assert_eq!(f.source, "=test");
+        assert_eq!((f.is_vararg, f.num_params), (1, 0));
+        // 'k' .. 'k' is folded into one constant
+        assert!(matches!(&f.constants[0], Constant::Str(s) if s == b"kk"));
+        assert_eq!(f.upvalue_names, vec!["_ENV"]);
+        assert_eq!(opcodes(&f), vec![
+            OP_LOADK, OP_CLOSURE, OP_NEWTABLE, OP_MOVE, OP_CALL, OP_SETLIST, OP_SETTABUP, OP_RETURN,
+        ]);
+        assert_eq!(f.line_info, vec![1, 5, 6, 6, 6, 6, 6, 6]);
+        let locals: Vec<(&str, u32, u32)> = f.loc_vars.iter().map(|l| (l.var_name.as_str(), l.start_pc, l.end_pc)).collect();
+        assert_eq!(locals, vec![("a", 1, 8), ("f", 2, 8)]);
+
+        let f1 = &f.protos[0];
+        assert_eq!((f1.line_defined, f1.last_line_defined, f1.num_params, f1.is_vararg), (2, 5, 1, 1));
+        assert_eq!(f1.upvalue_names, vec!["a", "f"]);
+        let upvalues: Vec<(u8, u8)> = f1.upvalues.iter().map(|u| (u.instack, u.idx)).collect();
+        assert_eq!(upvalues, vec![(1, 0), (1, 1)]);
+        assert_eq!(opcodes(f1), vec![
+            OP_CLOSURE, OP_GETUPVAL, OP_MOVE, OP_LOADK, OP_LOADK, OP_VARARG, OP_TAILCALL, OP_RETURN, OP_RETURN,
+        ]);
+        // 'k' is stored once
+        assert_eq!(f1.code[3].a_bx().1, f1.code[4].a_bx().1);
+        assert_eq!(f1.code[6].abc(), (2, 0, 0));
+
+        // `a` is two levels up: the inner function captures f1's upvalue
+        let f2 = &f1.protos[0];
+        let upvalues: Vec<(&str, u8, u8)> =
+            f2.upvalues.iter().zip(&f2.upvalue_names).map(|(u, n)| (n.as_str(), u.instack, u.idx)).collect();
+        assert_eq!(upvalues, vec![("a", 0, 0), ("x", 1, 0)]);
+        assert_eq!(f2.source, "=test");
+    }
+
+    #[test]
+    fn test_close_upvalues() {
+        // leaving a scope whose locals are captured closes them with JMP A
+        let f = compile("
+            for i = 1, 2 do
+              local f = function() return i end
+              if i then break end
+            end");
+        let jumps: Vec<(isize, isize)> =
+            f.code.iter().filter(|i| i.opcode() == OP_JMP).map(|i| i.a_sbx()).collect();
+        // the break closes from `i` (register 3) and leaves the loop, and
+        // so does the end of each iteration
+        assert_eq!(jumps, vec![(0, 1), (4, 2), (4, 0)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("break"), "test:1: <break> at line 1 not inside a loop");
+        assert_eq!(error("goto nowhere"), "test:1: no visible label 'nowhere' for <goto> at line 1");
+        assert_eq!(error("::a:: ::a::"), "test:1: label 'a' already defined on line 1");
+        assert_eq!(
+            error("do goto x; local a; ::x:: print(a) end"),
+            "test:1: <goto x> at line 1 jumps into the scope of local 'a'"
+        );
+        // a label at the end of the block is outside the scope of its locals
+        assert!(compiler::compile("=test", b"do goto x; local a ::x:: end").is_ok());
+        let names: Vec<String> = (0..201).map(|i| format!("v{}", i)).collect();
+        assert_eq!(
+            error(&format!("local {}", names.join(", "))),
+            "test:1: too many local variables (limit is 200) in main function"
+        );
+        assert_eq!(
+            error(&format!("print({})", vec!["1"; 300].join(", "))),
+            "test:1: function or expression needs too many registers"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_fold {
+
+    use super::compile_util::*;
+    use crate::binchunk::binary_chunk::Constant;
+    use crate::compiler;
+    use crate::vm::instruction::Instruction;
+    use crate::vm::opcodes::*;
+
+    #[test]
+    fn test_fold() {
+        let f = compile("local a, b, c, d, e = 2^10, 'a' .. 'b' .. 1, not nil, 1 << 4, -(~0 + 0.5)");
+        assert_eq!(opcodes(&f), vec![OP_LOADK, OP_LOADK, OP_LOADBOOL, OP_LOADK, OP_LOADK, OP_RETURN]);
+        assert!(matches!(f.constants[..], [
+            Constant::Number(p), Constant::Str(ref s), Constant::Integer(16), Constant::Number(n),
+        ] if p == 1024.0 && s == b"ab1" && n == 0.5));
+
+        // and/or keep one of their operands, adjusted to one value
+        let f = compile("return nil and f(), 1 or x, false or f()");
+        assert_eq!(opcodes(&f), vec![OP_LOADNIL, OP_LOADK, OP_GETTABUP, OP_CALL, OP_RETURN, OP_RETURN]);
+        assert_eq!(f.code[3].abc(), (2, 1, 2));
+        assert_eq!(f.code[4].abc(), (0, 4, 0));
+    }
+
+    #[test]
+    fn test_no_fold() {
+        // each of these fails, overflows or yields NaN at run time
+        for (src, op) in [
+            ("return 1 // 0", OP_IDIV),
+            ("return 1 % 0", OP_MOD),
+            ("return 1.0 // 0", OP_IDIV),
+            ("return 0 / 0", OP_DIV),
+            ("return 9223372036854775807 + 1", OP_ADD),
+            ("return 1 << 64", OP_SHL),
+            ("return -(-9223372036854775807 - 1)", OP_UNM),
+            ("return 1 + 'a'", OP_ADD),
+            ("return 'a' .. {}", OP_CONCAT),
+        ] {
+            assert!(opcodes(&compile(src)).contains(&op), "{}", src);
+        }
+        assert_eq!(opcodes(&compile("return 1 / 0")), vec![OP_LOADK, OP_RETURN, OP_RETURN]);
+    }
+
+    #[test]
+    fn test_dead_branches() {
+        let only_g = vec![OP_GETTABUP, OP_CALL, OP_RETURN];
+        assert_eq!(opcodes(&compile("if false then f() elseif nil then f() else g() end")), only_g);
+        assert_eq!(opcodes(&compile("if not 1 then f() elseif 1 then g() else f() end")), only_g);
+        assert_eq!(opcodes(&compile("while false do f() end g()")), only_g);
+        // comparisons are left to the VM
+        assert!(opcodes(&compile("while 1 > 2 do f() end")).contains(&OP_LT));
+
+        // code that still has to be checked for its jumps is kept
+        assert!(opcodes(&compile("if false then goto l end ::l:: g()")).contains(&OP_JMP));
+        assert!(compiler::compile("=test", b"if false then break end").is_err());
+        assert_eq!(opcodes(&compile("while false do break end g()")), only_g);
+    }
+}
+
+#[cfg(test)]
+mod test_attribs {
+
+    use super::compile_util::*;
+    use crate::api::lua_state::LuaAPI;
+    use crate::cli::run::{execute, new_state};
+    use crate::vm::instruction::Instruction;
+    use crate::vm::opcodes::*;
+
+    #[test]
+    fn test_const() {
+        // constants take no register and are folded into their uses
+        let f = compile("
+            local N <const> = 10
+            local M <const> = N * 2
+            local function get() return M end
+            local t = M + 1");
+        assert_eq!(opcodes(&f), vec![OP_CLOSURE, OP_LOADK, OP_RETURN]);
+        let names: Vec<&str> = f.loc_vars.iter().map(|v| v.var_name.as_str()).collect();
+        assert_eq!(names, vec!["get", "t"]);
+        assert!(f.protos[0].upvalues.is_empty());
+
+        // only the last of a list, with no adjustment, and only values
+        // known when compiling
+        let f = compile("local a <const>, b <const> = 1, 2 local c <const> = {} local d <const>, e = 3");
+        let names: Vec<&str> = f.loc_vars.iter().map(|v| v.var_name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "d", "e"]);
+
+        // shadowing a constant makes the name assignable again
+        let ls = run("local x <const> = 1 do local x = x + 1 x = x * 10 end local y = x return y");
+        assert_eq!(ls.to_integer(1), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("local x <foo> = 1"), "test:1: unknown attribute 'foo'");
+        assert_eq!(error("local a <close>, b <close> = nil"), "test:1: multiple to-be-closed variables in local list");
+        assert_eq!(error("local x <const> = 1\nx = 2"), "test:2: attempt to assign to const variable 'x'");
+        assert_eq!(error("local x <const> = {}\nfunction f() x = 2 end"), "test:2: attempt to assign to const variable 'x'");
+        assert_eq!(error("local a, f <close> = 1\na, f = 2, 3"), "test:2: attempt to assign to const variable 'f'");
+        assert_eq!(error("local f <const> = print\nfunction f() end"), "test:2: attempt to assign to const variable 'f'");
+        assert_eq!(error("if false then local k <const> = 1 k = 2 end"), "test:1: attempt to assign to const variable 'k'");
+        compile("local t <const> = {} t.x = 1 function t.f() end");
+    }
+
+    #[test]
+    fn test_close() {
+        // the scope of a <close> local is closed on every way out of it
+        let f = compile("
+            for i = 1, 2 do
+              local c <close> = nil
+              if i then break end
+            end
+            do local d <close> = false end
+            local e <close> = nil
+            return f()");
+        assert!(f.code.contains(&u32::encode_abc(OP_TBC, 4, 0, 0).unwrap()));
+        let jumps: Vec<(isize, isize)> =
+            f.code.iter().filter(|i| i.opcode() == OP_JMP).map(|i| i.a_sbx()).collect();
+        assert_eq!(jumps, vec![(0, 1), (5, 2), (5, 0), (1, 0)]);
+        // the call runs before `e` gets closed, so it is not a tail call
+        assert!(!opcodes(&f).contains(&OP_TAILCALL));
+
+        let ls = run("
+            local s = 0
+            do local c <close> = nil s = 1 end
+            local t <close> = false
+            s = s + 1
+            return s");
+        assert_eq!(ls.to_integer(1), 2);
+    }
+
+    // runs `src` with the base library, giving what it returns
+    fn values(src: &str) -> Vec<String> {
+        match execute(&mut new_state(), *compile(src), &[]) {
+            Ok(values) => values,
+            Err(failure) => panic!("{}", failure.msg),
+        }
+    }
+
+    #[test]
+    fn test_close_metamethod() {
+        // closed the last first on the way out of a block, with nil as
+        // the error
+        let src = "local log = {}
+            local function closable(name)
+              return setmetatable({}, {__close = function(v, e) log[#log + 1] = name .. ':' .. tostring(e) end})
+            end
+            do
+              local a <close> = closable('a')
+              local b <close> = closable('b')
+              log[#log + 1] = 'body'
+            end
+            return log[1], log[2], log[3]";
+        assert_eq!(values(src), ["body", "b:nil", "a:nil"]);
+
+        // on break, return and errors too, where closing gets the error
+        let src = "local log = ''
+            local mt = {__close = function(v, e) log = log .. v.name .. (e and ('!' .. e) or '') .. ' ' end}
+            local function closable(name) return setmetatable({name = name}, mt) end
+            for i = 1, 3 do
+              local c <close> = closable('loop' .. i)
+              if i == 2 then break end
+            end
+            local function f()
+              local r <close> = closable('ret')
+              return 1
+            end
+            f()
+            pcall(function()
+              local x <close> = closable('x')
+              local y <close> = closable('y')
+              error('oops', 0)
+            end)
+            return log";
+        assert_eq!(values(src), ["loop1 loop2 ret y!oops x!oops "]);
+
+        // an error while closing replaces the one being unwound
+        let src = "local ok, e = pcall(function()
+              local a <close> = setmetatable({}, {__close = function() error('in close', 0) end})
+              error('first', 0)
+            end)
+            return ok, e";
+        assert_eq!(values(src), ["false", "in close"]);
+    }
+
+    #[test]
+    fn test_non_closable() {
+        // a Lua error, which pcall catches
+        let src = "local a = 1
+            return pcall(function() local c <close> = {} end)";
+        assert_eq!(values(src), ["false", "test:2: variable 'c' got a non-closable value"]);
+    }
+}
+
+#[cfg(test)]
+mod test_formatter {
+
+    use crate::compiler;
+    use crate::compiler::formatter::{format, FormatOptions, QuoteStyle};
+
+    fn fmt(src: &str) -> String {
+        format("=test", src.as_bytes(), &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(fmt("local   x=1;;y = x+ -(-x)  z=- -1"), "local x = 1\ny = x + -(-x)\nz = - -1\n");
+        assert_eq!(
+            fmt("if a then b() elseif c then else d() end while x do end"),
+            "if a then\n    b()\nelseif c then\nelse\n    d()\nend\nwhile x do end\n"
+        );
+        assert_eq!(
+            fmt("local function f(a,...) return t.x, t['y z'], t['end'], s:m'k', g{1} end"),
+            "local function f(a, ...)\n    return t.x, t[\"y z\"], t[\"end\"], s:m(\"k\"), g({1})\nend\n"
+        );
+        // numerals and long strings are kept as written
+        assert_eq!(fmt("x = 0x10 + 1e3 .. [==[a]]b]==]"), "x = 0x10 + 1e3 .. [==[a]]b]==]\n");
+        // a statement starting with '(' is kept apart from the one before
+        assert_eq!(fmt("a = b;\n(f)()"), "a = b\n;(f)()\n");
+        assert_eq!(fmt("#!/bin/lua\nx=1"), "#!/bin/lua\nx = 1\n");
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = "-- head\n\nlocal t = { -- fields\n  a = 1, -- one\n  --[[ two ]] b = 2\n}\n\n\nf() -- call\ndo\n  -- empty\nend\n-- tail\n";
+        let expected = "-- head\n\nlocal t = { -- fields\n    a = 1, -- one\n    --[[ two ]]\n    b = 2,\n}\n\nf() -- call\ndo\n    -- empty\nend\n-- tail\n";
+        assert_eq!(fmt(src), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+
+    #[test]
+    fn test_options() {
+        let options = FormatOptions {
+            indent_width: 2,
+            use_tabs: false,
+            quote_style: QuoteStyle::Single,
+            trailing_comma: false,
+            line_width: 20,
+        };
+        let out = format("=test", b"do t = {'a', \"b'c\", \"\\n\\0\"} end", &options).unwrap();
+        assert_eq!(out, "do\n  t = {\n    'a',\n    \"b'c\",\n    '\\n\\000'\n  }\nend\n");
+
+        let options = FormatOptions { use_tabs: true, line_width: 10, ..FormatOptions::default() };
+        let out = format("=test", b"if x then print(aaaa, bbbb) end", &options).unwrap();
+        assert_eq!(out, "if x then\n\tprint(\n\t\taaaa,\n\t\tbbbb\n\t)\nend\n");
+    }
+
+    #[test]
+    fn test_same_code() {
+        let src = "
+            local a, b <const> = 10, {x = 1, [2] = 'y', 'z'}
+            local function f(n, ...) if n > 0 then return f(n - 1, ...) end return ... end
+            for i = 1, #b, 2 do b[i] = not b[i] and (a or i) end
+            repeat a = a // 2 .. 'x' until a == '' or -a ~= ~a << 1
+            return f(3, 'a', \"b\")";
+        let once = fmt(src);
+        let code = |s: &str| compiler::compile("=test", s.as_bytes()).unwrap().code;
+        assert_eq!(code(src), code(&once));
+        assert_eq!(fmt(&once), once);
+        assert!(format("=test", b"x = = 1", &FormatOptions::default()).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_linter {
+
+    use crate::compiler::linter::{lint, LintOptions};
+
+    // (rule, line, column) of each lint
+    fn check(src: &str) -> Vec<(&'static str, usize, usize)> {
+        let lints = lint("=test", src.as_bytes(), &LintOptions::default()).unwrap();
+        lints.iter().map(|l| (l.rule, l.line, l.column)).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(check("print(x, _ENV, string)\nx = 1\nprint(y)"), [("undefined-global", 3, 7)]);
+        assert_eq!(
+            check("local a, _b = 1\nlocal function f(p, q) return q end\nf(1, 2)\nfor i = 1, 2 do end"),
+            [("unused-local", 1, 7), ("unused-param", 2, 18), ("unused-local", 4, 5)]
+        );
+        // only reads count as uses
+        assert_eq!(check("local a\na = 1"), [("unused-local", 1, 7)]);
+        assert_eq!(
+            check("local a = 1\ndo local a = a print(a) end\nlocal function g(a) return a end\nreturn g"),
+            [("shadowing", 2, 10), ("shadowing", 3, 18)]
+        );
+        assert_eq!(
+            check("for k, v in pairs(t) do\n  k = v .. k\n  for i = 1, 2 do f(function() i = i end) end\nend\nt, f = 1, 2"),
+            [("loop-var-assign", 2, 3), ("loop-var-assign", 3, 32)]
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        assert_eq!(check("do return end\nprint(1)\nprint(2)"), [("unreachable-code", 2, 1)]);
+        assert_eq!(check("while true do break; print(1) end"), [("unreachable-code", 1, 22)]);
+        assert_eq!(check("if type then return 1 else error() end\nreturn 2"), []);
+        assert_eq!(check("if type then return 1 else do return end end\nreturn 2"), [("unreachable-code", 2, 1)]);
+        // a label can be reached by goto
+        assert_eq!(check("goto a\nprint(1)\n::a::\nprint(2)"), [("unreachable-code", 2, 1)]);
+    }
+
+    #[test]
+    fn test_arg_count() {
+        let src = "local function f(a, b) return a, b end\n\
+                   local g = function(a, ...) return a, ... end\n\
+                   f(1) f(1, 2) f(1, 2, 3) f(1, ...) f(1, 2, f())\n\
+                   g() g(1, 2, 3)";
+        assert_eq!(
+            check(src),
+            [("arg-count", 3, 1), ("arg-count", 3, 14), ("arg-count", 3, 43), ("arg-count", 4, 1)]
+        );
+        // unknown once reassigned
+        assert_eq!(check("local function f(a) return a end\nf = print\nf(1, 2)"), []);
+    }
+
+    #[test]
+    fn test_ignore() {
+        assert_eq!(check("print(x) -- lint: ignore"), []);
+        assert_eq!(check("-- lint: ignore\nlocal a = x"), []);
+        assert_eq!(check("local a = x -- lint: ignore unused-local"), [("undefined-global", 1, 11)]);
+        assert_eq!(check("local a = x -- lint: ignore unused-local, undefined-global"), []);
+        assert_eq!(check("-- lint: ignored\nprint(x)"), [("undefined-global", 2, 7)]);
+        let options = LintOptions { globals: vec![String::from("x")] };
+        assert!(lint("=test", b"print(x)", &options).unwrap()[0].to_string().ends_with("undefined global 'print' [undefined-global]"));
+    }
+}
+
+#[cfg(test)]
+mod test_repl {
+
+    use crate::cli::repl::{eval, Eval};
+    use crate::cli::run::new_state;
+    use crate::state::lua_state::LuaState;
+
+    fn values(ls: &mut LuaState, input: &str) -> Vec<String> {
+        match eval(ls, input) {
+            Eval::Done(values) => values,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn error(ls: &mut LuaState, input: &str) -> String {
+        match eval(ls, input) {
+            Eval::Error(msg) => msg,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let ls = &mut new_state();
+        assert_eq!(values(ls, "1 + 2"), ["3"]);
+        assert_eq!(values(ls, "'a' .. 1, nil, not nil, 2^2"), ["a1", "nil", "true", "4.0"]);
+        assert!(values(ls, "{}")[0].starts_with("table: 0x"));
+        // not an expression, so run as a statement
+        assert_eq!(values(ls, "local t = {1, 2}"), Vec::<String>::new());
+        assert_eq!(values(ls, "local t = {1, 2}\nreturn #t, t[2]"), ["2", "2"]);
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let ls = &mut new_state();
+        assert_eq!(eval(ls, "if true then"), Eval::Incomplete);
+        assert_eq!(eval(ls, "local s = [[a"), Eval::Incomplete);
+        assert_eq!(eval(ls, "local x = 1 +"), Eval::Incomplete);
+        assert_eq!(eval(ls, "x = = 1"), Eval::Error(String::from("stdin:1: unexpected symbol near '='")));
+        assert!(matches!(eval(ls, "local t = 1 + {}"), Eval::Error(_)));
+    }
+
+    #[test]
+    fn test_session() {
+        // the globals outlive the line defining them, and errors
+        let ls = &mut new_state();
+        assert_eq!(values(ls, "x = 10"), Vec::<String>::new());
+        assert_eq!(values(ls, "function f(n) return x + n end"), Vec::<String>::new());
+        assert_eq!(error(ls, "x = y.z"), "stdin:1: attempt to index a nil value");
+        assert_eq!(values(ls, "f(1), x"), ["11", "10"]);
+        assert_eq!(values(ls, "tostring(x), type(f), type(nil)"), ["10", "function", "nil"]);
+    }
+
+    #[test]
+    fn test_base_library() {
+        let ls = &mut new_state();
+        let src = "local t = {10, 20, 30, x = 1}\n\
+                   local n, sum = 0, 0\n\
+                   for k, v in pairs(t) do n = n + 1 sum = sum + v end\n\
+                   for i, v in ipairs(t) do sum = sum + i end\n\
+                   return n, sum";
+        assert_eq!(values(ls, src), ["4", "67"]);
+        assert_eq!(values(ls, "select('#', 1, nil, 3), select(2, 'a', 'b', 'c')"), ["3", "b", "c"]);
+        assert_eq!(values(ls, "select(-1, 'a', 'b')"), ["b"]);
+        assert_eq!(values(ls, "tonumber('0x10'), tonumber(' -7 '), tonumber('1e2'), tonumber('z', 36), tonumber('x')"), ["16", "-7", "100.0", "35", "nil"]);
+        assert_eq!(values(ls, "next({}), rawlen({1, 2}), rawequal(_G, _G), _VERSION"), ["nil", "2", "true", "Lua 5.3"]);
+
+        let src = "local mt = {__tostring = function(t) return 'point' end}\n\
+                   local p = setmetatable({}, mt)\n\
+                   return tostring(p), getmetatable(p) == mt, p";
+        assert_eq!(values(ls, src), ["point", "true", "point"]);
+        assert_eq!(values(ls, "getmetatable(setmetatable({}, {__metatable = 'locked'}))"), ["locked"]);
+        assert_eq!(
+            error(ls, "local t = setmetatable(setmetatable({}, {__metatable = 1}), {})"),
+            "stdin:1: cannot change a protected metatable"
+        );
+    }
+
+    #[test]
+    fn test_libraries() {
+        let ls = &mut new_state();
+        // strings index the string library
+        assert_eq!(values(ls, "(\"x\"):rep(2)"), ["xx"]);
+        assert_eq!(values(ls, "('hello'):sub(2, -2), ('abc'):upper(), ('ab'):byte(1, -1)"), ["ell", "ABC", "97", "98"]);
+        assert_eq!(
+            values(ls, "string.format('%5d|%-3s|%.2f|%x|%g|%q', 42, 'a', 1/3, 255, 1e20, 'a\\n')"),
+            ["   42|a  |0.33|ff|1e+20|\"a\\\n\""]
+        );
+        assert_eq!(values(ls, "math.floor(3.5), math.max(2, 7, 1), math.type(1.0), math.tointeger(4.0)"), ["3", "7", "float", "4"]);
+        let src = "local t = {3, 1, 2}\n\
+                   table.sort(t)\n\
+                   table.insert(t, 1, 0)\n\
+                   return table.concat(t, ','), table.remove(t), table.unpack(t)";
+        assert_eq!(values(ls, src), ["0,1,2,3", "3", "0", "1", "2"]);
+        assert_eq!(values(ls, "local t = {'b', 'c', 'a'} table.sort(t, function(x, y) return x > y end) return table.concat(t)"), ["cba"]);
+        assert_eq!(error(ls, "string.rep()"), "stdin:1: bad argument #1 to 'rep' (string expected, got no value)");
+        assert_eq!(error(ls, "table.concat({{}})"), "stdin:1: invalid value (at index 1) in table for 'concat'");
+    }
+
+    #[test]
+    fn test_errors() {
+        let ls = &mut new_state();
+        assert_eq!(values(ls, "pcall(error, 'x')"), ["false", "x"]);
+        assert_eq!(values(ls, "pcall(function() error('x') end)"), ["false", "stdin:1: x"]);
+        assert_eq!(values(ls, "pcall(function() error('x', 0) end)"), ["false", "x"]);
+        assert_eq!(values(ls, "pcall(function() error({}) end) == false, select(2, pcall(error, 42))"), ["true", "42"]);
+        assert_eq!(values(ls, "pcall(function(a, b) return a + b end, 1, 2)"), ["true", "3"]);
+        assert_eq!(values(ls, "pcall(function() local t = nil; return t.x end)"), ["false", "stdin:1: attempt to index a nil value"]);
+        assert_eq!(values(ls, "xpcall(error, function(e) return 'handled ' .. e end, 'x')"), ["false", "handled x"]);
+        assert_eq!(values(ls, "select(2, pcall(assert, false))"), ["assertion failed!"]);
+        assert_eq!(values(ls, "assert(1, 2)"), ["1", "2"]);
+
+        // upvalues of the frames given up keep their last values
+        let src = "local get\n\
+                   pcall(function() local v = 1; get = function() return v end; v = 2; error() end)\n\
+                   return get()";
+        assert_eq!(values(ls, src), ["2"]);
+        // and the state works after errors
+        assert_eq!(error(ls, "error('boom')"), "stdin:1: boom");
+        assert_eq!(error(ls, "error()"), "(error object is a nil value)");
+        assert_eq!(error(ls, "local n = select(0)"), "stdin:1: bad argument #1 to 'select' (index out of range)");
+        assert_eq!(error(ls, "local t = setmetatable(1, {})"), "stdin:1: bad argument #1 to 'setmetatable' (table expected, got number)");
+        assert_eq!(values(ls, "1"), ["1"]);
+    }
+}
+
+#[cfg(test)]
+mod test_lua {
+
+    use crate::binchunk::{self, binary_chunk::*, header_const::*};
+    use crate::cli::lua::{arg_table, main, parse_args, Options};
+    use crate::cli::run::{execute, load, new_state};
+    use crate::compiler;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("lua -i -e x=1 -lm -- s.lua -v")).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                interactive: true,
+                version: true,
+                actions: vec![('e', String::from("x=1")), ('l', String::from("m"))],
+                script: Some(6),
+                ..Default::default()
+            }
+        );
+        assert_eq!(parse_args(&args("lua - a")).unwrap().script, Some(1));
+        assert_eq!(parse_args(&args("lua -E")).unwrap().script, None);
+        assert!(parse_args(&args("lua -e")).is_err());
+        assert!(parse_args(&args("lua -x s.lua")).is_err());
+    }
+
+    #[test]
+    fn test_arg_table() {
+        let line = args("lua -e x=1 s.lua a b");
+        let arg = arg_table(&line, parse_args(&line).unwrap().script);
+        let expected: Vec<(i64, String)> =
+            [(-3, "lua"), (-2, "-e"), (-1, "x=1"), (0, "s.lua"), (1, "a"), (2, "b")].iter().map(|&(i, a)| (i, a.to_string())).collect();
+        assert_eq!(arg, expected);
+        assert_eq!(arg_table(&args("lua -v"), None)[0], (0, String::from("lua")));
+    }
+
+    #[test]
+    fn test_shared_state() {
+        // the chunks of the command line run one after the other in the
+        // same state, where `arg` holds the command line
+        assert_eq!(main(&args("lua -e x=1 -e assert(x==1)")), 0);
+        assert_eq!(main(&args("lua -e assert(arg[0]=='lua'and(arg[1]=='-e'))")), 0);
+        assert_eq!(main(&args("lua -e x=1 -e assert(y)")), 1);
+    }
+
+    #[test]
+    fn test_require() {
+        // -l name runs the module with its name and file, then sets the
+        // global `name` to what it returns
+        let dir = std::env::temp_dir().join(format!("lua_engine_require_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("mymod.lua"), "local name, file = ...\nreturn {name = name, file = file}").unwrap();
+        std::fs::write(dir.join("nothing.lua"), "local x = 1").unwrap();
+        std::env::set_var("LUA_PATH_5_3", dir.join("?.lua"));
+        assert_eq!(main(&args("lua -l mymod -e assert(mymod.name=='mymod'and(type(mymod.file)=='string'))")), 0);
+        assert_eq!(main(&args("lua -l nothing -e assert(nothing==true)")), 0);
+        assert_eq!(main(&args("lua -l missing")), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let main_func = compiler::compile("@s.lua", b"local t = {1, 2}\nreturn #t + 1").unwrap();
+        let chunk = BinaryChunk {
+            header: Header {
+                signature: LUA_SIGNATURE,
+                version: LUAC_VERSION,
+                format: LUAC_FORMAT,
+                luac_data: LUAC_DATA,
+                cint_size: CINT_SIZE,
+                sizet_size: CSIZET_SIZE,
+                instruction_size: INSTRUCTION_SIZE,
+                lua_integer_size: LUA_INTEGER_SIZE,
+                lua_number_size: LUA_NUMBER_SIZE,
+                luac_int: LUAC_INT,
+                luac_num: LUAC_NUM,
+            },
+            size_upvalues: 1,
+            main_func,
+        };
+        // precompiled chunks are told apart by their signature
+        let values = |source: &str, chunk: &[u8]| execute(&mut new_state(), load(source, chunk).unwrap(), &[]).ok().unwrap();
+        assert_eq!(values("=stdin", &binchunk::dump(&chunk).unwrap()), ["3"]);
+        assert_eq!(values("=stdin", b"return 3"), ["3"]);
+        assert!(load("=stdin", &binchunk::dump(&chunk).unwrap()[..20]).is_err());
+
+        // floats as "%.14g" gives them, but never looking like integers
+        let src = b"local z = 0\nreturn 10/2, 2^10, 1e100, -0.0, 1/z, -1/z, 0.1, 1e-5, 2^53, 3.14159265358979";
+        let floats = values("=stdin", src);
+        assert_eq!(floats, ["5.0", "1024.0", "1e+100", "-0.0", "inf", "-inf", "0.1", "1e-05", "9.007199254741e+15", "3.1415926535898"]);
+        let nan = &values("=stdin", b"local z = 0\nreturn z/z")[0];
+        assert!(nan == "-nan" || nan == "nan");
+        assert_eq!(values("=stdin", b"local one = 1.0\nreturn one + 1, 1.5 .. '', 123456789012345678"), ["2.0", "1.5", "123456789012345678"]);
+
+        // the arguments of a script are its `...`
+        let args = [String::from("p"), String::from("q")];
+        assert_eq!(execute(&mut new_state(), load("=stdin", b"return select('#', ...), ...").unwrap(), &args).ok().unwrap(), ["2", "p", "q"]);
+
+        let failure = execute(&mut new_state(), load("@s.lua", b"local a = 1\nlocal b = a .. {}").unwrap(), &[]).err().unwrap();
+        assert!(failure.msg.starts_with("s.lua:2: "));
+        assert!(failure.traceback.contains("\ts.lua:2: in main chunk"));
+    }
+}
+
+#[cfg(test)]
+mod test_luac {
+
+    use crate::binchunk::listing;
+    use crate::cli::luac::{combine, parse_args};
+    use crate::compiler;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    fn list(src: &str, full: bool) -> String {
+        let f = compiler::compile("@t.lua", src.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        listing::list(&mut out, &f, full).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("luac -l -l -s -o - a.lua b.lua")).unwrap();
+        assert_eq!((options.listing, options.output, options.stripping), (2, None, true));
+        assert_eq!(options.files, ["a.lua", "b.lua"]);
+        let options = parse_args(&args("luac -p -- -x.lua")).unwrap();
+        assert_eq!((options.dumping, options.output.as_deref()), (false, Some("luac.out")));
+        assert_eq!(options.files, ["-x.lua"]);
+        assert!(parse_args(&args("luac -v")).is_ok());
+        assert!(parse_args(&args("luac")).is_err());
+        assert!(parse_args(&args("luac -o")).is_err());
+        assert!(parse_args(&args("luac -x a.lua")).is_err());
+    }
+
+    #[test]
+    fn test_listing() {
+        let short = list("local a = 1\nreturn a + 2", false);
+        assert_eq!(
+            short,
+            "main <@t.lua:0,0> (4 instructions)\n\
+             0+ params, 2 slots, 1 upvalues, 1 locals, 2 constants, 0 functions\n\
+             \t1\t[1]\tLOADK    \t0 -1\n\
+             \t2\t[2]\tADD      \t1 0 -2\n\
+             \t3\t[2]\tRETURN   \t1 2\n\
+             \t4\t[2]\tRETURN   \t0 1\n"
+        );
+        let full = list("local a = 1\nreturn a + 2", true);
+        assert_eq!(
+            &full[short.len()..],
+            "constants (2):\n\t1\t1\n\t2\t2\nlocals (1):\n\t0\ta\t2\t5\nupvalues (1):\n\t0\t_ENV\t1\t0\n"
+        );
+        // nested functions follow their parent
+        assert!(list("local function f() end", false).contains("\nfunction <@t.lua:1,1> (1 instructions)\n"));
+    }
+
+    #[test]
+    fn test_combine() {
+        let load = |src: &str| *compiler::compile("=t", src.as_bytes()).unwrap();
+        let main_func = combine(vec![load("local a = 1"), load("x = 1")]).unwrap();
+        assert_eq!(main_func.protos.len(), 2);
+        assert!(main_func.line_info.is_empty());
+        assert_eq!(main_func.protos[1].upvalues[0].instack, 0);
+        assert_eq!(main_func.protos[1].code, load("x = 1").code);
+        assert_eq!(combine(vec![load("x = 1")]).unwrap().upvalues[0].instack, 1);
+    }
+}
+
+#[cfg(test)]
+mod test_call_frames {
+
+    use std::rc::Rc;
+
+    use crate::api::lua_state::LuaAPI;
+    use crate::binchunk::binary_chunk::Prototype;
+    use crate::compiler;
+    use crate::state::closure::Closure;
+    use crate::state::lua_state::LuaState;
+
+    fn compile(src: &str) -> Prototype {
+        *compiler::compile("=test", src.as_bytes()).unwrap()
+    }
+
+    fn integers(ls: &LuaState, from: usize) -> Vec<i64> {
+        (from..=ls.get_top()).map(|idx| ls.to_integer(idx as isize)).collect()
+    }
+
+    #[test]
+    fn test_main() {
+        let mut ls = LuaState::new(8, compile("local a, b = 1, 2\nfor i = 1, 3 do a = a * 2 end\nreturn b, a, a + b"));
+        ls.run();
+        assert!(ls.frames().is_empty());
+        assert_eq!(integers(&ls, 1), [2, 8, 10]);
+
+        let mut ls = LuaState::new(8, compile("return ..."));
+        ls.run();
+        assert_eq!(ls.get_top(), 0);
+    }
+
+    #[test]
+    fn test_nested() {
+        let child = || compile("return function(x, y, ...) local z = x + y return z, ... end").protos.remove(0);
+        let mut ls = LuaState::new(8, compile("local a = 1"));
+        let top = ls.get_top();
+        let func = ls.frames()[0].base + top;
+
+        // all the results, and the top after them
+        ls.push_nil();
+        (1..=4).for_each(|n| ls.push_integer(n));
+        ls.push_frame(Rc::new(Closure::new(child())), func, 4, -1);
+        assert_eq!(ls.frames().len(), 2);
+        assert_eq!(ls.frames()[1].varargs.len(), 2);
+        ls.run();
+        assert_eq!(ls.frames().len(), 1);
+        assert_eq!(integers(&ls, top + 1), [3, 3, 4]);
+
+        // as many as expected, with the registers of the caller back
+        ls.set_top(top as isize);
+        ls.push_nil();
+        (1..=2).for_each(|n| ls.push_integer(n));
+        ls.push_frame(Rc::new(Closure::new(child())), func, 2, 2);
+        ls.run();
+        assert_eq!(ls.get_top(), top + 2);
+        assert!(ls.is_nil((top + 2) as isize));
+        assert_eq!(ls.to_integer((top + 1) as isize), 3);
+    }
+}
+
+#[cfg(test)]
+mod test_closures {
+
+    use std::panic;
+
+    use crate::api::lua_state::LuaAPI;
+    use crate::compiler;
+    use crate::state::lua_state::LuaState;
+
+    fn run(src: &str) -> LuaState {
+        let proto = *compiler::compile("=test", src.as_bytes()).unwrap();
+        let mut ls = LuaState::new(8, proto);
+        ls.run();
+        ls
+    }
+
+    fn integers(ls: &LuaState) -> Vec<i64> {
+        (1..=ls.get_top()).map(|idx| ls.to_integer(idx as isize)).collect()
+    }
+
+    #[test]
+    fn test_call() {
+        let ls = run("local function add(a, b) return a + b end\nreturn add(1, 2), add(3, 4, 5)");
+        assert_eq!(integers(&ls), [3, 7]);
+
+        // missing arguments are nil
+        let ls = run("local function f(a, b) return b end\nreturn f(1)");
+        assert_eq!(ls.get_top(), 1);
+        assert!(ls.is_nil(1));
+
+        let ls = run("local f = function() end\nlocal g = function() end\nreturn f == f, f == g");
+        assert!(ls.to_boolean(1));
+        assert!(!ls.to_boolean(2));
+    }
+
+    #[test]
+    fn test_multiple_results() {
+        let src = "local function f(...) return ... end\n\
+                   local function g(a, b, c) return c, b, a end\n";
+        assert_eq!(integers(&run(&format!("{}return g(f(1, 2, 3))", src))), [3, 2, 1]);
+        assert_eq!(integers(&run(&format!("{}return 0, f(1, 2, 3)", src))), [0, 1, 2, 3]);
+        assert_eq!(integers(&run(&format!("{}local a, b = f(1), f(2, 3)\nreturn a, b", src))), [1, 2]);
+        assert_eq!(integers(&run(&format!("{}local t = {{f(4, 5, 6)}}\nreturn #t, t[3]", src))), [3, 6]);
+        assert_eq!(run(&format!("{}return f()", src)).get_top(), 0);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        // deeper than the stack allows for calls that keep their frame
+        let src = |tail| format!(
+            "local function loop(self, n, tail)\n\
+               if n == 0 then return 'done' end\n\
+               if tail then return self(self, n - 1, tail) end\n\
+               local s = self(self, n - 1, tail)\n\
+               return s\n\
+             end\n\
+             return loop(loop, 300000, {})", tail);
+        let ls = run(&src(true));
+        assert_eq!(ls.to_string(1), "done");
+
+        let result = panic::catch_unwind(|| run(&src(false)));
+        let e = result.err().unwrap();
+        assert_eq!(e.downcast_ref::<&str>(), Some(&"stack overflow"));
+    }
+
+    #[test]
+    fn test_method_call() {
+        let ls = run("local t = {n = 1}\nfunction t.get(self, d) return self.n + d end\nreturn t:get(2)");
+        assert_eq!(integers(&ls), [3]);
+    }
+
+    #[test]
+    fn test_generic_for() {
+        let src = "local function iter(s, i) if i < s then return i + 1, i * 2 end end\n\
+                   local sum, fs = 0, {}\n\
+                   for i, v in iter, 3, 0 do sum = sum + v fs[i] = function() return i end end\n\
+                   return sum, fs[1](), fs[3]()";
+        assert_eq!(integers(&run(src)), [6, 1, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to index a nil value")]
+    fn test_method_call_nil() {
+        run("local t\nt:get()");
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to call a number value")]
+    fn test_call_number() {
+        run("local f = 1\nf()");
+    }
+}
+
+#[cfg(test)]
+mod test_upvalues {
+
+    use crate::api::lua_state::LuaAPI;
+    use crate::compiler;
+    use crate::state::lua_state::LuaState;
+
+    fn run(src: &str) -> Vec<i64> {
+        let proto = *compiler::compile("=test", src.as_bytes()).unwrap();
+        let mut ls = LuaState::new(8, proto);
+        ls.run();
+        (1..=ls.get_top()).map(|idx| ls.to_integer(idx as isize)).collect()
+    }
+
+    #[test]
+    fn test_open() {
+        // the closure sees the local change, and the local the closure
+        assert_eq!(run("local n = 1\nlocal function get() return n end\nn = 2\nreturn get()"), [2]);
+        assert_eq!(run("local n = 1\nlocal function inc() n = n + 1 end\ninc() inc()\nreturn n"), [3]);
+    }
+
+    #[test]
+    fn test_closed() {
+        let src = "local function counter()\n\
+                     local n = 0\n\
+                     return function() n = n + 1 return n end\n\
+                   end\n\
+                   local a, b = counter(), counter()\n\
+                   a() a()\n\
+                   return a(), b()";
+        assert_eq!(run(src), [3, 1]);
+
+        // before a tail call leaves the frame
+        let src = "local function id(f) return f end\n\
+                   local function make(n) local function get() return n end return id(get) end\n\
+                   local get = make(5)\n\
+                   local pad = {1, 2, 3, 4, 5, 6}\n\
+                   return get()";
+        assert_eq!(run(src), [5]);
+    }
+
+    #[test]
+    fn test_shared() {
+        // sibling closures share the variable, before and after it is closed
+        let src = "local function pair()\n\
+                     local n = 0\n\
+                     local function inc() n = n + 1 end\n\
+                     local function get() return n end\n\
+                     inc()\n\
+                     return inc, get\n\
+                   end\n\
+                   local inc, get = pair()\n\
+                   inc() inc()\n\
+                   return get()";
+        assert_eq!(run(src), [3]);
+
+        // and with the closures nested in them
+        let src = "local n = 0\n\
+                   local function outer() return function() n = n + 10 end end\n\
+                   outer()() outer()()\n\
+                   return n";
+        assert_eq!(run(src), [20]);
+    }
+
+    #[test]
+    fn test_loops() {
+        // a fresh variable for each iteration
+        let src = "local fs = {}\n\
+                   for i = 1, 3 do fs[i] = function() return i end end\n\
+                   return fs[1](), fs[2](), fs[3]()";
+        assert_eq!(run(src), [1, 2, 3]);
+
+        let src = "local fs = {}\n\
+                   local i = 1\n\
+                   while i <= 3 do\n\
+                     local j = i * 10\n\
+                     fs[i] = function() j = j + 1 return j end\n\
+                     i = i + 1\n\
+                   end\n\
+                   fs[1]()\n\
+                   return fs[1](), fs[2](), fs[3]()";
+        assert_eq!(run(src), [12, 21, 31]);
+
+        // closed by the jump of a break too
+        let src = "local fs = {}\n\
+                   for i = 1, 10 do\n\
+                     local k = i\n\
+                     fs[i] = function() return k end\n\
+                     if i == 2 then break end\n\
+                   end\n\
+                   return #fs, fs[1](), fs[2]()";
+        assert_eq!(run(src), [2, 1, 2]);
+    }
+
+    #[test]
+    fn test_globals() {
+        assert_eq!(run("x = 1\nlocal function f() y = x + 1 end\nf()\nreturn x, y"), [1, 2]);
+        let proto = *compiler::compile("=test", b"return z").unwrap();
+        let mut ls = LuaState::new(8, proto);
+        ls.run();
+        assert!(ls.is_nil(1));
+    }
+}
@@ -0,0 +1,23 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    InvalidOpcode(u8),
+    WrongMode { opname: &'static str },
+    OperandOutOfRange { field: &'static str, value: isize, max: isize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+            EncodeError::WrongMode { opname } => write!(f, "{} does not take operands in this form", opname.trim_end()),
+            EncodeError::OperandOutOfRange { field, value, max } => {
+                write!(f, "operand {} out of range: {} (max {})", field, value, max)
+            }
+        }
+    }
+}
+
+impl Error for EncodeError {}
@@ -0,0 +1,63 @@
+use crate::state::lua_state::LuaState;
+use crate::api::lua_state::LuaAPI;
+use crate::api::lua_vm::LuaVM;
+
+use super::instruction::Instruction;
+
+// R(A) := closure(KPROTO[Bx])
+pub fn closure(i: u32, vm: &mut LuaState) {
+    let (a, bx) = i.a_bx();
+    vm.load_proto(a + 1, bx as usize);
+}
+
+// R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1)); the arguments go
+// up to the top when B is 0, and the results set it when C is 0
+pub fn call(i: u32, vm: &mut LuaState) {
+    let (a, b, c) = i.abc();
+    let n_args = push_args(a, b, vm);
+    vm.precall(n_args, c - 1);
+}
+
+// return R(A)(R(A+1), ... ,R(A+B-1)), in the frame of the running function
+pub fn tail_call(i: u32, vm: &mut LuaState) {
+    let (a, b, _) = i.abc();
+    vm.close(1);
+    let n_args = push_args(a, b, vm);
+    vm.tail_call(a + 1, n_args);
+}
+
+// leaves the function in R(A) and its arguments at the top, giving how
+// many arguments there are
+fn push_args(a: isize, b: isize, vm: &mut LuaState) -> usize {
+    if b != 0 {
+        vm.set_top(a + b);
+    }
+    vm.get_top() - a as usize - 1
+}
+
+// R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2)); the generator is
+// called above the control variables, where its results are wanted
+pub fn tfor_call(i: u32, vm: &mut LuaState) {
+    let (mut a, _, c) = i.abc();
+    a += 1;
+
+    vm.set_top(a + 2);
+    for idx in a..a + 3 {
+        vm.push_value(idx);
+    }
+    vm.precall(2, c);
+}
+
+// return R(A), ... ,R(A+B-2); up to the top when B is 0
+pub fn return_(i: u32, vm: &mut LuaState) {
+    let (a, b, _) = i.abc();
+    vm.close(1);
+    let n = if b == 0 { vm.get_top() as isize - a } else { b - 1 };
+    vm.post_call(a + 1, n as usize);
+}
+
+// R(A), R(A+1), ..., R(A+B-2) = vararg; all of them when B is 0
+pub fn vararg(i: u32, vm: &mut LuaState) {
+    let (a, b, _) = i.abc();
+    vm.load_vararg(a + 1, b - 1);
+}
@@ -45,4 +45,15 @@ pub fn for_loop(i: u32, vm: &mut LuaState) {
         vm.add_pc(sbx);
         vm.copy(a, a+3);
     }
-}
\ No newline at end of file
+}
+
+// if R(A+1) ~= nil then { R(A) = R(A+1); pc += sBx }
+pub fn tfor_loop(i: u32, vm: &mut LuaState) {
+    let (mut a, sbx) = i.a_sbx();
+    a += 1;
+
+    if !vm.is_nil(a + 1) {
+        vm.copy(a + 1, a);
+        vm.add_pc(sbx);
+    }
+}
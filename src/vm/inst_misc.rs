@@ -13,6 +13,11 @@ pub fn jmp(i: u32, vm: &mut LuaState) {
     let (a, sbx) = i.a_sbx();
     vm.add_pc(sbx);
     if a != 0 {
-        unimplemented!()
+        vm.close(a);
     }
+}
+
+pub fn tbc(i: u32, vm: &mut LuaState) {
+    let (a, _, _) = i.abc();
+    vm.mark_to_be_closed(a + 1);
 }
\ No newline at end of file
@@ -2,7 +2,7 @@ use crate::{state::lua_state::LuaState, api::{lua_state::LuaAPI, lua_vm::LuaVM}}
 
 use super::instruction::Instruction;
 
-const LFIELDS_PER_FLUSH: isize = 50;
+pub const LFIELDS_PER_FLUSH: isize = 50;
 
 pub fn new_table(i: u32, vm: &mut LuaState) {
     let (mut a, b, c) = i.abc();
@@ -22,6 +22,18 @@ pub fn get_table(i: u32, vm: &mut LuaState) {
     vm.replace(a);
 }
 
+// R(A+1) := R(B); R(A) := R(B)[RK(C)]
+pub fn self_(i: u32, vm: &mut LuaState) {
+    let (mut a, mut b, c) = i.abc();
+    a += 1;
+    b += 1;
+
+    vm.copy(b, a + 1);
+    vm.get_rk(c);
+    vm.get_table(b);
+    vm.replace(a);
+}
+
 pub fn set_table(i: u32, vm: &mut LuaState) {
     let (mut a, b, c) = i.abc();
     a += 1;
@@ -32,13 +44,19 @@ pub fn set_table(i: u32, vm: &mut LuaState) {
 }
 
 pub fn set_list(i: u32, vm: &mut LuaState) {
-    let (mut a, b, mut c) = i.abc();
+    let (mut a, mut b, mut c) = i.abc();
     a += 1;
 
     if c > 0 {
         c -= 1;
     } else {
-        c = vm.fetch().ax();
+        c = vm.fetch().ax() - 1;
+    }
+
+    // the values from a multiple-results call, up to the top
+    let b_is_zero = b == 0;
+    if b_is_zero {
+        b = vm.get_top() as isize - a;
     }
 
     let mut idx = (c * LFIELDS_PER_FLUSH) as i64;
@@ -47,10 +65,13 @@ pub fn set_list(i: u32, vm: &mut LuaState) {
         vm.push_value(a + j);
         vm.set_i(a, idx);
     }
+
+    if b_is_zero {
+        vm.set_top(vm.register_count() as isize);
+    }
 }
 
-#[warn(dead_code)]
-fn int2fb(mut x: usize) -> usize {
+pub fn int2fb(mut x: usize) -> usize {
     let mut e = 0; /* exponent */
     if x < 8 {
         return x;
@@ -0,0 +1,46 @@
+use crate::state::lua_state::LuaState;
+use crate::api::lua_state::LuaAPI;
+use crate::api::lua_vm::LuaVM;
+
+use super::instruction::Instruction;
+
+// R(A) := UpValue[B]
+pub fn get_upval(i: u32, vm: &mut LuaState) {
+    let (mut a, b, _) = i.abc();
+    a += 1;
+
+    vm.get_upval(b);
+    vm.replace(a);
+}
+
+// UpValue[B] := R(A)
+pub fn set_upval(i: u32, vm: &mut LuaState) {
+    let (mut a, b, _) = i.abc();
+    a += 1;
+
+    vm.push_value(a);
+    vm.set_upval(b);
+}
+
+// R(A) := UpValue[B][RK(C)]
+pub fn get_tab_up(i: u32, vm: &mut LuaState) {
+    let (mut a, b, c) = i.abc();
+    a += 1;
+
+    vm.get_upval(b);
+    vm.get_rk(c);
+    vm.get_table(-2);
+    vm.replace(a);
+    vm.pop(1);
+}
+
+// UpValue[A][RK(B)] := RK(C)
+pub fn set_tab_up(i: u32, vm: &mut LuaState) {
+    let (a, b, c) = i.abc();
+
+    vm.get_upval(a);
+    vm.get_rk(b);
+    vm.get_rk(c);
+    vm.set_table(-3);
+    vm.pop(1);
+}
@@ -1,9 +1,18 @@
 use crate::state::lua_state::LuaState;
 
-use super::opcodes::{OpArgMode, OpMode, OPCODES};
+use super::encode_error::EncodeError;
+use super::opcodes::{OpArgMode, OpMode, Opcode, OPCODES, UNKNOWN};
 
-const MAXARG_BX: isize = (1 << 18) - 1;
-const MAXARG_SBX: isize = MAXARG_BX >> 1;
+pub const MAXARG_A: isize = (1 << 8) - 1;
+pub const MAXARG_B: isize = (1 << 9) - 1;
+pub const MAXARG_C: isize = (1 << 9) - 1;
+pub const MAXARG_BX: isize = (1 << 18) - 1;
+pub const MAXARG_SBX: isize = MAXARG_BX >> 1;
+pub const MAXARG_AX: isize = (1 << 26) - 1;
+
+/* B and C operands in OpArgK mode name a constant when this bit is set */
+pub const BITRK: isize = 1 << 8;
+pub const MAXINDEXRK: isize = BITRK - 1;
 
 pub trait Instruction {
     fn opname(self) -> &'static str;
@@ -16,23 +25,65 @@ pub trait Instruction {
     fn a_sbx(self) -> (isize, isize);
     fn ax(self) -> isize;
     fn execute(self, _: &mut LuaState);
+
+    fn encode_abc(op: u8, a: isize, b: isize, c: isize) -> Result<Self, EncodeError> where Self: Sized;
+    fn encode_abx(op: u8, a: isize, bx: isize) -> Result<Self, EncodeError> where Self: Sized;
+    fn encode_asbx(op: u8, a: isize, sbx: isize) -> Result<Self, EncodeError> where Self: Sized;
+    fn encode_ax(op: u8, ax: isize) -> Result<Self, EncodeError> where Self: Sized;
+}
+
+// whether an RK operand names a constant rather than a register
+pub fn is_k(x: isize) -> bool {
+    x & BITRK != 0
+}
+
+// the constant index an RK operand names
+pub fn index_k(x: isize) -> isize {
+    x & MAXINDEXRK
+}
+
+// the RK operand naming constant `idx`
+pub fn rk_ask(idx: isize) -> Result<isize, EncodeError> {
+    check("RK", idx, MAXINDEXRK)?;
+    Ok(idx | BITRK)
+}
+
+pub(crate) fn check(field: &'static str, value: isize, max: isize) -> Result<u32, EncodeError> {
+    if value < 0 || value > max {
+        return Err(EncodeError::OperandOutOfRange { field, value, max });
+    }
+    Ok(value as u32)
+}
+
+fn check_op(op: u8, mode: OpMode) -> Result<u32, EncodeError> {
+    let opcode = OPCODES.get(op as usize).ok_or(EncodeError::InvalidOpcode(op))?;
+    if opcode.op_mode != mode {
+        return Err(EncodeError::WrongMode { opname: opcode.name });
+    }
+    Ok(op as u32)
+}
+
+// the 6-bit opcode field goes past the table, for instructions that
+// did not come through the verifier
+fn opcode_info(i: u32) -> &'static Opcode {
+    OPCODES.get(i.opcode() as usize).unwrap_or(&UNKNOWN)
 }
 
 impl Instruction for u32 {
     fn opname(self) -> &'static str {
-        OPCODES[self.opcode() as usize].name
+        opcode_info(self).name
     }
 
     fn opmode(self) -> OpMode {
-        OPCODES[self.opcode() as usize].op_mode
+        opcode_info(self).op_mode
     }
 
     fn b_mode(self) -> OpArgMode {
-        OPCODES[self.opcode() as usize].arg_b_mode
+        opcode_info(self).arg_b_mode
     }
 
     fn c_mode(self) -> OpArgMode {
-        OPCODES[self.opcode() as usize].arg_c_mode
+        opcode_info(self).arg_c_mode
     }
 
     fn opcode(self) -> u8 {
@@ -65,4 +116,27 @@ impl Instruction for u32 {
         let action = OPCODES[self.opcode() as usize].action;
         action(self, vm);
     }
+
+    fn encode_abc(op: u8, a: isize, b: isize, c: isize) -> Result<u32, EncodeError> {
+        let op = check_op(op, OpMode::IABC)?;
+        Ok(check("B", b, MAXARG_B)? << 23 | check("C", c, MAXARG_C)? << 14 | check("A", a, MAXARG_A)? << 6 | op)
+    }
+
+    fn encode_abx(op: u8, a: isize, bx: isize) -> Result<u32, EncodeError> {
+        let op = check_op(op, OpMode::IABx)?;
+        Ok(check("Bx", bx, MAXARG_BX)? << 14 | check("A", a, MAXARG_A)? << 6 | op)
+    }
+
+    fn encode_asbx(op: u8, a: isize, sbx: isize) -> Result<u32, EncodeError> {
+        let op = check_op(op, OpMode::IAsBx)?;
+        if !(-MAXARG_SBX..=MAXARG_BX - MAXARG_SBX).contains(&sbx) {
+            return Err(EncodeError::OperandOutOfRange { field: "sBx", value: sbx, max: MAXARG_BX - MAXARG_SBX });
+        }
+        Ok(((sbx + MAXARG_SBX) as u32) << 14 | check("A", a, MAXARG_A)? << 6 | op)
+    }
+
+    fn encode_ax(op: u8, ax: isize) -> Result<u32, EncodeError> {
+        let op = check_op(op, OpMode::IAx)?;
+        Ok(check("Ax", ax, MAXARG_AX)? << 6 | op)
+    }
 }
@@ -1,7 +1,11 @@
 pub mod opcodes;
+pub mod opcodes_54;
 pub mod instruction;
+pub mod encode_error;
 mod inst_misc;
 mod inst_load;
 mod inst_operators;
 mod inst_for;
-mod inst_table;
\ No newline at end of file
+mod inst_call;
+mod inst_upvalue;
+pub mod inst_table;
\ No newline at end of file
@@ -1,11 +1,13 @@
 use crate::state::lua_state::LuaState;
+use super::inst_call::*;
 use super::inst_for::*;
 use super::inst_load::*;
 use super::inst_misc::*;
 use super::inst_operators::*;
 use super::inst_table::*;
+use super::inst_upvalue::*;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum OpMode {
     IABC,
     IABx,
@@ -31,20 +33,70 @@ pub struct Opcode {
     pub action: fn(i: u32, vm: &mut LuaState)
 }
 
+pub const OP_MOVE: u8 = 0;
+pub const OP_LOADK: u8 = 1;
+pub const OP_LOADKX: u8 = 2;
+pub const OP_LOADBOOL: u8 = 3;
+pub const OP_LOADNIL: u8 = 4;
+pub const OP_GETUPVAL: u8 = 5;
+pub const OP_GETTABUP: u8 = 6;
+pub const OP_GETTABLE: u8 = 7;
+pub const OP_SETTABUP: u8 = 8;
+pub const OP_SETUPVAL: u8 = 9;
+pub const OP_SETTABLE: u8 = 10;
+pub const OP_NEWTABLE: u8 = 11;
+pub const OP_SELF: u8 = 12;
+pub const OP_ADD: u8 = 13;
+pub const OP_SUB: u8 = 14;
+pub const OP_MUL: u8 = 15;
+pub const OP_MOD: u8 = 16;
+pub const OP_POW: u8 = 17;
+pub const OP_DIV: u8 = 18;
+pub const OP_IDIV: u8 = 19;
+pub const OP_BAND: u8 = 20;
+pub const OP_BOR: u8 = 21;
+pub const OP_BXOR: u8 = 22;
+pub const OP_SHL: u8 = 23;
+pub const OP_SHR: u8 = 24;
+pub const OP_UNM: u8 = 25;
+pub const OP_BNOT: u8 = 26;
+pub const OP_NOT: u8 = 27;
+pub const OP_LEN: u8 = 28;
+pub const OP_CONCAT: u8 = 29;
+pub const OP_JMP: u8 = 30;
+pub const OP_EQ: u8 = 31;
+pub const OP_LT: u8 = 32;
+pub const OP_LE: u8 = 33;
+pub const OP_TEST: u8 = 34;
+pub const OP_TESTSET: u8 = 35;
+pub const OP_CALL: u8 = 36;
+pub const OP_TAILCALL: u8 = 37;
+pub const OP_RETURN: u8 = 38;
+pub const OP_FORLOOP: u8 = 39;
+pub const OP_FORPREP: u8 = 40;
+pub const OP_TFORCALL: u8 = 41;
+pub const OP_TFORLOOP: u8 = 42;
+pub const OP_SETLIST: u8 = 43;
+pub const OP_CLOSURE: u8 = 44;
+pub const OP_VARARG: u8 = 45;
+pub const OP_EXTRAARG: u8 = 46;
+// not in 5.3: the compiler emits it for Lua 5.4's <close> locals
+pub const OP_TBC: u8 = 47;
+
 pub const OPCODES: &[Opcode] = &[
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "MOVE    ", action: move_}, // R(A) := R(B)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABx, name: "LOADK   ", action: load_k}, // R(A) := Kst(Bx)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABx, name: "LOADKX  ", action: load_kx}, // R(A) := Kst(extra arg)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "LOADBOOL", action: load_bool}, // R(A) := (bool)B; if (C) pc++
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "LOADNIL ", action: load_nil}, // R(A), R(A+1), ..., R(A+B) := nil
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "GETUPVAL", action: fail}, // R(A) := UpValue[B]
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "GETTABUP", action: fail}, // R(A) := UpValue[B][RK(C)]
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "GETUPVAL", action: get_upval}, // R(A) := UpValue[B]
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "GETTABUP", action: get_tab_up}, // R(A) := UpValue[B][RK(C)]
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "GETTABLE", action: get_table}, // R(A) := R(B)[RK(C)]
-    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SETTABUP", action: fail}, // UpValue[A][RK(B)] := RK(C)
-    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "SETUPVAL", action: fail}, // UpValue[B] := R(A)
+    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SETTABUP", action: set_tab_up}, // UpValue[A][RK(B)] := RK(C)
+    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "SETUPVAL", action: set_upval}, // UpValue[B] := R(A)
     Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SETTABLE", action: set_table}, // R(A)[RK(B)] := RK(C)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "NEWTABLE", action: new_table}, // R(A) := {} (size = B,C)
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SELF    ", action: fail}, // R(A+1) := R(B); R(A) := R(B)[RK(C)]
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SELF    ", action: self_}, // R(A+1) := R(B); R(A) := R(B)[RK(C)]
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "ADD     ", action: add}, // R(A) := RK(B) + RK(C)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "SUB     ", action: sub}, // R(A) := RK(B) - RK(C)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "MUL     ", action: mul}, // R(A) := RK(B) * RK(C)
@@ -68,19 +120,23 @@ pub const OPCODES: &[Opcode] = &[
     Opcode{ test_flag: true, set_a_flag: false, arg_b_mode: OpArgMode::OpArgK, arg_c_mode: OpArgMode::OpArgK, op_mode: OpMode::IABC, name: "LE      ", action: le}, // if ((RK(B) <= RK(C)) ~= A) then pc++
     Opcode{ test_flag: true, set_a_flag: false, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TEST    ", action: test}, // if not (R(A) <=> C) then pc++
     Opcode{ test_flag: true, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TESTSET ", action: test_set}, // if (R(B) <=> C) then R(A) := R(B) else pc++
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "CALL    ", action: fail}, // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TAILCALL", action: fail}, // return R(A)(R(A+1), ... ,R(A+B-1))
-    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "RETURN  ", action: fail}, // return R(A), ... ,R(A+B-2)
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "CALL    ", action: call}, // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TAILCALL", action: tail_call}, // return R(A)(R(A+1), ... ,R(A+B-1))
+    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "RETURN  ", action: return_}, // return R(A), ... ,R(A+B-2)
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IAsBx, name: "FORLOOP ", action: for_loop}, // R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
     Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IAsBx, name: "FORPREP ", action: for_prep}, // R(A)-=R(A+2); pc+=sBx
-    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TFORCALL", action: fail},  // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IAsBx, name: "TFORLOOP", action: fail}, // if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
+    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "TFORCALL", action: tfor_call},  // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgR, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IAsBx, name: "TFORLOOP", action: tfor_loop}, // if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
     Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IABC, name: "SETLIST ", action: set_list},  // R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABx, name: "CLOSURE ", action: fail},  // R(A) := closure(KPROTO[Bx])
-    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "VARARG  ", action: fail},  // R(A), R(A+1), ..., R(A+B-2) = vararg
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABx, name: "CLOSURE ", action: closure},  // R(A) := closure(KPROTO[Bx])
+    Opcode{ test_flag: false, set_a_flag: true, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "VARARG  ", action: vararg},  // R(A), R(A+1), ..., R(A+B-2) = vararg
     Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgU, arg_c_mode: OpArgMode::OpArgU, op_mode: OpMode::IAx, name: "EXTRAARG", action: fail},   // extra (larger) argument for previous opcode
+    Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "TBC     ", action: tbc},       // mark R(A) to be closed
 ];
 
+// what an opcode past the end of the table is listed as
+pub const UNKNOWN: Opcode = Opcode{ test_flag: false, set_a_flag: false, arg_b_mode: OpArgMode::OpArgN, arg_c_mode: OpArgMode::OpArgN, op_mode: OpMode::IABC, name: "UNKNOWN ", action: fail};
+
 fn fail(_: u32, _: &mut LuaState) {
     unimplemented!()
 }
\ No newline at end of file
@@ -0,0 +1,213 @@
+use super::encode_error::EncodeError;
+use super::instruction::check;
+
+// the instruction set of Lua 5.4, which this VM does not run: it is
+// only here for listing 5.4 chunks and converting them to JSON and back
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OpMode54 {
+    IABC,
+    IABx,
+    IAsBx,
+    IAx,
+    IsJ
+}
+
+pub struct Opcode54 {
+    pub op_mode: OpMode54,
+    pub name: &'static str
+}
+
+const fn op(op_mode: OpMode54, name: &'static str) -> Opcode54 {
+    Opcode54 { op_mode, name }
+}
+
+pub const OPCODES_54: &[Opcode54] = &[
+    op(OpMode54::IABC, "MOVE"),
+    op(OpMode54::IAsBx, "LOADI"),
+    op(OpMode54::IAsBx, "LOADF"),
+    op(OpMode54::IABx, "LOADK"),
+    op(OpMode54::IABx, "LOADKX"),
+    op(OpMode54::IABC, "LOADFALSE"),
+    op(OpMode54::IABC, "LFALSESKIP"),
+    op(OpMode54::IABC, "LOADTRUE"),
+    op(OpMode54::IABC, "LOADNIL"),
+    op(OpMode54::IABC, "GETUPVAL"),
+    op(OpMode54::IABC, "SETUPVAL"),
+    op(OpMode54::IABC, "GETTABUP"),
+    op(OpMode54::IABC, "GETTABLE"),
+    op(OpMode54::IABC, "GETI"),
+    op(OpMode54::IABC, "GETFIELD"),
+    op(OpMode54::IABC, "SETTABUP"),
+    op(OpMode54::IABC, "SETTABLE"),
+    op(OpMode54::IABC, "SETI"),
+    op(OpMode54::IABC, "SETFIELD"),
+    op(OpMode54::IABC, "NEWTABLE"),
+    op(OpMode54::IABC, "SELF"),
+    op(OpMode54::IABC, "ADDI"),
+    op(OpMode54::IABC, "ADDK"),
+    op(OpMode54::IABC, "SUBK"),
+    op(OpMode54::IABC, "MULK"),
+    op(OpMode54::IABC, "MODK"),
+    op(OpMode54::IABC, "POWK"),
+    op(OpMode54::IABC, "DIVK"),
+    op(OpMode54::IABC, "IDIVK"),
+    op(OpMode54::IABC, "BANDK"),
+    op(OpMode54::IABC, "BORK"),
+    op(OpMode54::IABC, "BXORK"),
+    op(OpMode54::IABC, "SHRI"),
+    op(OpMode54::IABC, "SHLI"),
+    op(OpMode54::IABC, "ADD"),
+    op(OpMode54::IABC, "SUB"),
+    op(OpMode54::IABC, "MUL"),
+    op(OpMode54::IABC, "MOD"),
+    op(OpMode54::IABC, "POW"),
+    op(OpMode54::IABC, "DIV"),
+    op(OpMode54::IABC, "IDIV"),
+    op(OpMode54::IABC, "BAND"),
+    op(OpMode54::IABC, "BOR"),
+    op(OpMode54::IABC, "BXOR"),
+    op(OpMode54::IABC, "SHL"),
+    op(OpMode54::IABC, "SHR"),
+    op(OpMode54::IABC, "MMBIN"),
+    op(OpMode54::IABC, "MMBINI"),
+    op(OpMode54::IABC, "MMBINK"),
+    op(OpMode54::IABC, "UNM"),
+    op(OpMode54::IABC, "BNOT"),
+    op(OpMode54::IABC, "NOT"),
+    op(OpMode54::IABC, "LEN"),
+    op(OpMode54::IABC, "CONCAT"),
+    op(OpMode54::IABC, "CLOSE"),
+    op(OpMode54::IABC, "TBC"),
+    op(OpMode54::IsJ, "JMP"),
+    op(OpMode54::IABC, "EQ"),
+    op(OpMode54::IABC, "LT"),
+    op(OpMode54::IABC, "LE"),
+    op(OpMode54::IABC, "EQK"),
+    op(OpMode54::IABC, "EQI"),
+    op(OpMode54::IABC, "LTI"),
+    op(OpMode54::IABC, "LEI"),
+    op(OpMode54::IABC, "GTI"),
+    op(OpMode54::IABC, "GEI"),
+    op(OpMode54::IABC, "TEST"),
+    op(OpMode54::IABC, "TESTSET"),
+    op(OpMode54::IABC, "CALL"),
+    op(OpMode54::IABC, "TAILCALL"),
+    op(OpMode54::IABC, "RETURN"),
+    op(OpMode54::IABC, "RETURN0"),
+    op(OpMode54::IABC, "RETURN1"),
+    op(OpMode54::IABx, "FORLOOP"),
+    op(OpMode54::IABx, "FORPREP"),
+    op(OpMode54::IABx, "TFORPREP"),
+    op(OpMode54::IABC, "TFORCALL"),
+    op(OpMode54::IABx, "TFORLOOP"),
+    op(OpMode54::IABC, "SETLIST"),
+    op(OpMode54::IABx, "CLOSURE"),
+    op(OpMode54::IABC, "VARARG"),
+    op(OpMode54::IABC, "VARARGPREP"),
+    op(OpMode54::IAx, "EXTRAARG"),
+];
+
+const MAXARG_A: isize = (1 << 8) - 1;
+const MAXARG_B: isize = (1 << 8) - 1;
+const MAXARG_C: isize = (1 << 8) - 1;
+const MAXARG_BX: isize = (1 << 17) - 1;
+const MAXARG_SBX: isize = MAXARG_BX >> 1;
+const MAXARG_AX: isize = (1 << 25) - 1;
+const MAXARG_SJ: isize = MAXARG_AX >> 1;
+
+// the fields of a 5.4 instruction: op 7 bits, A 8, k 1, B 8 and C 8,
+// with Bx taking k, B and C and sJ everything but op
+pub trait Instruction54 {
+    fn opcode_54(self) -> Option<&'static Opcode54>;
+    fn a_54(self) -> isize;
+    fn k_54(self) -> bool;
+    fn b_54(self) -> isize;
+    fn c_54(self) -> isize;
+    fn bx_54(self) -> isize;
+    fn sbx_54(self) -> isize;
+    fn ax_54(self) -> isize;
+    fn sj_54(self) -> isize;
+}
+
+impl Instruction54 for u32 {
+    fn opcode_54(self) -> Option<&'static Opcode54> {
+        OPCODES_54.get((self & 0x7F) as usize)
+    }
+
+    fn a_54(self) -> isize {
+        (self >> 7 & 0xFF) as isize
+    }
+
+    fn k_54(self) -> bool {
+        self >> 15 & 1 != 0
+    }
+
+    fn b_54(self) -> isize {
+        (self >> 16 & 0xFF) as isize
+    }
+
+    fn c_54(self) -> isize {
+        (self >> 24) as isize
+    }
+
+    fn bx_54(self) -> isize {
+        (self >> 15) as isize
+    }
+
+    fn sbx_54(self) -> isize {
+        self.bx_54() - MAXARG_SBX
+    }
+
+    fn ax_54(self) -> isize {
+        (self >> 7) as isize
+    }
+
+    fn sj_54(self) -> isize {
+        self.ax_54() - MAXARG_SJ
+    }
+}
+
+fn check_op(op: u8, mode: OpMode54) -> Result<u32, EncodeError> {
+    let opcode = OPCODES_54.get(op as usize).ok_or(EncodeError::InvalidOpcode(op))?;
+    if opcode.op_mode != mode {
+        return Err(EncodeError::WrongMode { opname: opcode.name });
+    }
+    Ok(op as u32)
+}
+
+// the inverse of the accessors above
+pub fn encode_abck_54(op: u8, a: isize, k: bool, b: isize, c: isize) -> Result<u32, EncodeError> {
+    let op = check_op(op, OpMode54::IABC)?;
+    Ok(check("C", c, MAXARG_C)? << 24
+        | check("B", b, MAXARG_B)? << 16
+        | (k as u32) << 15
+        | check("A", a, MAXARG_A)? << 7
+        | op)
+}
+
+pub fn encode_abx_54(op: u8, a: isize, bx: isize) -> Result<u32, EncodeError> {
+    let op = check_op(op, OpMode54::IABx)?;
+    Ok(check("Bx", bx, MAXARG_BX)? << 15 | check("A", a, MAXARG_A)? << 7 | op)
+}
+
+pub fn encode_asbx_54(op: u8, a: isize, sbx: isize) -> Result<u32, EncodeError> {
+    let op = check_op(op, OpMode54::IAsBx)?;
+    if !(-MAXARG_SBX..=MAXARG_BX - MAXARG_SBX).contains(&sbx) {
+        return Err(EncodeError::OperandOutOfRange { field: "sBx", value: sbx, max: MAXARG_BX - MAXARG_SBX });
+    }
+    Ok(((sbx + MAXARG_SBX) as u32) << 15 | check("A", a, MAXARG_A)? << 7 | op)
+}
+
+pub fn encode_ax_54(op: u8, ax: isize) -> Result<u32, EncodeError> {
+    let op = check_op(op, OpMode54::IAx)?;
+    Ok(check("Ax", ax, MAXARG_AX)? << 7 | op)
+}
+
+pub fn encode_sj_54(op: u8, sj: isize) -> Result<u32, EncodeError> {
+    let op = check_op(op, OpMode54::IsJ)?;
+    if !(-MAXARG_SJ..=MAXARG_AX - MAXARG_SJ).contains(&sj) {
+        return Err(EncodeError::OperandOutOfRange { field: "sJ", value: sj, max: MAXARG_AX - MAXARG_SJ });
+    }
+    Ok(((sj + MAXARG_SJ) as u32) << 7 | op)
+}